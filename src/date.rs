@@ -0,0 +1,313 @@
+// Dates as git reads and writes them: the raw `<seconds> <tz>` of ident
+// lines, approxidate input for selectors and expiry settings, and the
+// forms they are shown in.
+
+use std::error::Error;
+
+fn now() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs() as i64)
+}
+
+// Seconds east of UTC of a `+hhmm` or `-hhmm` timezone
+fn tz_offset(tz: &str) -> i64 {
+    let zone: i64 = tz.parse().unwrap_or(0);
+    zone.signum() * ((zone.abs() / 100) * 3600 + (zone.abs() % 100) * 60)
+}
+
+// Seconds east of UTC of the local timezone at `time`
+fn local_utc_offset(time: i64) -> i64 {
+    #[cfg(unix)]
+    {
+        let time = time as libc::time_t;
+        // SAFETY: localtime_r only writes the tm it is given
+        let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+        if unsafe { libc::localtime_r(&time, &mut tm) }.is_null() {
+            return 0;
+        }
+        tm.tm_gmtoff as i64
+    }
+    // Elsewhere the local timezone is not looked up, and dates are UTC
+    #[cfg(not(unix))]
+    {
+        let _ = time;
+        0
+    }
+}
+
+// The current time in the raw form, in the local timezone.
+pub(crate) fn now_raw() -> String {
+    let now = now();
+    let offset = local_utc_offset(now) / 60;
+    format!(
+        "{} {}{:02}{:02}",
+        now,
+        if offset < 0 { '-' } else { '+' },
+        offset.abs() / 60,
+        offset.abs() % 60
+    )
+}
+
+// Dates are accepted in git's internal `<seconds> <tz>` form, optionally
+// with a leading `@`.
+pub(crate) fn parse_raw(date: &str) -> Result<String, Box<dyn Error>> {
+    let invalid = || format!("invalid date format: {}", date);
+    let (seconds, tz) = date.split_once(' ').ok_or_else(invalid)?;
+    let seconds = seconds.strip_prefix('@').unwrap_or(seconds);
+    let tz_valid = tz.len() == 5
+        && (tz.starts_with('+') || tz.starts_with('-'))
+        && tz[1..].bytes().all(|b| b.is_ascii_digit());
+    if seconds.parse::<u64>().is_err() || !tz_valid {
+        return Err(From::from(invalid()));
+    }
+
+    Ok(format!("{} {}", seconds, tz))
+}
+
+// An expiry setting, where as in git `now` and `all` expire everything,
+// even what was written this second
+pub(crate) fn expiry(date: &str) -> Option<i64> {
+    match date.trim().to_ascii_lowercase().as_str() {
+        "now" | "all" => Some(i64::MAX),
+        _ => approxidate(date),
+    }
+}
+
+// The subset of git's approxidate that reflog selectors and expiry
+// settings use: `now`, `yesterday`, `never`, `<n>.<unit>.ago`, a unix
+// timestamp, or `YYYY-MM-DD[ HH:MM[:SS]]`, taken as UTC.
+pub(crate) fn approxidate(date: &str) -> Option<i64> {
+    approxidate_at(date, now())
+}
+
+fn approxidate_at(date: &str, now: i64) -> Option<i64> {
+    let date = date.trim().to_ascii_lowercase();
+    match date.as_str() {
+        "now" => return Some(now),
+        "yesterday" => return Some(now - 86400),
+        "never" | "false" => return Some(0),
+        _ => {}
+    }
+    if let Ok(seconds) = date.parse::<i64>() {
+        return Some(seconds);
+    }
+
+    if let Some((year, rest)) = date.split_once('-') {
+        let (month, rest) = rest.split_once('-')?;
+        let (day, time) = match rest.split_once([' ', 't']) {
+            Some((day, time)) => (day, time),
+            None => (rest, "0:0"),
+        };
+        let mut clock = time.split(':').map(str::parse::<i64>);
+        let hours = clock.next()?.ok()?;
+        let minutes = clock.next().unwrap_or(Ok(0)).ok()?;
+        let seconds = clock.next().unwrap_or(Ok(0)).ok()?;
+        let (year, month, day) = (year.parse().ok()?, month.parse().ok()?, day.parse().ok()?);
+        // Out of range fields are refused rather than carried over, which
+        // also keeps the arithmetic below from overflowing
+        if !(0..=9999).contains(&year)
+            || !(1..=12).contains(&month)
+            || !(1..=31).contains(&day)
+            || !(0..24).contains(&hours)
+            || !(0..60).contains(&minutes)
+            || !(0..=60).contains(&seconds)
+        {
+            return None;
+        }
+        let days = days_from_civil(year, month, day);
+        return Some(days * 86400 + hours * 3600 + minutes * 60 + seconds);
+    }
+
+    // Amounts of time, as in `2.weeks.ago` or `90 days`
+    let words: Vec<&str> = date
+        .split(['.', ' ', ','])
+        .filter(|word| !word.is_empty() && *word != "ago")
+        .collect();
+    if words.is_empty() || !words.len().is_multiple_of(2) {
+        return None;
+    }
+    let mut ago = 0;
+    for pair in words.chunks(2) {
+        let count: i64 = pair[0].parse().ok()?;
+        let unit = match pair[1].trim_end_matches('s') {
+            "second" => 1,
+            "minute" => 60,
+            "hour" => 3600,
+            "day" => 86400,
+            "week" => 7 * 86400,
+            "month" => 30 * 86400,
+            "year" => 365 * 86400,
+            _ => return None,
+        };
+        ago = count.checked_mul(unit)?.checked_add(ago)?;
+    }
+    now.checked_sub(ago)
+}
+
+// The YYYY-MM-DD of an ident line's date, in its own timezone.
+pub(crate) fn ident_short(ident: &str) -> String {
+    let mut parts = ident.rsplitn(3, ' ');
+    let (Some(tz), Some(seconds)) = (parts.next(), parts.next()) else {
+        return String::new();
+    };
+    let (Ok(seconds), Ok(_)) = (seconds.parse::<i64>(), tz.parse::<i64>()) else {
+        return String::new();
+    };
+    let (year, month, day) = civil_from_days((seconds + tz_offset(tz)).div_euclid(86400));
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+// A time as git's RFC 2822 dates show it, like `Wed, 15 Nov 2023
+// 22:13:20 +0000`, in the given timezone.
+pub(crate) fn rfc2822(seconds: i64, tz: &str) -> String {
+    let local = seconds + tz_offset(tz);
+    let days = local.div_euclid(86400);
+    let (year, month, day) = civil_from_days(days);
+    let time = local.rem_euclid(86400);
+    let weekdays = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+    let months = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    format!(
+        "{}, {} {} {} {:02}:{:02}:{:02} {}",
+        weekdays[(days + 4).rem_euclid(7) as usize],
+        day,
+        months[month as usize - 1],
+        year,
+        time / 3600,
+        time / 60 % 60,
+        time % 60,
+        tz
+    )
+}
+
+// Civil date from days since the epoch, after Howard Hinnant.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+// Days since the epoch of a civil date, the inverse of the above.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn civil_dates_round_trip() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+        assert_eq!(civil_from_days(11016), (2000, 2, 29));
+        assert_eq!(days_from_civil(2000, 3, 1), 11017);
+        for days in (-800_000..800_000).step_by(997) {
+            let (year, month, day) = civil_from_days(days);
+            assert_eq!(days_from_civil(year, month, day), days);
+        }
+    }
+
+    #[test]
+    fn raw_dates_are_checked() {
+        assert_eq!(parse_raw("1112911993 +0200").unwrap(), "1112911993 +0200");
+        assert_eq!(parse_raw("@0 -0130").unwrap(), "0 -0130");
+        for date in [
+            "",
+            "1112911993",
+            "-1 +0000",
+            "1 0000",
+            "1 +00000",
+            "1 +0a00",
+        ] {
+            assert!(parse_raw(date).is_err(), "{}", date);
+        }
+
+        let now = now_raw();
+        assert_eq!(parse_raw(&now).unwrap(), now);
+        assert!(now.split(' ').next().unwrap().parse::<i64>().unwrap() > 1112911993);
+    }
+
+    #[test]
+    fn dates_show_in_their_own_timezone() {
+        assert_eq!(
+            rfc2822(1112911993, "+0000"),
+            "Thu, 7 Apr 2005 22:13:13 +0000"
+        );
+        assert_eq!(
+            rfc2822(1112911993, "+0200"),
+            "Fri, 8 Apr 2005 00:13:13 +0200"
+        );
+        assert_eq!(
+            rfc2822(1112911993, "-0930"),
+            "Thu, 7 Apr 2005 12:43:13 -0930"
+        );
+        assert_eq!(rfc2822(-1, "+0000"), "Wed, 31 Dec 1969 23:59:59 +0000");
+
+        assert_eq!(
+            ident_short("A U Thor <a@example.com> 1112911993 +0000"),
+            "2005-04-07"
+        );
+        assert_eq!(
+            ident_short("A U Thor <a@example.com> 1112911993 +0200"),
+            "2005-04-08"
+        );
+        assert_eq!(ident_short("A U Thor <a@example.com>"), "");
+        assert_eq!(ident_short("A <a@example.com> x +0000"), "");
+    }
+
+    #[test]
+    fn approxidates_read_like_git() {
+        let now = 1112911993;
+        assert_eq!(approxidate_at("now", now), Some(now));
+        assert_eq!(approxidate_at(" Yesterday ", now), Some(now - 86400));
+        assert_eq!(approxidate_at("never", now), Some(0));
+        assert_eq!(approxidate_at("1000", now), Some(1000));
+        assert_eq!(approxidate_at("2.weeks.ago", now), Some(now - 14 * 86400));
+        assert_eq!(
+            approxidate_at("1 hour, 30 minutes ago", now),
+            Some(now - 5400)
+        );
+        assert_eq!(approxidate_at("90 days", now), Some(now - 90 * 86400));
+        assert_eq!(approxidate_at("2005-04-07", now), Some(1112832000));
+        assert_eq!(approxidate_at("2005-04-07 22:13:13", now), Some(now));
+        assert_eq!(approxidate_at("2005-04-07T22:13", now), Some(now - 13));
+
+        assert_eq!(expiry("all"), Some(i64::MAX));
+        assert_eq!(expiry(" NOW"), Some(i64::MAX));
+        assert_eq!(expiry("never"), Some(0));
+    }
+
+    #[test]
+    fn approxidate_refuses_what_does_not_fit() {
+        for date in [
+            "",
+            "tomorrow",
+            "2 fortnights ago",
+            "weeks ago",
+            "9999999999999 years ago",
+            "9223372036854775807 seconds 1 second ago",
+            "99999999999999999-01-01",
+            "2005-13-07",
+            "2005-04-07 25:00",
+            "2005-04",
+        ] {
+            assert_eq!(approxidate_at(date, 1112911993), None, "{}", date);
+        }
+    }
+}
@@ -1,24 +1,40 @@
+use std::cell::{OnceCell, RefCell};
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet};
 use std::error::Error;
 use std::fmt;
 use std::fs;
 use std::fs::File;
-use std::io::{self, Read, Write};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::net::{TcpListener, TcpStream};
 use std::path::Path;
 use std::path::PathBuf;
+use std::process::{self, Stdio};
 use std::string::String;
 use std::string::ToString;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 
 use clap::{Parser, Subcommand};
-use flate2::read::ZlibDecoder;
+use flate2::read::{GzDecoder, ZlibDecoder};
 use flate2::write::ZlibEncoder;
-use hex;
+use flate2::{Compression, Crc, Decompress, FlushDecompress, Status};
 use ini::Ini;
 use sha1::{Digest, Sha1};
 
+mod date;
+mod pathspec;
+mod refspec;
+
+use pathspec::Pathspec;
+
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 #[command(propagate_version = true)]
 struct Cli {
+    #[arg(long, global = true)]
+    namespace: Option<String>,
     #[command(subcommand)]
     command: Option<Commands>,
 }
@@ -30,26 +46,384 @@ enum Commands {
         path: String,
     },
     CatFile {
+        #[arg(short = 't', group = "query")]
+        show_type: bool,
+        #[arg(short = 's', group = "query")]
+        show_size: bool,
+        #[arg(short = 'e', group = "query")]
+        exists: bool,
+        #[arg(short = 'p', group = "query")]
+        pretty: bool,
+        #[arg(long, group = "query")]
+        textconv: bool,
         object_type: String,
-        object: String,
-    }
+        object: Option<String>,
+    },
+    CountObjects {
+        #[arg(short, long)]
+        verbose: bool,
+    },
+    InterpretTrailers {
+        #[arg(long = "trailer")]
+        trailers: Vec<String>,
+        #[arg(long = "where")]
+        placement: Option<String>,
+        #[arg(long)]
+        if_exists: Option<String>,
+        #[arg(long)]
+        if_missing: Option<String>,
+        #[arg(long)]
+        only_trailers: bool,
+        #[arg(long)]
+        unfold: bool,
+        #[arg(long)]
+        parse: bool,
+        #[arg(long)]
+        in_place: bool,
+        files: Vec<String>,
+    },
+    IndexPack {
+        #[arg(short = 'o')]
+        index_file: Option<String>,
+        #[arg(long)]
+        stdin: bool,
+        #[arg(long)]
+        strict: bool,
+        pack_file: Option<String>,
+    },
+    UnpackObjects {
+        #[arg(short = 'n')]
+        dry_run: bool,
+        #[arg(long)]
+        strict: bool,
+    },
+    UploadPack {
+        #[arg(long)]
+        stateless_rpc: bool,
+        #[arg(long)]
+        advertise_refs: bool,
+        directory: String,
+    },
+    ReceivePack {
+        #[arg(long)]
+        stateless_rpc: bool,
+        #[arg(long)]
+        advertise_refs: bool,
+        directory: String,
+    },
+    Daemon {
+        #[arg(long)]
+        base_path: Option<String>,
+        #[arg(long)]
+        export_all: bool,
+        #[arg(long, default_value = "0.0.0.0")]
+        listen: String,
+        #[arg(long, default_value_t = 9418)]
+        port: u16,
+        #[arg(long, default_value_t = 32)]
+        max_connections: usize,
+        // Seconds to wait for the request, and then for each read or write
+        // while serving it; 0 waits forever
+        #[arg(long, default_value_t = 10)]
+        init_timeout: u64,
+        #[arg(long, default_value_t = 300)]
+        timeout: u64,
+        directories: Vec<String>,
+    },
+    ServeHttp {
+        #[arg(long, default_value = "127.0.0.1")]
+        listen: String,
+        #[arg(long, default_value_t = 8080)]
+        port: u16,
+        #[arg(long)]
+        user: Option<String>,
+        #[arg(long, default_value_t = 32)]
+        max_connections: usize,
+        // Seconds to wait for each read or write; 0 waits forever
+        #[arg(long, default_value_t = 60)]
+        timeout: u64,
+        directory: String,
+    },
+    DiffTree {
+        #[arg(short)]
+        recursive: bool,
+        #[arg(short = 't')]
+        show_trees: bool,
+        #[arg(long)]
+        root: bool,
+        #[arg(long)]
+        name_only: bool,
+        #[arg(long)]
+        name_status: bool,
+        #[arg(long, num_args = 0..=1, require_equals = true)]
+        abbrev: Option<Option<usize>>,
+        old: String,
+        new: Option<String>,
+        #[arg(last = true)]
+        paths: Vec<PathBuf>,
+    },
+    RevList {
+        #[arg(long)]
+        all: bool,
+        #[arg(long)]
+        topo_order: bool,
+        #[arg(long)]
+        date_order: bool,
+        #[arg(long)]
+        reverse: bool,
+        #[arg(long)]
+        first_parent: bool,
+        #[arg(short = 'n', long)]
+        max_count: Option<usize>,
+        #[arg(long)]
+        count: bool,
+        #[arg(long)]
+        abbrev_commit: bool,
+        #[arg(long)]
+        abbrev: Option<usize>,
+        revisions: Vec<String>,
+        #[arg(last = true)]
+        paths: Vec<PathBuf>,
+    },
+    Cherry {
+        #[arg(short)]
+        verbose: bool,
+        upstream: Option<String>,
+        head: Option<String>,
+        limit: Option<String>,
+    },
+    PatchId {
+        #[arg(long, overrides_with = "unstable")]
+        stable: bool,
+        #[arg(long)]
+        unstable: bool,
+    },
+    NameRev {
+        #[arg(long)]
+        tags: bool,
+        #[arg(long)]
+        name_only: bool,
+        #[arg(long)]
+        no_undefined: bool,
+        #[arg(long)]
+        always: bool,
+        #[arg(long, alias = "stdin")]
+        annotate_stdin: bool,
+        revisions: Vec<String>,
+    },
+    Reflog {
+        #[command(subcommand)]
+        command: ReflogCommand,
+    },
+    VerifyTag {
+        #[arg(short, long)]
+        verbose: bool,
+        #[arg(long)]
+        raw: bool,
+        #[arg(required = true)]
+        tags: Vec<String>,
+    },
+    RevParse {
+        #[arg(allow_hyphen_values = true, trailing_var_arg = true)]
+        args: Vec<String>,
+    },
+    CheckRefFormat {
+        #[arg(long, alias = "print")]
+        normalize: bool,
+        #[arg(long, overrides_with = "no_allow_onelevel")]
+        allow_onelevel: bool,
+        #[arg(long)]
+        no_allow_onelevel: bool,
+        #[arg(long)]
+        refspec_pattern: bool,
+        #[arg(long, conflicts_with_all = ["normalize", "allow_onelevel", "no_allow_onelevel", "refspec_pattern"])]
+        branch: bool,
+        #[arg(allow_hyphen_values = true)]
+        refname: String,
+    },
+    Config {
+        #[arg(long, conflicts_with = "worktree")]
+        local: bool,
+        #[arg(long)]
+        worktree: bool,
+        #[arg(long, conflicts_with_all = ["list", "value"])]
+        unset: bool,
+        #[arg(long, conflicts_with_all = ["list", "value", "unset"])]
+        unset_all: bool,
+        #[arg(long, conflicts_with_all = ["list", "unset"], requires = "value")]
+        replace_all: bool,
+        #[arg(short, long, conflicts_with_all = ["name", "unset"])]
+        list: bool,
+        #[arg(required_unless_present = "list")]
+        name: Option<String>,
+        value: Option<String>,
+    },
+    Var {
+        #[arg(short = 'l', conflicts_with = "variable")]
+        list: bool,
+        #[arg(required_unless_present = "list")]
+        variable: Option<String>,
+    },
+    UpdateIndex {
+        #[arg(long)]
+        add: bool,
+        #[arg(long)]
+        remove: bool,
+        #[arg(long)]
+        refresh: bool,
+        #[arg(long)]
+        cacheinfo: Vec<String>,
+        #[arg(long, allow_hyphen_values = true)]
+        chmod: Option<String>,
+        #[arg(long, overrides_with = "no_skip_worktree")]
+        skip_worktree: bool,
+        #[arg(long)]
+        no_skip_worktree: bool,
+        #[arg(long, overrides_with = "no_assume_unchanged")]
+        assume_unchanged: bool,
+        #[arg(long)]
+        no_assume_unchanged: bool,
+        paths: Vec<PathBuf>,
+    },
+    CheckoutIndex {
+        #[arg(short, long)]
+        all: bool,
+        #[arg(short, long)]
+        force: bool,
+        #[arg(short)]
+        update: bool,
+        #[arg(long, default_value = "")]
+        prefix: String,
+        paths: Vec<PathBuf>,
+    },
+    LsFiles {
+        #[arg(short, long)]
+        cached: bool,
+        #[arg(short, long)]
+        deleted: bool,
+        #[arg(short, long)]
+        modified: bool,
+        #[arg(short, long)]
+        others: bool,
+        #[arg(short, long)]
+        ignored: bool,
+        #[arg(short = 'z')]
+        null: bool,
+        #[arg(short = 'x', long, value_name = "pattern")]
+        exclude: Vec<String>,
+        #[arg(long)]
+        exclude_standard: bool,
+        #[arg(long)]
+        error_unmatch: bool,
+        paths: Vec<PathBuf>,
+    },
+    Notes {
+        #[arg(long = "ref")]
+        notes_ref: Option<String>,
+        #[command(subcommand)]
+        command: Option<NotesCommand>,
+    },
+    VerifyPack {
+        #[arg(short, long)]
+        verbose: bool,
+        #[arg(short = 's', long)]
+        stat_only: bool,
+        #[arg(required = true)]
+        packs: Vec<String>,
+    },
+    Repack {
+        #[arg(short = 'a')]
+        all: bool,
+        #[arg(short = 'd')]
+        delete: bool,
+        #[arg(long)]
+        cruft: bool,
+        #[arg(long, value_name = "approxidate", requires = "cruft")]
+        cruft_expiration: Option<String>,
+        #[arg(long, value_name = "n")]
+        max_pack_size: Option<String>,
+        #[arg(short, long)]
+        quiet: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum ReflogCommand {
+    Expire {
+        #[arg(long)]
+        expire: Option<String>,
+        #[arg(long)]
+        all: bool,
+        refs: Vec<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum NotesCommand {
+    List {
+        object: Option<String>,
+    },
+    Add {
+        #[arg(short = 'm')]
+        messages: Vec<String>,
+        #[arg(short = 'F')]
+        file: Option<String>,
+        #[arg(short)]
+        force: bool,
+        object: Option<String>,
+    },
+    Show {
+        object: Option<String>,
+    },
+    Edit {
+        object: Option<String>,
+    },
+    Remove {
+        objects: Vec<String>,
+    },
 }
 
 struct GitRepository {
     worktree: String,
     gitdir: String,
+    odb: OnceCell<OdbStack>,
+    // Refs live in reftables rather than files
+    reftable: bool,
+    // config.worktree is read after config
+    worktree_config: bool,
 }
 
 impl GitRepository {
     fn new(path: &str, force: bool) -> Result<GitRepository, Box<dyn Error>> {
         let worktree = path.to_string();
-        let gitdir = format!("{}/.git", worktree);
+        let gitdir = Path::new(path)
+            .join(".git")
+            .to_str()
+            .ok_or("repository path is not valid UTF-8")?
+            .to_string();
 
         if !(force || Path::new(&gitdir).is_dir()) {
             return Err(From::from(format!("Not a git repository {}", path)));
         };
 
-        Ok(GitRepository { worktree, gitdir })
+        Ok(GitRepository {
+            worktree,
+            gitdir,
+            odb: OnceCell::new(),
+            reftable: false,
+            worktree_config: false,
+        })
+    }
+
+    // A bare repository has no worktree; the git directory stands in for it.
+    fn new_bare(path: &str) -> GitRepository {
+        GitRepository {
+            worktree: path.to_string(),
+            gitdir: path.to_string(),
+            odb: OnceCell::new(),
+            reftable: false,
+            worktree_config: false,
+        }
     }
 }
 
@@ -62,16 +436,20 @@ enum GitObject {
 
 impl fmt::Display for GitObject {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            GitObject::Commit(_) => write!(f, "commit"),
-            GitObject::Tree(_) => write!(f, "tree"),
-            GitObject::Tag(_) => write!(f, "tag"),
-            GitObject::Blob(_) => write!(f, "blob"),
-        }
+        write!(f, "{}", self.object_type())
     }
 }
 
 impl GitObject {
+    fn object_type(&self) -> &'static str {
+        match self {
+            GitObject::Commit(_) => "commit",
+            GitObject::Tree(_) => "tree",
+            GitObject::Tag(_) => "tag",
+            GitObject::Blob(_) => "blob",
+        }
+    }
+
     fn new(data: Vec<u8>, object_type: &str) -> Result<Self, Box<dyn Error>> {
         match object_type {
             "commit" => Ok(GitObject::Commit(GitCommit::new(data))),
@@ -88,13 +466,8 @@ impl GitObject {
             GitObject::Tree(t) => Ok(t.serialize()),
             GitObject::Tag(t) => Ok(t.serialize()),
             GitObject::Blob(b) => Ok(b.serialize()),
-            _ => Err(From::from(format!("Unknown type {}", self))),
         }
     }
-
-    fn deserialize(&self) {
-        self.deserialize();
-    }
 }
 
 struct GitCommit {
@@ -119,14 +492,19 @@ impl GitObjectBehavior for GitCommit {
     }
 
     fn serialize(&self) -> &Vec<u8> {
-        todo!()
+        &self.data
     }
 
     fn deserialize(data: &[u8]) -> Result<Self, String>
     where
         Self: Sized,
     {
-        todo!()
+        let kvlm = parse_kvlm(data).map_err(|e| e.to_string())?;
+        if kvlm.get("tree").is_none() {
+            return Err(String::from("Malformed commit: missing tree"));
+        }
+        round_trip(data, &kvlm.serialize(), "commit")?;
+        Ok(GitCommit::new(data.to_vec()))
     }
 }
 
@@ -143,7 +521,13 @@ impl GitObjectBehavior for GitTree {
     where
         Self: Sized,
     {
-        todo!()
+        let leaves = parse_tree(data).map_err(|e| e.to_string())?;
+        round_trip(
+            data,
+            &tree_serialize(&leaves).map_err(|e| e.to_string())?,
+            "tree",
+        )?;
+        Ok(GitTree::new(data.to_vec()))
     }
 }
 
@@ -153,14 +537,19 @@ impl GitObjectBehavior for GitTag {
     }
 
     fn serialize(&self) -> &Vec<u8> {
-        todo!()
+        &self.data
     }
 
     fn deserialize(data: &[u8]) -> Result<Self, String>
     where
         Self: Sized,
     {
-        todo!()
+        let kvlm = parse_kvlm(data).map_err(|e| e.to_string())?;
+        if kvlm.get("object").is_none() {
+            return Err(String::from("Malformed tag: missing object"));
+        }
+        round_trip(data, &kvlm.serialize(), "tag")?;
+        Ok(GitTag::new(data.to_vec()))
     }
 }
 
@@ -177,7 +566,17 @@ impl GitObjectBehavior for GitBlob {
     where
         Self: Sized,
     {
-        todo!()
+        Ok(GitBlob::new(data.to_vec()))
+    }
+}
+
+// Parsed objects are kept as the bytes they came from, so they must be
+// exactly what serializing the parsed form gives back; anything else
+// would be rewritten under a different id.
+fn round_trip(data: &[u8], serialized: &[u8], object_type: &str) -> Result<(), String> {
+    match data == serialized {
+        true => Ok(()),
+        false => Err(format!("Malformed {}: not in canonical form", object_type)),
     }
 }
 
@@ -188,204 +587,12450 @@ trait GitObjectBehavior {
 
     fn serialize(&self) -> &Vec<u8>;
 
+    #[allow(dead_code)]
     fn deserialize(data: &[u8]) -> Result<Self, String>
     where
         Self: Sized;
 }
 
-fn read_object(repo: &GitRepository, sha: &str) -> Result<GitObject, Box<dyn Error>> {
-    let path = repo_file(
-        &repo,
-        &format!("objects/{0}/{1}", &sha[0..=1], &sha[2..]).as_str(),
-    );
-
-    assert!(path.is_file());
+// Damaged object data: what kind of data it is, how far in the parser
+// got, and what it found there.
+#[derive(Debug)]
+struct CorruptObject {
+    kind: &'static str,
+    offset: usize,
+    problem: String,
+}
 
-    let file = File::open(path)?;
+impl fmt::Display for CorruptObject {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Malformed {}: {} at offset {}",
+            self.kind, self.problem, self.offset
+        )
+    }
+}
 
-    let mut decoder = ZlibDecoder::new(file);
-    let mut decompressed_data: Vec<u8> = Vec::new();
+impl Error for CorruptObject {}
 
-    let file_length: usize = decoder.read_to_end(&mut decompressed_data)?;
+fn corrupt(kind: &'static str, offset: usize, problem: impl Into<String>) -> Box<dyn Error> {
+    Box::new(CorruptObject {
+        kind,
+        offset,
+        problem: problem.into(),
+    })
+}
 
-    let ascii_space = decompressed_data.iter().position(|&b| b == b' ').unwrap();
-    let object_type: &[u8] = &decompressed_data[0..ascii_space];
-    let object_type_string: String = String::from_utf8(object_type.to_vec())?;
+// Key-value list with message: the layout shared by commits and tags.
+// Continuation lines of multi-line values are stored without their
+// leading space.
+struct Kvlm {
+    headers: Vec<(String, Vec<u8>)>,
+    // Objects written by hand may end with the headers, and no blank line
+    blank_line: bool,
+    message: Vec<u8>,
+}
 
-    let null_byte: usize = decompressed_data.iter().position(|&b| b == b'\0').unwrap();
-    let size: &str = std::str::from_utf8(&decompressed_data[ascii_space + 1..null_byte]).unwrap();
-    let size: usize = size.parse::<usize>()?;
+impl Kvlm {
+    fn get(&self, key: &str) -> Option<&[u8]> {
+        self.get_all(key).into_iter().next()
+    }
 
-    if size != file_length - null_byte - 1 {
-        return Err(From::from(format!("Malformed object {0}: bad length", sha)));
+    fn get_all(&self, key: &str) -> Vec<&[u8]> {
+        self.headers
+            .iter()
+            .filter(|(k, _)| k == key)
+            .map(|(_, v)| v.as_slice())
+            .collect()
     }
 
-    let object_content = decompressed_data[null_byte + 1..].to_vec();
+    fn get_str(&self, key: &str) -> Option<&str> {
+        self.get(key)
+            .and_then(|value| std::str::from_utf8(value).ok())
+    }
 
-    GitObject::new(object_content, object_type_string.as_str())
+    fn serialize(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        for (key, value) in &self.headers {
+            data.extend_from_slice(key.as_bytes());
+            data.push(b' ');
+            for &b in value {
+                data.push(b);
+                if b == b'\n' {
+                    data.push(b' ');
+                }
+            }
+            data.push(b'\n');
+        }
+        if self.blank_line {
+            data.push(b'\n');
+        }
+        data.extend_from_slice(&self.message);
+        data
+    }
 }
 
-fn write_object(repo: &GitRepository, obj: &GitObject) -> String {
-    let data = obj.serialize().unwrap();
-    let data_slice = &data;
+fn parse_kvlm(data: &[u8]) -> Result<Kvlm, Box<dyn Error>> {
+    let mut headers = Vec::new();
+    let mut position = 0;
 
-    // Create the header
-    let header = format!("{} {}{}", obj.to_string(), data_slice.len(), '\0');
+    while position < data.len() && data[position] != b'\n' {
+        let rest = &data[position..];
+        let space = rest.iter().position(|&b| b == b' ');
+        let newline = rest.iter().position(|&b| b == b'\n');
+        let (space, mut end) = match (space, newline) {
+            (Some(space), Some(newline)) if space < newline => (space, newline),
+            _ => return Err(corrupt("object", position, "bad header line")),
+        };
 
-    // Concatenate the header and data
-    let file_content = [header.as_bytes(), data_slice].concat();
-    let mut hasher = Sha1::new();
-    hasher.update(&file_content);
-    let sha1_hash = hasher.finalize().to_vec();
-    let sha1_hex = hex::encode(sha1_hash);
+        // A value continues on every following line that starts with a space
+        while rest.get(end + 1) == Some(&b' ') {
+            end += 1 + rest[end + 1..]
+                .iter()
+                .position(|&b| b == b'\n')
+                .ok_or_else(|| corrupt("object", position, "unterminated header"))?;
+        }
 
-    let path = repo_file(
-        &repo,
-        &format!("objects/{0}1/{1}", &sha1_hex[0..=1], &sha1_hex[2..]).as_str(),
-    );
-    if !path.exists() {
-        let file = File::create(path).unwrap();
-        let mut encoder = ZlibEncoder::new(file, flate2::Compression::best());
-        encoder.write_all(&file_content).unwrap();
+        let key = String::from_utf8(rest[..space].to_vec())
+            .map_err(|_| corrupt("object", position, "header name is not UTF-8"))?;
+        let mut value = Vec::with_capacity(end - space);
+        let mut i = space + 1;
+        while i < end {
+            value.push(rest[i]);
+            i += if rest[i] == b'\n' { 2 } else { 1 };
+        }
+        headers.push((key, value));
+
+        position += end + 1;
     }
 
-    return sha1_hex;
+    let blank_line = position < data.len();
+    let message = data.get(position + 1..).unwrap_or_default().to_vec();
+    Ok(Kvlm {
+        headers,
+        blank_line,
+        message,
+    })
 }
 
-fn repo_path(repo: &GitRepository, path: &str) -> PathBuf {
-    Path::new(&repo.gitdir).join(&path).to_path_buf()
+struct GitTreeLeaf {
+    mode: String,
+    path: Vec<u8>,
+    sha: String,
 }
 
-fn repo_file(repo: &GitRepository, path: &str) -> PathBuf {
-    let path = repo_path(&repo, &path);
-    let dirs = path.parent().unwrap();
-    let _ = repo_dir(&repo, &dirs.to_str().unwrap());
-    path
+// The entries of a tree, which must be in tree order; diffing walks two
+// trees side by side and relies on it.
+fn parse_tree(data: &[u8]) -> Result<Vec<GitTreeLeaf>, Box<dyn Error>> {
+    let leaves = parse_tree_unsorted(data)?;
+    let mut offset = 0;
+    for pair in leaves.windows(2) {
+        offset += pair[0].mode.len() + pair[0].path.len() + 22;
+        if tree_sort_key(&pair[0]) >= tree_sort_key(&pair[1]) {
+            return Err(corrupt(
+                "tree",
+                offset,
+                format!(
+                    "entry '{}' is out of order",
+                    String::from_utf8_lossy(&pair[1].path)
+                ),
+            ));
+        }
+    }
+
+    Ok(leaves)
 }
 
-fn repo_dir(repo: &GitRepository, path: &str) -> Result<PathBuf, Box<dyn Error>> {
-    let path = repo_path(&repo, &path);
-    if path.exists() {
-        if path.is_dir() {
-            return Ok(path.clone());
-        } else {
-            return Err(From::from(format!("Not a directory {path:?}")));
+fn parse_tree_unsorted(data: &[u8]) -> Result<Vec<GitTreeLeaf>, Box<dyn Error>> {
+    let mut leaves = Vec::new();
+    let mut position = 0;
+
+    while position < data.len() {
+        let rest = &data[position..];
+        let null_byte = rest
+            .iter()
+            .position(|&b| b == b'\0')
+            .ok_or_else(|| corrupt("tree", position, "missing path terminator"))?;
+        let space = rest[..null_byte]
+            .iter()
+            .position(|&b| b == b' ')
+            .ok_or_else(|| corrupt("tree", position, "missing mode"))?;
+        let sha = rest
+            .get(null_byte + 1..null_byte + 21)
+            .ok_or_else(|| corrupt("tree", position, "truncated entry"))?;
+        let mode = String::from_utf8(rest[..space].to_vec())
+            .map_err(|_| corrupt("tree", position, "mode is not UTF-8"))?;
+
+        leaves.push(GitTreeLeaf {
+            mode,
+            path: rest[space + 1..null_byte].to_vec(),
+            sha: hex::encode(sha),
+        });
+
+        position += null_byte + 21;
+    }
+
+    Ok(leaves)
+}
+
+// Trees are sorted by name, with subtrees compared as if their name
+// ended in `/`.
+fn tree_sort_key(leaf: &GitTreeLeaf) -> Vec<u8> {
+    let mut key = leaf.path.clone();
+    if leaf.mode == "40000" {
+        key.push(b'/');
+    }
+    key
+}
+
+fn tree_serialize(leaves: &[GitTreeLeaf]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut sorted: Vec<&GitTreeLeaf> = leaves.iter().collect();
+    sorted.sort_by_cached_key(|leaf| tree_sort_key(leaf));
+    let mut names = HashSet::new();
+    for leaf in &sorted {
+        if leaf.path.is_empty() || leaf.path.contains(&b'/') || leaf.path.contains(&b'\0') {
+            return Err(From::from(format!(
+                "invalid tree entry name '{}'",
+                String::from_utf8_lossy(&leaf.path)
+            )));
+        }
+        if !names.insert(&leaf.path) {
+            return Err(From::from(format!(
+                "duplicate tree entry '{}'",
+                String::from_utf8_lossy(&leaf.path)
+            )));
         }
     }
 
-    fs::create_dir_all(&path).expect("Couldn't create directories {path:?}");
-    Ok(path.to_path_buf())
+    let mut data = Vec::new();
+    for leaf in sorted {
+        data.extend_from_slice(leaf.mode.as_bytes());
+        data.push(b' ');
+        data.extend_from_slice(&leaf.path);
+        data.push(b'\0');
+        data.extend_from_slice(&hex::decode(&leaf.sha)?);
+    }
+
+    Ok(data)
 }
 
-fn repo_find(path: &str) -> Result<GitRepository, Box<dyn Error>> {
-    let current_path = fs::canonicalize(Path::new(path)).unwrap();
+fn is_hex_sha(value: &[u8]) -> bool {
+    value.len() == 40 && value.iter().all(u8::is_ascii_hexdigit)
+}
+
+// The checks fsck makes of a tree, commit or tag before it is trusted,
+// failing with the id and description of the first problem. Warnings
+// git only reports in strict mode are errors here.
+fn object_check(object_type: &str, data: &[u8]) -> Result<(), Box<dyn Error>> {
+    let problem = match object_type {
+        "tree" => tree_check(data),
+        "commit" => commit_check(data),
+        "tag" => tag_check(data),
+        _ => None,
+    };
+    match problem {
+        Some((id, description)) => Err(From::from(format!("{}: {}", id, description))),
+        None => Ok(()),
+    }
+}
+
+fn tree_check(data: &[u8]) -> Option<(&'static str, &'static str)> {
+    let Ok(leaves) = parse_tree_unsorted(data) else {
+        return Some(("badTree", "cannot be parsed as a tree"));
+    };
 
-    if current_path.join(".git").is_dir() {
-            return GitRepository::new(current_path.to_str().unwrap(), false);
+    let mut names = HashSet::new();
+    let mut previous: Option<Vec<u8>> = None;
+    for leaf in &leaves {
+        let path = leaf.path.as_slice();
+        if leaf.sha == NULL_SHA {
+            return Some(("nullSha1", "contains entries pointing to null sha1"));
+        }
+        if path.is_empty() {
+            return Some(("emptyName", "contains empty pathname"));
+        }
+        if path.contains(&b'/') {
+            return Some(("fullPathname", "contains full pathnames"));
+        }
+        match path {
+            b"." => return Some(("hasDot", "contains '.'")),
+            b".." => return Some(("hasDotdot", "contains '..'")),
+            _ if path.eq_ignore_ascii_case(b".git") => {
+                return Some(("hasDotgit", "contains '.git'"))
+            }
+            _ => {}
+        }
+        if leaf.mode.starts_with('0') {
+            return Some(("zeroPaddedFilemode", "contains zero-padded file modes"));
+        }
+        if !matches!(
+            leaf.mode.as_str(),
+            "100644" | "100755" | "120000" | "40000" | "160000"
+        ) {
+            return Some(("badFilemode", "contains bad file modes"));
         }
+        if !names.insert(path) {
+            return Some(("duplicateEntries", "contains duplicate file entries"));
+        }
+        let key = tree_sort_key(leaf);
+        if previous.as_ref().is_some_and(|previous| *previous > key) {
+            return Some(("treeNotSorted", "not properly sorted"));
+        }
+        previous = Some(key);
+    }
 
-    let current_path_ref: &Path = current_path.as_path();
+    None
+}
 
-    let mut current_dir = current_path_ref;
+// `Name <email> seconds tz`, checked the way fsck reads it.
+fn ident_check(ident: &[u8]) -> Option<(&'static str, &'static str)> {
+    let stop = |from: usize| {
+        ident[from..]
+            .iter()
+            .position(|b| matches!(b, b'<' | b'>' | b'\n'))
+            .map_or(ident.len(), |i| from + i)
+    };
 
-    while let Some(parent) = current_dir.parent() {
-        if parent.join(".git").is_dir() {
-            return GitRepository::new(parent.to_str().unwrap(), false);
+    if ident.first() == Some(&b'<') {
+        return Some((
+            "missingNameBeforeEmail",
+            "invalid author/committer line - missing space before email",
+        ));
+    }
+    let open = stop(0);
+    match ident.get(open) {
+        Some(b'>') => return Some(("badName", "invalid author/committer line - bad name")),
+        Some(b'<') => {}
+        _ => {
+            return Some((
+                "missingEmail",
+                "invalid author/committer line - missing email",
+            ))
         }
-        current_dir = parent;
+    }
+    if ident[open - 1] != b' ' {
+        return Some((
+            "missingSpaceBeforeEmail",
+            "invalid author/committer line - missing space before email",
+        ));
+    }
+    let close = stop(open + 1);
+    if ident.get(close) != Some(&b'>') {
+        return Some(("badEmail", "invalid author/committer line - bad email"));
+    }
+    if ident.get(close + 1) != Some(&b' ') {
+        return Some((
+            "missingSpaceBeforeDate",
+            "invalid author/committer line - missing space before date",
+        ));
     }
 
-    Err(From::from(format!("No .git repository")))
+    let date = &ident[close + 2..];
+    let digits = date.iter().take_while(|b| b.is_ascii_digit()).count();
+    if date.first() == Some(&b'0') && date.get(1) != Some(&b' ') {
+        return Some((
+            "zeroPaddedDate",
+            "invalid author/committer line - zero-padded date",
+        ));
+    }
+    let seconds = std::str::from_utf8(&date[..digits]).unwrap_or_default();
+    if digits > 0 && seconds.parse::<i64>().is_err() {
+        return Some((
+            "badDateOverflow",
+            "invalid author/committer line - date causes integer overflow",
+        ));
+    }
+    if digits == 0 || date.get(digits) != Some(&b' ') {
+        return Some(("badDate", "invalid author/committer line - bad date"));
+    }
+    let tz = &date[digits + 1..];
+    if tz.len() != 5 || !matches!(tz[0], b'+' | b'-') || !tz[1..].iter().all(u8::is_ascii_digit) {
+        return Some((
+            "badTimezone",
+            "invalid author/committer line - bad time zone",
+        ));
+    }
+
+    None
 }
 
-fn is_dir_empty(dir_path: &str) -> bool {
-    if let Ok(entries) = fs::read_dir(dir_path) {
-        for _ in entries {
-            return false;
+fn commit_check(data: &[u8]) -> Option<(&'static str, &'static str)> {
+    let header_end = data
+        .windows(2)
+        .position(|pair| pair == b"\n\n")
+        .unwrap_or(data.len());
+    if data[..header_end].contains(&0) {
+        return Some(("nulInHeader", "unterminated header: NUL in header"));
+    }
+    let Ok(kvlm) = parse_kvlm(data) else {
+        return Some(("missingTree", "invalid format - expected 'tree' line"));
+    };
+
+    let mut headers = kvlm.headers.iter().peekable();
+    match headers.next() {
+        Some((key, value)) if key == "tree" => {
+            if !is_hex_sha(value) {
+                return Some(("badTreeSha1", "invalid 'tree' line format - bad sha1"));
+            }
+        }
+        _ => return Some(("missingTree", "invalid format - expected 'tree' line")),
+    }
+    while let Some((_, value)) = headers.next_if(|(key, _)| key == "parent") {
+        if !is_hex_sha(value) {
+            return Some(("badParentSha1", "invalid 'parent' line format - bad sha1"));
         }
     }
-    true
+    let problem = match headers.next() {
+        Some((key, value)) if key == "author" => ident_check(value),
+        _ => Some(("missingAuthor", "invalid format - expected 'author' line")),
+    };
+    if problem.is_some() {
+        return problem;
+    }
+    match headers.next() {
+        Some((key, value)) if key == "committer" => ident_check(value),
+        _ => Some((
+            "missingCommitter",
+            "invalid format - expected 'committer' line",
+        )),
+    }
 }
 
-fn repo_create(path: &str) -> Result<GitRepository, Box<dyn Error>> {
-    let repo = GitRepository::new(&path, true).unwrap();
+fn tag_check(data: &[u8]) -> Option<(&'static str, &'static str)> {
+    let Ok(kvlm) = parse_kvlm(data) else {
+        return Some(("missingObject", "invalid format - expected 'object' line"));
+    };
 
-    let worktree = Path::new(&repo.worktree);
-    let gitdir = Path::new(&repo.gitdir);
-    if worktree.exists() {
-        if !worktree.is_dir() {
-            return Err(From::from("{worktree:?} is not a directory!"));
+    let mut headers = kvlm.headers.iter().peekable();
+    match headers.next() {
+        Some((key, value)) if key == "object" => {
+            if !is_hex_sha(value) {
+                return Some(("badObjectSha1", "invalid 'object' line format - bad sha1"));
+            }
+        }
+        _ => return Some(("missingObject", "invalid format - expected 'object' line")),
+    }
+    match headers.next() {
+        Some((key, value)) if key == "type" => {
+            if !matches!(value.as_slice(), b"commit" | b"tree" | b"blob" | b"tag") {
+                return Some(("badType", "invalid 'type' value"));
+            }
         }
+        _ => return Some(("missingTypeEntry", "invalid format - expected 'type' line")),
+    }
+    match headers.next() {
+        Some((key, _)) if key == "tag" => {}
+        _ => return Some(("missingTagEntry", "invalid format - expected 'tag' line")),
+    }
+    // Tags made before git recorded taggers have none
+    match headers.next_if(|(key, _)| key == "tagger") {
+        Some((_, value)) => ident_check(value),
+        None => None,
+    }
+}
 
-        if gitdir.exists() && !is_dir_empty(&repo.gitdir) {
-            return Err(From::from("{gitdir} is not empty!"));
+// Object storage. Each backend answers for the objects it holds; an
+// `OdbStack` reads from the first backend that has an object and writes
+// to the first backend.
+trait Odb {
+    fn contains(&self, sha: &str) -> Result<bool, Box<dyn Error>>;
+    fn read(&self, sha: &str) -> Result<Option<GitObject>, Box<dyn Error>>;
+    // Type and size without the content, as cheaply as the backend allows
+    fn read_header(&self, sha: &str) -> Result<Option<(String, usize)>, Box<dyn Error>>;
+    fn write(&self, obj: &GitObject) -> Result<String, Box<dyn Error>>;
+    // Every object held, sorted and without duplicates
+    fn enumerate(&self) -> Result<Vec<String>, Box<dyn Error>>;
+}
+
+// The sorted union of what several backends hold.
+fn odb_enumerate_all<'a>(
+    backends: impl Iterator<Item = &'a dyn Odb>,
+) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut shas = Vec::new();
+    for backend in backends {
+        shas.extend(backend.enumerate()?);
+    }
+    shas.sort_unstable();
+    shas.dedup();
+    Ok(shas)
+}
+
+struct OdbStack {
+    backends: Vec<Box<dyn Odb>>,
+}
+
+impl Odb for OdbStack {
+    fn contains(&self, sha: &str) -> Result<bool, Box<dyn Error>> {
+        for backend in &self.backends {
+            if backend.contains(sha)? {
+                return Ok(true);
+            }
         }
-    } else {
-        let _ = fs::create_dir_all(&worktree);
+        Ok(false)
     }
 
-    assert!(repo_dir(&repo, "branches").is_ok());
-    assert!(repo_dir(&repo, "objects").is_ok());
-    assert!(repo_dir(&repo, "refs/tags").is_ok());
-    assert!(repo_dir(&repo, "refs/heads").is_ok());
+    fn read(&self, sha: &str) -> Result<Option<GitObject>, Box<dyn Error>> {
+        for backend in &self.backends {
+            if let Some(object) = backend.read(sha)? {
+                return Ok(Some(object));
+            }
+        }
+        Ok(None)
+    }
 
-    let description = "Unnamed repository; edit this file 'description' to name the repository.\n";
-    let description_file = repo_file(&repo, "description");
-    File::create(&description_file)?.write(description.as_bytes())?;
+    fn read_header(&self, sha: &str) -> Result<Option<(String, usize)>, Box<dyn Error>> {
+        for backend in &self.backends {
+            if let Some(header) = backend.read_header(sha)? {
+                return Ok(Some(header));
+            }
+        }
+        Ok(None)
+    }
 
-    repo_default_config().write_to_file(repo_file(&repo, "config"))?;
+    fn write(&self, obj: &GitObject) -> Result<String, Box<dyn Error>> {
+        let sha = hash_object(obj.object_type(), obj.serialize()?);
+        if self.contains(&sha)? {
+            return Ok(sha);
+        }
+        self.backends
+            .first()
+            .ok_or("No object store to write to")?
+            .write(obj)
+    }
 
-    Ok(repo)
+    fn enumerate(&self) -> Result<Vec<String>, Box<dyn Error>> {
+        odb_enumerate_all(self.backends.iter().map(|backend| backend.as_ref()))
+    }
 }
 
-fn cat_file(repo: &GitRepository, obj: &String) -> Result<(), Box<dyn Error>> {
-    let git_obj = read_object(&repo, obj)?;
-    let serialized_data = git_obj.serialize()?;
+// Loose objects and packs under an `objects` directory, followed by the
+// stores it borrows from through `info/alternates`.
+fn odb_open(
+    objects: &Path,
+    depth: usize,
+    compression: Compression,
+) -> Result<OdbStack, Box<dyn Error>> {
+    let mut backends: Vec<Box<dyn Odb>> = vec![
+        Box::new(LooseOdb {
+            dir: objects.to_path_buf(),
+            compression,
+        }),
+        Box::new(PackedOdb {
+            dir: objects.join("pack"),
+            packs: OnceCell::new(),
+        }),
+    ];
 
-    // Get the mutable stdout handle
-    let stdout = io::stdout();
-    let mut handle = stdout.lock();
+    let mut alternates = Vec::new();
+    if let Ok(content) = fs::read_to_string(objects.join("info/alternates")) {
+        alternates.extend(
+            content
+                .lines()
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .map(|line| objects.join(line)),
+        );
+    }
+    // Set by receive-pack for its hooks, so they see the quarantined objects'
+    // surroundings
+    if depth == 0 {
+        if let Some(dirs) = std::env::var_os("GIT_ALTERNATE_OBJECT_DIRECTORIES") {
+            alternates
+                .extend(std::env::split_paths(&dirs).filter(|dir| !dir.as_os_str().is_empty()));
+        }
+    }
+    if !alternates.is_empty() {
+        let mut stores = Vec::new();
+        for dir in alternates {
+            // The same nesting limit git uses
+            if depth >= 5 {
+                return Err(From::from(format!(
+                    "{}: alternates are nested too deeply",
+                    objects.display()
+                )));
+            }
+            if dir.is_dir() {
+                stores.push(odb_open(&dir, depth + 1, compression)?);
+            }
+        }
+        backends.push(Box::new(AlternatesOdb { stores }));
+    }
 
-    // Write the serialized data to stdout
-    handle.write_all(&serialized_data)?;
+    Ok(OdbStack { backends })
+}
 
-    Ok(())
+fn repo_odb(repo: &GitRepository) -> Result<&OdbStack, Box<dyn Error>> {
+    if let Some(odb) = repo.odb.get() {
+        return Ok(odb);
+    }
+
+    let config = match repo_path(repo, "config").exists() {
+        true => repo_config(repo)?,
+        false => Ini::new(),
+    };
+    // git deflates loose objects for speed unless told otherwise
+    let compression = config_compression(
+        &config,
+        &["core.loosecompression", "core.compression"],
+        Compression::fast(),
+    )?;
+    let odb = odb_open(&repo_path(repo, "objects"), 0, compression)?;
+    Ok(repo.odb.get_or_init(|| odb))
 }
 
-fn repo_default_config() -> Ini {
-    let mut config = Ini::new();
+struct LooseOdb {
+    dir: PathBuf,
+    compression: Compression,
+}
 
-    config
-        .with_section(Some("core"))
-        .set("repositoryformatversion", "0")
-        .set("filemode", "false")
-        .set("bare", "false");
+impl LooseOdb {
+    // Only a full object id names a file
+    fn object_path(&self, sha: &str) -> Option<PathBuf> {
+        is_hex_sha(sha.as_bytes()).then(|| self.dir.join(&sha[0..=1]).join(&sha[2..]))
+    }
 
-    config
+    fn scan(&self) -> Result<LooseScan, Box<dyn Error>> {
+        let mut objects = Vec::new();
+        let mut garbage = Vec::new();
+        for byte in 0..=255u8 {
+            let fanout = hex::encode([byte]);
+            let Ok(entries) = fs::read_dir(self.dir.join(&fanout)) else {
+                continue;
+            };
+            for entry in entries {
+                let entry = entry?;
+                let name = entry.file_name();
+                match name.to_str() {
+                    Some(name)
+                        if name.len() == 38
+                            && name.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')) =>
+                    {
+                        objects.push((format!("{}{}", fanout, name), entry.path()))
+                    }
+                    _ => garbage.push(entry.path()),
+                }
+            }
+        }
+        objects.sort_unstable();
+        Ok(LooseScan { objects, garbage })
+    }
 }
 
-fn main() {
-    let cli = Cli::parse();
-    match &cli.command {
-        Some(Commands::Init { path }) => {
-            let _ = repo_create(path).unwrap();
+// Loose objects with their files, and files in the fan-out directories
+// that are not objects.
+struct LooseScan {
+    objects: Vec<(String, PathBuf)>,
+    garbage: Vec<PathBuf>,
+}
+
+impl Odb for LooseOdb {
+    fn contains(&self, sha: &str) -> Result<bool, Box<dyn Error>> {
+        Ok(self.object_path(sha).is_some_and(|path| path.is_file()))
+    }
+
+    fn read(&self, sha: &str) -> Result<Option<GitObject>, Box<dyn Error>> {
+        let Some(path) = self.object_path(sha).filter(|path| path.is_file()) else {
+            return Ok(None);
+        };
+
+        let file = File::open(path)?;
+
+        let mut decoder = ZlibDecoder::new(file);
+        let mut decompressed_data: Vec<u8> = Vec::new();
+
+        let file_length: usize = decoder.read_to_end(&mut decompressed_data)?;
+
+        let null_byte: usize = decompressed_data
+            .iter()
+            .position(|&b| b == b'\0')
+            .ok_or_else(|| corrupt("object", 0, format!("{} has no header end", sha)))?;
+        let ascii_space = decompressed_data[..null_byte]
+            .iter()
+            .position(|&b| b == b' ')
+            .ok_or_else(|| corrupt("object", 0, format!("{} has no object type", sha)))?;
+        let object_type_string = std::str::from_utf8(&decompressed_data[0..ascii_space])
+            .map_err(|_| corrupt("object", 0, format!("{} has a bad object type", sha)))?
+            .to_string();
+
+        let size = std::str::from_utf8(&decompressed_data[ascii_space + 1..null_byte])
+            .ok()
+            .and_then(|size| size.parse::<usize>().ok())
+            .ok_or_else(|| corrupt("object", ascii_space + 1, format!("{} has a bad size", sha)))?;
+
+        if size != file_length - null_byte - 1 {
+            return Err(corrupt(
+                "object",
+                null_byte + 1,
+                format!(
+                    "{} has {} bytes but claims {}",
+                    sha,
+                    file_length - null_byte - 1,
+                    size
+                ),
+            ));
         }
-        Some(Commands::CatFile { object_type, object} ) => {
-            let repo = repo_find(".").unwrap();
-            let _ = cat_file(&repo, object);
+
+        let object_content = decompressed_data[null_byte + 1..].to_vec();
+
+        GitObject::new(object_content, &object_type_string).map(Some)
+    }
+
+    fn read_header(&self, sha: &str) -> Result<Option<(String, usize)>, Box<dyn Error>> {
+        let Some(path) = self.object_path(sha).filter(|path| path.is_file()) else {
+            return Ok(None);
+        };
+
+        // Inflate only as far as the end of the header
+        let mut header = Vec::new();
+        let mut decoder = ZlibDecoder::new(File::open(path)?);
+        let mut byte = [0];
+        while header.len() < 32 {
+            decoder.read_exact(&mut byte).map_err(|_| {
+                corrupt("object", header.len(), format!("{} has no header end", sha))
+            })?;
+            if byte[0] == 0 {
+                let bad_header = || corrupt("object", 0, format!("{} has a bad header", sha));
+                let header = std::str::from_utf8(&header).map_err(|_| bad_header())?;
+                let (object_type, size) = header.split_once(' ').ok_or_else(bad_header)?;
+                let size = size.parse().map_err(|_| bad_header())?;
+                return Ok(Some((object_type.to_string(), size)));
+            }
+            header.push(byte[0]);
         }
-        None => {
-            let repo = repo_find("target").unwrap();
-            let git_obj: GitObject =
-                read_object(&repo, &"4089f12ac270e114bdff71ba7a01ea86fe2f4319").unwrap();
-            write_object(&repo, &git_obj);
-            match git_obj {
-                GitObject::Commit(_) => {}
-                GitObject::Tree(t) => {}
-                GitObject::Tag(_) => {}
-                GitObject::Blob(_) => {}
+        Err(corrupt("object", 0, format!("{} has a bad header", sha)))
+    }
+
+    fn enumerate(&self) -> Result<Vec<String>, Box<dyn Error>> {
+        Ok(self
+            .scan()?
+            .objects
+            .into_iter()
+            .map(|(sha, _)| sha)
+            .collect())
+    }
+
+    fn write(&self, obj: &GitObject) -> Result<String, Box<dyn Error>> {
+        let data = obj.serialize()?;
+        let sha1_hex = hash_object(obj.object_type(), data);
+
+        // Objects are immutable, so one already on disk needs no deflating
+        let path = self.object_path(&sha1_hex).ok_or("bad object path")?;
+        if path.exists() {
+            return Ok(sha1_hex);
+        }
+
+        // Written aside and renamed into place, so readers never see a
+        // partial object
+        let dir = path.parent().ok_or("bad object path")?;
+        fs::create_dir_all(dir)?;
+        static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let temp = dir.join(format!(
+            "tmp_obj_{}_{}",
+            process::id(),
+            TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let written = (|| -> Result<(), Box<dyn Error>> {
+            let mut encoder = ZlibEncoder::new(File::create(&temp)?, self.compression);
+            encoder.write_all(&object_content(obj.object_type(), data))?;
+            let file = encoder.finish()?;
+            file.sync_all()?;
+
+            let mut permissions = file.metadata()?.permissions();
+            permissions.set_readonly(true);
+            fs::set_permissions(&temp, permissions)?;
+
+            // Losing a race against a writer of the same object is fine
+            if let Err(e) = rename_over(&temp, &path) {
+                if !path.exists() {
+                    return Err(From::from(e));
+                }
             }
-            println!("Unknown command");
+            Ok(())
+        })();
+        let _ = fs::remove_file(&temp);
+        written?;
+
+        Ok(sha1_hex)
+    }
+}
+
+// Stores borrowed through alternates are only ever read from.
+struct AlternatesOdb {
+    stores: Vec<OdbStack>,
+}
+
+impl Odb for AlternatesOdb {
+    fn contains(&self, sha: &str) -> Result<bool, Box<dyn Error>> {
+        for store in &self.stores {
+            if store.contains(sha)? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn read(&self, sha: &str) -> Result<Option<GitObject>, Box<dyn Error>> {
+        for store in &self.stores {
+            if let Some(object) = store.read(sha)? {
+                return Ok(Some(object));
+            }
+        }
+        Ok(None)
+    }
+
+    fn read_header(&self, sha: &str) -> Result<Option<(String, usize)>, Box<dyn Error>> {
+        for store in &self.stores {
+            if let Some(header) = store.read_header(sha)? {
+                return Ok(Some(header));
+            }
+        }
+        Ok(None)
+    }
+
+    fn write(&self, _obj: &GitObject) -> Result<String, Box<dyn Error>> {
+        Err(From::from("Alternate object stores are read-only"))
+    }
+
+    fn enumerate(&self) -> Result<Vec<String>, Box<dyn Error>> {
+        odb_enumerate_all(self.stores.iter().map(|store| store as &dyn Odb))
+    }
+}
+
+// Objects that live only as long as the store, for writes that must not
+// reach the disk.
+#[derive(Default)]
+struct MemoryOdb {
+    objects: RefCell<BTreeMap<String, (&'static str, Vec<u8>)>>,
+}
+
+impl Odb for MemoryOdb {
+    fn contains(&self, sha: &str) -> Result<bool, Box<dyn Error>> {
+        Ok(self.objects.borrow().contains_key(sha))
+    }
+
+    fn read(&self, sha: &str) -> Result<Option<GitObject>, Box<dyn Error>> {
+        match self.objects.borrow().get(sha) {
+            Some((object_type, data)) => GitObject::new(data.clone(), object_type).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn read_header(&self, sha: &str) -> Result<Option<(String, usize)>, Box<dyn Error>> {
+        Ok(self
+            .objects
+            .borrow()
+            .get(sha)
+            .map(|(object_type, data)| (object_type.to_string(), data.len())))
+    }
+
+    fn enumerate(&self) -> Result<Vec<String>, Box<dyn Error>> {
+        Ok(self.objects.borrow().keys().cloned().collect())
+    }
+
+    fn write(&self, obj: &GitObject) -> Result<String, Box<dyn Error>> {
+        let data = obj.serialize()?;
+        let sha = hash_object(obj.object_type(), data);
+        self.objects
+            .borrow_mut()
+            .insert(sha.clone(), (obj.object_type(), data.clone()));
+        Ok(sha)
+    }
+}
+
+fn read_object(repo: &GitRepository, sha: &str) -> Result<GitObject, Box<dyn Error>> {
+    repo_odb(repo)?
+        .read(sha)?
+        .ok_or_else(|| From::from(format!("Not a valid object name {}", sha)))
+}
+
+fn object_exists(repo: &GitRepository, sha: &str) -> Result<bool, Box<dyn Error>> {
+    repo_odb(repo)?.contains(sha)
+}
+
+fn object_content(object_type: &str, data: &[u8]) -> Vec<u8> {
+    let header = format!("{} {}{}", object_type, data.len(), '\0');
+    [header.as_bytes(), data].concat()
+}
+
+fn hash_object(object_type: &str, data: &[u8]) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(object_content(object_type, data));
+    hex::encode(hasher.finalize())
+}
+
+// Nothing is written that fsck would reject
+fn write_object(repo: &GitRepository, obj: &GitObject) -> Result<String, Box<dyn Error>> {
+    let data = obj.serialize()?;
+    object_check(obj.object_type(), data)
+        .map_err(|e| format!("object {}: {}", hash_object(obj.object_type(), data), e))?;
+    repo_odb(repo)?.write(obj)
+}
+
+// Paths inside the repository are written with `/`. They are joined one
+// component at a time, as Windows takes `/` for a separator only in paths
+// without the `\\?\` long path prefix.
+fn path_join(base: &Path, path: &str) -> PathBuf {
+    path.split('/')
+        .filter(|part| !part.is_empty())
+        .fold(base.to_path_buf(), |joined, part| joined.join(part))
+}
+
+// Paths in trees and the index are bytes. Unix file names are bytes too;
+// elsewhere they have to be valid Unicode to be stored.
+fn path_bytes(path: &std::ffi::OsStr) -> Result<Vec<u8>, Box<dyn Error>> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        Ok(path.as_bytes().to_vec())
+    }
+    #[cfg(not(unix))]
+    {
+        let path = path
+            .to_str()
+            .ok_or_else(|| format!("{}: path is not valid Unicode", path.to_string_lossy()))?;
+        Ok(path.as_bytes().to_vec())
+    }
+}
+
+fn bytes_path(bytes: &[u8]) -> Result<PathBuf, Box<dyn Error>> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        Ok(PathBuf::from(std::ffi::OsStr::from_bytes(bytes)))
+    }
+    #[cfg(not(unix))]
+    {
+        Ok(PathBuf::from(std::str::from_utf8(bytes)?))
+    }
+}
+
+// Paths as git prints them: quoted with C-style escapes when they hold
+// control characters, quotes or backslashes, or, with core.quotePath,
+// bytes outside ASCII.
+fn quote_path(path: &[u8], quote_high: bool) -> Vec<u8> {
+    let needs_quoting =
+        |b: u8| b < 0x20 || b == b'"' || b == b'\\' || b == 0x7f || (b >= 0x80 && quote_high);
+    if !path.iter().any(|&b| needs_quoting(b)) {
+        return path.to_vec();
+    }
+
+    let mut quoted = Vec::with_capacity(path.len() + 2);
+    quoted.push(b'"');
+    for &b in path {
+        match b {
+            0x07 => quoted.extend_from_slice(b"\\a"),
+            0x08 => quoted.extend_from_slice(b"\\b"),
+            b'\t' => quoted.extend_from_slice(b"\\t"),
+            b'\n' => quoted.extend_from_slice(b"\\n"),
+            0x0b => quoted.extend_from_slice(b"\\v"),
+            0x0c => quoted.extend_from_slice(b"\\f"),
+            b'\r' => quoted.extend_from_slice(b"\\r"),
+            b'"' => quoted.extend_from_slice(b"\\\""),
+            b'\\' => quoted.extend_from_slice(b"\\\\"),
+            b if needs_quoting(b) => quoted.extend_from_slice(format!("\\{:03o}", b).as_bytes()),
+            b => quoted.push(b),
+        }
+    }
+    quoted.push(b'"');
+    quoted
+}
+
+// Paths given on the command line, relative to the current directory
+// in a worktree and to the top of the tree in a bare repository.
+fn pathspecs(repo: &GitRepository, paths: &[PathBuf]) -> Result<Pathspec, Box<dyn Error>> {
+    match repo.worktree == repo.gitdir {
+        true => Pathspec::parse(paths, &|path| {
+            pathspec::pathspec_normalize(&path_bytes(path.as_os_str())?)
+        }),
+        false => Pathspec::parse(paths, &|path| worktree_path(repo, path)),
+    }
+}
+
+fn repo_path(repo: &GitRepository, path: &str) -> PathBuf {
+    path_join(Path::new(&repo.gitdir), path)
+}
+
+// What may be stored in the index: a relative path of non-empty
+// components, none of them `.`, `..` or `.git`. The `.git` check also
+// covers what NTFS or HFS+ would open as `.git`, as git does with
+// core.protectNTFS and core.protectHFS; both are checked everywhere.
+fn verify_path(path: &[u8]) -> bool {
+    path.split(|&b| b == b'/').all(|part| {
+        !matches!(part, b"" | b"." | b"..") && !is_ntfs_dotgit(part) && !is_hfs_dotgit(part)
+    })
+}
+
+// Whether NTFS would open `part` as `.git`: in any case, by the short
+// name `git~1`, with trailing dots and spaces, or naming a stream after
+// a `:`, like `.git::$INDEX_ALLOCATION`. `\` separates components too.
+fn is_ntfs_dotgit(part: &[u8]) -> bool {
+    part.split(|&b| b == b'\\').any(|part| {
+        let part = part.to_ascii_lowercase();
+        let Some(rest) = part
+            .strip_prefix(b".git")
+            .or_else(|| part.strip_prefix(b"git~1"))
+        else {
+            return false;
+        };
+        rest.iter()
+            .take_while(|&&b| b != b':')
+            .all(|&b| b == b'.' || b == b' ')
+    })
+}
+
+// Whether HFS+ would open `part` as `.git`, folding case and ignoring the
+// code points below. Malformed UTF-8 ends the name, as it does in git.
+fn is_hfs_dotgit(part: &[u8]) -> bool {
+    const IGNORED: [char; 16] = [
+        '\u{200c}', '\u{200d}', '\u{200e}', '\u{200f}', '\u{202a}', '\u{202b}', '\u{202c}',
+        '\u{202d}', '\u{202e}', '\u{206a}', '\u{206b}', '\u{206c}', '\u{206d}', '\u{206e}',
+        '\u{206f}', '\u{feff}',
+    ];
+    let mut chars = Vec::new();
+    for chunk in part.utf8_chunks() {
+        let valid = chunk.valid().chars().filter(|c| !IGNORED.contains(c));
+        chars.extend(valid.map(|c| c.to_ascii_lowercase()));
+        if !chunk.invalid().is_empty() {
+            break;
+        }
+    }
+    chars == ['.', 'g', 'i', 't']
+}
+
+// The first directory leading to `path` in the worktree that is really a
+// symlink, which git will not look through.
+fn symlink_leading_path(
+    repo: &GitRepository,
+    path: &[u8],
+) -> Result<Option<PathBuf>, Box<dyn Error>> {
+    for (i, _) in path.iter().enumerate().filter(|(_, &b)| b == b'/') {
+        let dir = worktree_file(repo, &path[..i])?;
+        match fs::symlink_metadata(&dir) {
+            Ok(metadata) if metadata.file_type().is_symlink() => return Ok(Some(dir)),
+            Ok(metadata) if metadata.is_dir() => {}
+            _ => break,
+        }
+    }
+    Ok(None)
+}
+
+fn worktree_file(repo: &GitRepository, path: &[u8]) -> Result<PathBuf, Box<dyn Error>> {
+    let mut file = PathBuf::from(&repo.worktree);
+    for part in path.split(|&b| b == b'/').filter(|part| !part.is_empty()) {
+        file.push(bytes_path(part)?);
+    }
+    Ok(file)
+}
+
+fn repo_file(repo: &GitRepository, path: &str) -> PathBuf {
+    let path = repo_path(repo, path);
+    if let Some(dirs) = path.parent() {
+        let _ = fs::create_dir_all(dirs);
+    }
+    path
+}
+
+// A new directory in `parent`, private to the caller, named `prefix`
+// and something unique. An existing directory is never reused, so
+// neither another thread nor someone who guessed the name shares it.
+fn create_unique_dir(parent: &Path, prefix: &str) -> io::Result<PathBuf> {
+    static DIR_COUNTER: AtomicUsize = AtomicUsize::new(0);
+    loop {
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |duration| duration.subsec_nanos());
+        let dir = parent.join(format!(
+            "{}{}-{}-{:08x}",
+            prefix,
+            process::id(),
+            DIR_COUNTER.fetch_add(1, Ordering::Relaxed),
+            nanos
+        ));
+        let mut builder = fs::DirBuilder::new();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::DirBuilderExt;
+            builder.mode(0o700);
+        }
+        match builder.create(&dir) {
+            Ok(()) => return Ok(dir),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// A lock file or a scratch directory of ours, removed when dropped so no
+// early return leaves it behind. A lock renamed into place with `commit`
+// is no longer ours: the path may already be another writer's lock.
+struct PathGuard(Option<PathBuf>);
+
+impl PathGuard {
+    fn path(&self) -> &Path {
+        self.0.as_deref().unwrap_or(Path::new(""))
+    }
+
+    fn commit(mut self, to: &Path) -> io::Result<()> {
+        rename_over(self.path(), to)?;
+        self.0 = None;
+        Ok(())
+    }
+}
+
+impl Drop for PathGuard {
+    fn drop(&mut self) {
+        if let Some(path) = self.0.take() {
+            let _ = match path.is_dir() {
+                true => fs::remove_dir_all(path),
+                false => fs::remove_file(path),
+            };
+        }
+    }
+}
+
+// Rename `from` over `to`. Windows refuses while another process has `to`
+// open, which readers only do briefly, so keep trying for a while.
+fn rename_over(from: &Path, to: &Path) -> io::Result<()> {
+    let mut delay = 1;
+    loop {
+        match fs::rename(from, to) {
+            Err(e)
+                if cfg!(windows) && e.kind() == io::ErrorKind::PermissionDenied && delay < 1024 =>
+            {
+                thread::sleep(Duration::from_millis(delay));
+                delay *= 2;
+            }
+            result => return result,
+        }
+    }
+}
+
+fn repo_dir(repo: &GitRepository, path: &str) -> Result<PathBuf, Box<dyn Error>> {
+    let path = repo_path(repo, path);
+    if path.exists() {
+        if path.is_dir() {
+            return Ok(path.clone());
+        } else {
+            return Err(From::from(format!("Not a directory {path:?}")));
+        }
+    }
+
+    fs::create_dir_all(&path).expect("Couldn't create directories {path:?}");
+    Ok(path.to_path_buf())
+}
+
+// Search `path` and the directories above it for a worktree with a
+// `.git` directory, or for a git directory itself. Inside `.git`, that
+// is the repository found, with no worktree, as for git.
+fn repo_find(path: &str) -> Result<GitRepository, Box<dyn Error>> {
+    let current_path = fs::canonicalize(Path::new(path)).unwrap();
+
+    for dir in current_path.ancestors() {
+        if dir.join(".git").is_dir() {
+            let repo = repo_safe(GitRepository::new(dir.to_str().unwrap(), false)?)
+                .and_then(repo_check_format)?;
+            // core.bare, which config.worktree may set, leaves no worktree
+            return match config_get_bool(&config_load(&repo_config_paths(&repo))?, "core.bare")? {
+                Some(true) => Ok(GitRepository {
+                    worktree: repo.gitdir.clone(),
+                    ..repo
+                }),
+                _ => Ok(repo),
+            };
+        }
+        if is_git_dir(dir) {
+            return repo_safe(GitRepository::new_bare(dir.to_str().unwrap()))
+                .and_then(repo_check_format);
+        }
+    }
+
+    Err(From::from("No .git repository"))
+}
+
+fn is_git_dir(dir: &Path) -> bool {
+    dir.join("HEAD").is_file() && dir.join("objects").is_dir() && dir.join("refs").is_dir()
+}
+
+fn repo_require_worktree(repo: &GitRepository) -> Result<(), Box<dyn Error>> {
+    match repo.worktree == repo.gitdir {
+        true => Err(From::from("this operation must be run in a work tree")),
+        false => Ok(()),
+    }
+}
+
+// Refuse a repository owned by someone else, as its config and hooks
+// would run as us, unless protected config lists it in safe.directory.
+// `*` trusts every repository and an empty value forgets those before.
+fn repo_safe(repo: GitRepository) -> Result<GitRepository, Box<dyn Error>> {
+    if repo_owned(&repo) || safe_directory(&config_protected()?, &repo.worktree) {
+        return Ok(repo);
+    }
+    Err(From::from(format!(
+        "detected dubious ownership in repository at '{0}'\n\
+         To add an exception for this directory, call:\n\n\
+         \tgit config --global --add safe.directory {0}",
+        repo.worktree
+    )))
+}
+
+// Whether we own both the worktree and the git directory of `repo`.
+fn repo_owned(repo: &GitRepository) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        // SAFETY: geteuid cannot fail and touches no memory
+        let mut uid = unsafe { libc::geteuid() };
+        // Under sudo, repositories belong to the user who ran it
+        if uid == 0 {
+            if let Some(sudo_uid) = std::env::var("SUDO_UID")
+                .ok()
+                .and_then(|id| id.parse().ok())
+            {
+                uid = sudo_uid;
+            }
+        }
+        let owned = |path: &str| fs::metadata(path).is_ok_and(|metadata| metadata.uid() == uid);
+        owned(&repo.worktree) && owned(&repo.gitdir)
+    }
+    // Owners elsewhere are not uids, and are not checked
+    #[cfg(not(unix))]
+    {
+        let _ = repo;
+        true
+    }
+}
+
+// Whether the safe.directory values of `config` trust `path`. A value
+// ending in `/*` trusts everything below the directory before it.
+fn safe_directory(config: &Ini, path: &str) -> bool {
+    let mut safe = false;
+    for value in config_get_all(config, "safe.directory") {
+        let value = match (value.strip_prefix("~/"), std::env::var("HOME")) {
+            (Some(rest), Ok(home)) => format!("{}/{}", home.trim_end_matches('/'), rest),
+            _ => value.to_string(),
+        };
+        safe = match value.as_str() {
+            "" => false,
+            "*" => true,
+            _ => match value.strip_suffix('*').filter(|dir| dir.ends_with('/')) {
+                Some(dir) => safe || path.starts_with(dir),
+                None => safe || value == path,
+            },
+        };
+    }
+    safe
+}
+
+// Extensions git honours in a version 0 repository, and those it only
+// accepts at version 1.
+const EXTENSIONS_V0: [&str; 4] = ["noop", "preciousobjects", "partialclone", "worktreeconfig"];
+const EXTENSIONS_V1: [&str; 3] = ["noop-v1", "objectformat", "refstorage"];
+
+// Refuse a repository laid out in a way this implementation does not
+// understand, rather than corrupting it: format versions above 1,
+// extensions it does not know in a version 1 repository, and object
+// formats other than SHA-1 and ref storage other than files or
+// reftables. As in git, version 0 ignores unknown extensions but not
+// those requiring version 1.
+fn repo_check_format(mut repo: GitRepository) -> Result<GitRepository, Box<dyn Error>> {
+    let config = config_load(&[repo_path(&repo, "config")])?;
+    let version = match config_get(&config, "core.repositoryformatversion") {
+        Some(value) => value.trim().parse::<u32>().map_err(|_| {
+            format!(
+                "bad numeric config value '{}' for 'core.repositoryformatversion'",
+                value
+            )
+        })?,
+        None => 0,
+    };
+    if version > 1 {
+        return Err(From::from(format!(
+            "Expected git repo version <= 1, found {}",
+            version
+        )));
+    }
+
+    let mut unknown = Vec::new();
+    let mut v1_only = Vec::new();
+    for (section, properties) in config.iter() {
+        if !section.is_some_and(|section| config_section_matches(section, "extensions", None)) {
+            continue;
+        }
+        for (key, value) in properties.iter() {
+            let key = key.to_ascii_lowercase();
+            if key == "worktreeconfig" {
+                repo.worktree_config = matches!(
+                    value.to_ascii_lowercase().as_str(),
+                    "true" | "yes" | "on" | "1" | ""
+                );
+            }
+            if EXTENSIONS_V0.contains(&key.as_str()) {
+                continue;
+            }
+            if !EXTENSIONS_V1.contains(&key.as_str()) {
+                unknown.push(key);
+                continue;
+            }
+            if version == 0 {
+                v1_only.push(key);
+                continue;
+            }
+            match (key.as_str(), value.to_ascii_lowercase().as_str()) {
+                ("objectformat", "sha1") | ("refstorage", "files") | ("noop-v1", _) => {}
+                ("refstorage", "reftable") => repo.reftable = true,
+                ("objectformat", "sha256") => {
+                    return Err(From::from(format!(
+                        "extensions.{} '{}' is not supported",
+                        key, value
+                    )))
+                }
+                _ => {
+                    return Err(From::from(format!(
+                        "invalid value for 'extensions.{}': '{}'",
+                        key, value
+                    )))
+                }
+            }
+        }
+    }
+
+    let (problem, extensions) = match version {
+        0 => ("repo version is 0, but v1-only", v1_only),
+        _ => ("unknown repository", unknown),
+    };
+    match extensions.len() {
+        0 => Ok(repo),
+        n => Err(From::from(format!(
+            "{} extension{} found:\n\t{}",
+            problem,
+            if n == 1 { "" } else { "s" },
+            extensions.join("\n\t")
+        ))),
+    }
+}
+
+// Open the repository at `path` itself, without searching parent
+// directories: either a worktree with a `.git` directory or a bare
+// repository. `<path>.git` is tried too, as servers are asked for both.
+fn repo_open(path: &str) -> Result<GitRepository, Box<dyn Error>> {
+    for candidate in [path.to_string(), format!("{}.git", path)] {
+        let Ok(dir) = fs::canonicalize(&candidate) else {
+            continue;
+        };
+        let candidate = dir.to_str().ok_or("repository path is not valid UTF-8")?;
+        if dir.join(".git").is_dir() {
+            return repo_safe(GitRepository::new(candidate, false)?).and_then(repo_check_format);
+        }
+        if is_git_dir(&dir) {
+            return repo_safe(GitRepository::new_bare(candidate)).and_then(repo_check_format);
+        }
+    }
+
+    Err(From::from(format!(
+        "'{}' does not appear to be a git repository",
+        path
+    )))
+}
+
+fn is_dir_empty(dir_path: &str) -> bool {
+    match fs::read_dir(dir_path) {
+        Ok(mut entries) => entries.next().is_none(),
+        Err(_) => true,
+    }
+}
+
+fn repo_create(path: &str) -> Result<GitRepository, Box<dyn Error>> {
+    let repo = GitRepository::new(path, true).unwrap();
+
+    let worktree = Path::new(&repo.worktree);
+    let gitdir = Path::new(&repo.gitdir);
+    if worktree.exists() {
+        if !worktree.is_dir() {
+            return Err(From::from("{worktree:?} is not a directory!"));
+        }
+
+        if gitdir.exists() && !is_dir_empty(&repo.gitdir) {
+            return Err(From::from("{gitdir} is not empty!"));
+        }
+    } else {
+        let _ = fs::create_dir_all(worktree);
+    }
+
+    assert!(repo_dir(&repo, "branches").is_ok());
+    assert!(repo_dir(&repo, "objects").is_ok());
+    assert!(repo_dir(&repo, "refs/tags").is_ok());
+    assert!(repo_dir(&repo, "refs/heads").is_ok());
+
+    let description = "Unnamed repository; edit this file 'description' to name the repository.\n";
+    let description_file = repo_file(&repo, "description");
+    File::create(&description_file)?.write_all(description.as_bytes())?;
+
+    // What the filesystem can represent is probed rather than assumed
+    let filemode = fs_keeps_executable_bit(&description_file);
+    let symlinks = fs_supports_symlinks(gitdir);
+    let ignore_case = fs_ignores_case(&description_file);
+    repo_default_config(filemode, symlinks, ignore_case)
+        .write_to_file(repo_file(&repo, "config"))?;
+
+    Ok(repo)
+}
+
+// Whether flipping the executable bit of `file` sticks, which it does not
+// on filesystems like FAT. The bit is restored afterwards.
+fn fs_keeps_executable_bit(file: &Path) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let Ok(metadata) = fs::metadata(file) else {
+            return false;
+        };
+        let mode = metadata.permissions().mode();
+        let flipped = fs::Permissions::from_mode(mode ^ 0o100);
+        let keeps = fs::set_permissions(file, flipped).is_ok()
+            && fs::metadata(file).is_ok_and(|m| m.permissions().mode() == mode ^ 0o100);
+        let _ = fs::set_permissions(file, fs::Permissions::from_mode(mode));
+        keeps
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = file;
+        false
+    }
+}
+
+// Whether symlinks can be created in `dir`. Windows allows it only with
+// developer mode or the matching privilege.
+fn fs_supports_symlinks(dir: &Path) -> bool {
+    let link = dir.join(format!("symlink-probe-{}", process::id()));
+    #[cfg(unix)]
+    let created = std::os::unix::fs::symlink("target", &link).is_ok();
+    #[cfg(windows)]
+    let created = std::os::windows::fs::symlink_file("target", &link).is_ok();
+    #[cfg(not(any(unix, windows)))]
+    let created = false;
+
+    let _ = fs::remove_file(&link);
+    created
+}
+
+// Whether `file` can also be found under a differently cased name.
+fn fs_ignores_case(file: &Path) -> bool {
+    let Some(name) = file.file_name().and_then(|name| name.to_str()) else {
+        return false;
+    };
+    let swapped: String = name
+        .chars()
+        .map(|c| match c.is_ascii_lowercase() {
+            true => c.to_ascii_uppercase(),
+            false => c.to_ascii_lowercase(),
+        })
+        .collect();
+    swapped != name && file.with_file_name(swapped).exists()
+}
+
+// Space a file takes on disk, which is what git reports rather than its
+// length.
+fn disk_usage(metadata: &fs::Metadata) -> u64 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        metadata.blocks() * 512
+    }
+    #[cfg(not(unix))]
+    {
+        metadata.len()
+    }
+}
+
+fn count_objects(repo: &GitRepository, verbose: bool) -> Result<(), Box<dyn Error>> {
+    let loose = LooseOdb {
+        dir: repo_path(repo, "objects"),
+        compression: Compression::default(),
+    };
+    let packed = PackedOdb {
+        dir: repo_path(repo, "objects/pack"),
+        packs: OnceCell::new(),
+    };
+
+    let scan = loose.scan()?;
+    let mut size = 0;
+    let mut prune_packable = 0;
+    for (sha, path) in &scan.objects {
+        size += disk_usage(&fs::symlink_metadata(path)?);
+        if packed.contains(sha)? {
+            prune_packable += 1;
+        }
+    }
+
+    if !verbose {
+        println!("{} objects, {} kilobytes", scan.objects.len(), size / 1024);
+        return Ok(());
+    }
+
+    let mut garbage = scan.garbage;
+    let mut size_pack = 0;
+    if let Ok(entries) = fs::read_dir(repo_path(repo, "objects/pack")) {
+        for entry in entries {
+            let path = entry?.path();
+            let extension = path.extension().and_then(|extension| extension.to_str());
+            let paired = match extension {
+                Some("pack") => path.with_extension("idx").is_file(),
+                Some("idx") => path.with_extension("pack").is_file(),
+                Some("keep" | "bitmap" | "rev" | "promisor" | "mtimes") => true,
+                _ => false,
+            };
+            match paired {
+                // Packs count by length, unlike loose objects
+                true if matches!(extension, Some("pack" | "idx")) => {
+                    size_pack += fs::metadata(&path)?.len()
+                }
+                true => {}
+                false => garbage.push(path),
+            }
+        }
+    }
+    garbage.sort();
+
+    let cwd = fs::canonicalize(std::env::current_dir()?)?;
+    let mut size_garbage = 0;
+    for path in &garbage {
+        size_garbage += disk_usage(&fs::symlink_metadata(path)?);
+        let shown = path.strip_prefix(&cwd).unwrap_or(path);
+        eprintln!("warning: garbage found: {}", shown.display());
+    }
+
+    let packs = packed.packs()?;
+    println!("count: {}", scan.objects.len());
+    println!("size: {}", size / 1024);
+    println!(
+        "in-pack: {}",
+        packs.iter().map(|pack| pack.index.len()).sum::<usize>()
+    );
+    println!("packs: {}", packs.len());
+    println!("size-pack: {}", size_pack / 1024);
+    println!("prune-packable: {}", prune_packable);
+    println!("garbage: {}", garbage.len());
+    println!("size-garbage: {}", size_garbage / 1024);
+
+    Ok(())
+}
+
+fn cat_file(repo: &GitRepository, obj: &str) -> Result<(), Box<dyn Error>> {
+    let git_obj = read_object(repo, obj)?;
+    let serialized_data = git_obj.serialize()?;
+
+    // Get the mutable stdout handle
+    let stdout = io::stdout();
+    let mut handle = stdout.lock();
+
+    // Write the serialized data to stdout
+    handle.write_all(serialized_data)?;
+
+    Ok(())
+}
+
+// Show an object for people: trees as `ls-tree` lists them, everything
+// else as it is stored.
+fn cat_file_pretty(repo: &GitRepository, sha: &str) -> Result<(), Box<dyn Error>> {
+    let object = read_object(repo, sha)?;
+    let mut out = io::stdout().lock();
+    let GitObject::Tree(tree) = &object else {
+        out.write_all(object.serialize()?)?;
+        return Ok(());
+    };
+
+    let quote_high = config_get_bool(&repo_config(repo)?, "core.quotepath")?.unwrap_or(true);
+    for leaf in parse_tree(tree.serialize())? {
+        let object_type = match leaf.mode.as_str() {
+            "40000" => "tree",
+            "160000" => "commit",
+            _ => "blob",
+        };
+        write!(out, "{:0>6} {} {}\t", leaf.mode, object_type, leaf.sha)?;
+        out.write_all(&quote_path(&leaf.path, quote_high))?;
+        out.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+// Show a blob named by `<rev>:<path>` as its diff driver's textconv
+// filter turns it into text, or as it is without one.
+fn cat_file_textconv(repo: &GitRepository, name: &str) -> Result<(), Box<dyn Error>> {
+    let Some((_, path)) = name.split_once(':') else {
+        return Err(From::from(format!(
+            "<object>:<path> required, only <object> '{}' given",
+            name
+        )));
+    };
+    let path = match path.as_bytes() {
+        [b'0'..=b'3', b':', ..] => &path[2..],
+        _ => path,
+    };
+    let sha = object_resolve(repo, name)?;
+    let GitObject::Blob(blob) = read_object(repo, &sha)? else {
+        return cat_file_pretty(repo, &sha);
+    };
+
+    let config = config_all(repo)?;
+    let command = match attr_get(repo, &config, path.as_bytes(), "diff")? {
+        Some(AttrValue::Value(driver)) => {
+            config_get(&config, &format!("diff.{}.textconv", driver)).map(ToString::to_string)
+        }
+        _ => None,
+    };
+    let data = match command {
+        Some(command) => textconv(&command, path.as_bytes(), blob.serialize())?,
+        None => blob.serialize().to_vec(),
+    };
+    io::stdout().lock().write_all(&data)?;
+    Ok(())
+}
+
+// Run a textconv filter on a blob. It gets a temporary file carrying the
+// blob's own name, so the filter can go by the extension.
+fn textconv(command: &str, path: &[u8], data: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    // A fresh directory of our own, so nothing planted in the shared
+    // temporary directory is reused or followed
+    let dir = create_unique_dir(&std::env::temp_dir(), "eekgit-blob-")?;
+    let name = path.rsplit(|&b| b == b'/').next().unwrap_or(path);
+    let file = dir.join(bytes_path(name)?);
+    let written = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&file)
+        .and_then(|mut blob| blob.write_all(data));
+    let output = written.and_then(|_| {
+        process::Command::new("sh")
+            .arg("-c")
+            .arg(format!("{} \"$@\"", command))
+            .arg(command)
+            .arg(&file)
+            .stdin(Stdio::null())
+            .stderr(Stdio::inherit())
+            .output()
+    });
+    let _ = fs::remove_dir_all(&dir);
+
+    match output {
+        Ok(output) if output.status.success() => Ok(output.stdout),
+        _ => Err(From::from("unable to read files to diff")),
+    }
+}
+
+// An annotated tag taken apart. A signature, PGP or SSH, ends the
+// message and signs everything before it.
+struct TagInfo {
+    object: String,
+    tagger: Option<String>,
+    payload: Vec<u8>,
+    signature: Option<Vec<u8>>,
+}
+
+const SIGNATURE_MARKERS: [&[u8]; 4] = [
+    b"-----BEGIN PGP SIGNATURE-----",
+    b"-----BEGIN PGP MESSAGE-----",
+    b"-----BEGIN SIGNED MESSAGE-----",
+    b"-----BEGIN SSH SIGNATURE-----",
+];
+
+fn tag_parse(data: &[u8]) -> Result<TagInfo, Box<dyn Error>> {
+    let kvlm = parse_kvlm(data)?;
+    for key in ["object", "type", "tag"] {
+        if kvlm.get(key).is_none() {
+            return Err(From::from(format!("Malformed tag: missing {}", key)));
+        }
+    }
+
+    // The last line opening a signature starts it
+    let start = (0..data.len())
+        .rev()
+        .filter(|&i| i == 0 || data[i - 1] == b'\n')
+        .find(|&i| {
+            SIGNATURE_MARKERS
+                .iter()
+                .any(|marker| data[i..].starts_with(marker))
+        })
+        .unwrap_or(data.len());
+
+    Ok(TagInfo {
+        object: kvlm_shas(&kvlm, "object")?.remove(0),
+        tagger: kvlm.get_str("tagger").map(ToString::to_string),
+        payload: data[..start].to_vec(),
+        signature: (start < data.len()).then(|| data[start..].to_vec()),
+    })
+}
+
+// Run a signing tool with `input` on stdin, collecting what it prints.
+fn signature_tool(
+    program: &str,
+    args: &[&std::ffi::OsStr],
+    input: &[u8],
+) -> Result<process::Output, Box<dyn Error>> {
+    let mut child = process::Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("cannot run {}: {}", program, e))?;
+    // The tool may not read all of its input
+    if let Some(mut stdin) = child.stdin.take() {
+        let _ = stdin.write_all(input);
+    }
+    Ok(child.wait_with_output()?)
+}
+
+// Check a PGP signature with gpg, which reports through its status
+// lines; `raw` shows those instead of gpg's messages.
+fn verify_gpg_signature(
+    config: &Ini,
+    payload: &[u8],
+    signature: &Path,
+    raw: bool,
+) -> Result<bool, Box<dyn Error>> {
+    let program = config_get(config, "gpg.openpgp.program")
+        .or_else(|| config_get(config, "gpg.program"))
+        .unwrap_or("gpg");
+    let output = signature_tool(
+        program,
+        &[
+            "--keyid-format=long".as_ref(),
+            "--status-fd=1".as_ref(),
+            "--verify".as_ref(),
+            signature.as_os_str(),
+            "-".as_ref(),
+        ],
+        payload,
+    )?;
+    io::stderr().write_all(if raw { &output.stdout } else { &output.stderr })?;
+
+    let status = String::from_utf8_lossy(&output.stdout);
+    let good = status
+        .lines()
+        .any(|line| line.starts_with("[GNUPG:] GOODSIG "));
+    Ok(output.status.success() && good)
+}
+
+// Check an SSH signature with ssh-keygen against the signers listed in
+// gpg.ssh.allowedSignersFile.
+fn verify_ssh_signature(
+    config: &Ini,
+    payload: &[u8],
+    signature: &Path,
+) -> Result<bool, Box<dyn Error>> {
+    let Some(allowed) = config_get(config, "gpg.ssh.allowedsignersfile")
+        .map(PathBuf::from)
+        .filter(|path| path.is_file())
+    else {
+        eprintln!("error: gpg.ssh.allowedSignersFile needs to be configured and exist for ssh signature verification");
+        return Ok(false);
+    };
+    let program = config_get(config, "gpg.ssh.program").unwrap_or("ssh-keygen");
+    let (allowed, signature) = (allowed.as_os_str(), signature.as_os_str());
+
+    let found = signature_tool(
+        program,
+        &[
+            "-Y".as_ref(),
+            "find-principals".as_ref(),
+            "-f".as_ref(),
+            allowed,
+            "-s".as_ref(),
+            signature,
+        ],
+        b"",
+    )?;
+    let principals = String::from_utf8_lossy(&found.stdout).into_owned();
+    if !found.status.success() || principals.trim().is_empty() {
+        // Still say whether the signature itself holds up
+        let check = signature_tool(
+            program,
+            &[
+                "-Y".as_ref(),
+                "check-novalidate".as_ref(),
+                "-n".as_ref(),
+                "git".as_ref(),
+                "-s".as_ref(),
+                signature,
+            ],
+            payload,
+        )?;
+        io::stderr().write_all(&check.stdout)?;
+        eprintln!("No principal matched.");
+        return Ok(false);
+    }
+
+    for principal in principals.lines().filter(|line| !line.is_empty()) {
+        let output = signature_tool(
+            program,
+            &[
+                "-Y".as_ref(),
+                "verify".as_ref(),
+                "-n".as_ref(),
+                "git".as_ref(),
+                "-f".as_ref(),
+                allowed,
+                "-I".as_ref(),
+                principal.as_ref(),
+                "-s".as_ref(),
+                signature,
+            ],
+            payload,
+        )?;
+        io::stderr().write_all(&output.stdout)?;
+        io::stderr().write_all(&output.stderr)?;
+        if output.status.success() && output.stdout.starts_with(b"Good \"git\" signature") {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+// Check the signature on the annotated tag `name`, reporting problems
+// the way git does.
+fn verify_tag(
+    repo: &GitRepository,
+    config: &Ini,
+    name: &str,
+    verbose: bool,
+    raw: bool,
+) -> Result<bool, Box<dyn Error>> {
+    let sha = object_resolve(repo, name)?;
+    let data = match read_object(repo, &sha)? {
+        GitObject::Tag(tag) => tag.serialize().clone(),
+        object => {
+            eprintln!(
+                "error: {}: cannot verify a non-tag object of type {}.",
+                name, object
+            );
+            return Ok(false);
+        }
+    };
+    let tag = tag_parse(&data)?;
+    if verbose {
+        io::stdout().write_all(&tag.payload)?;
+        io::stdout().flush()?;
+    }
+    let Some(signature) = &tag.signature else {
+        eprintln!("error: no signature found");
+        return Ok(false);
+    };
+
+    // The signature goes in a fresh directory of our own, which is
+    // removed whatever the verification gives
+    let dir = create_unique_dir(&std::env::temp_dir(), "eekgit-vtag-")?;
+    let path = dir.join("signature");
+    let written = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&path)
+        .and_then(|mut file| file.write_all(signature));
+    let verified = match written {
+        Ok(()) if signature.starts_with(b"-----BEGIN SSH SIGNATURE-----") => {
+            verify_ssh_signature(config, &tag.payload, &path)
+        }
+        Ok(()) => verify_gpg_signature(config, &tag.payload, &path, raw),
+        Err(e) => Err(From::from(e)),
+    };
+    let _ = fs::remove_dir_all(&dir);
+    verified
+}
+
+const PACK_SIGNATURE: &[u8] = b"PACK";
+const PACK_IDX_SIGNATURE: &[u8] = b"\xfftOc";
+
+enum PackEntryKind {
+    Base(&'static str),
+    OfsDelta(usize),
+    RefDelta(String),
+}
+
+struct PackEntry {
+    offset: usize,
+    end: usize,
+    kind: PackEntryKind,
+    data: Vec<u8>,
+}
+
+struct PackObject {
+    sha: String,
+    object_type: &'static str,
+    data: Vec<u8>,
+    offset: usize,
+    // Inflated size of the entry, which for deltas is the delta itself
+    size: usize,
+    packed_size: usize,
+    crc: u32,
+    depth: usize,
+    base: Option<String>,
+}
+
+fn pack_object_type(type_id: u8) -> Result<&'static str, Box<dyn Error>> {
+    match type_id {
+        1 => Ok("commit"),
+        2 => Ok("tree"),
+        3 => Ok("blob"),
+        4 => Ok("tag"),
+        _ => Err(From::from(format!("Unknown pack object type {}", type_id))),
+    }
+}
+
+fn read_pack_byte(pack: &[u8], position: &mut usize) -> Result<u8, Box<dyn Error>> {
+    let byte = *pack
+        .get(*position)
+        .ok_or("Truncated pack: unexpected end of data")?;
+    *position += 1;
+    Ok(byte)
+}
+
+// Inflate one zlib stream starting at the beginning of `data`, returning
+// the inflated bytes and how many compressed bytes were consumed.
+fn inflate_pack_data(data: &[u8], size: usize) -> Result<(Vec<u8>, usize), Box<dyn Error>> {
+    let mut decompress = Decompress::new(true);
+    // The stated size is not trusted for more than a modest buffer
+    let mut inflated = Vec::with_capacity(size.min(1 << 20) + 1);
+
+    loop {
+        let consumed = decompress.total_in() as usize;
+        let produced = inflated.len();
+        let status =
+            decompress.decompress_vec(&data[consumed..], &mut inflated, FlushDecompress::None)?;
+
+        match status {
+            Status::StreamEnd => break,
+            _ if inflated.len() > size => break,
+            Status::Ok | Status::BufError => {
+                if inflated.len() == inflated.capacity() {
+                    inflated.reserve(inflated.len().min(size - inflated.len()) + 1);
+                } else if decompress.total_in() as usize == consumed && inflated.len() == produced {
+                    return Err(From::from("Truncated pack: incomplete zlib stream"));
+                }
+            }
+        }
+    }
+
+    if inflated.len() != size {
+        return Err(From::from(format!(
+            "Malformed pack: inflated size {} does not match {}",
+            inflated.len(),
+            size
+        )));
+    }
+
+    Ok((inflated, decompress.total_in() as usize))
+}
+
+// Parse the entry at the start of `entry`, which sits at `offset` in its pack.
+fn parse_pack_entry(entry: &[u8], offset: usize) -> Result<PackEntry, Box<dyn Error>> {
+    let mut position = 0;
+
+    let mut byte = read_pack_byte(entry, &mut position)?;
+    let type_id = (byte >> 4) & 0x7;
+    let mut size = (byte & 0x0f) as usize;
+    let mut shift = 4;
+    while byte & 0x80 != 0 {
+        byte = read_pack_byte(entry, &mut position)?;
+        if shift >= usize::BITS {
+            return Err(From::from("Malformed pack: object size too large"));
+        }
+        size |= ((byte & 0x7f) as usize) << shift;
+        shift += 7;
+    }
+
+    let kind = match type_id {
+        6 => {
+            byte = read_pack_byte(entry, &mut position)?;
+            let mut distance = (byte & 0x7f) as usize;
+            while byte & 0x80 != 0 {
+                byte = read_pack_byte(entry, &mut position)?;
+                distance = distance
+                    .checked_add(1)
+                    .and_then(|distance| distance.checked_mul(0x80))
+                    .ok_or("Malformed pack: delta base offset out of range")?
+                    | (byte & 0x7f) as usize;
+            }
+            let base = offset
+                .checked_sub(distance)
+                .ok_or("Malformed pack: delta base offset out of range")?;
+            PackEntryKind::OfsDelta(base)
+        }
+        7 => {
+            let base = entry
+                .get(position..position + 20)
+                .ok_or("Truncated pack: unexpected end of data")?;
+            position += 20;
+            PackEntryKind::RefDelta(hex::encode(base))
+        }
+        _ => PackEntryKind::Base(pack_object_type(type_id)?),
+    };
+
+    let (data, consumed) = inflate_pack_data(&entry[position..], size)?;
+
+    Ok(PackEntry {
+        offset,
+        end: offset + position + consumed,
+        kind,
+        data,
+    })
+}
+
+// Check the header and trailing checksum and split a pack into its entries.
+fn parse_pack(pack: &[u8]) -> Result<Vec<PackEntry>, Box<dyn Error>> {
+    if pack.len() < 32 || &pack[0..4] != PACK_SIGNATURE {
+        return Err(From::from("Not a pack file"));
+    }
+
+    let version = u32::from_be_bytes(pack[4..8].try_into()?);
+    if version != 2 && version != 3 {
+        return Err(From::from(format!("Unsupported pack version {}", version)));
+    }
+
+    let trailer = pack.len() - 20;
+    let mut hasher = Sha1::new();
+    hasher.update(&pack[..trailer]);
+    if hasher.finalize().as_slice() != &pack[trailer..] {
+        return Err(From::from("Pack checksum mismatch"));
+    }
+
+    // The count is not trusted for the allocation: an entry takes at
+    // least two bytes
+    let count = u32::from_be_bytes(pack[8..12].try_into()?) as usize;
+    let mut entries = Vec::with_capacity(count.min(pack.len() / 2));
+    let mut offset = 12;
+    for _ in 0..count {
+        let entry = parse_pack_entry(&pack[offset..trailer], offset)?;
+        offset = entry.end;
+        entries.push(entry);
+    }
+
+    if offset != trailer {
+        return Err(From::from("Malformed pack: garbage after last object"));
+    }
+
+    Ok(entries)
+}
+
+fn read_delta_size(delta: &[u8], position: &mut usize) -> Result<usize, Box<dyn Error>> {
+    let mut size = 0;
+    let mut shift = 0;
+    loop {
+        let byte = read_pack_byte(delta, position)?;
+        if shift >= usize::BITS {
+            return Err(From::from("Malformed delta: size too large"));
+        }
+        size |= ((byte & 0x7f) as usize) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            return Ok(size);
+        }
+    }
+}
+
+fn apply_delta(base: &[u8], delta: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut position = 0;
+
+    if read_delta_size(delta, &mut position)? != base.len() {
+        return Err(From::from("Malformed delta: base size mismatch"));
+    }
+    let result_size = read_delta_size(delta, &mut position)?;
+    let mut result = Vec::with_capacity(result_size.min(1 << 20));
+
+    while position < delta.len() {
+        let instruction = read_pack_byte(delta, &mut position)?;
+        if instruction & 0x80 != 0 {
+            // Copy from base: bits 0-3 select offset bytes, bits 4-6 size bytes
+            let mut copy_offset = 0;
+            for i in 0..4 {
+                if instruction & (1 << i) != 0 {
+                    copy_offset |= (read_pack_byte(delta, &mut position)? as usize) << (8 * i);
+                }
+            }
+            let mut copy_size = 0;
+            for i in 0..3 {
+                if instruction & (0x10 << i) != 0 {
+                    copy_size |= (read_pack_byte(delta, &mut position)? as usize) << (8 * i);
+                }
+            }
+            if copy_size == 0 {
+                copy_size = 0x10000;
+            }
+            let chunk = base
+                .get(copy_offset..copy_offset + copy_size)
+                .ok_or("Malformed delta: copy out of range")?;
+            result.extend_from_slice(chunk);
+        } else if instruction != 0 {
+            let chunk = delta
+                .get(position..position + instruction as usize)
+                .ok_or("Malformed delta: insert out of range")?;
+            result.extend_from_slice(chunk);
+            position += instruction as usize;
+        } else {
+            return Err(From::from("Malformed delta: reserved instruction"));
+        }
+    }
+
+    if result.len() != result_size {
+        return Err(From::from("Malformed delta: result size mismatch"));
+    }
+
+    Ok(result)
+}
+
+// Resolve every entry of a parsed pack to a full object. Bases for
+// REF_DELTA entries not in the pack are looked up in `repo` when given.
+fn resolve_pack(
+    pack: &[u8],
+    entries: Vec<PackEntry>,
+    repo: Option<&GitRepository>,
+) -> Result<Vec<PackObject>, Box<dyn Error>> {
+    let by_offset: HashMap<usize, usize> = entries
+        .iter()
+        .enumerate()
+        .map(|(i, entry)| (entry.offset, i))
+        .collect();
+    let mut by_sha: HashMap<String, usize> = HashMap::new();
+    let mut resolved: Vec<Option<PackObject>> = Vec::new();
+    resolved.resize_with(entries.len(), || None);
+    let mut external: HashMap<String, (&'static str, Vec<u8>)> = HashMap::new();
+
+    // Deltas may refer to bases that only become available once other
+    // deltas are resolved, so keep sweeping until nothing changes.
+    let mut remaining = entries.len();
+    while remaining > 0 {
+        let before = remaining;
+
+        for (i, entry) in entries.iter().enumerate() {
+            if resolved[i].is_some() {
+                continue;
+            }
+
+            let delta = |base: &PackObject| -> Result<_, Box<dyn Error>> {
+                Ok((
+                    base.object_type,
+                    apply_delta(&base.data, &entry.data)?,
+                    base.depth + 1,
+                    Some(base.sha.clone()),
+                ))
+            };
+
+            let (object_type, data, depth, base) = match &entry.kind {
+                PackEntryKind::Base(object_type) => (*object_type, entry.data.clone(), 0, None),
+                PackEntryKind::OfsDelta(base_offset) => {
+                    let base = by_offset
+                        .get(base_offset)
+                        .ok_or("Malformed pack: delta base is not an object start")?;
+                    match &resolved[*base] {
+                        Some(base) => delta(base)?,
+                        None => continue,
+                    }
+                }
+                PackEntryKind::RefDelta(base_sha) => {
+                    match by_sha
+                        .get(base_sha)
+                        .and_then(|base| resolved[*base].as_ref())
+                    {
+                        Some(base) => delta(base)?,
+                        None => match external.get(base_sha) {
+                            Some((object_type, base_data)) => (
+                                *object_type,
+                                apply_delta(base_data, &entry.data)?,
+                                1,
+                                Some(base_sha.clone()),
+                            ),
+                            None => continue,
+                        },
+                    }
+                }
+            };
+
+            let sha = hash_object(object_type, &data);
+            let mut crc = Crc::new();
+            crc.update(&pack[entry.offset..entry.end]);
+
+            by_sha.insert(sha.clone(), i);
+            resolved[i] = Some(PackObject {
+                sha,
+                object_type,
+                data,
+                offset: entry.offset,
+                size: entry.data.len(),
+                packed_size: entry.end - entry.offset,
+                crc: crc.sum(),
+                depth,
+                base,
+            });
+            remaining -= 1;
+        }
+
+        if remaining == before {
+            // Only bases outside the pack can unblock us now
+            let missing: Vec<String> = entries
+                .iter()
+                .enumerate()
+                .filter_map(|(i, entry)| match &entry.kind {
+                    PackEntryKind::RefDelta(base)
+                        if resolved[i].is_none() && !by_sha.contains_key(base) =>
+                    {
+                        Some(base.clone())
+                    }
+                    _ => None,
+                })
+                .filter(|base| !external.contains_key(base))
+                .collect();
+
+            let mut found = false;
+            if let Some(repo) = repo {
+                for base in missing {
+                    if object_exists(repo, &base)? {
+                        let object = read_object(repo, &base)?;
+                        external.insert(base, (object.object_type(), object.serialize()?.clone()));
+                        found = true;
+                    }
+                }
+            }
+
+            if !found {
+                return Err(From::from(format!(
+                    "pack has {} unresolved deltas",
+                    remaining
+                )));
+            }
+        }
+    }
+
+    Ok(resolved.into_iter().map(Option::unwrap).collect())
+}
+
+// Version 2 pack index: fanout table, sorted names, CRCs, offsets, and
+// the pack and index checksums.
+fn write_pack_index(
+    objects: &[PackObject],
+    pack_checksum: &[u8],
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut sorted: Vec<&PackObject> = objects.iter().collect();
+    sorted.sort_by(|a, b| a.sha.cmp(&b.sha));
+
+    let mut index = Vec::new();
+    index.extend_from_slice(PACK_IDX_SIGNATURE);
+    index.extend_from_slice(&2u32.to_be_bytes());
+
+    let names: Vec<Vec<u8>> = sorted
+        .iter()
+        .map(|object| hex::decode(&object.sha))
+        .collect::<Result<_, _>>()?;
+    for byte in 0..=255u8 {
+        let count = names.iter().filter(|name| name[0] <= byte).count() as u32;
+        index.extend_from_slice(&count.to_be_bytes());
+    }
+    for name in &names {
+        index.extend_from_slice(name);
+    }
+    for object in &sorted {
+        index.extend_from_slice(&object.crc.to_be_bytes());
+    }
+
+    let mut large_offsets = Vec::new();
+    for object in &sorted {
+        if object.offset < 0x8000_0000 {
+            index.extend_from_slice(&(object.offset as u32).to_be_bytes());
+        } else {
+            let large_index = (large_offsets.len() / 8) as u32;
+            index.extend_from_slice(&(0x8000_0000 | large_index).to_be_bytes());
+            large_offsets.extend_from_slice(&(object.offset as u64).to_be_bytes());
+        }
+    }
+    index.extend_from_slice(&large_offsets);
+
+    index.extend_from_slice(pack_checksum);
+    let mut hasher = Sha1::new();
+    hasher.update(&index);
+    index.extend_from_slice(&hasher.finalize());
+
+    Ok(index)
+}
+
+// Build the index for a complete pack. Thin packs are rejected, as
+// there is no `--fix-thin` to append their missing bases. With `strict`,
+// every object must pass fsck.
+fn index_pack(pack: &[u8], strict: bool) -> Result<Vec<u8>, Box<dyn Error>> {
+    let entries = parse_pack(pack)?;
+    let objects = resolve_pack(pack, entries, None)?;
+    if strict {
+        pack_check(&objects)?;
+    }
+
+    write_pack_index(&objects, &pack[pack.len() - 20..])
+}
+
+struct PackIndexEntry {
+    sha: String,
+    crc: u32,
+    offset: usize,
+}
+
+fn read_be_u32(data: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes(data[at..at + 4].try_into().unwrap())
+}
+
+// Parse a version 2 pack index, returning its entries in name order and
+// the checksum of the pack it describes.
+fn read_pack_index(index: &[u8]) -> Result<(Vec<PackIndexEntry>, Vec<u8>), Box<dyn Error>> {
+    let header_len = 8 + 256 * 4;
+    if index.len() < header_len + 40 || &index[0..4] != PACK_IDX_SIGNATURE {
+        return Err(From::from("Not a version 2 pack index"));
+    }
+    let version = read_be_u32(index, 4);
+    if version != 2 {
+        return Err(From::from(format!(
+            "Unsupported pack index version {}",
+            version
+        )));
+    }
+
+    let trailer = index.len() - 20;
+    let mut hasher = Sha1::new();
+    hasher.update(&index[..trailer]);
+    if hasher.finalize().as_slice() != &index[trailer..] {
+        return Err(From::from("Pack index checksum mismatch"));
+    }
+
+    let mut previous = 0;
+    for i in 0..256 {
+        let count = read_be_u32(index, 8 + i * 4);
+        if count < previous {
+            return Err(From::from(
+                "Malformed pack index: fanout table is not sorted",
+            ));
+        }
+        previous = count;
+    }
+
+    let count = previous as usize;
+    let names = header_len;
+    let crcs = names + count * 20;
+    let offsets = crcs + count * 4;
+    let large_offsets = offsets + count * 4;
+    let min_len = large_offsets + 40;
+    if index.len() < min_len || !(index.len() - min_len).is_multiple_of(8) {
+        return Err(From::from("Malformed pack index: bad length"));
+    }
+
+    let mut entries = Vec::with_capacity(count);
+    for i in 0..count {
+        let offset = read_be_u32(index, offsets + i * 4);
+        let offset = if offset & 0x8000_0000 == 0 {
+            offset as usize
+        } else {
+            let at = large_offsets + (offset & 0x7fff_ffff) as usize * 8;
+            if at + 8 > trailer - 20 {
+                return Err(From::from(
+                    "Malformed pack index: large offset out of range",
+                ));
+            }
+            u64::from_be_bytes(index[at..at + 8].try_into()?) as usize
+        };
+
+        entries.push(PackIndexEntry {
+            sha: hex::encode(&index[names + i * 20..names + (i + 1) * 20]),
+            crc: read_be_u32(index, crcs + i * 4),
+            offset,
+        });
+    }
+
+    Ok((entries, index[trailer - 20..trailer].to_vec()))
+}
+
+// Check a pack against its index: both checksums, that every indexed
+// object is where the index says with the right CRC, and that all deltas
+// resolve. Prints the object listing and chain histogram when asked.
+fn verify_pack(path: &str, verbose: bool, stat_only: bool) -> Result<(), Box<dyn Error>> {
+    let index_path = Path::new(path).with_extension("idx");
+    let pack_path = Path::new(path).with_extension("pack");
+
+    let (index_entries, pack_checksum) = read_pack_index(&fs::read(index_path)?)?;
+    let pack = fs::read(&pack_path)?;
+    let entries = parse_pack(&pack)?;
+    if pack[pack.len() - 20..] != pack_checksum[..] {
+        return Err(From::from("Packfile checksum does not match its index"));
+    }
+
+    let objects = resolve_pack(&pack, entries, None)?;
+    if objects.len() != index_entries.len() {
+        return Err(From::from(format!(
+            "Pack has {} objects but its index lists {}",
+            objects.len(),
+            index_entries.len()
+        )));
+    }
+
+    let by_offset: HashMap<usize, &PackObject> = objects
+        .iter()
+        .map(|object| (object.offset, object))
+        .collect();
+    for entry in &index_entries {
+        match by_offset.get(&entry.offset) {
+            Some(object) if object.sha == entry.sha => {
+                if object.crc != entry.crc {
+                    return Err(From::from(format!("CRC mismatch for object {}", entry.sha)));
+                }
+            }
+            _ => {
+                return Err(From::from(format!(
+                    "Object {} is not at offset {}",
+                    entry.sha, entry.offset
+                )))
+            }
+        }
+    }
+
+    if verbose && !stat_only {
+        for object in &objects {
+            print!(
+                "{} {:<6} {} {} {}",
+                object.sha, object.object_type, object.size, object.packed_size, object.offset
+            );
+            match &object.base {
+                Some(base) => println!(" {} {}", object.depth, base),
+                None => println!(),
+            }
+        }
+    }
+
+    if verbose || stat_only {
+        let mut chains: Vec<usize> = Vec::new();
+        for object in &objects {
+            if chains.len() <= object.depth {
+                chains.resize(object.depth + 1, 0);
+            }
+            chains[object.depth] += 1;
+        }
+
+        let plural = |count: usize| if count == 1 { "object" } else { "objects" };
+        // An empty pack has no objects at any depth
+        if let Some(&count) = chains.first().filter(|&&count| count > 0) {
+            println!("non delta: {} {}", count, plural(count));
+        }
+        for (depth, &count) in chains.iter().enumerate().skip(1) {
+            if count > 0 {
+                println!("chain length = {}: {} {}", depth, count, plural(count));
+            }
+        }
+    }
+
+    if verbose && !stat_only {
+        println!("{}: ok", pack_path.display());
+    }
+
+    Ok(())
+}
+
+// Like `index-pack`, but explodes the pack into loose objects instead. A
+// dry run writes to memory and lists the objects the repository lacks.
+// With `strict`, nothing is written unless every object passes fsck.
+fn unpack_objects(
+    repo: &GitRepository,
+    pack: &[u8],
+    dry_run: bool,
+    strict: bool,
+) -> Result<(), Box<dyn Error>> {
+    let memory = MemoryOdb::default();
+    let odb: &dyn Odb = match dry_run {
+        true => &memory,
+        false => repo_odb(repo)?,
+    };
+
+    let entries = parse_pack(pack)?;
+    let objects = resolve_pack(pack, entries, Some(repo))?;
+    if strict {
+        pack_check(&objects)?;
+    }
+    for object in objects {
+        let object = GitObject::new(object.data, object.object_type)?;
+        if dry_run
+            && object_exists(
+                repo,
+                &hash_object(object.object_type(), object.serialize()?),
+            )?
+        {
+            continue;
+        }
+        odb.write(&object)?;
+    }
+
+    for (sha, (object_type, _)) in memory.objects.borrow().iter() {
+        println!("would write {} {}", object_type, sha);
+    }
+
+    Ok(())
+}
+
+// A pack under `objects/pack` with its index loaded, so single objects
+// can be read without loading the whole pack.
+struct Pack {
+    path: PathBuf,
+    index: Vec<PackIndexEntry>,
+    // Entry offsets in pack order followed by the trailer offset, so the
+    // extent of every entry is known
+    offsets: Vec<usize>,
+}
+
+impl Pack {
+    fn open(index_path: &Path) -> Result<Pack, Box<dyn Error>> {
+        let (index, _) = read_pack_index(&fs::read(index_path)?)?;
+        let path = index_path.with_extension("pack");
+        let pack_len = fs::metadata(&path)?.len() as usize;
+        if pack_len < 32 {
+            return Err(From::from(format!("{} is not a pack file", path.display())));
+        }
+
+        let mut offsets: Vec<usize> = index.iter().map(|entry| entry.offset).collect();
+        offsets.sort_unstable();
+        offsets.push(pack_len - 20);
+
+        Ok(Pack {
+            path,
+            index,
+            offsets,
+        })
+    }
+
+    fn find(&self, sha: &str) -> Option<usize> {
+        self.index
+            .binary_search_by(|entry| entry.sha.as_str().cmp(sha))
+            .ok()
+            .map(|i| self.index[i].offset)
+    }
+
+    fn read_entry(&self, offset: usize) -> Result<PackEntry, Box<dyn Error>> {
+        let i = self
+            .offsets
+            .binary_search(&offset)
+            .map_err(|_| format!("No object at offset {} in {}", offset, self.path.display()))?;
+        let end = *self
+            .offsets
+            .get(i + 1)
+            .ok_or("Malformed pack: offset is the trailer")?;
+
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(offset as u64))?;
+        let mut entry = vec![0; end - offset];
+        file.read_exact(&mut entry)?;
+
+        parse_pack_entry(&entry, offset)
+    }
+
+    // A delta states the size of its result up front, but its type is
+    // that of the base at the end of the chain
+    fn header_at(&self, odb: &dyn Odb, offset: usize) -> Result<(String, usize), Box<dyn Error>> {
+        let entry = self.read_entry(offset)?;
+        let object_type = match &entry.kind {
+            PackEntryKind::Base(object_type) => {
+                return Ok((object_type.to_string(), entry.data.len()))
+            }
+            PackEntryKind::OfsDelta(base_offset) => self.header_at(odb, *base_offset)?.0,
+            PackEntryKind::RefDelta(base_sha) => {
+                odb.read_header(base_sha)?
+                    .ok_or_else(|| format!("Missing delta base {}", base_sha))?
+                    .0
+            }
+        };
+
+        let mut position = 0;
+        read_delta_size(&entry.data, &mut position)?;
+        Ok((object_type, read_delta_size(&entry.data, &mut position)?))
+    }
+
+    // Bases of deltas by object id come from `odb`
+    fn read_at(&self, odb: &dyn Odb, offset: usize) -> Result<(String, Vec<u8>), Box<dyn Error>> {
+        let entry = self.read_entry(offset)?;
+        match entry.kind {
+            PackEntryKind::Base(object_type) => Ok((object_type.to_string(), entry.data)),
+            PackEntryKind::OfsDelta(base_offset) => {
+                let (object_type, base) = self.read_at(odb, base_offset)?;
+                Ok((object_type, apply_delta(&base, &entry.data)?))
+            }
+            PackEntryKind::RefDelta(base_sha) => {
+                let base = odb
+                    .read(&base_sha)?
+                    .ok_or_else(|| format!("Missing delta base {}", base_sha))?;
+                Ok((
+                    base.object_type().to_string(),
+                    apply_delta(base.serialize()?, &entry.data)?,
+                ))
+            }
+        }
+    }
+}
+
+struct PackedOdb {
+    dir: PathBuf,
+    packs: OnceCell<Vec<Pack>>,
+}
+
+impl PackedOdb {
+    fn packs(&self) -> Result<&Vec<Pack>, Box<dyn Error>> {
+        if let Some(packs) = self.packs.get() {
+            return Ok(packs);
+        }
+
+        let mut index_paths = Vec::new();
+        if let Ok(entries) = fs::read_dir(&self.dir) {
+            for entry in entries {
+                let path = entry?.path();
+                if path.extension().is_some_and(|extension| extension == "idx")
+                    && path.with_extension("pack").is_file()
+                {
+                    index_paths.push(path);
+                }
+            }
+        }
+        index_paths.sort();
+
+        let packs = index_paths
+            .iter()
+            .map(|path| Pack::open(path))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(self.packs.get_or_init(|| packs))
+    }
+}
+
+impl Odb for PackedOdb {
+    fn contains(&self, sha: &str) -> Result<bool, Box<dyn Error>> {
+        Ok(self.packs()?.iter().any(|pack| pack.find(sha).is_some()))
+    }
+
+    fn read(&self, sha: &str) -> Result<Option<GitObject>, Box<dyn Error>> {
+        for pack in self.packs()? {
+            if let Some(offset) = pack.find(sha) {
+                let (object_type, data) = pack.read_at(self, offset)?;
+                return GitObject::new(data, &object_type).map(Some);
+            }
+        }
+        Ok(None)
+    }
+
+    fn read_header(&self, sha: &str) -> Result<Option<(String, usize)>, Box<dyn Error>> {
+        for pack in self.packs()? {
+            if let Some(offset) = pack.find(sha) {
+                return pack.header_at(self, offset).map(Some);
+            }
+        }
+        Ok(None)
+    }
+
+    fn write(&self, _obj: &GitObject) -> Result<String, Box<dyn Error>> {
+        Err(From::from(
+            "Packs are written whole, not one object at a time",
+        ))
+    }
+
+    fn enumerate(&self) -> Result<Vec<String>, Box<dyn Error>> {
+        let mut shas: Vec<String> = self
+            .packs()?
+            .iter()
+            .flat_map(|pack| pack.index.iter().map(|entry| entry.sha.clone()))
+            .collect();
+        shas.sort_unstable();
+        shas.dedup();
+        Ok(shas)
+    }
+}
+
+fn pack_type_id(object_type: &str) -> u8 {
+    match object_type {
+        "commit" => 1,
+        "tree" => 2,
+        "blob" => 3,
+        _ => 4,
+    }
+}
+
+fn pack_compression(repo: &GitRepository) -> Result<Compression, Box<dyn Error>> {
+    config_compression(
+        &repo_config(repo)?,
+        &["pack.compression", "core.compression"],
+        Compression::default(),
+    )
+}
+
+// One object stored whole: its type and size header, then the deflated
+// content.
+fn pack_entry(
+    repo: &GitRepository,
+    sha: &str,
+    compression: Compression,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let object = read_object(repo, sha)?;
+    let data = object.serialize()?;
+
+    let mut entry = Vec::new();
+    let mut size = data.len();
+    let mut byte = (pack_type_id(object.object_type()) << 4) | (size & 0x0f) as u8;
+    size >>= 4;
+    while size > 0 {
+        entry.push(byte | 0x80);
+        byte = (size & 0x7f) as u8;
+        size >>= 7;
+    }
+    entry.push(byte);
+
+    let mut encoder = ZlibEncoder::new(entry, compression);
+    encoder.write_all(data)?;
+    Ok(encoder.finish()?)
+}
+
+fn pack_from_entries(entries: &[Vec<u8>]) -> Vec<u8> {
+    let mut pack = Vec::new();
+    pack.extend_from_slice(PACK_SIGNATURE);
+    pack.extend_from_slice(&2u32.to_be_bytes());
+    pack.extend_from_slice(&(entries.len() as u32).to_be_bytes());
+    for entry in entries {
+        pack.extend_from_slice(entry);
+    }
+
+    let mut hasher = Sha1::new();
+    hasher.update(&pack);
+    pack.extend_from_slice(&hasher.finalize());
+    pack
+}
+
+// Write a version 2 pack holding the given objects whole, without deltas.
+fn write_pack(repo: &GitRepository, shas: &[String]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let compression = pack_compression(repo)?;
+    let entries = shas
+        .iter()
+        .map(|sha| pack_entry(repo, sha, compression))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(pack_from_entries(&entries))
+}
+
+// A number with git's optional `k`, `m` or `g` suffix.
+fn parse_size(value: &str) -> Option<usize> {
+    let value = value.trim();
+    let (digits, unit) = match value.chars().last()?.to_ascii_lowercase() {
+        'k' => (&value[..value.len() - 1], 1 << 10),
+        'm' => (&value[..value.len() - 1], 1 << 20),
+        'g' => (&value[..value.len() - 1], 1 << 30),
+        _ => (value, 1),
+    };
+    digits.parse::<usize>().ok()?.checked_mul(unit)
+}
+
+fn file_mtime(path: &Path) -> Result<u32, Box<dyn Error>> {
+    let modified = fs::metadata(path)?.modified()?;
+    let seconds = modified
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs());
+    Ok(seconds as u32)
+}
+
+const PACK_MTIMES_SIGNATURE: &[u8] = b"MTME";
+
+// The `.mtimes` file of a cruft pack: when each of its objects was last
+// written, in index order, so their expiry outlives the repack that
+// gathered them.
+fn write_pack_mtimes(mtimes: &[u32], pack_checksum: &[u8]) -> Vec<u8> {
+    let mut data = Vec::new();
+    data.extend_from_slice(PACK_MTIMES_SIGNATURE);
+    data.extend_from_slice(&1u32.to_be_bytes());
+    data.extend_from_slice(&1u32.to_be_bytes());
+    for mtime in mtimes {
+        data.extend_from_slice(&mtime.to_be_bytes());
+    }
+    data.extend_from_slice(pack_checksum);
+    let mut hasher = Sha1::new();
+    hasher.update(&data);
+    data.extend_from_slice(&hasher.finalize());
+    data
+}
+
+fn read_pack_mtimes(data: &[u8], count: usize) -> Result<Vec<u32>, Box<dyn Error>> {
+    if data.len() != 12 + count * 4 + 40 || &data[0..4] != PACK_MTIMES_SIGNATURE {
+        return Err(From::from("Malformed pack mtimes file"));
+    }
+    Ok((0..count).map(|i| read_be_u32(data, 12 + i * 4)).collect())
+}
+
+// A pack in the repository's own `objects/pack`, with when each of its
+// objects was last written: the times a cruft pack recorded, or the
+// pack's own mtime.
+struct RepackPack {
+    path: PathBuf,
+    kept: bool,
+    objects: Vec<(String, u32)>,
+}
+
+fn repack_existing(dir: &Path) -> Result<Vec<RepackPack>, Box<dyn Error>> {
+    let mut packs = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let is_pack = path
+            .extension()
+            .is_some_and(|extension| extension == "pack");
+        if !is_pack || !path.with_extension("idx").is_file() {
+            continue;
+        }
+
+        let (index, _) = read_pack_index(&fs::read(path.with_extension("idx"))?)?;
+        let mtimes = match fs::read(path.with_extension("mtimes")) {
+            Ok(data) => read_pack_mtimes(&data, index.len())?,
+            Err(_) => vec![file_mtime(&path)?; index.len()],
+        };
+        packs.push(RepackPack {
+            kept: path.with_extension("keep").exists(),
+            objects: index
+                .into_iter()
+                .map(|entry| entry.sha)
+                .zip(mtimes)
+                .collect(),
+            path,
+        });
+    }
+    packs.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(packs)
+}
+
+// Everything a repack must keep: what refs, HEAD, reflogs and the index
+// point at, and all that is reachable from there.
+fn repack_reachable(repo: &GitRepository) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut roots: Vec<String> = ref_list(repo)?.into_iter().map(|(_, sha)| sha).collect();
+    roots.extend(ref_resolve(repo, "HEAD")?);
+    for refname in reflog_list(repo)? {
+        for entry in reflog_read(repo, &refname)? {
+            roots.extend([entry.old, entry.new]);
+        }
+    }
+    for entry in index_read(repo)?.entries {
+        if entry.mode != 0o160000 {
+            roots.push(entry.sha);
+        }
+    }
+
+    let mut wants = Vec::new();
+    let mut seen = HashSet::new();
+    for sha in roots {
+        // Reflogs may name objects that have since been pruned
+        if sha != NULL_SHA && seen.insert(sha.clone()) && object_exists(repo, &sha)? {
+            wants.push(sha);
+        }
+    }
+    objects_for_fetch(repo, &wants, &[])
+}
+
+// Write a pack and its index into `dir`, each renamed into place, and
+// return the pack's file name without its extension.
+fn repack_write(dir: &Path, pack: &[u8], mtimes: Option<&[u32]>) -> Result<String, Box<dyn Error>> {
+    let checksum = &pack[pack.len() - 20..];
+    let name = format!("pack-{}", hex::encode(checksum));
+    let index = index_pack(pack, false)?;
+
+    let mut files = vec![("pack", pack.to_vec())];
+    if let Some(mtimes) = mtimes {
+        files.push(("mtimes", write_pack_mtimes(mtimes, checksum)));
+    }
+    files.push(("idx", index));
+    for (extension, data) in files {
+        let temp = dir.join(format!(".tmp-{}-{}.{}", process::id(), name, extension));
+        fs::write(&temp, data)?;
+        rename_over(&temp, &dir.join(format!("{}.{}", name, extension)))?;
+    }
+
+    Ok(name)
+}
+
+struct RepackOptions {
+    all: bool,
+    delete: bool,
+    cruft: bool,
+    cruft_expiration: Option<i64>,
+    max_pack_size: Option<usize>,
+    quiet: bool,
+}
+
+// Pack loose objects that are reachable, or with `all` everything that
+// is reachable and not in a `.keep` pack. With `delete`, packs made
+// redundant are removed along with loose copies of packed objects; a
+// cruft repack first gathers the unreachable objects it would drop into
+// a pack of their own, unless they are older than the expiration.
+fn repack(repo: &GitRepository, options: &RepackOptions) -> Result<(), Box<dyn Error>> {
+    let dir = repo_dir(repo, "objects/pack")?;
+    let existing = repack_existing(&dir)?;
+    let loose = LooseOdb {
+        dir: repo_path(repo, "objects"),
+        compression: Compression::default(),
+    }
+    .scan()?
+    .objects;
+
+    let kept: HashSet<&str> = existing
+        .iter()
+        .filter(|pack| pack.kept)
+        .flat_map(|pack| pack.objects.iter().map(|(sha, _)| sha.as_str()))
+        .collect();
+    let packed: HashSet<&str> = existing
+        .iter()
+        .flat_map(|pack| pack.objects.iter().map(|(sha, _)| sha.as_str()))
+        .collect();
+    let all = options.all || options.cruft;
+    let reachable = repack_reachable(repo)?;
+    let objects: Vec<String> = reachable
+        .iter()
+        .filter(|sha| match all {
+            true => !kept.contains(sha.as_str()),
+            false => !packed.contains(sha.as_str()),
+        })
+        .cloned()
+        .collect();
+
+    // Objects are split between packs in the order they were found, a new
+    // pack starting before any object that would take the last past the
+    // limit. An object bigger than the limit gets a pack to itself.
+    let compression = pack_compression(repo)?;
+    let mut chunks: Vec<Vec<Vec<u8>>> = Vec::new();
+    let mut size = 0;
+    for sha in &objects {
+        let entry = pack_entry(repo, sha, compression)?;
+        let overflows = options
+            .max_pack_size
+            .is_some_and(|limit| size + entry.len() + 32 > limit);
+        if chunks.is_empty() || (overflows && size > 0) {
+            chunks.push(Vec::new());
+            size = 0;
+        }
+        size += entry.len();
+        chunks.last_mut().unwrap().push(entry);
+    }
+    let mut names = HashSet::new();
+    for entries in &chunks {
+        names.insert(repack_write(&dir, &pack_from_entries(entries), None)?);
+    }
+
+    if all && options.cruft && options.delete {
+        let reachable: HashSet<&str> = reachable.iter().map(String::as_str).collect();
+        let mut cruft: BTreeMap<&str, u32> = BTreeMap::new();
+        for pack in existing.iter().filter(|pack| !pack.kept) {
+            for (sha, mtime) in &pack.objects {
+                let latest = cruft.entry(sha.as_str()).or_insert(0);
+                *latest = (*latest).max(*mtime);
+            }
+        }
+        for (sha, path) in &loose {
+            let mtime = file_mtime(path)?;
+            let latest = cruft.entry(sha.as_str()).or_insert(0);
+            *latest = (*latest).max(mtime);
+        }
+        cruft.retain(|sha, mtime| {
+            !reachable.contains(sha)
+                && !kept.contains(sha)
+                && options
+                    .cruft_expiration
+                    .is_none_or(|expiration| *mtime as i64 >= expiration)
+        });
+
+        if !cruft.is_empty() {
+            let entries = cruft
+                .keys()
+                .map(|sha| pack_entry(repo, sha, compression))
+                .collect::<Result<Vec<_>, _>>()?;
+            let mtimes: Vec<u32> = cruft.values().copied().collect();
+            names.insert(repack_write(
+                &dir,
+                &pack_from_entries(&entries),
+                Some(&mtimes),
+            )?);
+        }
+    }
+
+    if names.is_empty() && !options.quiet {
+        println!("Nothing new to pack.");
+    }
+    if !options.delete {
+        return Ok(());
+    }
+
+    // The index goes first, so no reader finds it without its pack
+    for pack in &existing {
+        let name = pack.path.file_stem().and_then(|stem| stem.to_str());
+        if pack.kept || !all || name.is_some_and(|name| names.contains(name)) {
+            continue;
+        }
+        for extension in ["idx", "pack", "rev", "bitmap", "mtimes", "promisor"] {
+            let path = pack.path.with_extension(extension);
+            if let Err(e) = fs::remove_file(&path) {
+                if e.kind() != io::ErrorKind::NotFound {
+                    return Err(From::from(e));
+                }
+            }
+        }
+    }
+
+    // Loose copies of anything now packed are redundant
+    let packed: HashSet<String> = repack_existing(&dir)?
+        .into_iter()
+        .flat_map(|pack| pack.objects.into_iter().map(|(sha, _)| sha))
+        .collect();
+    for (sha, path) in &loose {
+        if packed.contains(sha) {
+            fs::remove_file(path)?;
+            let _ = path.parent().map(fs::remove_dir);
+        }
+    }
+
+    Ok(())
+}
+
+// Resolve a ref such as `HEAD` or `refs/heads/main` to an object id,
+// following symbolic refs and falling back to `packed-refs`. Files like
+// FETCH_HEAD and MERGE_HEAD hold a line per commit, and more after the
+// id; the first id is the one they name.
+fn ref_resolve(repo: &GitRepository, name: &str) -> Result<Option<String>, Box<dyn Error>> {
+    // Pseudorefs like FETCH_HEAD stay files even beside a reftable stack
+    if repo.reftable {
+        if let Some(sha) = reftable_resolve(&reftable_refs(repo)?, name) {
+            return Ok(Some(sha));
+        }
+        if name == "HEAD" || name.starts_with("refs/") {
+            return Ok(None);
+        }
+    }
+    let path = repo_path(repo, name);
+    if path.is_file() {
+        let content = fs::read_to_string(&path)?;
+        let content = content.trim_end();
+        return match content.strip_prefix("ref: ") {
+            Some(target) => ref_resolve(repo, target),
+            None => Ok(content.split_whitespace().next().map(ToString::to_string)),
+        };
+    }
+
+    Ok(packed_refs(repo)?
+        .into_iter()
+        .find(|(ref_name, _)| ref_name == name)
+        .map(|(_, sha)| sha))
+}
+
+// Turn a name given on the command line into an object id: a full hex
+// id or a ref, optionally followed by `~<n>`, `^<n>` and `^{<type>}`
+// suffixes.
+fn object_resolve(repo: &GitRepository, name: &str) -> Result<String, Box<dyn Error>> {
+    if let Some((rev, path)) = name.split_once(':') {
+        return object_resolve_path(repo, rev, path);
+    }
+    let invalid = || format!("Not a valid object name {}", name);
+    let split = name.find(['~', '^']).unwrap_or(name.len());
+    let (base, mut suffix) = name.split_at(split);
+    let mut sha = object_resolve_base(repo, base)?;
+
+    while let Some(operator) = suffix.chars().next() {
+        suffix = &suffix[1..];
+        if operator == '^' && suffix.starts_with('{') {
+            let end = suffix.find('}').ok_or_else(invalid)?;
+            sha = object_peel(repo, &sha, &suffix[1..end])?;
+            suffix = &suffix[end + 1..];
+            continue;
+        }
+
+        let digits = suffix
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(suffix.len());
+        let count = match digits {
+            0 => 1,
+            _ => suffix[..digits].parse()?,
+        };
+        suffix = &suffix[digits..];
+
+        let parents = |sha: &str| {
+            kvlm_shas(
+                &commit_kvlm(repo, &object_peel(repo, sha, "commit")?)?,
+                "parent",
+            )
+        };
+        match operator {
+            '~' => {
+                for _ in 0..count {
+                    sha = parents(&sha)?.into_iter().next().ok_or_else(invalid)?;
+                }
+            }
+            '^' if count == 0 => sha = object_peel(repo, &sha, "commit")?,
+            '^' => {
+                sha = parents(&sha)?
+                    .into_iter()
+                    .nth(count - 1)
+                    .ok_or_else(invalid)?
+            }
+            _ => return Err(From::from(invalid())),
+        }
+    }
+
+    Ok(sha)
+}
+
+// `<rev>:<path>` names what is at `path` in the tree of `rev`, and
+// `:<path>` or `:<n>:<path>` the index entry at stage n, 0 by default.
+fn object_resolve_path(
+    repo: &GitRepository,
+    rev: &str,
+    path: &str,
+) -> Result<String, Box<dyn Error>> {
+    if rev.is_empty() {
+        let (stage, path) = match path.as_bytes() {
+            [n @ b'0'..=b'3', b':', ..] => ((n - b'0') as u16, &path[2..]),
+            _ => (0, path),
+        };
+        let index = index_read(repo)?;
+        if let Some(entry) = index
+            .entries
+            .iter()
+            .find(|entry| entry.path == path.as_bytes() && entry.stage() == stage)
+        {
+            return Ok(entry.sha.clone());
+        }
+        return Err(From::from(if index.contains(path.as_bytes()) {
+            format!(
+                "path '{}' is in the index, but not at stage {}",
+                path, stage
+            )
+        } else if worktree_file(repo, path.as_bytes())?.exists() {
+            format!("path '{}' exists on disk, but not in the index", path)
+        } else {
+            format!(
+                "path '{}' does not exist (neither on disk nor in the index)",
+                path
+            )
+        }));
+    }
+
+    let missing = || format!("path '{}' does not exist in '{}'", path, rev);
+    let mut sha = object_peel(repo, &object_resolve(repo, rev)?, "tree")?;
+    for part in path.split('/').filter(|part| !part.is_empty()) {
+        let GitObject::Tree(tree) = read_object(repo, &sha)? else {
+            return Err(From::from(missing()));
+        };
+        sha = parse_tree(tree.serialize())?
+            .into_iter()
+            .find(|leaf| leaf.path == part.as_bytes())
+            .ok_or_else(missing)?
+            .sha;
+    }
+    Ok(sha)
+}
+
+// Peel tags, and commits down to their tree, until reaching an object of
+// `object_type`; an empty type just peels tags.
+fn object_peel(
+    repo: &GitRepository,
+    sha: &str,
+    object_type: &str,
+) -> Result<String, Box<dyn Error>> {
+    let mut sha = sha.to_string();
+    loop {
+        let object = read_object(repo, &sha)?;
+        if object.object_type() == object_type
+            || (object_type.is_empty() && !matches!(object, GitObject::Tag(_)))
+        {
+            return Ok(sha);
+        }
+        sha = match &object {
+            GitObject::Tag(tag) => vec![tag_parse(tag.serialize())?.object],
+            GitObject::Commit(commit) if object_type == "tree" => {
+                kvlm_shas(&parse_kvlm(commit.serialize())?, "tree")?
+            }
+            _ => Vec::new(),
+        }
+        .into_iter()
+        .next()
+        .ok_or_else(|| format!("{} is a {}, not a {}", sha, object, object_type))?;
+    }
+}
+
+// One line of a reflog: `<old> <new> <ident> <time> <tz>\t<message>`.
+struct ReflogEntry {
+    old: String,
+    new: String,
+    time: i64,
+    tz: String,
+    message: String,
+    line: String,
+}
+
+// The log of `refname`, oldest entry first; a ref without one has an
+// empty log.
+fn reflog_read(repo: &GitRepository, refname: &str) -> Result<Vec<ReflogEntry>, Box<dyn Error>> {
+    if repo.reftable {
+        return Ok(reftable_logs(repo)?
+            .into_iter()
+            .filter(|((name, _), _)| name == refname)
+            .map(|(_, entry)| entry)
+            .collect());
+    }
+    let path = repo_path(repo, &format!("logs/{}", refname));
+    if !path.is_file() {
+        return Ok(Vec::new());
+    }
+
+    Ok(fs::read_to_string(path)?
+        .lines()
+        .filter_map(reflog_parse_line)
+        .collect())
+}
+
+// One line of a reflog file, or nothing for a line too damaged to read
+fn reflog_parse_line(line: &str) -> Option<ReflogEntry> {
+    let (head, message) = line.split_once('\t').unwrap_or((line, ""));
+    let mut fields = head.rsplitn(3, ' ');
+    let (tz, time, rest) = (fields.next()?, fields.next()?, fields.next()?);
+    let mut ids = rest.splitn(3, ' ');
+    let (old, new) = (ids.next()?, ids.next()?);
+    Some(ReflogEntry {
+        old: old.to_string(),
+        new: new.to_string(),
+        time: time.parse().unwrap_or(0),
+        tz: tz.to_string(),
+        message: message.to_string(),
+        line: line.to_string(),
+    })
+}
+
+// The ref whose log `name@{...}` reads: the current branch for an empty
+// name, otherwise the first ref `name` could mean that has a log.
+fn reflog_ref(repo: &GitRepository, name: &str) -> Option<String> {
+    if name.is_empty() {
+        return Some(ref_symbolic_target(repo, "HEAD").unwrap_or_else(|| "HEAD".to_string()));
+    }
+    [
+        name.to_string(),
+        format!("refs/{}", name),
+        format!("refs/tags/{}", name),
+        format!("refs/heads/{}", name),
+        format!("refs/remotes/{}", name),
+        format!("refs/remotes/{}/HEAD", name),
+    ]
+    .into_iter()
+    .find(|candidate| match repo.reftable {
+        true => reflog_read(repo, candidate).is_ok_and(|entries| !entries.is_empty()),
+        false => repo_path(repo, &format!("logs/{}", candidate)).is_file(),
+    })
+}
+
+// The branch (or detached commit) checked out `n` switches ago, as the
+// HEAD log records checkouts.
+fn reflog_previous_branch(
+    repo: &GitRepository,
+    n: usize,
+) -> Result<Option<String>, Box<dyn Error>> {
+    let Some(skip) = n.checked_sub(1) else {
+        return Ok(None);
+    };
+    Ok(reflog_read(repo, "HEAD")?
+        .iter()
+        .rev()
+        .filter_map(|entry| {
+            let (from, _) = entry
+                .message
+                .strip_prefix("checkout: moving from ")?
+                .split_once(" to ")?;
+            Some(from.to_string())
+        })
+        .nth(skip))
+}
+
+// Resolve `name@{selector}`: the n-th prior value of a ref, its value at
+// a date, or with `@{-n}` the n-th previously checked out branch.
+fn reflog_resolve(
+    repo: &GitRepository,
+    name: &str,
+    selector: &str,
+) -> Result<String, Box<dyn Error>> {
+    let invalid = || format!("Not a valid object name {}@{{{}}}", name, selector);
+    if let Some(n) = selector.strip_prefix('-') {
+        let n = n.parse().map_err(|_| invalid())?;
+        if !name.is_empty() {
+            return Err(From::from(invalid()));
+        }
+        let branch = reflog_previous_branch(repo, n)?.ok_or_else(invalid)?;
+        return object_resolve_base(repo, &branch);
+    }
+
+    let refname = reflog_ref(repo, name).ok_or_else(invalid)?;
+    let entries = reflog_read(repo, &refname)?;
+    let shown = refname.strip_prefix("refs/heads/").unwrap_or(&refname);
+    let too_short = || format!("log for '{}' only has {} entries", shown, entries.len());
+
+    // Small numbers count entries back, large ones are timestamps
+    match selector.parse::<usize>() {
+        Ok(n) if n < 100_000_000 => {
+            return match entries.len().checked_sub(n + 1).map(|i| &entries[i]) {
+                Some(entry) => Ok(entry.new.clone()),
+                None if n == entries.len() => match entries.first() {
+                    Some(entry) if entry.old != NULL_SHA => Ok(entry.old.clone()),
+                    _ => Err(From::from(too_short())),
+                },
+                None => Err(From::from(too_short())),
+            };
+        }
+        _ => {}
+    }
+
+    let date = date::approxidate(selector).ok_or_else(invalid)?;
+    if let Some(entry) = entries.iter().rev().find(|entry| entry.time <= date) {
+        return Ok(entry.new.clone());
+    }
+    let oldest = entries.first().ok_or_else(invalid)?;
+    eprintln!(
+        "warning: log for '{}' only goes back to {}",
+        shown,
+        date::rfc2822(oldest.time, &oldest.tz)
+    );
+    match oldest.old.as_str() {
+        NULL_SHA => Ok(oldest.new.clone()),
+        old => Ok(old.to_string()),
+    }
+}
+
+// Prune entries older than `expire` from the log of `refname`. Lines
+// that cannot be read as entries are kept as they are.
+fn reflog_expire(repo: &GitRepository, refname: &str, expire: i64) -> Result<(), Box<dyn Error>> {
+    // A reftable forgets entries through deletion records in a new table
+    if repo.reftable {
+        let expired: Vec<(String, u64)> = reftable_logs(repo)?
+            .into_iter()
+            .filter(|((name, _), entry)| name == refname && entry.time < expire)
+            .map(|(key, _)| key)
+            .collect();
+        if expired.is_empty() {
+            return Ok(());
+        }
+        return reftable_add(repo, |_| Ok((Vec::new(), expired)));
+    }
+
+    let path = repo_path(repo, &format!("logs/{}", refname));
+    if !path.is_file() {
+        return Ok(());
+    }
+    let text = fs::read_to_string(&path)?;
+    let mut kept = String::new();
+    for line in text.lines() {
+        if reflog_parse_line(line).is_none_or(|entry| entry.time >= expire) {
+            kept.push_str(line);
+            kept.push('\n');
+        }
+    }
+    if kept.lines().count() == text.lines().count() {
+        return Ok(());
+    }
+
+    let lock = repo_path(repo, &format!("logs/{}.lock", refname));
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&lock)
+        .map_err(|e| format!("cannot lock reflog for '{}': {}", refname, e))?;
+    let written = file
+        .write_all(kept.as_bytes())
+        .and_then(|()| rename_over(&lock, &path));
+    if written.is_err() {
+        let _ = fs::remove_file(&lock);
+    }
+    Ok(written?)
+}
+
+// Every ref with a log, HEAD included.
+fn reflog_list(repo: &GitRepository) -> Result<Vec<String>, Box<dyn Error>> {
+    if repo.reftable {
+        let mut refs: Vec<String> = reftable_logs(repo)?
+            .into_keys()
+            .map(|(name, _)| name)
+            .collect();
+        refs.dedup();
+        return Ok(refs);
+    }
+    let mut refs = Vec::new();
+    if repo_path(repo, "logs/HEAD").is_file() {
+        refs.push("HEAD".to_string());
+    }
+    let mut dirs = vec!["refs".to_string()];
+    while let Some(dir) = dirs.pop() {
+        let Ok(entries) = fs::read_dir(repo_path(repo, &format!("logs/{}", dir))) else {
+            continue;
+        };
+        for entry in entries {
+            let entry = entry?;
+            let name = format!("{}/{}", dir, entry.file_name().to_string_lossy());
+            if entry.file_type()?.is_dir() {
+                dirs.push(name);
+            } else if !name.ends_with(".lock") {
+                refs.push(name);
+            }
+        }
+    }
+    refs.sort();
+    Ok(refs)
+}
+
+// Options and revisions come in any order and are answered in turn, as
+// git does. Options it does not know are echoed back, for scripts that
+// pass them on to other commands. With --verify or --short, the single
+// revision allowed is printed last.
+fn rev_parse(repo: &GitRepository, args: &[String]) -> Result<(), Box<dyn Error>> {
+    let config = repo_config(repo)?;
+    let single = || "Needed a single revision";
+    let mut verify = false;
+    let mut short = None;
+    let mut abbrev_ref = None;
+    for arg in args {
+        match arg.as_str() {
+            "--verify" => verify = true,
+            "--short" => short = Some(None),
+            "--abbrev-ref" | "--abbrev-ref=strict" => abbrev_ref = Some(true),
+            "--abbrev-ref=loose" => abbrev_ref = Some(false),
+            _ => {
+                if let Some(length) = arg.strip_prefix("--short=") {
+                    let length = length
+                        .parse::<usize>()
+                        .map_err(|_| format!("bad --short length '{}'", length))?;
+                    short = Some(Some(length));
+                }
+            }
+        }
+    }
+    let strict = match abbrev_ref {
+        Some(strict) => {
+            strict && config_get_bool(&config, "core.warnambiguousrefs")?.unwrap_or(true)
+        }
+        None => false,
+    };
+    let abbrev = match short {
+        Some(length) => Some(Abbrev::new(repo, length)?),
+        None => None,
+    };
+    verify |= abbrev.is_some();
+
+    let cwd = fs::canonicalize(".")?;
+    let bare =
+        repo.worktree == repo.gitdir && config_get_bool(&config, "core.bare")?.unwrap_or(true);
+    let mut revisions = Vec::new();
+    for arg in args {
+        match arg.as_str() {
+            "--verify" | "--short" | "--abbrev-ref" => {}
+            _ if arg.starts_with("--short=") || arg.starts_with("--abbrev-ref=") => {}
+            "--git-dir" => match cwd.as_path() {
+                dir if dir == Path::new(&repo.gitdir) => println!("."),
+                dir if dir == Path::new(&repo.worktree) => println!(".git"),
+                _ => println!("{}", repo.gitdir),
+            },
+            "--absolute-git-dir" => println!("{}", repo.gitdir),
+            "--show-toplevel" => {
+                repo_require_worktree(repo)?;
+                println!("{}", repo.worktree);
+            }
+            "--is-inside-work-tree" => {
+                let inside = repo.worktree != repo.gitdir && !cwd.starts_with(&repo.gitdir);
+                println!("{}", inside);
+            }
+            "--is-bare-repository" => println!("{}", bare),
+            _ if arg.starts_with('-') => println!("{}", arg),
+            _ => revisions.push(arg.as_str()),
+        }
+        if verify {
+            continue;
+        }
+        for revision in revisions.drain(..) {
+            rev_parse_show(repo, revision, None, abbrev_ref.map(|_| strict))?;
+        }
+    }
+
+    if verify {
+        let [revision] = revisions[..] else {
+            return Err(From::from(single()));
+        };
+        object_resolve(repo, revision).map_err(|_| single())?;
+        rev_parse_show(repo, revision, abbrev.as_ref(), abbrev_ref.map(|_| strict))?;
+    }
+
+    Ok(())
+}
+
+// A revision as its object id, abbreviated if asked, or with
+// `abbrev_ref` as the short name of the ref it names. Revisions that are
+// not refs have no such name and print nothing.
+fn rev_parse_show(
+    repo: &GitRepository,
+    revision: &str,
+    abbrev: Option<&Abbrev>,
+    abbrev_ref: Option<bool>,
+) -> Result<(), Box<dyn Error>> {
+    let sha = object_resolve(repo, revision)?;
+    let Some(strict) = abbrev_ref else {
+        println!(
+            "{}",
+            abbrev.map_or(sha.clone(), |abbrev| abbrev.apply(&sha))
+        );
+        return Ok(());
+    };
+
+    let mut refname = match &ref_dwim(repo, revision)?[..] {
+        [] => return Ok(()),
+        [refname] => refname.clone(),
+        _ => {
+            eprintln!("error: refname '{}' is ambiguous", revision);
+            return Ok(());
+        }
+    };
+    while let Some(target) = ref_symbolic_target(repo, &refname) {
+        refname = target;
+    }
+    println!("{}", ref_shorten(repo, &refname, strict)?);
+    Ok(())
+}
+
+// Where a short ref name is looked for, in order, as a prefix and suffix.
+const REF_RULES: [(&str, &str); 6] = [
+    ("", ""),
+    ("refs/", ""),
+    ("refs/tags/", ""),
+    ("refs/heads/", ""),
+    ("refs/remotes/", ""),
+    ("refs/remotes/", "/HEAD"),
+];
+
+// Only all-caps names like HEAD live directly in the git directory
+fn ref_rule_applies(rule: usize, name: &str) -> bool {
+    rule > 0
+        || name.starts_with("refs/")
+        || name.bytes().all(|b| b.is_ascii_uppercase() || b == b'_')
+}
+
+// The full names of the refs a short name could stand for, in rule
+// order; the first is the one it means.
+fn ref_dwim(repo: &GitRepository, name: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut refnames = Vec::new();
+    for (i, (prefix, suffix)) in REF_RULES.iter().enumerate() {
+        let candidate = format!("{}{}{}", prefix, name, suffix);
+        if ref_rule_applies(i, name) && ref_resolve(repo, &candidate)?.is_some() {
+            refnames.push(candidate);
+        }
+    }
+    Ok(refnames)
+}
+
+// The shortest name that still finds `refname` by REF_RULES. Loosely, no
+// rule tried before must find another ref under that name; strictly, no
+// other rule at all may.
+fn ref_shorten(
+    repo: &GitRepository,
+    refname: &str,
+    strict: bool,
+) -> Result<String, Box<dyn Error>> {
+    for (i, (prefix, suffix)) in REF_RULES.iter().enumerate().skip(1).rev() {
+        let Some(short) = refname
+            .strip_prefix(prefix)
+            .and_then(|rest| rest.strip_suffix(suffix))
+            .filter(|short| !short.is_empty())
+        else {
+            continue;
+        };
+
+        let mut ambiguous = false;
+        for (j, (prefix, suffix)) in REF_RULES.iter().enumerate() {
+            if j == i || (!strict && j > i) || !ref_rule_applies(j, short) {
+                continue;
+            }
+            if ref_resolve(repo, &format!("{}{}{}", prefix, short, suffix))?.is_some() {
+                ambiguous = true;
+                break;
+            }
+        }
+        if !ambiguous {
+            return Ok(short.to_string());
+        }
+    }
+
+    Ok(refname.to_string())
+}
+
+fn object_resolve_base(repo: &GitRepository, name: &str) -> Result<String, Box<dyn Error>> {
+    if let Some((name, selector)) = name
+        .strip_suffix('}')
+        .and_then(|name| name.split_once("@{"))
+    {
+        return reflog_resolve(repo, name, selector);
+    }
+    if name == "@" {
+        return object_resolve_base(repo, "HEAD");
+    }
+
+    if name.len() == 40 && name.bytes().all(|b| b.is_ascii_hexdigit()) {
+        let sha = name.to_ascii_lowercase();
+        if object_exists(repo, &sha)? {
+            return Ok(sha);
+        }
+    }
+
+    if let Some(refname) = ref_dwim(repo, name)?.first() {
+        if let Some(sha) = ref_resolve(repo, refname)? {
+            return Ok(sha);
+        }
+    }
+
+    // Like git, refs win over abbreviated object names
+    if (4..40).contains(&name.len()) && name.bytes().all(|b| b.is_ascii_hexdigit()) {
+        let prefix = name.to_ascii_lowercase();
+        let matches: Vec<String> = repo_odb(repo)?
+            .enumerate()?
+            .into_iter()
+            .filter(|sha| sha.starts_with(&prefix))
+            .collect();
+        match matches.len() {
+            0 => {}
+            1 => return Ok(matches[0].clone()),
+            _ => {
+                ambiguous_object_hints(repo, name, &matches)?;
+                return Err(From::from(format!("Not a valid object name {}", name)));
+            }
+        }
+    }
+
+    Err(From::from(format!("Not a valid object name {}", name)))
+}
+
+// Tell the user which objects an ambiguous short name could mean, tags
+// and commits first, the way git does.
+fn ambiguous_object_hints(
+    repo: &GitRepository,
+    name: &str,
+    candidates: &[String],
+) -> Result<(), Box<dyn Error>> {
+    let abbrev = Abbrev::new(repo, None)?;
+    let mut lines = Vec::new();
+    for sha in candidates {
+        let short = abbrev.apply(sha);
+        let (rank, line) = match read_object(repo, sha)? {
+            GitObject::Tag(tag) => {
+                let kvlm = parse_kvlm(tag.serialize())?;
+                let date = kvlm
+                    .get_str("tagger")
+                    .map(date::ident_short)
+                    .unwrap_or_default();
+                let tag_name = kvlm.get_str("tag").unwrap_or_default();
+                (0, format!("{} tag {} - {}", short, date, tag_name))
+            }
+            GitObject::Commit(commit) => {
+                let kvlm = parse_kvlm(commit.serialize())?;
+                let date = kvlm
+                    .get_str("author")
+                    .map(date::ident_short)
+                    .unwrap_or_default();
+                let message = String::from_utf8_lossy(&kvlm.message).into_owned();
+                let subject = message.lines().next().unwrap_or_default().to_string();
+                (1, format!("{} commit {} - {}", short, date, subject))
+            }
+            GitObject::Tree(_) => (2, format!("{} tree", short)),
+            GitObject::Blob(_) => (3, format!("{} blob", short)),
+        };
+        lines.push((rank, sha, line));
+    }
+    lines.sort();
+
+    eprintln!("error: short object ID {} is ambiguous", name);
+    eprintln!("hint: The candidates are:");
+    for (_, _, line) in lines {
+        eprintln!("hint:   {}", line);
+    }
+    Ok(())
+}
+
+// Shortens object names to the fewest digits no other object shares, and
+// never to fewer than core.abbrev.
+struct Abbrev {
+    shas: Vec<String>,
+    min: usize,
+}
+
+impl Abbrev {
+    fn new(repo: &GitRepository, min: Option<usize>) -> Result<Abbrev, Box<dyn Error>> {
+        let shas = repo_odb(repo)?.enumerate()?;
+        let min = match min {
+            Some(min) => min,
+            None => {
+                let config = repo_config(repo)?;
+                match config_get(&config, "core.abbrev") {
+                    None | Some("auto") => {
+                        // Enough digits to expect no clashes, as git reckons it
+                        let bits = usize::BITS - shas.len().leading_zeros();
+                        (bits as usize).div_ceil(2).max(7)
+                    }
+                    Some("no") => 40,
+                    Some(value) => match value.parse::<usize>() {
+                        Ok(min @ 4..=40) => min,
+                        _ => {
+                            return Err(From::from(format!(
+                                "abbrev length out of range: {}",
+                                value
+                            )))
+                        }
+                    },
+                }
+            }
+        };
+
+        Ok(Abbrev {
+            shas,
+            min: min.clamp(4, 40),
+        })
+    }
+
+    fn apply(&self, sha: &str) -> String {
+        let common = |other: &String| {
+            other
+                .bytes()
+                .zip(sha.bytes())
+                .take_while(|(a, b)| a == b)
+                .count()
+        };
+        // Only the neighbours in sorted order can share a longer prefix
+        let (before, after) = match self.shas.binary_search_by(|other| other.as_str().cmp(sha)) {
+            Ok(i) => (i.checked_sub(1), i + 1),
+            Err(i) => (i.checked_sub(1), i),
+        };
+        let shared = before
+            .and_then(|i| self.shas.get(i))
+            .map_or(0, common)
+            .max(self.shas.get(after).map_or(0, common));
+
+        let len = (shared + 1).max(self.min).min(sha.len());
+        sha[..len].to_string()
+    }
+}
+
+// The ref a symbolic ref like `HEAD` points at, if it is symbolic.
+fn ref_symbolic_target(repo: &GitRepository, name: &str) -> Option<String> {
+    if repo.reftable {
+        return match reftable_refs(repo).ok()?.remove(name)? {
+            ReftableValue::Symref(target) => Some(target),
+            _ => None,
+        };
+    }
+    let content = fs::read_to_string(repo_path(repo, name)).ok()?;
+    content
+        .trim_end()
+        .strip_prefix("ref: ")
+        .map(ToString::to_string)
+}
+
+fn packed_refs(repo: &GitRepository) -> Result<Vec<(String, String)>, Box<dyn Error>> {
+    let path = repo_path(repo, "packed-refs");
+    if !path.is_file() {
+        return Ok(Vec::new());
+    }
+
+    Ok(fs::read_to_string(path)?
+        .lines()
+        .filter(|line| !line.starts_with('#') && !line.starts_with('^'))
+        .filter_map(|line| line.split_once(' '))
+        .map(|(sha, name)| (name.to_string(), sha.to_string()))
+        .collect())
+}
+
+// Every ref under `refs/` sorted by name; loose refs shadow packed ones.
+fn ref_list(repo: &GitRepository) -> Result<Vec<(String, String)>, Box<dyn Error>> {
+    if repo.reftable {
+        let refs = reftable_refs(repo)?;
+        return Ok(refs
+            .keys()
+            .filter(|name| name.starts_with("refs/"))
+            .filter_map(|name| Some((name.clone(), reftable_resolve(&refs, name)?)))
+            .collect());
+    }
+    let mut refs: BTreeMap<String, String> = packed_refs(repo)?.into_iter().collect();
+
+    let mut dirs = vec!["refs".to_string()];
+    while let Some(dir) = dirs.pop() {
+        let Ok(entries) = fs::read_dir(repo_path(repo, &dir)) else {
+            continue;
+        };
+        for entry in entries {
+            let entry = entry?;
+            let name = format!("{}/{}", dir, entry.file_name().to_string_lossy());
+            if entry.file_type()?.is_dir() {
+                dirs.push(name);
+            } else if !name.ends_with(".lock") {
+                if let Some(sha) = ref_resolve(repo, &name)? {
+                    refs.insert(name, sha);
+                }
+            }
+        }
+    }
+
+    Ok(refs.into_iter().collect())
+}
+
+// Refs and their logs kept in a stack of reftables, for repositories with
+// extensions.refStorage=reftable. `reftable/tables.list` names the tables
+// oldest first, and newer tables override older ones. Each table covers
+// a range of update indexes and holds ref records, then log records,
+// packed into blocks with the keys prefix-compressed.
+const REFTABLE_SIGNATURE: &[u8] = b"REFT";
+const REFTABLE_BLOCK_SIZE: usize = 4096;
+const REFTABLE_FOOTER_LEN: usize = 68;
+const REFTABLE_RESTART_INTERVAL: usize = 16;
+// Each table in the stack is kept at least this many times the size of
+// the next newer one, so the stack stays logarithmic in the refs it holds
+const REFTABLE_GEOMETRIC_FACTOR: u64 = 2;
+
+#[derive(Clone, PartialEq)]
+enum ReftableValue {
+    Deleted,
+    Object(String),
+    Symref(String),
+}
+
+struct Reftable {
+    min_update_index: u64,
+    max_update_index: u64,
+    refs: Vec<(String, ReftableValue)>,
+    // Keyed by ref and update index; `None` deletes an older entry
+    logs: Vec<((String, u64), Option<ReflogEntry>)>,
+}
+
+fn reftable_be(data: &[u8], at: usize, len: usize) -> Result<u64, Box<dyn Error>> {
+    let bytes = data
+        .get(at..at + len)
+        .ok_or_else(|| corrupt("reftable", at, "unexpected end of data"))?;
+    Ok(bytes.iter().fold(0, |value, &b| value << 8 | b as u64))
+}
+
+// The same 7-bit groups as pack delta offsets, each continuation adding one
+fn reftable_varint(data: &[u8], position: &mut usize) -> Result<u64, Box<dyn Error>> {
+    let mut byte = reftable_be(data, *position, 1)?;
+    *position += 1;
+    let mut value = byte & 0x7f;
+    while byte & 0x80 != 0 {
+        byte = reftable_be(data, *position, 1)?;
+        value = value
+            .checked_add(1)
+            .and_then(|value| value.checked_mul(0x80))
+            .ok_or_else(|| corrupt("reftable", *position, "varint too large"))?
+            | byte & 0x7f;
+        *position += 1;
+    }
+    Ok(value)
+}
+
+fn reftable_put_varint(out: &mut Vec<u8>, mut value: u64) {
+    let mut bytes = vec![(value & 0x7f) as u8];
+    while value >= 0x80 {
+        value = (value >> 7) - 1;
+        bytes.push(0x80 | (value & 0x7f) as u8);
+    }
+    out.extend(bytes.iter().rev());
+}
+
+fn reftable_bytes<'a>(
+    data: &'a [u8],
+    position: &mut usize,
+    len: usize,
+) -> Result<&'a [u8], Box<dyn Error>> {
+    let bytes = data
+        .get(*position..*position + len)
+        .ok_or_else(|| corrupt("reftable", *position, "unexpected end of data"))?;
+    *position += len;
+    Ok(bytes)
+}
+
+fn reftable_string(data: &[u8], position: &mut usize) -> Result<String, Box<dyn Error>> {
+    let len = reftable_varint(data, position)? as usize;
+    Ok(String::from_utf8_lossy(reftable_bytes(data, position, len)?).into_owned())
+}
+
+// Walk the records of one block, handing each full key, its value type
+// and the position of its value to `record`, which reads the value.
+fn reftable_records(
+    block: &[u8],
+    start: usize,
+    mut record: impl FnMut(&[u8], u8, &mut usize) -> Result<(), Box<dyn Error>>,
+) -> Result<(), Box<dyn Error>> {
+    let restarts = reftable_be(block, block.len().saturating_sub(2), 2)? as usize;
+    let end = block
+        .len()
+        .checked_sub(2 + 3 * restarts)
+        .filter(|&end| end >= start)
+        .ok_or_else(|| corrupt("reftable", 0, "bad restart count"))?;
+
+    let records = &block[..end];
+    let mut position = start;
+    let mut key: Vec<u8> = Vec::new();
+    while position < end {
+        let prefix = reftable_varint(records, &mut position)? as usize;
+        let suffix_and_type = reftable_varint(records, &mut position)?;
+        if prefix > key.len() {
+            return Err(corrupt("reftable", position, "key prefix too long"));
+        }
+        key.truncate(prefix);
+        key.extend_from_slice(reftable_bytes(
+            records,
+            &mut position,
+            (suffix_and_type >> 3) as usize,
+        )?);
+        record(&key, (suffix_and_type & 7) as u8, &mut position)?;
+    }
+    Ok(())
+}
+
+fn reftable_parse(table: &[u8]) -> Result<Reftable, Box<dyn Error>> {
+    if table.len() < 24 + REFTABLE_FOOTER_LEN || &table[..4] != REFTABLE_SIGNATURE {
+        return Err(corrupt("reftable", 0, "not a reftable"));
+    }
+    if table[4] != 1 {
+        return Err(From::from(format!(
+            "Unsupported reftable version {}",
+            table[4]
+        )));
+    }
+    let block_size = reftable_be(table, 5, 3)? as usize;
+    let min_update_index = reftable_be(table, 8, 8)?;
+    let max_update_index = reftable_be(table, 16, 8)?;
+
+    let footer = table.len() - REFTABLE_FOOTER_LEN;
+    let mut crc = Crc::new();
+    crc.update(&table[footer..table.len() - 4]);
+    if table[footer..footer + 24] != table[..24]
+        || crc.sum() as u64 != reftable_be(table, table.len() - 4, 4)?
+    {
+        return Err(corrupt("reftable", footer, "bad footer"));
+    }
+    let log_position = reftable_be(table, footer + 48, 8)? as usize;
+
+    let mut refs = Vec::new();
+    let mut logs = Vec::new();
+    let mut offset = 0;
+    while offset < footer {
+        // The first block shares its start with the file header
+        let header = if offset == 0 { 24 } else { 0 };
+        let block_type = reftable_be(table, offset + header, 1)? as u8;
+        let block_len = reftable_be(table, offset + header + 1, 3)? as usize;
+        let start = header + 4;
+        if block_len < start {
+            return Err(corrupt("reftable", offset, "bad block length"));
+        }
+
+        let (block, next) = match block_type {
+            b'r' => {
+                let block = table
+                    .get(offset..offset + block_len)
+                    .ok_or_else(|| corrupt("reftable", offset, "truncated block"))?;
+                // Blocks are padded out with zeros unless the next follows at once
+                let unpadded = block_size == 0
+                    || block_len >= block_size
+                    || table.get(offset + block_len).is_some_and(|&b| b != 0);
+                let next = offset + if unpadded { block_len } else { block_size };
+                (block.to_vec(), next)
+            }
+            b'l' => {
+                let (head, data) = table
+                    .get(offset..offset + start)
+                    .zip(table.get(offset + start..footer))
+                    .ok_or_else(|| corrupt("reftable", offset, "truncated block"))?;
+                let (inflated, consumed) = inflate_pack_data(data, block_len - start)
+                    .map_err(|e| corrupt("reftable", offset, e.to_string()))?;
+                let block = [head, &inflated[..]].concat();
+                (block, offset + start + consumed)
+            }
+            _ if offset < log_position => {
+                offset = log_position;
+                continue;
+            }
+            _ => break,
+        };
+
+        if block_type == b'r' {
+            reftable_records(&block, start, |key, value_type, position| {
+                let name = String::from_utf8_lossy(key).into_owned();
+                reftable_varint(&block, position)?;
+                let value = match value_type {
+                    0 => ReftableValue::Deleted,
+                    1 | 2 => {
+                        let sha = hex::encode(reftable_bytes(&block, position, 20)?);
+                        if value_type == 2 {
+                            reftable_bytes(&block, position, 20)?;
+                        }
+                        ReftableValue::Object(sha)
+                    }
+                    3 => ReftableValue::Symref(reftable_string(&block, position)?),
+                    _ => return Err(corrupt("reftable", *position, "unknown ref value type")),
+                };
+                refs.push((name, value));
+                Ok(())
+            })?;
+        } else {
+            reftable_records(&block, start, |key, value_type, position| {
+                let split = key
+                    .len()
+                    .checked_sub(9)
+                    .filter(|&split| key[split] == 0)
+                    .ok_or_else(|| corrupt("reftable", *position, "bad log key"))?;
+                let refname = String::from_utf8_lossy(&key[..split]).into_owned();
+                let update_index = !reftable_be(key, split + 1, 8)?;
+                let entry = match value_type {
+                    0 => None,
+                    1 => {
+                        let old = hex::encode(reftable_bytes(&block, position, 20)?);
+                        let new = hex::encode(reftable_bytes(&block, position, 20)?);
+                        let name = reftable_string(&block, position)?;
+                        let email = reftable_string(&block, position)?;
+                        let time = reftable_varint(&block, position)? as i64;
+                        let minutes = reftable_be(&block, *position, 2)? as u16 as i16;
+                        *position += 2;
+                        let message = reftable_string(&block, position)?;
+                        let message = message.strip_suffix('\n').unwrap_or(&message).to_string();
+                        let tz = format!(
+                            "{}{:02}{:02}",
+                            if minutes < 0 { '-' } else { '+' },
+                            minutes.unsigned_abs() / 60,
+                            minutes.unsigned_abs() % 60
+                        );
+                        let line = format!(
+                            "{} {} {} <{}> {} {}\t{}",
+                            old, new, name, email, time, tz, message
+                        );
+                        Some(ReflogEntry {
+                            old,
+                            new,
+                            time,
+                            tz,
+                            message,
+                            line,
+                        })
+                    }
+                    _ => return Err(corrupt("reftable", *position, "unknown log value type")),
+                };
+                logs.push(((refname, update_index), entry));
+                Ok(())
+            })?;
+        }
+        offset = next;
+    }
+
+    Ok(Reftable {
+        min_update_index,
+        max_update_index,
+        refs,
+        logs,
+    })
+}
+
+fn reftable_stack_names(repo: &GitRepository) -> Result<Vec<String>, Box<dyn Error>> {
+    Ok(fs::read_to_string(repo_path(repo, "reftable/tables.list"))
+        .map_err(|e| format!("cannot read reftable stack: {}", e))?
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(ToString::to_string)
+        .collect())
+}
+
+fn reftable_read(repo: &GitRepository, name: &str) -> Result<Reftable, Box<dyn Error>> {
+    let path = repo_path(repo, &format!("reftable/{}", name));
+    fs::read(&path)
+        .map_err(Box::<dyn Error>::from)
+        .and_then(|table| reftable_parse(&table))
+        .map_err(|e| From::from(format!("{}: {}", path.display(), e)))
+}
+
+// The names and tables of the stack. A compaction can remove tables
+// after we read the list, so a missing table means reading it again.
+fn reftable_stack(repo: &GitRepository) -> Result<(Vec<String>, Vec<Reftable>), Box<dyn Error>> {
+    for _ in 0..5 {
+        let names = reftable_stack_names(repo)?;
+        let stack = names
+            .iter()
+            .map(|name| reftable_read(repo, name))
+            .collect::<Result<_, _>>();
+        match stack {
+            Ok(stack) => return Ok((names, stack)),
+            Err(e) if names == reftable_stack_names(repo)? => return Err(e),
+            Err(_) => {}
+        }
+    }
+    Err(From::from(
+        "cannot read reftable stack: tables keep changing",
+    ))
+}
+
+// Every ref the stack holds, with deleted ones gone
+fn reftable_refs(repo: &GitRepository) -> Result<BTreeMap<String, ReftableValue>, Box<dyn Error>> {
+    let mut refs = BTreeMap::new();
+    for table in reftable_stack(repo)?.1 {
+        refs.extend(table.refs);
+    }
+    refs.retain(|_, value| *value != ReftableValue::Deleted);
+    Ok(refs)
+}
+
+// Every log entry the stack holds, by ref and then oldest first
+fn reftable_logs(
+    repo: &GitRepository,
+) -> Result<BTreeMap<(String, u64), ReflogEntry>, Box<dyn Error>> {
+    let mut logs = BTreeMap::new();
+    for table in reftable_stack(repo)?.1 {
+        logs.extend(table.logs);
+    }
+    Ok(logs
+        .into_iter()
+        .filter_map(|(key, entry)| Some((key, entry?)))
+        .collect())
+}
+
+// Pack `(key, value type, value)` records into blocks of `block_type`,
+// restarting the key compression every so often. Ref blocks are padded
+// to the block size; log blocks are deflated instead.
+fn reftable_write_blocks(
+    out: &mut Vec<u8>,
+    block_type: u8,
+    records: &[(Vec<u8>, u8, Vec<u8>)],
+) -> Result<(), Box<dyn Error>> {
+    let mut i = 0;
+    while i < records.len() {
+        let block_start = if out.len() == 24 { 0 } else { out.len() };
+        let header = out.len() - block_start + 4;
+        let mut body = Vec::new();
+        let mut restarts = Vec::new();
+        let mut previous: &[u8] = &[];
+        let mut count = 0;
+        while let Some((key, value_type, value)) = records.get(i) {
+            let restart = count % REFTABLE_RESTART_INTERVAL == 0;
+            let prefix = match restart {
+                true => 0,
+                false => key.iter().zip(previous).take_while(|(a, b)| a == b).count(),
+            };
+            let mut record = Vec::new();
+            reftable_put_varint(&mut record, prefix as u64);
+            reftable_put_varint(
+                &mut record,
+                ((key.len() - prefix) as u64) << 3 | *value_type as u64,
+            );
+            record.extend_from_slice(&key[prefix..]);
+            record.extend_from_slice(value);
+
+            let trailer = 3 * (restarts.len() + restart as usize) + 2;
+            if header + body.len() + record.len() + trailer > REFTABLE_BLOCK_SIZE {
+                if body.is_empty() {
+                    return Err(From::from("reftable record too large for a block"));
+                }
+                break;
+            }
+            if restart {
+                restarts.push(header + body.len());
+            }
+            body.extend(record);
+            previous = key;
+            count += 1;
+            i += 1;
+        }
+        for restart in &restarts {
+            body.extend_from_slice(&(*restart as u32).to_be_bytes()[1..]);
+        }
+        body.extend_from_slice(&(restarts.len() as u16).to_be_bytes());
+
+        out.push(block_type);
+        out.extend_from_slice(&((header + body.len()) as u32).to_be_bytes()[1..]);
+        match block_type {
+            b'l' => {
+                let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(&body)?;
+                out.extend(encoder.finish()?);
+            }
+            _ => {
+                out.extend(body);
+                out.resize(block_start + REFTABLE_BLOCK_SIZE, 0);
+            }
+        }
+    }
+    Ok(())
+}
+
+// A table covering update indexes `min..=max` that holds `refs` and
+// `logs`, where a `None` log entry deletes an older one.
+fn reftable_encode(
+    (min_update_index, max_update_index): (u64, u64),
+    refs: &[(String, ReftableValue)],
+    logs: &[((String, u64), Option<ReflogEntry>)],
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut header = REFTABLE_SIGNATURE.to_vec();
+    header.push(1);
+    header.extend_from_slice(&(REFTABLE_BLOCK_SIZE as u32).to_be_bytes()[1..]);
+    header.extend_from_slice(&min_update_index.to_be_bytes());
+    header.extend_from_slice(&max_update_index.to_be_bytes());
+
+    let mut ref_records: Vec<(Vec<u8>, u8, Vec<u8>)> = refs
+        .iter()
+        .map(|(name, value)| -> Result<_, Box<dyn Error>> {
+            // Records are put at the table's first update index
+            let mut data = vec![0];
+            let value_type = match value {
+                ReftableValue::Deleted => 0,
+                ReftableValue::Object(sha) => {
+                    data.extend(hex::decode(sha)?);
+                    1
+                }
+                ReftableValue::Symref(target) => {
+                    reftable_put_varint(&mut data, target.len() as u64);
+                    data.extend_from_slice(target.as_bytes());
+                    3
+                }
+            };
+            Ok((name.as_bytes().to_vec(), value_type, data))
+        })
+        .collect::<Result<_, _>>()?;
+    ref_records.sort_by(|a, b| a.0.cmp(&b.0));
+    let mut log_records: Vec<(Vec<u8>, u8, Vec<u8>)> = logs
+        .iter()
+        .map(
+            |((name, update_index), entry)| -> Result<_, Box<dyn Error>> {
+                let key = [name.as_bytes(), &[0], &(!update_index).to_be_bytes()].concat();
+                let Some(entry) = entry else {
+                    return Ok((key, 0, Vec::new()));
+                };
+                // The identity is only kept in the line, as `name <email>`
+                let ident = entry
+                    .line
+                    .get(82..)
+                    .and_then(|ident| ident.split_once('\t'))
+                    .and_then(|(ident, _)| ident.split_once(" <"))
+                    .and_then(|(name, rest)| Some((name, rest.split_once('>')?.0)))
+                    .ok_or_else(|| format!("bad reflog entry for {}", name))?;
+                let minutes =
+                    entry.tz[1..3].parse::<i16>()? * 60 + entry.tz[3..5].parse::<i16>()?;
+                let minutes = if entry.tz.starts_with('-') {
+                    -minutes
+                } else {
+                    minutes
+                };
+
+                let mut data = hex::decode(&entry.old)?;
+                data.extend(hex::decode(&entry.new)?);
+                for field in [ident.0, ident.1] {
+                    reftable_put_varint(&mut data, field.len() as u64);
+                    data.extend_from_slice(field.as_bytes());
+                }
+                reftable_put_varint(&mut data, entry.time as u64);
+                data.extend_from_slice(&minutes.to_be_bytes());
+                let message = match entry.message.is_empty() {
+                    true => String::new(),
+                    false => format!("{}\n", entry.message),
+                };
+                reftable_put_varint(&mut data, message.len() as u64);
+                data.extend_from_slice(message.as_bytes());
+                Ok((key, 1, data))
+            },
+        )
+        .collect::<Result<_, _>>()?;
+    log_records.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut out = header.clone();
+    reftable_write_blocks(&mut out, b'r', &ref_records)?;
+    let log_position = match log_records.is_empty() {
+        true => 0,
+        false => out.len(),
+    };
+    reftable_write_blocks(&mut out, b'l', &log_records)?;
+
+    let mut footer = header;
+    footer.extend_from_slice(&[0; 24]);
+    footer.extend_from_slice(&(log_position as u64).to_be_bytes());
+    footer.extend_from_slice(&[0; 8]);
+    let mut crc = Crc::new();
+    crc.update(&footer);
+    footer.extend_from_slice(&crc.sum().to_be_bytes());
+    out.extend(footer);
+    Ok(out)
+}
+
+fn reftable_table_name(
+    (min_update_index, max_update_index): (u64, u64),
+) -> Result<String, Box<dyn Error>> {
+    let suffix = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)?
+        .subsec_nanos()
+        ^ process::id();
+    Ok(format!(
+        "0x{:012x}-0x{:012x}-{:08x}.ref",
+        min_update_index, max_update_index, suffix
+    ))
+}
+
+// Write a table under a new name, through a temporary file renamed into
+// place, so readers never see a partial table. Returns the name.
+fn reftable_write(
+    repo: &GitRepository,
+    update_indexes: (u64, u64),
+    refs: &[(String, ReftableValue)],
+    logs: &[((String, u64), Option<ReflogEntry>)],
+) -> Result<String, Box<dyn Error>> {
+    let data = reftable_encode(update_indexes, refs, logs)?;
+    let name = reftable_table_name(update_indexes)?;
+    static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let temp = repo_path(
+        repo,
+        &format!(
+            "reftable/tmp_table_{}_{}",
+            process::id(),
+            TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ),
+    );
+    let written = (|| -> Result<(), Box<dyn Error>> {
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&temp)?;
+        file.write_all(&data)?;
+        file.sync_all()?;
+        rename_over(&temp, &repo_path(repo, &format!("reftable/{}", name)))?;
+        Ok(())
+    })();
+    if written.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    written?;
+    Ok(name)
+}
+
+// Merge the newest tables of the stack for as long as a table is not
+// at least REFTABLE_GEOMETRIC_FACTOR times the size of the tables newer
+// than it, as git's auto-compaction does. `names` is updated to the new
+// stack, and the tables it no longer names are returned for removal.
+fn reftable_compact(
+    repo: &GitRepository,
+    names: &mut Vec<String>,
+) -> Result<Vec<String>, Box<dyn Error>> {
+    let sizes = names
+        .iter()
+        .map(|name| Ok(fs::metadata(repo_path(repo, &format!("reftable/{}", name)))?.len()))
+        .collect::<Result<Vec<u64>, Box<dyn Error>>>()?;
+    let mut start = names.len().saturating_sub(1);
+    let mut total = sizes.last().copied().unwrap_or(0);
+    while start > 0 && sizes[start - 1] < REFTABLE_GEOMETRIC_FACTOR * total {
+        start -= 1;
+        total += sizes[start];
+    }
+    if start + 1 >= names.len() {
+        return Ok(Vec::new());
+    }
+
+    let mut refs = BTreeMap::new();
+    let mut logs = BTreeMap::new();
+    let mut range = (u64::MAX, 0);
+    for name in &names[start..] {
+        let table = reftable_read(repo, name)?;
+        range = (
+            range.0.min(table.min_update_index),
+            range.1.max(table.max_update_index),
+        );
+        refs.extend(table.refs);
+        logs.extend(table.logs);
+    }
+    // Deletions only need keeping while there are older tables to shadow
+    if start == 0 {
+        refs.retain(|_, value| *value != ReftableValue::Deleted);
+        logs.retain(|_, entry| entry.is_some());
+    }
+
+    let refs: Vec<_> = refs.into_iter().collect();
+    let logs: Vec<_> = logs.into_iter().collect();
+    let name = reftable_write(repo, range, &refs, &logs)?;
+    Ok(names.splice(start.., [name]).collect())
+}
+
+// Add a table to the stack under `tables.list.lock`, then compact the
+// stack. `build` sees the refs as they are once the lock is held, and
+// gives the records for the new table, or fails to leave the stack as
+// it was.
+fn reftable_add(
+    repo: &GitRepository,
+    build: impl FnOnce(
+        &BTreeMap<String, ReftableValue>,
+    )
+        -> Result<(Vec<(String, ReftableValue)>, Vec<(String, u64)>), Box<dyn Error>>,
+) -> Result<(), Box<dyn Error>> {
+    let list = repo_path(repo, "reftable/tables.list");
+    let lock = repo_path(repo, "reftable/tables.list.lock");
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&lock)
+        .map_err(|e| format!("cannot lock references: {}", e))?;
+
+    // Tables written so far, removed again if the stack is not updated
+    let mut written = Vec::new();
+    let added = (|| -> Result<Option<Vec<String>>, Box<dyn Error>> {
+        let (mut names, stack) = reftable_stack(repo)?;
+        let update_index = stack
+            .iter()
+            .map(|table| table.max_update_index)
+            .max()
+            .unwrap_or(0)
+            + 1;
+        let mut refs = BTreeMap::new();
+        for table in stack {
+            refs.extend(table.refs);
+        }
+        refs.retain(|_, value| *value != ReftableValue::Deleted);
+
+        let (records, log_deletions) = build(&refs)?;
+        if records.is_empty() && log_deletions.is_empty() {
+            return Ok(None);
+        }
+        let logs: Vec<_> = log_deletions.into_iter().map(|key| (key, None)).collect();
+        let name = reftable_write(repo, (update_index, update_index), &records, &logs)?;
+        written.push(name.clone());
+        names.push(name);
+
+        let mut compacted = names.clone();
+        let removed = match reftable_compact(repo, &mut compacted) {
+            Ok(removed) => {
+                written.extend(compacted.last().cloned());
+                names = compacted;
+                removed
+            }
+            // A failed compaction still leaves the new table in the stack
+            Err(_) => Vec::new(),
+        };
+        let content: String = names.iter().map(|name| format!("{}\n", name)).collect();
+        fs::write(&lock, content)?;
+        rename_over(&lock, &list)?;
+        Ok(Some(removed))
+    })();
+
+    // Until it is renamed over the list the lock is still ours to remove;
+    // after that it may well be another writer's
+    let removed = match added {
+        Ok(Some(removed)) => removed,
+        Ok(None) => return Ok(fs::remove_file(&lock)?),
+        Err(e) => {
+            let _ = fs::remove_file(&lock);
+            for name in written {
+                let _ = fs::remove_file(repo_path(repo, &format!("reftable/{}", name)));
+            }
+            return Err(e);
+        }
+    };
+    for name in removed {
+        fs::remove_file(repo_path(repo, &format!("reftable/{}", name)))?;
+    }
+    Ok(())
+}
+
+// Follow symbolic refs through the stack to an object id
+fn reftable_resolve(refs: &BTreeMap<String, ReftableValue>, name: &str) -> Option<String> {
+    let mut name = name;
+    for _ in 0..5 {
+        match refs.get(name)? {
+            ReftableValue::Object(sha) => return Some(sha.clone()),
+            ReftableValue::Symref(target) => name = target,
+            ReftableValue::Deleted => return None,
+        }
+    }
+    None
+}
+
+const NULL_SHA: &str = "0000000000000000000000000000000000000000";
+
+fn pkt_line_write(out: &mut impl Write, data: &[u8]) -> io::Result<()> {
+    write!(out, "{:04x}", data.len() + 4)?;
+    out.write_all(data)
+}
+
+fn pkt_flush(out: &mut impl Write) -> io::Result<()> {
+    out.write_all(b"0000")?;
+    out.flush()
+}
+
+// Read one pkt-line, returning `None` for a flush packet.
+fn pkt_line_read(input: &mut impl Read) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+    let mut length = [0; 4];
+    input
+        .read_exact(&mut length)
+        .map_err(|_| "the remote end hung up unexpectedly")?;
+    let length = usize::from_str_radix(std::str::from_utf8(&length)?, 16)?;
+    if length == 0 {
+        return Ok(None);
+    }
+    if length < 4 {
+        return Err(From::from(format!(
+            "protocol error: bad line length {}",
+            length
+        )));
+    }
+
+    let mut data = vec![0; length - 4];
+    input.read_exact(&mut data)?;
+    Ok(Some(data))
+}
+
+// Like `pkt_line_read`, but for text lines with the newline stripped.
+fn pkt_line_read_text(input: &mut impl Read) -> Result<Option<String>, Box<dyn Error>> {
+    match pkt_line_read(input)? {
+        Some(line) => {
+            let line = String::from_utf8(line)?;
+            Ok(Some(line.strip_suffix('\n').unwrap_or(&line).to_string()))
+        }
+        None => Ok(None),
+    }
+}
+
+// Send `data` on a side-band channel in packets of at most `max` bytes.
+fn sideband_write(out: &mut impl Write, band: u8, data: &[u8], max: usize) -> io::Result<()> {
+    for chunk in data.chunks(max - 5) {
+        let mut packet = Vec::with_capacity(chunk.len() + 1);
+        packet.push(band);
+        packet.extend_from_slice(chunk);
+        pkt_line_write(out, &packet)?;
+    }
+    out.flush()
+}
+
+// The ref prefix GIT_NAMESPACE keeps a server's refs under: `a/b` is
+// `refs/namespaces/a/refs/namespaces/b/`. Clients see the refs inside
+// it without the prefix, and nothing outside it.
+fn ref_namespace() -> String {
+    std::env::var("GIT_NAMESPACE")
+        .unwrap_or_default()
+        .split('/')
+        .filter(|component| !component.is_empty())
+        .map(|component| format!("refs/namespaces/{}/", component))
+        .collect()
+}
+
+// Write the ref advertisement that opens both fetch and push sessions,
+// returning every advertised object id. Only fetches are shown HEAD and
+// the peeled values of tags.
+fn advertise_refs(
+    repo: &GitRepository,
+    out: &mut impl Write,
+    capabilities: &str,
+    fetch: bool,
+    namespace: &str,
+) -> Result<HashSet<String>, Box<dyn Error>> {
+    let mut lines = Vec::new();
+    if fetch {
+        if let Some(sha) = ref_resolve(repo, &format!("{}HEAD", namespace))? {
+            lines.push((sha, "HEAD".to_string()));
+        }
+    }
+    for (name, sha) in ref_list(repo)? {
+        // A namespace's own HEAD is listed among its refs; it was sent above
+        let name = match name.strip_prefix(namespace) {
+            Some(name) if name.starts_with("refs/") => name.to_string(),
+            _ => continue,
+        };
+        let peeled = match fetch && name.starts_with("refs/tags/") {
+            true => Some(object_peel(repo, &sha, "")?).filter(|peeled| *peeled != sha),
+            false => None,
+        };
+        lines.push((sha, name.clone()));
+        if let Some(peeled) = peeled {
+            lines.push((peeled, format!("{}^{{}}", name)));
+        }
+    }
+
+    if lines.is_empty() {
+        let line = format!("{} capabilities^{{}}\0{}\n", NULL_SHA, capabilities);
+        pkt_line_write(out, line.as_bytes())?;
+    }
+    for (i, (sha, name)) in lines.iter().enumerate() {
+        let line = match i {
+            0 => format!("{} {}\0{}\n", sha, name, capabilities),
+            _ => format!("{} {}\n", sha, name),
+        };
+        pkt_line_write(out, line.as_bytes())?;
+    }
+    pkt_flush(out)?;
+
+    Ok(lines.into_iter().map(|(sha, _)| sha).collect())
+}
+
+fn commit_kvlm(repo: &GitRepository, sha: &str) -> Result<Kvlm, Box<dyn Error>> {
+    match read_object(repo, sha)? {
+        GitObject::Commit(commit) => parse_kvlm(commit.serialize()),
+        object => Err(From::from(format!("{} is a {}, not a commit", sha, object))),
+    }
+}
+
+fn kvlm_shas(kvlm: &Kvlm, key: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    kvlm.get_all(key)
+        .into_iter()
+        .map(|value| Ok(String::from_utf8(value.to_vec())?))
+        .collect()
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum ChangeKind {
+    Added,
+    Deleted,
+    Modified,
+    TypeChanged,
+}
+
+impl ChangeKind {
+    fn status(self) -> char {
+        match self {
+            ChangeKind::Added => 'A',
+            ChangeKind::Deleted => 'D',
+            ChangeKind::Modified => 'M',
+            ChangeKind::TypeChanged => 'T',
+        }
+    }
+}
+
+// One changed entry; the leaves keep their own names, `path` is the
+// full path from the root.
+struct TreeChange {
+    kind: ChangeKind,
+    path: Vec<u8>,
+    old: Option<GitTreeLeaf>,
+    new: Option<GitTreeLeaf>,
+}
+
+// Compares two trees entry by entry. Subtrees with the same id on both
+// sides are skipped without being read, so the cost follows the size of
+// the change rather than the size of the trees.
+struct TreeDiff<'a> {
+    repo: &'a GitRepository,
+    recursive: bool,
+    show_trees: bool,
+    paths: Pathspec,
+}
+
+impl<'a> TreeDiff<'a> {
+    fn new(repo: &'a GitRepository) -> Self {
+        TreeDiff {
+            repo,
+            recursive: false,
+            show_trees: false,
+            paths: Pathspec::default(),
+        }
+    }
+
+    // `None` stands for the empty tree on either side.
+    fn diff(
+        &self,
+        old: Option<&str>,
+        new: Option<&str>,
+    ) -> Result<Vec<TreeChange>, Box<dyn Error>> {
+        let mut changes = Vec::new();
+        self.diff_into(old, new, b"", &mut changes)?;
+        Ok(changes)
+    }
+
+    fn entries(&self, tree: Option<&str>) -> Result<Vec<GitTreeLeaf>, Box<dyn Error>> {
+        let Some(tree) = tree else {
+            return Ok(Vec::new());
+        };
+        let GitObject::Tree(data) = read_object(self.repo, tree)? else {
+            return Err(From::from(format!("{} is not a tree", tree)));
+        };
+        parse_tree(data.serialize())
+    }
+
+    // Whether `path` is matched by the limiting paths, and whether it is
+    // a directory that may hold paths that are.
+    fn path_match(&self, path: &[u8]) -> (bool, bool) {
+        if self.paths.is_empty() {
+            return (true, false);
+        }
+        (self.paths.matches(path), self.paths.leads(path))
+    }
+
+    fn diff_into(
+        &self,
+        old: Option<&str>,
+        new: Option<&str>,
+        prefix: &[u8],
+        changes: &mut Vec<TreeChange>,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut old = self.entries(old)?.into_iter().peekable();
+        let mut new = self.entries(new)?.into_iter().peekable();
+
+        loop {
+            let (old_leaf, new_leaf) = match (old.peek(), new.peek()) {
+                (None, None) => break,
+                (Some(_), None) => (old.next(), None),
+                (None, Some(_)) => (None, new.next()),
+                (Some(a), Some(b)) => match tree_sort_key(a).cmp(&tree_sort_key(b)) {
+                    std::cmp::Ordering::Less => (old.next(), None),
+                    std::cmp::Ordering::Greater => (None, new.next()),
+                    std::cmp::Ordering::Equal => (old.next(), new.next()),
+                },
+            };
+            let name = &old_leaf
+                .as_ref()
+                .or(new_leaf.as_ref())
+                .ok_or("tree diff out of step")?
+                .path;
+            let mut path = prefix.to_vec();
+            if !path.is_empty() {
+                path.push(b'/');
+            }
+            path.extend_from_slice(name);
+
+            let is_tree =
+                |leaf: &Option<GitTreeLeaf>| leaf.as_ref().is_some_and(|leaf| leaf.mode == "40000");
+            let (inside, leads) = self.path_match(&path);
+            if !inside && !(leads && (is_tree(&old_leaf) || is_tree(&new_leaf))) {
+                continue;
+            }
+
+            let kind = match (&old_leaf, &new_leaf) {
+                (Some(a), Some(b)) if a.sha == b.sha && a.mode == b.mode => continue,
+                (Some(a), Some(b)) if mode_class(&a.mode) != mode_class(&b.mode) => {
+                    ChangeKind::TypeChanged
+                }
+                (Some(_), Some(_)) => ChangeKind::Modified,
+                (Some(_), None) => ChangeKind::Deleted,
+                (None, _) => ChangeKind::Added,
+            };
+            if self.recursive && (is_tree(&old_leaf) || is_tree(&new_leaf)) {
+                let old_tree = old_leaf.as_ref().map(|leaf| leaf.sha.clone());
+                let new_tree = new_leaf.as_ref().map(|leaf| leaf.sha.clone());
+                if self.show_trees {
+                    changes.push(TreeChange {
+                        kind,
+                        path: path.clone(),
+                        old: old_leaf,
+                        new: new_leaf,
+                    });
+                }
+                self.diff_into(old_tree.as_deref(), new_tree.as_deref(), &path, changes)?;
+            } else {
+                changes.push(TreeChange {
+                    kind,
+                    path,
+                    old: old_leaf,
+                    new: new_leaf,
+                });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Kinds of entry whose swapping is a type change rather than a change
+// of content: trees, files of either mode, symlinks and gitlinks.
+fn mode_class(mode: &str) -> &'static str {
+    match mode {
+        "40000" => "tree",
+        "120000" => "symlink",
+        "160000" => "gitlink",
+        _ => "file",
+    }
+}
+
+// Lines of unchanged text shown around each hunk.
+const DIFF_CONTEXT: usize = 3;
+
+// A shortest edit script between two lists of lines, after Myers: one
+// of b' ', b'-' or b'+' per line, in the order a patch lists them.
+fn diff_lines(old: &[&[u8]], new: &[&[u8]]) -> Vec<u8> {
+    let mut deleted = vec![false; old.len()];
+    let mut inserted = vec![false; new.len()];
+    diff_mark(old, new, &mut deleted, &mut inserted);
+
+    // Slide each run of changed lines as far down as it will go, as git
+    // does, so an ambiguous change is shown the same way
+    diff_slide_down(old, &mut deleted);
+    diff_slide_down(new, &mut inserted);
+
+    let mut ops = Vec::with_capacity(old.len() + new.len());
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if deleted.get(i) == Some(&true) {
+            ops.push(b'-');
+            i += 1;
+        } else if inserted.get(j) == Some(&true) {
+            ops.push(b'+');
+            j += 1;
+        } else {
+            ops.push(b' ');
+            i += 1;
+            j += 1;
+        }
+    }
+    ops
+}
+
+// Mark the lines of `old` to delete and of `new` to insert for a
+// shortest edit script, splitting the problem at the middle of an
+// optimal path as Myers' linear space refinement does. Only two vectors
+// of furthest reaching paths are kept, so memory stays linear in the
+// input instead of growing with the square of the edit distance.
+fn diff_mark(old: &[&[u8]], new: &[&[u8]], deleted: &mut [bool], inserted: &mut [bool]) {
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let a = &old[prefix..old.len() - suffix];
+    let b = &new[prefix..new.len() - suffix];
+    let deleted = &mut deleted[prefix..old.len() - suffix];
+    let inserted = &mut inserted[prefix..new.len() - suffix];
+
+    match diff_middle(a, b) {
+        // Each part is smaller than the whole, so this ends
+        Some((x, y)) if (x, y) != (0, 0) && (x, y) != (a.len(), b.len()) => {
+            let (deleted_before, deleted_after) = deleted.split_at_mut(x);
+            let (inserted_before, inserted_after) = inserted.split_at_mut(y);
+            diff_mark(&a[..x], &b[..y], deleted_before, inserted_before);
+            diff_mark(&a[x..], &b[y..], deleted_after, inserted_after);
+        }
+        // One side is empty
+        _ => {
+            deleted.fill(true);
+            inserted.fill(true);
+        }
+    }
+}
+
+// A point halfway along a shortest edit path between `a` and `b`, which
+// have neither a first nor a last line in common, found by searching
+// from both ends until the two searches meet on a diagonal k = x - y.
+fn diff_middle(a: &[&[u8]], b: &[&[u8]]) -> Option<(usize, usize)> {
+    let (n, m) = (a.len() as isize, b.len() as isize);
+    if n == 0 || m == 0 {
+        return None;
+    }
+    // The furthest x reached on each diagonal, from the start and from
+    // the end, the latter counted back from the end
+    let max = (n + m + 1) / 2;
+    let offset = max;
+    let mut forward = vec![-1; 2 * max as usize + 2];
+    let mut backward = vec![-1; 2 * max as usize + 2];
+    forward[offset as usize + 1] = 0;
+    backward[offset as usize + 1] = 0;
+    let delta = n - m;
+    let get = |v: &[isize], k: isize| v.get((offset + k) as usize).copied().unwrap_or(-1);
+
+    // Diagonals trimmed at either end for running off the edit graph
+    let (mut forward_trim, mut backward_trim) = ((0, 0), (0, 0));
+    for d in 0..max {
+        let mut k = -d + forward_trim.0;
+        while k <= d - forward_trim.1 {
+            let mut x = match k == -d || (k != d && get(&forward, k - 1) < get(&forward, k + 1)) {
+                true => get(&forward, k + 1),
+                false => get(&forward, k - 1) + 1,
+            };
+            let mut y = x - k;
+            while x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            forward[(offset + k) as usize] = x;
+            if x > n {
+                forward_trim.1 += 2;
+            } else if y > m {
+                forward_trim.0 += 2;
+            } else if delta % 2 != 0 {
+                let back = get(&backward, delta - k);
+                if back != -1 && x >= n - back {
+                    return Some((x as usize, y as usize));
+                }
+            }
+            k += 2;
+        }
+
+        let mut k = -d + backward_trim.0;
+        while k <= d - backward_trim.1 {
+            let mut x = match k == -d || (k != d && get(&backward, k - 1) < get(&backward, k + 1)) {
+                true => get(&backward, k + 1),
+                false => get(&backward, k - 1) + 1,
+            };
+            let mut y = x - k;
+            while x < n && y < m && a[(n - x - 1) as usize] == b[(m - y - 1) as usize] {
+                x += 1;
+                y += 1;
+            }
+            backward[(offset + k) as usize] = x;
+            if x > n {
+                backward_trim.1 += 2;
+            } else if y > m {
+                backward_trim.0 += 2;
+            } else if delta % 2 == 0 {
+                let front = get(&forward, delta - k);
+                if front != -1 && front >= n - x {
+                    let front_y = front - (delta - k);
+                    return Some((front as usize, front_y as usize));
+                }
+            }
+            k += 2;
+        }
+    }
+    None
+}
+
+fn diff_slide_down(lines: &[&[u8]], changed: &mut [bool]) {
+    let mut start = 0;
+    while start < lines.len() {
+        if !changed[start] {
+            start += 1;
+            continue;
+        }
+        let mut end = start;
+        while end < lines.len() && changed[end] {
+            end += 1;
+        }
+        while end < lines.len() && lines[start] == lines[end] {
+            changed[start] = false;
+            changed[end] = true;
+            start += 1;
+            end += 1;
+            while end < lines.len() && changed[end] {
+                end += 1;
+            }
+        }
+        start = end;
+    }
+}
+
+// The hunks of a unified diff between two texts, as git prints them
+// after the file header.
+fn diff_hunks(old: &[u8], new: &[u8], out: &mut Vec<u8>) {
+    let old: Vec<&[u8]> = old.split_inclusive(|&b| b == b'\n').collect();
+    let new: Vec<&[u8]> = new.split_inclusive(|&b| b == b'\n').collect();
+    let ops = diff_lines(&old, &new);
+
+    // Where each op starts in the old and in the new text
+    let mut positions = Vec::with_capacity(ops.len() + 1);
+    let (mut i, mut j) = (0, 0);
+    for &op in &ops {
+        positions.push((i, j));
+        i += usize::from(op != b'+');
+        j += usize::from(op != b'-');
+    }
+    positions.push((i, j));
+
+    let mut next = 0;
+    while let Some(start) = ops[next..].iter().position(|&op| op != b' ') {
+        let start = next + start;
+        // Changes close enough to share their context make one hunk
+        let mut end = start;
+        loop {
+            while end < ops.len() && ops[end] != b' ' {
+                end += 1;
+            }
+            match ops[end..].iter().position(|&op| op != b' ') {
+                Some(gap) if gap <= 2 * DIFF_CONTEXT => end += gap,
+                _ => break,
+            }
+        }
+        let from = start.saturating_sub(DIFF_CONTEXT);
+        let to = (end + DIFF_CONTEXT).min(ops.len());
+        next = to;
+
+        let range = |start: usize, end: usize| match end - start {
+            0 => format!("{},0", start),
+            1 => format!("{}", start + 1),
+            count => format!("{},{}", start + 1, count),
+        };
+        let ((old_start, new_start), (old_end, new_end)) = (positions[from], positions[to]);
+        out.extend_from_slice(
+            format!(
+                "@@ -{} +{} @@\n",
+                range(old_start, old_end),
+                range(new_start, new_end)
+            )
+            .as_bytes(),
+        );
+        for index in from..to {
+            let (i, j) = positions[index];
+            let line = match ops[index] {
+                b'+' => new[j],
+                _ => old[i],
+            };
+            out.push(ops[index]);
+            out.extend_from_slice(line);
+            if !line.ends_with(b"\n") {
+                out.extend_from_slice(b"\n\\ No newline at end of file\n");
+            }
+        }
+    }
+}
+
+// Git only diffs text; anything with a NUL early on is binary.
+fn is_binary(data: &[u8]) -> bool {
+    data[..data.len().min(8000)].contains(&0)
+}
+
+// What a tree entry holds as diffable text: a blob's content, or the
+// commit a gitlink points at.
+fn diff_content(
+    repo: &GitRepository,
+    leaf: Option<&GitTreeLeaf>,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    match leaf {
+        None => Ok(Vec::new()),
+        Some(leaf) if leaf.mode == "160000" => {
+            Ok(format!("Subproject commit {}\n", leaf.sha).into_bytes())
+        }
+        Some(leaf) => match read_object(repo, &leaf.sha)? {
+            GitObject::Blob(blob) => Ok(blob.serialize().clone()),
+            object => Err(From::from(format!(
+                "{} is a {}, not a blob",
+                leaf.sha, object
+            ))),
+        },
+    }
+}
+
+// The patch for one changed file in git's format. Ids are written in
+// full; a type change comes out as a deletion and an addition.
+fn diff_patch(
+    repo: &GitRepository,
+    change: &TreeChange,
+    quote_high: bool,
+    out: &mut Vec<u8>,
+) -> Result<(), Box<dyn Error>> {
+    let (old, new) = match change.kind {
+        ChangeKind::TypeChanged => {
+            for (old, new) in [(change.old.as_ref(), None), (None, change.new.as_ref())] {
+                file_patch(repo, &change.path, old, new, quote_high, out)?;
+            }
+            return Ok(());
+        }
+        _ => (change.old.as_ref(), change.new.as_ref()),
+    };
+    file_patch(repo, &change.path, old, new, quote_high, out)
+}
+
+fn file_patch(
+    repo: &GitRepository,
+    path: &[u8],
+    old: Option<&GitTreeLeaf>,
+    new: Option<&GitTreeLeaf>,
+    quote_high: bool,
+    out: &mut Vec<u8>,
+) -> Result<(), Box<dyn Error>> {
+    let quoted = |prefix: &[u8]| quote_path(&[prefix, path].concat(), quote_high);
+    let (a, b) = (quoted(b"a/"), quoted(b"b/"));
+    out.extend_from_slice(b"diff --git ");
+    out.extend_from_slice(&a);
+    out.push(b' ');
+    out.extend_from_slice(&b);
+    out.push(b'\n');
+
+    let mode = |leaf: &GitTreeLeaf| format!("{:0>6}", leaf.mode);
+    let mut header = match (old, new) {
+        (None, Some(new)) => format!("new file mode {}\n", mode(new)),
+        (Some(old), None) => format!("deleted file mode {}\n", mode(old)),
+        (Some(old), Some(new)) if old.mode != new.mode => {
+            format!("old mode {}\nnew mode {}\n", mode(old), mode(new))
+        }
+        _ => String::new(),
+    };
+    let old_sha = old.map_or(NULL_SHA, |leaf| &leaf.sha);
+    let new_sha = new.map_or(NULL_SHA, |leaf| &leaf.sha);
+    if old_sha == new_sha {
+        // Only the mode changed
+        out.extend_from_slice(header.as_bytes());
+        return Ok(());
+    }
+    header.push_str(&format!("index {}..{}", old_sha, new_sha));
+    match (old, new) {
+        (Some(old), Some(new)) if old.mode == new.mode => {
+            header.push_str(&format!(" {}\n", mode(new)))
+        }
+        _ => header.push('\n'),
+    }
+    out.extend_from_slice(header.as_bytes());
+
+    let (old_data, new_data) = (diff_content(repo, old)?, diff_content(repo, new)?);
+    let from = if old.is_some() {
+        a
+    } else {
+        b"/dev/null".to_vec()
+    };
+    let to = if new.is_some() {
+        b
+    } else {
+        b"/dev/null".to_vec()
+    };
+    if is_binary(&old_data) || is_binary(&new_data) {
+        out.extend_from_slice(b"Binary files ");
+        out.extend_from_slice(&from);
+        out.extend_from_slice(b" and ");
+        out.extend_from_slice(&to);
+        out.extend_from_slice(b" differ\n");
+        return Ok(());
+    }
+    // An empty file added or removed has no hunks
+    if old_data == new_data {
+        return Ok(());
+    }
+    out.extend_from_slice(b"--- ");
+    out.extend_from_slice(&from);
+    out.extend_from_slice(b"\n+++ ");
+    out.extend_from_slice(&to);
+    out.push(b'\n');
+    diff_hunks(&old_data, &new_data, out);
+    Ok(())
+}
+
+// Hashes a patch the way `git patch-id` does: whitespace, hunk headers
+// and index lines are left out, so the same change made elsewhere in a
+// file or on other blobs hashes the same. A stable id adds up one hash
+// per file, so the order of the files does not matter either.
+struct PatchId {
+    hasher: Sha1,
+    sum: [u8; 20],
+    len: usize,
+    // Lines left in the current hunk on each side, or -1 in a header
+    before: i64,
+    after: i64,
+    binary: bool,
+    old_sha: Vec<u8>,
+    new_sha: Vec<u8>,
+    stable: bool,
+}
+
+impl PatchId {
+    fn new(stable: bool) -> PatchId {
+        PatchId {
+            hasher: Sha1::new(),
+            sum: [0; 20],
+            len: 0,
+            before: -1,
+            after: -1,
+            binary: false,
+            old_sha: Vec::new(),
+            new_sha: Vec::new(),
+            stable,
+        }
+    }
+
+    fn flush(&mut self) {
+        let hash = std::mem::take(&mut self.hasher).finalize();
+        let mut carry = 0u16;
+        for (sum, byte) in self.sum.iter_mut().zip(hash) {
+            carry += u16::from(*sum) + u16::from(byte);
+            *sum = carry as u8;
+            carry >>= 8;
+        }
+    }
+
+    // Take the next line of the patch, newline included; `false` means
+    // the line is past the end of the patch.
+    fn line(&mut self, line: &[u8]) -> bool {
+        // Whatever comes before the first file is commit message
+        if self.len == 0 && !line.starts_with(b"diff ") {
+            return true;
+        }
+
+        if self.before == -1 {
+            if line.starts_with(b"GIT binary patch") || line.starts_with(b"Binary files") {
+                // Binary changes are known by their blob ids alone
+                self.binary = true;
+                self.before = 0;
+                self.hasher.update(&self.old_sha);
+                self.hasher.update(&self.new_sha);
+                if self.stable {
+                    self.flush();
+                }
+                return true;
+            } else if let Some(ids) = line.strip_prefix(b"index ") {
+                if let Some(dots) = ids.windows(2).position(|pair| pair == b"..") {
+                    let rest = &ids[dots + 2..];
+                    let end = rest
+                        .iter()
+                        .position(|&b| b == b' ')
+                        .unwrap_or(rest.len().saturating_sub(1));
+                    self.old_sha = ids[..dots.min(64)].to_vec();
+                    self.new_sha = rest[..end.min(64)].to_vec();
+                }
+                return true;
+            } else if line.starts_with(b"--- ") {
+                self.before = 1;
+                self.after = 1;
+            } else if !line.first().is_some_and(u8::is_ascii_alphabetic) {
+                return false;
+            }
+        }
+
+        if self.binary {
+            if line.starts_with(b"diff ") {
+                self.binary = false;
+                self.before = -1;
+            }
+            return true;
+        }
+
+        if self.before == 0 && self.after == 0 {
+            if line.starts_with(b"@@ -") {
+                if let Some((before, after)) = hunk_counts(line) {
+                    self.before = before;
+                    self.after = after;
+                }
+                return true;
+            }
+            if !line.starts_with(b"diff ") {
+                return false;
+            }
+            // The header of the next file
+            if self.stable {
+                self.flush();
+            }
+            self.before = -1;
+            self.after = -1;
+        }
+
+        match line.first() {
+            Some(b'-') => self.before -= 1,
+            Some(b'+') => self.after -= 1,
+            Some(b' ') => {
+                self.before -= 1;
+                self.after -= 1;
+            }
+            _ => {}
+        }
+        let kept: Vec<u8> = line
+            .iter()
+            .copied()
+            .filter(|b| !matches!(b, b' ' | b'\t'..=b'\r'))
+            .collect();
+        self.len += kept.len();
+        self.hasher.update(&kept);
+        true
+    }
+
+    fn finish(mut self) -> String {
+        self.flush();
+        hex::encode(self.sum)
+    }
+}
+
+// The line counts of a hunk header `@@ -a,b +c,d @@`; a count left out
+// means one line.
+fn hunk_counts(line: &[u8]) -> Option<(i64, i64)> {
+    let line = String::from_utf8_lossy(line);
+    let (old, rest) = line.strip_prefix("@@ -")?.split_once(" +")?;
+    let new = rest.split(' ').next()?;
+    let count = |range: &str| {
+        let (start, count) = range.split_once(',').unwrap_or((range, "1"));
+        start.parse::<u64>().ok()?;
+        count.parse().ok()
+    };
+    Some((count(old)?, count(new)?))
+}
+
+// The commit a line of `git log` or `format-patch` output introduces,
+// which starts a new patch for `patch-id`.
+fn patch_id_commit(line: &[u8]) -> Option<String> {
+    let rest = [&b"diff-tree "[..], b"commit ", b"From "]
+        .into_iter()
+        .find_map(|prefix| line.strip_prefix(prefix))
+        .unwrap_or(line);
+    let sha = rest.get(..40)?;
+    match sha.iter().all(u8::is_ascii_hexdigit) {
+        true => Some(String::from_utf8_lossy(sha).to_ascii_lowercase()),
+        false => None,
+    }
+}
+
+// The stable patch id of a commit's change against its first parent,
+// or `None` for a merge, which has no single change to compare.
+fn commit_patch_id(repo: &GitRepository, sha: &str) -> Result<Option<String>, Box<dyn Error>> {
+    let kvlm = commit_kvlm(repo, sha)?;
+    let parents = kvlm_shas(&kvlm, "parent")?;
+    if parents.len() > 1 {
+        return Ok(None);
+    }
+    let tree = kvlm_shas(&kvlm, "tree")?;
+    let parent_tree = match parents.first() {
+        Some(parent) => kvlm_shas(&commit_kvlm(repo, parent)?, "tree")?
+            .into_iter()
+            .next(),
+        None => None,
+    };
+
+    let mut diff = TreeDiff::new(repo);
+    diff.recursive = true;
+    let mut patch = Vec::new();
+    for change in diff.diff(parent_tree.as_deref(), tree.first().map(String::as_str))? {
+        diff_patch(repo, &change, true, &mut patch)?;
+    }
+    let mut id = PatchId::new(true);
+    for line in patch.split_inclusive(|&b| b == b'\n') {
+        id.line(line);
+    }
+    Ok(Some(id.finish()))
+}
+
+// The ref `git cherry` compares against when not told: the branch
+// `branch.<name>.merge` names on `branch.<name>.remote`.
+fn branch_upstream(repo: &GitRepository) -> Result<Option<String>, Box<dyn Error>> {
+    let Some(branch) = ref_symbolic_target(repo, "HEAD")
+        .and_then(|target| target.strip_prefix("refs/heads/").map(ToString::to_string))
+    else {
+        return Ok(None);
+    };
+    let config = repo_config(repo)?;
+    let (Some(remote), Some(merge)) = (
+        config_get(&config, &format!("branch.{}.remote", branch)),
+        config_get(&config, &format!("branch.{}.merge", branch)),
+    ) else {
+        return Ok(None);
+    };
+    if remote == "." {
+        return Ok(Some(merge.to_string()));
+    }
+    let merge = merge.strip_prefix("refs/heads/").unwrap_or(merge);
+    Ok(Some(format!("refs/remotes/{}/{}", remote, merge)))
+}
+
+// List the commits on `head` since `limit` that are not on `upstream`,
+// oldest first, marking with `-` those whose change `upstream` already
+// has under another id and with `+` the rest.
+fn cherry(
+    repo: &GitRepository,
+    upstream: &str,
+    head: &str,
+    limit: Option<&str>,
+    verbose: bool,
+) -> Result<(), Box<dyn Error>> {
+    let upstream = object_resolve(repo, upstream)?;
+    let head = object_resolve(repo, head)?;
+    let limit = limit.map(|limit| object_resolve(repo, limit)).transpose()?;
+    let commits = |start: &str, hidden: &[&String]| -> Result<Vec<String>, Box<dyn Error>> {
+        let mut walk = RevWalk::new(repo);
+        walk.push(start)?;
+        for sha in hidden {
+            walk.hide(sha)?;
+        }
+        walk.collect()
+    };
+
+    let mut upstream_ids = HashSet::new();
+    for sha in commits(&upstream, &[&head])? {
+        upstream_ids.extend(commit_patch_id(repo, &sha)?);
+    }
+
+    let hidden: Vec<&String> = [Some(&upstream), limit.as_ref()]
+        .into_iter()
+        .flatten()
+        .collect();
+    for sha in commits(&head, &hidden)?.into_iter().rev() {
+        // Merges are left out
+        let Some(id) = commit_patch_id(repo, &sha)? else {
+            continue;
+        };
+        let sign = if upstream_ids.contains(&id) { '-' } else { '+' };
+        if verbose {
+            let kvlm = commit_kvlm(repo, &sha)?;
+            let message = String::from_utf8_lossy(&kvlm.message);
+            println!("{} {} {}", sign, sha, message.lines().next().unwrap_or(""));
+        } else {
+            println!("{} {}", sign, sha);
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum RevSort {
+    // Newest first among the commits reached so far, as git does by default
+    Default,
+    // No parent before all of its children, newest first otherwise
+    Date,
+    // No parent before all of its children, keeping lines of history together
+    Topo,
+}
+
+struct RevCommit {
+    tree: String,
+    parents: Vec<String>,
+    time: i64,
+}
+
+// Walks the commits reachable from the pushed commits but not from the
+// hidden ones. Ordering, first-parent mode, path limiting and a count
+// limit are set on the fields before iterating.
+struct RevWalk<'a> {
+    repo: &'a GitRepository,
+    sort: RevSort,
+    reverse: bool,
+    first_parent: bool,
+    paths: Pathspec,
+    max_count: Option<usize>,
+    starts: Vec<String>,
+    hidden_starts: Vec<String>,
+    hidden: HashSet<String>,
+    commits: HashMap<String, RevCommit>,
+    output: Option<std::vec::IntoIter<String>>,
+}
+
+impl<'a> RevWalk<'a> {
+    fn new(repo: &'a GitRepository) -> Self {
+        RevWalk {
+            repo,
+            sort: RevSort::Default,
+            reverse: false,
+            first_parent: false,
+            paths: Pathspec::default(),
+            max_count: None,
+            starts: Vec::new(),
+            hidden_starts: Vec::new(),
+            hidden: HashSet::new(),
+            commits: HashMap::new(),
+            output: None,
+        }
+    }
+
+    fn push(&mut self, sha: &str) -> Result<(), Box<dyn Error>> {
+        self.starts.push(object_peel(self.repo, sha, "commit")?);
+        Ok(())
+    }
+
+    fn hide(&mut self, sha: &str) -> Result<(), Box<dyn Error>> {
+        self.hidden_starts
+            .push(object_peel(self.repo, sha, "commit")?);
+        Ok(())
+    }
+
+    // Only meaningful once the walk has started.
+    fn is_hidden(&self, sha: &str) -> bool {
+        self.hidden.contains(sha)
+    }
+
+    fn commit(&mut self, sha: &str) -> Result<&RevCommit, Box<dyn Error>> {
+        if !self.commits.contains_key(sha) {
+            let kvlm = commit_kvlm(self.repo, sha)?;
+            // The committer line ends in `<timestamp> <tz>`
+            let time = kvlm
+                .get_str("committer")
+                .and_then(|committer| committer.rsplit(' ').nth(1))
+                .and_then(|time| time.parse().ok())
+                .unwrap_or(0);
+            let commit = RevCommit {
+                tree: kvlm_shas(&kvlm, "tree")?
+                    .into_iter()
+                    .next()
+                    .ok_or("Malformed commit: missing tree")?,
+                parents: kvlm_shas(&kvlm, "parent")?,
+                time,
+            };
+            self.commits.insert(sha.to_string(), commit);
+        }
+
+        Ok(&self.commits[sha])
+    }
+
+    // Whether the limiting paths are the same in both trees.
+    fn same_paths(&self, old: Option<&str>, new: &str) -> Result<bool, Box<dyn Error>> {
+        let mut diff = TreeDiff::new(self.repo);
+        diff.recursive = true;
+        diff.paths = self.paths.clone();
+        Ok(diff.diff(old, Some(new))?.is_empty())
+    }
+
+    // Whether a commit is shown, and which parents the walk goes on to.
+    // With paths, a commit that leaves them as one of its parents had
+    // them is hidden and only that parent is followed.
+    fn simplify(&mut self, sha: &str) -> Result<(bool, Vec<String>), Box<dyn Error>> {
+        let commit = self.commit(sha)?;
+        let tree = commit.tree.clone();
+        let mut parents = commit.parents.clone();
+        if self.first_parent {
+            parents.truncate(1);
+        }
+        if self.paths.is_empty() {
+            return Ok((true, parents));
+        }
+
+        if parents.is_empty() {
+            return Ok((!self.same_paths(None, &tree)?, parents));
+        }
+        for parent in &parents {
+            let parent_tree = self.commit(parent)?.tree.clone();
+            if self.same_paths(Some(&parent_tree), &tree)? {
+                return Ok((false, vec![parent.clone()]));
+            }
+        }
+
+        Ok((true, parents))
+    }
+
+    fn prepare(&mut self) -> Result<Vec<String>, Box<dyn Error>> {
+        let mut stack = self.hidden_starts.clone();
+        while let Some(sha) = stack.pop() {
+            if self.hidden.insert(sha.clone()) {
+                stack.extend(self.commit(&sha)?.parents.clone());
+            }
+        }
+
+        let mut queue = BinaryHeap::new();
+        let mut seen = HashSet::new();
+        for sha in self.starts.clone() {
+            if !self.hidden.contains(&sha) && seen.insert(sha.clone()) {
+                queue.push((self.commit(&sha)?.time, sha));
+            }
+        }
+
+        let mut walked = Vec::new();
+        let mut shown = HashSet::new();
+        let mut edges = HashMap::new();
+        while let Some((_, sha)) = queue.pop() {
+            let (show, parents) = self.simplify(&sha)?;
+            for parent in &parents {
+                if !self.hidden.contains(parent) && seen.insert(parent.clone()) {
+                    queue.push((self.commit(parent)?.time, parent.clone()));
+                }
+            }
+            if show {
+                shown.insert(sha.clone());
+            }
+            edges.insert(sha.clone(), parents);
+            walked.push(sha);
+        }
+
+        let mut order = match self.sort {
+            RevSort::Default => walked,
+            RevSort::Date | RevSort::Topo => self.sort_topologically(&walked, &edges),
+        };
+        order.retain(|sha| shown.contains(sha));
+        if let Some(max_count) = self.max_count {
+            order.truncate(max_count);
+        }
+        if self.reverse {
+            order.reverse();
+        }
+
+        Ok(order)
+    }
+
+    fn sort_topologically(
+        &self,
+        walked: &[String],
+        edges: &HashMap<String, Vec<String>>,
+    ) -> Vec<String> {
+        let mut children: HashMap<&str, usize> =
+            walked.iter().map(|sha| (sha.as_str(), 0)).collect();
+        for parents in edges.values() {
+            for parent in parents {
+                if let Some(count) = children.get_mut(parent.as_str()) {
+                    *count += 1;
+                }
+            }
+        }
+
+        let time = |sha: &str| self.commits.get(sha).map_or(0, |commit| commit.time);
+        let mut heap = BinaryHeap::new();
+        let mut stack = Vec::new();
+        for sha in walked
+            .iter()
+            .rev()
+            .filter(|sha| children[sha.as_str()] == 0)
+        {
+            heap.push((time(sha), sha.as_str()));
+            stack.push(sha.as_str());
+        }
+
+        let mut order = Vec::new();
+        loop {
+            let next = match self.sort {
+                RevSort::Date => heap.pop().map(|(_, sha)| sha),
+                _ => stack.pop(),
+            };
+            let Some(sha) = next else {
+                break;
+            };
+            for parent in &edges[sha] {
+                if let Some(count) = children.get_mut(parent.as_str()) {
+                    *count -= 1;
+                    if *count == 0 {
+                        heap.push((time(parent), parent.as_str()));
+                        stack.push(parent.as_str());
+                    }
+                }
+            }
+            order.push(sha.to_string());
+        }
+
+        order
+    }
+}
+
+impl Iterator for RevWalk<'_> {
+    type Item = Result<String, Box<dyn Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.output.is_none() {
+            match self.prepare() {
+                Ok(order) => self.output = Some(order.into_iter()),
+                Err(e) => {
+                    self.output = Some(Vec::new().into_iter());
+                    return Some(Err(e));
+                }
+            }
+        }
+
+        self.output.as_mut()?.next().map(Ok)
+    }
+}
+
+// The best name `name-rev` has found for a commit so far.
+struct RevName {
+    tip: String,
+    tagger_date: i64,
+    generation: usize,
+    distance: usize,
+    from_tag: bool,
+}
+
+impl RevName {
+    // Names from the oldest tag win even when farther away, then tags
+    // over other refs, then the fewest hops.
+    fn is_better(
+        existing: Option<&RevName>,
+        tagger_date: i64,
+        distance: usize,
+        from_tag: bool,
+    ) -> bool {
+        let Some(name) = existing else {
+            return true;
+        };
+        if from_tag && name.from_tag {
+            return name.tagger_date > tagger_date
+                || (name.tagger_date == tagger_date && name.distance > distance);
+        }
+        if name.from_tag != from_tag {
+            return from_tag;
+        }
+        if name.distance != distance {
+            return name.distance > distance;
+        }
+        name.tagger_date > tagger_date
+    }
+
+    fn tip_base(&self) -> &str {
+        self.tip.strip_suffix("^0").unwrap_or(&self.tip)
+    }
+
+    fn display(&self) -> String {
+        match self.generation {
+            0 => self.tip.clone(),
+            generation => format!("{}~{}", self.tip_base(), generation),
+        }
+    }
+}
+
+// Merge parents are reached through `^<n>`, which counts as far more
+// than any run of first parents.
+const NAME_REV_MERGE_WEIGHT: usize = 65535;
+
+// Names commits by the refs they are reachable from, like `v1~3^2`, by
+// walking back from every ref tip and keeping the best name per commit.
+struct NameRev {
+    names: HashMap<String, RevName>,
+    // Refs name the objects they point at directly, whatever their type
+    exact: HashMap<String, String>,
+}
+
+impl NameRev {
+    fn new(
+        repo: &GitRepository,
+        tags_only: bool,
+        shorten: bool,
+    ) -> Result<NameRev, Box<dyn Error>> {
+        let mut walk = RevWalk::new(repo);
+        let mut exact = HashMap::new();
+        let mut tips = Vec::new();
+        for (refname, sha) in ref_list(repo)? {
+            let from_tag = refname.starts_with("refs/tags/");
+            if tags_only && !from_tag {
+                continue;
+            }
+            let tip = match refname.strip_prefix("refs/tags/") {
+                Some(tag) if shorten => tag,
+                _ => refname
+                    .strip_prefix("refs/heads/")
+                    .or_else(|| refname.strip_prefix("refs/"))
+                    .unwrap_or(&refname),
+            }
+            .to_string();
+            exact.entry(sha.clone()).or_insert_with(|| tip.clone());
+
+            // Tags are dated by the outermost tagger
+            let mut target = sha;
+            let mut tagger_date = None;
+            let mut deref = false;
+            let commit = loop {
+                match read_object(repo, &target)? {
+                    GitObject::Tag(tag) => {
+                        let tag = tag_parse(tag.serialize())?;
+                        tagger_date = tagger_date.or_else(|| {
+                            tag.tagger
+                                .as_deref()
+                                .and_then(|tagger| tagger.rsplit(' ').nth(1))
+                                .and_then(|time| time.parse().ok())
+                        });
+                        target = tag.object;
+                        deref = true;
+                    }
+                    GitObject::Commit(_) => break Some(target),
+                    _ => break None,
+                }
+            };
+            if let Some(commit) = commit {
+                let tagger_date = match tagger_date {
+                    Some(date) => date,
+                    None => walk.commit(&commit)?.time,
+                };
+                let tip = if deref { format!("{}^0", tip) } else { tip };
+                tips.push((commit, tip, tagger_date, from_tag));
+            }
+        }
+        // Tags first, oldest first, so the names they give are kept
+        tips.sort_by_key(|&(_, _, tagger_date, from_tag)| (!from_tag, tagger_date));
+
+        let mut names: HashMap<String, RevName> = HashMap::new();
+        for (commit, tip, tagger_date, from_tag) in tips {
+            if !RevName::is_better(names.get(&commit), tagger_date, 0, from_tag) {
+                continue;
+            }
+            names.insert(
+                commit.clone(),
+                RevName {
+                    tip,
+                    tagger_date,
+                    generation: 0,
+                    distance: 0,
+                    from_tag,
+                },
+            );
+
+            let mut stack = vec![commit];
+            while let Some(sha) = stack.pop() {
+                let parents = walk.commit(&sha)?.parents.clone();
+                let name = &names[&sha];
+                let mut queue = Vec::new();
+                for (i, parent) in parents.into_iter().enumerate() {
+                    let (generation, distance) = match i {
+                        0 => (name.generation + 1, name.distance + 1),
+                        _ => (0, name.distance + NAME_REV_MERGE_WEIGHT),
+                    };
+                    if !RevName::is_better(names.get(&parent), tagger_date, distance, from_tag) {
+                        continue;
+                    }
+                    let tip = match (i, name.generation) {
+                        (0, _) => name.tip.clone(),
+                        (_, 0) => format!("{}^{}", name.tip_base(), i + 1),
+                        (_, generation) => {
+                            format!("{}~{}^{}", name.tip_base(), generation, i + 1)
+                        }
+                    };
+                    queue.push((
+                        parent,
+                        RevName {
+                            tip,
+                            tagger_date,
+                            generation,
+                            distance,
+                            from_tag,
+                        },
+                    ));
+                }
+                // The first parent is walked first
+                for (parent, name) in queue.into_iter().rev() {
+                    names.insert(parent.clone(), name);
+                    stack.push(parent);
+                }
+            }
+        }
+
+        Ok(NameRev { names, exact })
+    }
+
+    fn name(&self, sha: &str) -> Option<String> {
+        match self.names.get(sha) {
+            Some(name) => Some(name.display()),
+            None => self.exact.get(sha).cloned(),
+        }
+    }
+
+    // Follow every full object id in `line` with its name, or replace it
+    // with just the name.
+    fn annotate(&self, line: &str, name_only: bool) -> String {
+        let is_hex = |b: u8| b.is_ascii_digit() || (b'a'..=b'f').contains(&b);
+        let bytes = line.as_bytes();
+        let mut out = String::new();
+        let mut start = 0;
+        let mut run = 0;
+        for i in 0..bytes.len() {
+            if !is_hex(bytes[i]) {
+                run = 0;
+                continue;
+            }
+            run += 1;
+            if run != 40 || bytes.get(i + 1).is_some_and(|&b| is_hex(b)) {
+                continue;
+            }
+            let sha = &line[i + 1 - 40..=i];
+            let Some(name) = self.names.get(sha) else {
+                continue;
+            };
+            match name_only {
+                true => out.push_str(&line[start..i + 1 - 40]),
+                false => out.push_str(&format!("{} (", &line[start..=i])),
+            }
+            out.push_str(&name.display());
+            if !name_only {
+                out.push(')');
+            }
+            start = i + 1;
+        }
+        out.push_str(&line[start..]);
+        out
+    }
+}
+
+// Collect a tree and everything below it into `objects`, skipping
+// anything in `skip` or already `seen`. Gitlinks point into other
+// repositories and are left out.
+fn collect_tree(
+    repo: &GitRepository,
+    tree: &str,
+    skip: &HashSet<String>,
+    seen: &mut HashSet<String>,
+    objects: &mut Vec<String>,
+) -> Result<(), Box<dyn Error>> {
+    let mut trees = vec![tree.to_string()];
+    while let Some(tree) = trees.pop() {
+        if skip.contains(&tree) || !seen.insert(tree.clone()) {
+            continue;
+        }
+        objects.push(tree.clone());
+
+        let GitObject::Tree(data) = read_object(repo, &tree)? else {
+            return Err(From::from(format!("{} is not a tree", tree)));
+        };
+        for leaf in parse_tree(data.serialize())? {
+            match leaf.mode.as_str() {
+                "40000" => trees.push(leaf.sha),
+                "160000" => {}
+                _ => {
+                    if !skip.contains(&leaf.sha) && seen.insert(leaf.sha.clone()) {
+                        objects.push(leaf.sha);
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Everything reachable from `wants` that the client does not already
+// have through `haves`. Trees and blobs are only excluded when they are
+// reachable from the commits where the two histories meet, so a few
+// objects the client has may be sent again.
+fn objects_for_fetch(
+    repo: &GitRepository,
+    wants: &[String],
+    haves: &[String],
+) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut walk = RevWalk::new(repo);
+    for have in haves {
+        let have = object_peel(repo, have, "")?;
+        if let GitObject::Commit(_) = read_object(repo, &have)? {
+            walk.hide(&have)?;
+        }
+    }
+
+    let mut objects = Vec::new();
+    let mut seen = HashSet::new();
+    let mut trees = Vec::new();
+    let mut stack = wants.to_vec();
+    while let Some(sha) = stack.pop() {
+        if !seen.insert(sha.clone()) {
+            continue;
+        }
+
+        match read_object(repo, &sha)? {
+            GitObject::Tag(tag) => {
+                let kvlm = parse_kvlm(tag.serialize())?;
+                objects.push(sha);
+                stack.extend(kvlm_shas(&kvlm, "object")?);
+            }
+            GitObject::Commit(_) => walk.push(&sha)?,
+            GitObject::Tree(_) => {
+                seen.remove(&sha);
+                trees.push(sha);
+            }
+            GitObject::Blob(_) => objects.push(sha),
+        }
+    }
+
+    let mut boundary = HashSet::new();
+    for commit in walk.by_ref().collect::<Result<Vec<_>, _>>()? {
+        let info = walk.commit(&commit)?;
+        trees.push(info.tree.clone());
+        let parents = info.parents.clone();
+        boundary.extend(parents.into_iter().filter(|parent| walk.is_hidden(parent)));
+        objects.push(commit);
+    }
+
+    let mut excluded = HashSet::new();
+    for commit in boundary {
+        let tree = walk.commit(&commit)?.tree.clone();
+        collect_tree(repo, &tree, &HashSet::new(), &mut excluded, &mut Vec::new())?;
+    }
+    for tree in trees {
+        collect_tree(repo, &tree, &excluded, &mut seen, &mut objects)?;
+    }
+
+    Ok(objects)
+}
+
+// How much of a session one invocation handles: all of it over a
+// two-way stream, or one half of a stateless exchange as used over HTTP,
+// where the advertisement and each later round are separate requests.
+#[derive(Clone, Copy, PartialEq)]
+enum ServiceMode {
+    Full,
+    AdvertiseRefs,
+    StatelessRpc,
+}
+
+impl ServiceMode {
+    fn from_flags(stateless_rpc: bool, advertise_refs: bool) -> Self {
+        match (stateless_rpc, advertise_refs) {
+            (_, true) => ServiceMode::AdvertiseRefs,
+            (true, false) => ServiceMode::StatelessRpc,
+            (false, false) => ServiceMode::Full,
+        }
+    }
+}
+
+// Serve one fetch over `input`/`output`: advertise refs, read wants and
+// haves, then send a pack of whatever the client is missing.
+fn upload_pack(
+    repo: &GitRepository,
+    input: &mut impl Read,
+    output: &mut impl Write,
+    mode: ServiceMode,
+) -> Result<(), Box<dyn Error>> {
+    let namespace = ref_namespace();
+    let symref = match ref_symbolic_target(repo, &format!("{}HEAD", namespace)) {
+        Some(target) => format!(
+            " symref=HEAD:{}",
+            target.strip_prefix(&namespace).unwrap_or(&target)
+        ),
+        None => String::new(),
+    };
+    let capabilities = format!(
+        "side-band side-band-64k no-progress{} agent={}/{}",
+        symref,
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION")
+    );
+    let advertised = match mode {
+        ServiceMode::StatelessRpc => {
+            advertise_refs(repo, &mut io::sink(), &capabilities, true, &namespace)?
+        }
+        _ => advertise_refs(repo, output, &capabilities, true, &namespace)?,
+    };
+    if mode == ServiceMode::AdvertiseRefs {
+        return Ok(());
+    }
+
+    let mut wants = Vec::new();
+    let mut client_capabilities = Vec::new();
+    while let Some(line) = pkt_line_read_text(input)? {
+        let Some(want) = line.strip_prefix("want ") else {
+            return Err(From::from(format!("protocol error: unexpected '{}'", line)));
+        };
+        let (sha, capabilities) = want.split_once(' ').unwrap_or((want, ""));
+        if wants.is_empty() {
+            client_capabilities = capabilities.split(' ').map(ToString::to_string).collect();
+        }
+        if !advertised.contains(sha) {
+            let error = format!("ERR upload-pack: not our ref {}", sha);
+            pkt_line_write(output, error.as_bytes())?;
+            output.flush()?;
+            return Err(From::from(error));
+        }
+        wants.push(sha.to_string());
+    }
+
+    // The client only wanted the advertisement
+    if wants.is_empty() {
+        return Ok(());
+    }
+
+    let mut common = Vec::new();
+    loop {
+        match pkt_line_read_text(input)? {
+            None => {
+                if common.is_empty() {
+                    pkt_line_write(output, b"NAK\n")?;
+                    output.flush()?;
+                }
+                // A stateless client sends its next round as a new request
+                if mode == ServiceMode::StatelessRpc {
+                    return Ok(());
+                }
+            }
+            Some(line) if line == "done" => {
+                if common.is_empty() {
+                    pkt_line_write(output, b"NAK\n")?;
+                }
+                break;
+            }
+            Some(line) => {
+                let Some(have) = line.strip_prefix("have ") else {
+                    return Err(From::from(format!("protocol error: unexpected '{}'", line)));
+                };
+                if object_exists(repo, have)? {
+                    common.push(have.to_string());
+                    if common.len() == 1 {
+                        pkt_line_write(output, format!("ACK {}\n", have).as_bytes())?;
+                        output.flush()?;
+                    }
+                }
+            }
+        }
+    }
+
+    let has_capability = |name: &str| client_capabilities.iter().any(|c| c == name);
+    let sideband = match (has_capability("side-band-64k"), has_capability("side-band")) {
+        (true, _) => Some(65520),
+        (false, true) => Some(1000),
+        (false, false) => None,
+    };
+    let progress = sideband.filter(|_| !has_capability("no-progress"));
+
+    let objects = objects_for_fetch(repo, &wants, &common)?;
+    if let Some(max) = progress {
+        let message = format!("Enumerating objects: {}, done.\n", objects.len());
+        sideband_write(output, 2, message.as_bytes(), max)?;
+    }
+
+    let pack = write_pack(repo, &objects)?;
+    match sideband {
+        Some(max) => {
+            sideband_write(output, 1, &pack, max)?;
+            if let Some(max) = progress {
+                let message = format!("Total {} (delta 0), reused 0 (delta 0)\n", objects.len());
+                sideband_write(output, 2, message.as_bytes(), max)?;
+            }
+            pkt_flush(output)?;
+        }
+        None => {
+            output.write_all(&pack)?;
+            output.flush()?;
+        }
+    }
+
+    Ok(())
+}
+
+fn read_pack_stream_byte(input: &mut impl Read, pack: &mut Vec<u8>) -> Result<u8, Box<dyn Error>> {
+    let mut byte = [0];
+    input.read_exact(&mut byte)?;
+    pack.push(byte[0]);
+    Ok(byte[0])
+}
+
+// Copy exactly one pack off `input`, which stays open afterwards for
+// the rest of the conversation. The zlib streams have to be inflated to
+// find where each entry ends.
+fn read_pack_stream(input: &mut impl BufRead) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut pack = vec![0; 12];
+    input.read_exact(&mut pack)?;
+    if &pack[..4] != PACK_SIGNATURE {
+        return Err(From::from("protocol error: bad pack header"));
+    }
+
+    for _ in 0..read_be_u32(&pack, 8) {
+        let mut byte = read_pack_stream_byte(input, &mut pack)?;
+        let type_id = (byte >> 4) & 7;
+        while byte & 0x80 != 0 {
+            byte = read_pack_stream_byte(input, &mut pack)?;
+        }
+        match type_id {
+            6 => while read_pack_stream_byte(input, &mut pack)? & 0x80 != 0 {},
+            7 => {
+                for _ in 0..20 {
+                    read_pack_stream_byte(input, &mut pack)?;
+                }
+            }
+            _ => {}
+        }
+
+        // Give the inflater whatever is buffered and keep only what it used
+        let mut decompress = Decompress::new(true);
+        let mut scratch = Vec::with_capacity(64 * 1024);
+        loop {
+            let buffer = input.fill_buf()?;
+            if buffer.is_empty() {
+                return Err(From::from("early EOF"));
+            }
+            let before = decompress.total_in();
+            scratch.clear();
+            let status = decompress.decompress_vec(buffer, &mut scratch, FlushDecompress::None)?;
+            let used = (decompress.total_in() - before) as usize;
+            pack.extend_from_slice(&buffer[..used]);
+            input.consume(used);
+            if status == Status::StreamEnd {
+                break;
+            }
+        }
+    }
+
+    let mut trailer = [0; 20];
+    input.read_exact(&mut trailer)?;
+    pack.extend_from_slice(&trailer);
+    Ok(pack)
+}
+
+fn pack_check(objects: &[PackObject]) -> Result<(), Box<dyn Error>> {
+    for object in objects {
+        object_check(object.object_type, &object.data)
+            .map_err(|e| format!("object {}: {}", object.sha, e))?;
+    }
+    Ok(())
+}
+
+// Index a received pack into `quarantine/pack`.
+fn receive_pack_file(
+    input: &mut impl BufRead,
+    quarantine: &Path,
+    fsck: bool,
+) -> Result<(), Box<dyn Error>> {
+    let pack = read_pack_stream(input)?;
+    let index = index_pack(&pack, fsck)?;
+
+    let dir = quarantine.join("pack");
+    fs::create_dir_all(&dir)?;
+    let name = format!("pack-{}", hex::encode(&pack[pack.len() - 20..]));
+    fs::write(dir.join(format!("{}.pack", name)), &pack)?;
+    fs::write(dir.join(format!("{}.idx", name)), &index)?;
+    Ok(())
+}
+
+// Whether everything `tip` reaches is there, down to the history the
+// existing refs already have, as git's connectivity check makes sure.
+fn receive_connected(
+    repo: &GitRepository,
+    tip: &str,
+    existing: &[String],
+) -> Result<bool, Box<dyn Error>> {
+    // Commits and trees are read on the way; a missing one ends the walk
+    let Ok(objects) = objects_for_fetch(repo, &[tip.to_string()], existing) else {
+        return Ok(false);
+    };
+    for sha in objects {
+        if !object_exists(repo, &sha)? {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+// Move quarantined packs into the object store, each pack before its
+// index so no reader finds an index without its pack.
+fn migrate_quarantine(repo: &GitRepository, quarantine: &Path) -> Result<(), Box<dyn Error>> {
+    let dir = quarantine.join("pack");
+    if !dir.is_dir() {
+        return Ok(());
+    }
+
+    let target = repo_dir(repo, "objects/pack")?;
+    for extension in ["pack", "idx"] {
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|e| e == extension) {
+                let name = path.file_name().ok_or("bad quarantine entry")?;
+                rename_over(&path, &target.join(name))?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Messages for the person pushing: side-band channel 2 when the client
+// asked for it, otherwise stderr, which the transport relays.
+fn remote_message(out: &mut impl Write, sideband: bool, message: &[u8]) -> io::Result<()> {
+    if message.is_empty() {
+        return Ok(());
+    }
+    match sideband {
+        true => sideband_write(out, 2, message, 65520),
+        false => io::stderr().write_all(message),
+    }
+}
+
+fn hook_path(repo: &GitRepository, name: &str) -> Option<PathBuf> {
+    let path = repo_path(repo, &format!("hooks/{}", name));
+    let metadata = fs::metadata(&path).ok()?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        if metadata.permissions().mode() & 0o111 == 0 {
+            return None;
+        }
+    }
+
+    metadata.is_file().then_some(path)
+}
+
+// Run a hook if it is installed, relaying its output to the client.
+// A missing hook counts as success.
+fn run_hook(
+    repo: &GitRepository,
+    name: &str,
+    args: &[&str],
+    input: &[u8],
+    env: &[(&str, &Path)],
+    out: &mut impl Write,
+    sideband: bool,
+) -> Result<bool, Box<dyn Error>> {
+    let Some(path) = hook_path(repo, name) else {
+        return Ok(true);
+    };
+
+    let mut child = process::Command::new(path)
+        .args(args)
+        .current_dir(&repo.worktree)
+        .env("GIT_DIR", &repo.gitdir)
+        .envs(env.iter().copied())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    // Fed from its own thread, so a hook that writes a lot before reading
+    // does not block on us. It is free to exit without reading its input.
+    let stdin = child.stdin.take();
+    let output = thread::scope(|scope| {
+        if let Some(mut stdin) = stdin {
+            scope.spawn(move || {
+                let _ = stdin.write_all(input);
+            });
+        }
+        child.wait_with_output()
+    })?;
+    remote_message(out, sideband, &output.stdout)?;
+    remote_message(out, sideband, &output.stderr)?;
+
+    Ok(output.status.success())
+}
+
+fn is_ancestor(repo: &GitRepository, ancestor: &str, commit: &str) -> Result<bool, Box<dyn Error>> {
+    let mut seen = HashSet::new();
+    let mut stack = vec![commit.to_string()];
+    while let Some(commit) = stack.pop() {
+        if commit == ancestor {
+            return Ok(true);
+        }
+        if !seen.insert(commit.clone()) {
+            continue;
+        }
+        let GitObject::Commit(data) = read_object(repo, &commit)? else {
+            return Ok(false);
+        };
+        stack.extend(kvlm_shas(&parse_kvlm(data.serialize())?, "parent")?);
+    }
+
+    Ok(false)
+}
+
+fn packed_refs_delete(repo: &GitRepository, names: &[&str]) -> Result<(), Box<dyn Error>> {
+    let path = repo_path(repo, "packed-refs");
+    if !path.is_file() {
+        return Ok(());
+    }
+
+    let content = fs::read_to_string(&path)?;
+    let mut kept = String::new();
+    let mut deleting = false;
+    for line in content.lines() {
+        // A `^` line holds the peeled value of the ref above it
+        if !line.starts_with('^') {
+            deleting = line
+                .split_once(' ')
+                .is_some_and(|(_, name)| names.contains(&name));
+        }
+        if !deleting {
+            kept.push_str(line);
+            kept.push('\n');
+        }
+    }
+
+    if kept != content {
+        let lock = repo_path(repo, "packed-refs.lock");
+        fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&lock)
+            .map_err(|e| format!("cannot lock packed-refs: {}", e))?;
+        let lock = PathGuard(Some(lock));
+        fs::write(lock.path(), kept)?;
+        lock.commit(&path)?;
+    }
+
+    Ok(())
+}
+
+// Whether `name` is a well-formed ref name: slash-separated components
+// that are not empty, do not start with '.' or end with ".lock", and
+// hold no "..", "@{", control characters, spaces or any of ~^:?[\*.
+// A refspec pattern may use a single '*'.
+fn refname_valid(name: &str, allow_onelevel: bool, refspec_pattern: bool) -> bool {
+    if name == "@" || name.ends_with('.') {
+        return false;
+    }
+    let mut star = refspec_pattern;
+    let components: Vec<&str> = name.split('/').collect();
+    for component in &components {
+        if component.is_empty() || component.starts_with('.') || component.ends_with(".lock") {
+            return false;
+        }
+        let bytes = component.as_bytes();
+        for (i, &b) in bytes.iter().enumerate() {
+            let prev = i.checked_sub(1).map(|i| bytes[i]);
+            match b {
+                b'.' if prev == Some(b'.') => return false,
+                b'{' if prev == Some(b'@') => return false,
+                b'*' if star => star = false,
+                b'*' | b' ' | b'~' | b'^' | b':' | b'?' | b'[' | b'\\' | 0x7f => return false,
+                b if b < 0x20 => return false,
+                _ => {}
+            }
+        }
+    }
+    allow_onelevel || components.len() > 1
+}
+
+// A ref name with its leading slashes dropped and runs of slashes
+// squeezed. A trailing slash is kept, so the name stays invalid.
+fn refname_normalize(name: &str) -> String {
+    let mut normalized = String::new();
+    for c in name.chars() {
+        if c != '/' || !(normalized.is_empty() || normalized.ends_with('/')) {
+            normalized.push(c);
+        }
+    }
+    normalized
+}
+
+// Take the lock for one ref update and check the ref still holds `old`;
+// the new value is written into the lock file, ready to be renamed.
+fn ref_lock(
+    repo: &GitRepository,
+    name: &str,
+    old: &str,
+    new: &str,
+) -> Result<PathGuard, Box<dyn Error>> {
+    if !refname_valid(name, true, false) {
+        return Err(From::from(format!(
+            "refusing to update ref with bad name '{}'",
+            name
+        )));
+    }
+    let path = repo_file(repo, &format!("{}.lock", name));
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&path)
+        .map_err(|e| format!("cannot lock ref '{}': {}", name, e))?;
+    let lock = PathGuard(Some(path));
+
+    let current = ref_resolve(repo, name)?.unwrap_or_else(|| NULL_SHA.to_string());
+    if current != old {
+        return Err(From::from(format!(
+            "cannot lock ref '{}': is at {} but expected {}",
+            name, current, old
+        )));
+    }
+    if new != NULL_SHA {
+        fs::write(lock.path(), format!("{}\n", new))?;
+    }
+
+    Ok(lock)
+}
+
+// Check one `(name, old, new)` update against the stack's refs and the
+// records already in the table being built, giving its record.
+fn reftable_update(
+    refs: &BTreeMap<String, ReftableValue>,
+    records: &[(String, ReftableValue)],
+    (name, old, new): (&str, &str, &str),
+) -> Result<(String, ReftableValue), Box<dyn Error>> {
+    if !refname_valid(name, true, false) {
+        return Err(From::from(format!(
+            "refusing to update ref with bad name '{}'",
+            name
+        )));
+    }
+    let current = reftable_resolve(refs, name).unwrap_or_else(|| NULL_SHA.to_string());
+    if current != old {
+        return Err(From::from(format!(
+            "cannot lock ref '{}': is at {} but expected {}",
+            name, current, old
+        )));
+    }
+    if records.iter().any(|(updated, _)| updated == name) {
+        return Err(From::from(format!(
+            "cannot lock ref '{}': updated twice",
+            name
+        )));
+    }
+    let value = match new == NULL_SHA {
+        true => ReftableValue::Deleted,
+        false => ReftableValue::Object(new.to_string()),
+    };
+    Ok((name.to_string(), value))
+}
+
+// Apply `(name, old, new)` ref updates each on its own, giving the error
+// of every one that failed. With reftable the updates that pass still
+// share one table.
+fn ref_transaction_each(
+    repo: &GitRepository,
+    updates: &[(&str, &str, &str)],
+) -> Result<Vec<Option<String>>, Box<dyn Error>> {
+    if !repo.reftable {
+        return Ok(updates
+            .iter()
+            .map(|update| ref_transaction(repo, &[*update]).err())
+            .map(|error| error.map(|e| e.to_string()))
+            .collect());
+    }
+
+    let mut errors = Vec::new();
+    reftable_add(repo, |refs| {
+        let mut records = Vec::new();
+        for update in updates {
+            match reftable_update(refs, &records, *update) {
+                Ok(record) => {
+                    records.push(record);
+                    errors.push(None);
+                }
+                Err(e) => errors.push(Some(e.to_string())),
+            }
+        }
+        Ok((records, Vec::new()))
+    })?;
+    Ok(errors)
+}
+
+// Apply `(name, old, new)` ref updates as one transaction: every ref is
+// locked and checked before any of them changes.
+fn ref_transaction(
+    repo: &GitRepository,
+    updates: &[(&str, &str, &str)],
+) -> Result<(), Box<dyn Error>> {
+    if repo.reftable {
+        return reftable_add(repo, |refs| {
+            let mut records = Vec::new();
+            for update in updates {
+                records.push(reftable_update(refs, &records, *update)?);
+            }
+            Ok((records, Vec::new()))
+        });
+    }
+
+    // Locks still held when this returns early are dropped, and so removed
+    let locks = updates
+        .iter()
+        .map(|(name, old, new)| ref_lock(repo, name, old, new))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let deleted: Vec<&str> = updates
+        .iter()
+        .filter(|(_, _, new)| *new == NULL_SHA)
+        .map(|(name, _, _)| *name)
+        .collect();
+    packed_refs_delete(repo, &deleted)?;
+    for ((name, _, new), lock) in updates.iter().zip(locks) {
+        let path = repo_path(repo, name);
+        if *new == NULL_SHA {
+            if path.is_file() {
+                fs::remove_file(path)?;
+            }
+        } else {
+            lock.commit(&path)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum ReceiveDeny {
+    Ignore,
+    Warn,
+    Refuse,
+}
+
+impl ReceiveDeny {
+    fn parse(value: &str) -> Result<Self, Box<dyn Error>> {
+        match value.to_ascii_lowercase().as_str() {
+            "ignore" | "false" | "no" | "off" | "0" => Ok(ReceiveDeny::Ignore),
+            "warn" => Ok(ReceiveDeny::Warn),
+            "refuse" | "true" | "yes" | "on" | "1" => Ok(ReceiveDeny::Refuse),
+            _ => Err(From::from(format!(
+                "unsupported receive deny value '{}'",
+                value
+            ))),
+        }
+    }
+}
+
+struct RefCommand {
+    old: String,
+    new: String,
+    name: String,
+    error: Option<&'static str>,
+}
+
+// Serve one push over `input`/`output`: advertise refs, read the ref
+// updates and the pack, then apply whatever the checks and hooks allow
+// and report the outcome for every ref.
+fn receive_pack(
+    repo: &mut GitRepository,
+    input: &mut impl BufRead,
+    output: &mut impl Write,
+    mode: ServiceMode,
+) -> Result<(), Box<dyn Error>> {
+    let capabilities = format!(
+        "report-status delete-refs side-band-64k atomic ofs-delta no-thin agent={}/{}",
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION")
+    );
+    let namespace = ref_namespace();
+    if mode != ServiceMode::StatelessRpc {
+        advertise_refs(repo, output, &capabilities, false, &namespace)?;
+    }
+    if mode == ServiceMode::AdvertiseRefs {
+        return Ok(());
+    }
+
+    let mut commands = Vec::new();
+    let mut client_capabilities = Vec::new();
+    while let Some(line) = pkt_line_read_text(input)? {
+        let (line, capabilities) = line.split_once('\0').unwrap_or((&line, ""));
+        if commands.is_empty() {
+            client_capabilities = capabilities.split(' ').map(ToString::to_string).collect();
+        }
+        let mut fields = line.splitn(3, ' ');
+        match (fields.next(), fields.next(), fields.next()) {
+            (Some(old), Some(new), Some(name)) if old.len() == 40 && new.len() == 40 => {
+                commands.push(RefCommand {
+                    old: old.to_string(),
+                    new: new.to_string(),
+                    name: name.to_string(),
+                    error: None,
+                });
+            }
+            _ => {
+                return Err(From::from(format!(
+                    "protocol error: expected old/new/ref, got '{}'",
+                    line
+                )))
+            }
+        }
+    }
+
+    // Nothing to push
+    if commands.is_empty() {
+        return Ok(());
+    }
+
+    let has_capability = |name: &str| client_capabilities.iter().any(|c| c == name);
+    let sideband = has_capability("side-band-64k");
+    let config = repo_config(repo)?;
+    let fsck = match config_get_bool(&config, "receive.fsckobjects")? {
+        Some(fsck) => fsck,
+        None => config_get_bool(&config, "transfer.fsckobjects")?.unwrap_or(false),
+    };
+
+    // Nothing the pack brings becomes visible to other readers until the
+    // pre-receive hook has accepted the push. Pushes served at the same
+    // time each get their own.
+    let quarantine = PathGuard(Some(create_unique_dir(
+        &repo_dir(repo, "objects")?,
+        "incoming-",
+    )?));
+    let unpacked = match commands.iter().any(|command| command.new != NULL_SHA) {
+        true => receive_pack_file(input, quarantine.path(), fsck),
+        false => Ok(()),
+    };
+    let unpack_status = match &unpacked {
+        Ok(_) => "unpack ok\n".to_string(),
+        Err(e) => format!("unpack {}\n", e),
+    };
+
+    // The new tips are checked against the pack and the repository together
+    if unpacked.is_ok() {
+        repo_odb(repo)?;
+        let mut odb = odb_open(quarantine.path(), 1, Compression::default())?;
+        odb.backends
+            .extend(repo.odb.take().ok_or("object store vanished")?.backends);
+        repo.odb = OnceCell::from(odb);
+    }
+    let existing: Vec<String> = ref_list(repo)?.into_iter().map(|(_, sha)| sha).collect();
+    for command in &mut commands {
+        let name = &command.name;
+        let bad_name = match name.strip_prefix("refs/") {
+            Some(rest) => !refname_valid(rest, false, false),
+            None => true,
+        };
+        command.error = match &unpacked {
+            Err(_) => Some("unpacker error"),
+            Ok(_) if bad_name => Some("funny refname"),
+            Ok(_) => {
+                let missing =
+                    command.new != NULL_SHA && !receive_connected(repo, &command.new, &existing)?;
+                missing.then_some("missing necessary objects")
+            }
+        };
+    }
+    repo.odb = OnceCell::new();
+
+    let pending: String = commands
+        .iter()
+        .filter(|command| command.error.is_none())
+        .map(|command| format!("{} {} {}\n", command.old, command.new, command.name))
+        .collect();
+    if !pending.is_empty() {
+        let objects = repo_path(repo, "objects");
+        let env = [
+            ("GIT_QUARANTINE_PATH", quarantine.path()),
+            ("GIT_OBJECT_DIRECTORY", quarantine.path()),
+            ("GIT_ALTERNATE_OBJECT_DIRECTORIES", objects.as_path()),
+        ];
+        if !run_hook(
+            repo,
+            "pre-receive",
+            &[],
+            pending.as_bytes(),
+            &env,
+            output,
+            sideband,
+        )? {
+            for command in commands
+                .iter_mut()
+                .filter(|command| command.error.is_none())
+            {
+                command.error = Some("pre-receive hook declined");
+            }
+        }
+    }
+
+    if commands.iter().any(|command| command.error.is_none()) {
+        migrate_quarantine(repo, quarantine.path())?;
+        repo.odb = OnceCell::new();
+    }
+    drop(quarantine);
+
+    let head = ref_symbolic_target(repo, "HEAD");
+    let bare = repo.worktree == repo.gitdir;
+    let deny = |name: &str| -> Result<ReceiveDeny, Box<dyn Error>> {
+        Ok(config_get(&config, name)
+            .map(ReceiveDeny::parse)
+            .transpose()?
+            .unwrap_or(ReceiveDeny::Refuse))
+    };
+    let deny_current_branch = deny("receive.denyCurrentBranch")?;
+    let deny_delete_current = deny("receive.denyDeleteCurrent")?;
+    let deny_non_fast_forwards =
+        config_get_bool(&config, "receive.denyNonFastForwards")?.unwrap_or(false);
+
+    for command in commands
+        .iter_mut()
+        .filter(|command| command.error.is_none())
+    {
+        let current = head.as_deref() == Some(format!("{}{}", namespace, command.name).as_str());
+        let name = &command.name;
+
+        if command.new == NULL_SHA {
+            if current && deny_delete_current == ReceiveDeny::Refuse {
+                let message = format!("error: refusing to delete the current branch: {}\n", name);
+                remote_message(output, sideband, message.as_bytes())?;
+                command.error = Some("deletion of the current branch prohibited");
+            } else if current && deny_delete_current == ReceiveDeny::Warn {
+                remote_message(output, sideband, b"warning: deleting the current branch\n")?;
+            }
+        } else {
+            if current && !bare && deny_current_branch == ReceiveDeny::Refuse {
+                let message = format!("error: refusing to update checked out branch: {}\n", name);
+                remote_message(output, sideband, message.as_bytes())?;
+                command.error = Some("branch is currently checked out");
+            } else if current && !bare && deny_current_branch == ReceiveDeny::Warn {
+                remote_message(output, sideband, b"warning: updating the current branch\n")?;
+            }
+
+            if command.error.is_none()
+                && deny_non_fast_forwards
+                && command.old != NULL_SHA
+                && name.starts_with("refs/heads/")
+                && !is_ancestor(repo, &command.old, &command.new)?
+            {
+                let message = format!(
+                    "error: denying non-fast-forward {} (you should pull first)\n",
+                    name
+                );
+                remote_message(output, sideband, message.as_bytes())?;
+                command.error = Some("non-fast-forward");
+            }
+        }
+
+        let args = [name.as_str(), &command.old, &command.new];
+        if command.error.is_none() && !run_hook(repo, "update", &args, b"", &[], output, sideband)?
+        {
+            command.error = Some("hook declined");
+        }
+    }
+
+    // An atomic push lands completely or not at all
+    let atomic = has_capability("atomic");
+    if atomic && commands.iter().any(|command| command.error.is_some()) {
+        for command in commands
+            .iter_mut()
+            .filter(|command| command.error.is_none())
+        {
+            command.error = Some("atomic push failure");
+        }
+    }
+
+    let pending: Vec<usize> = (0..commands.len())
+        .filter(|&i| commands[i].error.is_none())
+        .collect();
+    let namespaced: Vec<String> = commands
+        .iter()
+        .map(|command| format!("{}{}", namespace, command.name))
+        .collect();
+    let updates: Vec<(&str, &str, &str)> = pending
+        .iter()
+        .map(|&i| {
+            let command = &commands[i];
+            (
+                namespaced[i].as_str(),
+                command.old.as_str(),
+                command.new.as_str(),
+            )
+        })
+        .collect();
+    let errors = match atomic {
+        true => ref_transaction(repo, &updates)
+            .map(|_| vec![None; updates.len()])
+            .map_err(|e| e.to_string()),
+        false => ref_transaction_each(repo, &updates).map_err(|e| e.to_string()),
+    };
+    let errors = errors.unwrap_or_else(|e| vec![Some(e); updates.len()]);
+    let mut reported = Vec::new();
+    for (&i, error) in pending.iter().zip(errors) {
+        if let Some(error) = error {
+            // An atomic push fails as a whole, with one message for it
+            if !reported.contains(&error) {
+                remote_message(output, sideband, format!("error: {}\n", error).as_bytes())?;
+                reported.push(error);
+            }
+            commands[i].error = Some("failed to update ref");
+        }
+    }
+
+    if has_capability("report-status") {
+        let mut report = Vec::new();
+        pkt_line_write(&mut report, unpack_status.as_bytes())?;
+        for command in &commands {
+            let line = match command.error {
+                None => format!("ok {}\n", command.name),
+                Some(error) => format!("ng {} {}\n", command.name, error),
+            };
+            pkt_line_write(&mut report, line.as_bytes())?;
+        }
+        pkt_flush(&mut report)?;
+
+        match sideband {
+            true => sideband_write(output, 1, &report, 65520)?,
+            false => {
+                output.write_all(&report)?;
+                output.flush()?;
+            }
+        }
+    }
+
+    let updated: String = commands
+        .iter()
+        .filter(|command| command.error.is_none())
+        .map(|command| format!("{} {} {}\n", command.old, command.new, command.name))
+        .collect();
+    if !updated.is_empty() {
+        run_hook(
+            repo,
+            "post-receive",
+            &[],
+            updated.as_bytes(),
+            &[],
+            output,
+            sideband,
+        )?;
+    }
+    if sideband {
+        pkt_flush(output)?;
+    }
+
+    Ok(())
+}
+
+struct DaemonOptions {
+    base_path: Option<String>,
+    export_all: bool,
+    whitelist: Vec<PathBuf>,
+    init_timeout: Option<Duration>,
+    timeout: Option<Duration>,
+}
+
+// The repository a git:// client asked for, if it is exported: inside a
+// whitelisted directory when there is a whitelist, marked with
+// `git-daemon-export-ok` unless everything is exported, and not opted
+// out through `daemon.uploadpack`.
+fn daemon_repo(options: &DaemonOptions, path: &str) -> Option<GitRepository> {
+    if path.split('/').any(|part| part == "..") {
+        return None;
+    }
+    let path = match &options.base_path {
+        Some(base) => format!("{}/{}", base, path.trim_start_matches('/')),
+        None if path.starts_with('/') => path.to_string(),
+        None => return None,
+    };
+
+    let repo = repo_open(&path).ok()?;
+    let worktree = Path::new(&repo.worktree);
+    if !options.whitelist.is_empty()
+        && !options
+            .whitelist
+            .iter()
+            .any(|dir| worktree.starts_with(dir))
+    {
+        return None;
+    }
+    if !options.export_all && !repo_path(&repo, "git-daemon-export-ok").is_file() {
+        return None;
+    }
+    let config = repo_config(&repo).ok()?;
+    if config_get_bool(&config, "daemon.uploadpack").ok()? == Some(false) {
+        return None;
+    }
+
+    Some(repo)
+}
+
+fn daemon_serve(options: &DaemonOptions, stream: TcpStream) -> Result<(), Box<dyn Error>> {
+    // A client that stops talking, or stops reading, is dropped instead of
+    // holding one of the connections forever
+    stream.set_read_timeout(options.init_timeout)?;
+    stream.set_write_timeout(options.timeout)?;
+    let mut input = BufReader::new(stream.try_clone()?);
+    let mut output = BufWriter::new(stream.try_clone()?);
+
+    // `git-upload-pack /path\0host=example.com\0`, possibly followed by
+    // extra parameters we ignore
+    let request = pkt_line_read(&mut input)?.ok_or("protocol error: expected a request")?;
+    stream.set_read_timeout(options.timeout)?;
+    let request = String::from_utf8(request)?;
+    let line = request.split('\0').next().unwrap_or_default().trim_end();
+    let (service, path) = line
+        .split_once(' ')
+        .ok_or_else(|| format!("protocol error: bad request '{}'", line))?;
+
+    let error = match service {
+        "git-upload-pack" => match daemon_repo(options, path) {
+            Some(repo) => return upload_pack(&repo, &mut input, &mut output, ServiceMode::Full),
+            None => "access denied or repository not exported",
+        },
+        _ => "service not enabled",
+    };
+    let message = format!("ERR {}: {}", error, path);
+    pkt_line_write(&mut output, message.as_bytes())?;
+    output.flush()?;
+    Err(From::from(message))
+}
+
+// Read-only git:// server, one thread per connection. Connections past
+// `max_connections` are told so and closed straight away.
+fn daemon(
+    options: DaemonOptions,
+    listener: TcpListener,
+    max_connections: usize,
+) -> Result<(), Box<dyn Error>> {
+    let options = Arc::new(options);
+    let connections = Arc::new(AtomicUsize::new(0));
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("error: accept failed: {}", e);
+                continue;
+            }
+        };
+        if connections.load(Ordering::SeqCst) >= max_connections {
+            let mut stream = stream;
+            let _ = stream.set_write_timeout(Some(Duration::from_secs(1)));
+            let _ = pkt_line_write(&mut stream, b"ERR too many connections, try again later");
+            continue;
+        }
+
+        connections.fetch_add(1, Ordering::SeqCst);
+        let options = Arc::clone(&options);
+        let connections = Arc::clone(&connections);
+        thread::spawn(move || {
+            let peer = stream
+                .peer_addr()
+                .map(|address| address.to_string())
+                .unwrap_or_default();
+            if let Err(e) = daemon_serve(&options, stream) {
+                eprintln!("[{}] {}", peer, e);
+            }
+            connections.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+
+    Ok(())
+}
+
+struct HttpRequest {
+    method: String,
+    path: String,
+    query: String,
+    headers: Vec<(String, String)>,
+}
+
+impl HttpRequest {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+fn http_read_line(input: &mut impl BufRead) -> Result<Option<String>, Box<dyn Error>> {
+    let mut line = String::new();
+    if input.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    Ok(Some(line.trim_end_matches(['\r', '\n']).to_string()))
+}
+
+// Request bodies are held in memory, so they are refused past
+// http.maxRequestBuffer, this by default.
+const HTTP_MAX_REQUEST_BUFFER: usize = 100 << 20;
+
+fn http_read_chunked(
+    input: &mut impl BufRead,
+    limit: usize,
+) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+    let mut body = Vec::new();
+    loop {
+        let line = http_read_line(input)?.ok_or("unexpected end of chunked body")?;
+        let size = line.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16)?;
+        if size == 0 {
+            // Skip any trailer headers
+            while !http_read_line(input)?.unwrap_or_default().is_empty() {}
+            return Ok(Some(body));
+        }
+        if size > limit - body.len() {
+            return Ok(None);
+        }
+
+        let start = body.len();
+        body.resize(start + size, 0);
+        input.read_exact(&mut body[start..])?;
+        http_read_line(input)?;
+    }
+}
+
+// The body of `request`, or `None` if it, or what it inflates to, is
+// larger than `limit`.
+fn http_read_body(
+    input: &mut impl BufRead,
+    request: &HttpRequest,
+    limit: usize,
+) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+    let mut body = Vec::new();
+    if request
+        .header("Transfer-Encoding")
+        .is_some_and(|encoding| encoding.eq_ignore_ascii_case("chunked"))
+    {
+        match http_read_chunked(input, limit)? {
+            Some(chunked) => body = chunked,
+            None => return Ok(None),
+        }
+    } else if let Some(length) = request.header("Content-Length") {
+        let length: u64 = length.parse()?;
+        if length > limit as u64 {
+            return Ok(None);
+        }
+        input.take(length).read_to_end(&mut body)?;
+        if body.len() as u64 != length {
+            return Err(From::from("unexpected end of request body"));
+        }
+    }
+
+    if request.header("Content-Encoding") == Some("gzip") {
+        let mut inflated = Vec::new();
+        GzDecoder::new(body.as_slice())
+            .take(limit as u64 + 1)
+            .read_to_end(&mut inflated)?;
+        if inflated.len() > limit {
+            return Ok(None);
+        }
+        body = inflated;
+    }
+    Ok(Some(body))
+}
+
+// Whether a request says it has a body, which has to be read before the
+// connection can carry another request.
+fn http_has_body(request: &HttpRequest) -> bool {
+    request.header("Transfer-Encoding").is_some()
+        || request
+            .header("Content-Length")
+            .is_some_and(|length| length != "0")
+}
+
+// Read the head of the next request on a connection, or `None` once the
+// client has closed it. The body is left for http_read_body.
+fn http_read_request(input: &mut impl BufRead) -> Result<Option<HttpRequest>, Box<dyn Error>> {
+    let Some(line) = http_read_line(input)? else {
+        return Ok(None);
+    };
+    let mut fields = line.split(' ');
+    let (Some(method), Some(target)) = (fields.next(), fields.next()) else {
+        return Err(From::from(format!("bad request line '{}'", line)));
+    };
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+
+    let mut headers = Vec::new();
+    while let Some(line) = http_read_line(input)? {
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.trim().to_string(), value.trim().to_string()));
+        }
+    }
+
+    Ok(Some(HttpRequest {
+        method: method.to_string(),
+        path: path.to_string(),
+        query: query.to_string(),
+        headers,
+    }))
+}
+
+fn http_respond(
+    out: &mut impl Write,
+    status: &str,
+    headers: &[(&str, &str)],
+    body: &[u8],
+) -> io::Result<()> {
+    write!(out, "HTTP/1.1 {}\r\n", status)?;
+    write!(out, "Content-Length: {}\r\n", body.len())?;
+    write!(
+        out,
+        "Cache-Control: no-cache, max-age=0, must-revalidate\r\n"
+    )?;
+    write!(out, "Expires: Fri, 01 Jan 1980 00:00:00 GMT\r\n")?;
+    write!(out, "Pragma: no-cache\r\n")?;
+    for (name, value) in headers {
+        write!(out, "{}: {}\r\n", name, value)?;
+    }
+    write!(out, "\r\n")?;
+    out.write_all(body)?;
+    out.flush()
+}
+
+fn base64_encode(data: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut encoded = String::new();
+    for chunk in data.chunks(3) {
+        let bytes = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let group = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        for i in 0..4 {
+            match i <= chunk.len() {
+                true => encoded.push(ALPHABET[(group >> (18 - 6 * i) & 0x3f) as usize] as char),
+                false => encoded.push('='),
+            }
+        }
+    }
+
+    encoded
+}
+
+struct HttpOptions {
+    // Canonical, so that served repositories can be checked to be below it
+    root: PathBuf,
+    credentials: Option<String>,
+    timeout: Option<Duration>,
+}
+
+// Answer one smart HTTP request: `info/refs` advertisements and the
+// upload-pack and receive-pack RPCs, for repositories under the root.
+// Pushing is only allowed to authenticated users, unless a repository
+// sets `http.receivepack`. The body is only read once the request is
+// known to be allowed; whether it was is returned.
+fn http_serve_request(
+    options: &HttpOptions,
+    request: &HttpRequest,
+    input: &mut impl BufRead,
+    out: &mut impl Write,
+) -> Result<bool, Box<dyn Error>> {
+    let not_found =
+        |out: &mut _| http_respond(out, "404 Not Found", &[], b"Not Found\n").map(|_| false);
+
+    if let Some(credentials) = &options.credentials {
+        let expected = format!("Basic {}", base64_encode(credentials.as_bytes()));
+        if request.header("Authorization") != Some(expected.as_str()) {
+            let challenge = [("WWW-Authenticate", "Basic realm=\"eekgit\"")];
+            http_respond(out, "401 Unauthorized", &challenge, b"Unauthorized\n")?;
+            return Ok(false);
+        }
+    }
+
+    let (repo_path, service, mode) = match (request.method.as_str(), request.path.as_str()) {
+        ("GET", path) if path.ends_with("/info/refs") => {
+            let service = request
+                .query
+                .split('&')
+                .find_map(|param| param.strip_prefix("service="));
+            match service {
+                Some(service) => (
+                    &path[..path.len() - 10],
+                    service,
+                    ServiceMode::AdvertiseRefs,
+                ),
+                // The dumb protocol is not served
+                None => return Ok(not_found(out)?),
+            }
+        }
+        ("POST", path) => match path.rsplit_once('/') {
+            Some((repo_path, service)) => (repo_path, service, ServiceMode::StatelessRpc),
+            None => return Ok(not_found(out)?),
+        },
+        _ => return Ok(not_found(out)?),
+    };
+    let path = options.root.join(repo_path.trim_start_matches('/'));
+    let Ok(mut repo) = repo_open(&path.to_string_lossy()) else {
+        return Ok(not_found(out)?);
+    };
+    // Neither `..` nor a symlink may lead out of the root
+    let inside =
+        |path: &str| fs::canonicalize(path).is_ok_and(|path| path.starts_with(&options.root));
+    if !inside(&repo.worktree) || !inside(&repo.gitdir) {
+        return Ok(not_found(out)?);
+    }
+
+    let config = repo_config(&repo)?;
+    let enabled = match service {
+        "git-upload-pack" => config_get_bool(&config, "http.uploadpack")?.unwrap_or(true),
+        "git-receive-pack" => {
+            config_get_bool(&config, "http.receivepack")?.unwrap_or(options.credentials.is_some())
+        }
+        _ => return Ok(not_found(out)?),
+    };
+    if !enabled {
+        http_respond(out, "403 Forbidden", &[], b"Forbidden\n")?;
+        return Ok(false);
+    }
+
+    let limit = match config_get(&config, "http.maxRequestBuffer") {
+        Some(value) => parse_size(value)
+            .ok_or_else(|| format!("bad http.maxRequestBuffer value '{}'", value))?,
+        None => HTTP_MAX_REQUEST_BUFFER,
+    };
+    let Some(request_body) = http_read_body(input, request, limit)? else {
+        http_respond(out, "413 Payload Too Large", &[], b"Payload Too Large\n")?;
+        return Ok(false);
+    };
+
+    let mut body = Vec::new();
+    if mode == ServiceMode::AdvertiseRefs {
+        pkt_line_write(&mut body, format!("# service={}\n", service).as_bytes())?;
+        pkt_flush(&mut body)?;
+    }
+    let mut rpc_input = request_body.as_slice();
+    match service {
+        "git-upload-pack" => upload_pack(&repo, &mut rpc_input, &mut body, mode)?,
+        _ => receive_pack(&mut repo, &mut rpc_input, &mut body, mode)?,
+    }
+
+    let content_type = match mode {
+        ServiceMode::AdvertiseRefs => format!("application/x-{}-advertisement", service),
+        _ => format!("application/x-{}-result", service),
+    };
+    http_respond(out, "200 OK", &[("Content-Type", &content_type)], &body)?;
+    Ok(true)
+}
+
+fn http_serve(options: &HttpOptions, stream: TcpStream) -> Result<(), Box<dyn Error>> {
+    stream.set_read_timeout(options.timeout)?;
+    stream.set_write_timeout(options.timeout)?;
+    let mut input = BufReader::new(stream.try_clone()?);
+    let mut output = BufWriter::new(stream);
+
+    loop {
+        let request = match http_read_request(&mut input) {
+            Ok(Some(request)) => request,
+            Ok(None) => break,
+            // A kept-alive connection left idle is simply closed
+            Err(e)
+                if e.downcast_ref::<io::Error>().is_some_and(|e| {
+                    matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    )
+                }) =>
+            {
+                break
+            }
+            Err(e) => return Err(e),
+        };
+        let read_body = match http_serve_request(options, &request, &mut input, &mut output) {
+            Ok(read_body) => read_body,
+            Err(e) => {
+                let message = format!("{}\n", e);
+                http_respond(
+                    &mut output,
+                    "500 Internal Server Error",
+                    &[],
+                    message.as_bytes(),
+                )?;
+                return Err(e);
+            }
+        };
+        // An unread body is still in the way of the next request
+        if (!read_body && http_has_body(&request))
+            || request
+                .header("Connection")
+                .is_some_and(|connection| connection.eq_ignore_ascii_case("close"))
+        {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+// Smart HTTP server, one thread per connection. Connections past
+// `max_connections` are answered with a 503 and closed.
+fn http_server(
+    options: HttpOptions,
+    listener: TcpListener,
+    max_connections: usize,
+) -> Result<(), Box<dyn Error>> {
+    let options = Arc::new(options);
+    let connections = Arc::new(AtomicUsize::new(0));
+
+    for stream in listener.incoming() {
+        let mut stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("error: accept failed: {}", e);
+                continue;
+            }
+        };
+        if connections.load(Ordering::SeqCst) >= max_connections {
+            let _ = stream.set_write_timeout(Some(Duration::from_secs(1)));
+            let _ = http_respond(
+                &mut stream,
+                "503 Service Unavailable",
+                &[("Connection", "close")],
+                b"Too many connections\n",
+            );
+            continue;
+        }
+
+        connections.fetch_add(1, Ordering::SeqCst);
+        let options = Arc::clone(&options);
+        let connections = Arc::clone(&connections);
+        thread::spawn(move || {
+            if let Err(e) = http_serve(&options, stream) {
+                eprintln!("error: {}", e);
+            }
+            connections.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+
+    Ok(())
+}
+
+// An editable tree. Subtrees are only read once something below them
+// changes, and writing stores just the trees that changed; subtrees left
+// empty are dropped.
+struct TreeBuilder<'a> {
+    repo: &'a GitRepository,
+    entries: BTreeMap<Vec<u8>, TreeBuilderEntry<'a>>,
+}
+
+enum TreeBuilderEntry<'a> {
+    Leaf { mode: String, sha: String },
+    Tree(TreeBuilder<'a>),
+}
+
+impl<'a> TreeBuilder<'a> {
+    fn new(repo: &'a GitRepository) -> Self {
+        TreeBuilder {
+            repo,
+            entries: BTreeMap::new(),
+        }
+    }
+
+    fn load(repo: &'a GitRepository, tree: &str) -> Result<Self, Box<dyn Error>> {
+        let GitObject::Tree(data) = read_object(repo, tree)? else {
+            return Err(From::from(format!("{} is not a tree", tree)));
+        };
+        let mut builder = TreeBuilder::new(repo);
+        for leaf in parse_tree(data.serialize())? {
+            let entry = TreeBuilderEntry::Leaf {
+                mode: leaf.mode,
+                sha: leaf.sha,
+            };
+            builder.entries.insert(leaf.path, entry);
+        }
+
+        Ok(builder)
+    }
+
+    // The builder for the subtree `name`, loading it or creating it in
+    // place of whatever else is there.
+    fn subtree(&mut self, name: &[u8]) -> Result<&mut TreeBuilder<'a>, Box<dyn Error>> {
+        let subtree = match self.entries.remove(name) {
+            Some(TreeBuilderEntry::Tree(subtree)) => subtree,
+            Some(TreeBuilderEntry::Leaf { mode, sha }) if mode == "40000" => {
+                TreeBuilder::load(self.repo, &sha)?
+            }
+            _ => TreeBuilder::new(self.repo),
+        };
+        self.entries
+            .insert(name.to_vec(), TreeBuilderEntry::Tree(subtree));
+
+        match self.entries.get_mut(name) {
+            Some(TreeBuilderEntry::Tree(subtree)) => Ok(subtree),
+            _ => Err(From::from("tree builder lost a subtree")),
+        }
+    }
+
+    // Add or replace the entry at a slash-separated path, creating the
+    // trees leading to it.
+    fn insert(&mut self, path: &[u8], mode: &str, sha: &str) -> Result<(), Box<dyn Error>> {
+        match path.iter().position(|&b| b == b'/') {
+            Some(slash) => self
+                .subtree(&path[..slash])?
+                .insert(&path[slash + 1..], mode, sha),
+            None if path.is_empty() => Err(From::from("empty path in tree")),
+            None => {
+                let entry = TreeBuilderEntry::Leaf {
+                    mode: mode.to_string(),
+                    sha: sha.to_string(),
+                };
+                self.entries.insert(path.to_vec(), entry);
+                Ok(())
+            }
+        }
+    }
+
+    // Remove the entry at a path, returning whether there was one.
+    fn remove(&mut self, path: &[u8]) -> Result<bool, Box<dyn Error>> {
+        let Some(slash) = path.iter().position(|&b| b == b'/') else {
+            return Ok(self.entries.remove(path).is_some());
+        };
+        let is_tree = match self.entries.get(&path[..slash]) {
+            Some(TreeBuilderEntry::Tree(_)) => true,
+            Some(TreeBuilderEntry::Leaf { mode, .. }) => mode == "40000",
+            None => false,
+        };
+        match is_tree {
+            true => self.subtree(&path[..slash])?.remove(&path[slash + 1..]),
+            false => Ok(false),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.entries.values().all(|entry| match entry {
+            TreeBuilderEntry::Leaf { .. } => false,
+            TreeBuilderEntry::Tree(subtree) => subtree.is_empty(),
+        })
+    }
+
+    fn write(&self) -> Result<String, Box<dyn Error>> {
+        let mut leaves = Vec::new();
+        for (name, entry) in &self.entries {
+            let (mode, sha) = match entry {
+                TreeBuilderEntry::Leaf { mode, sha } => (mode.clone(), sha.clone()),
+                TreeBuilderEntry::Tree(subtree) if subtree.is_empty() => continue,
+                TreeBuilderEntry::Tree(subtree) => ("40000".to_string(), subtree.write()?),
+            };
+            leaves.push(GitTreeLeaf {
+                mode,
+                path: name.clone(),
+                sha,
+            });
+        }
+
+        write_object(
+            self.repo,
+            &GitObject::new(tree_serialize(&leaves)?, "tree")?,
+        )
+    }
+}
+
+const INDEX_SIGNATURE: &[u8] = b"DIRC";
+const INDEX_ASSUME_VALID: u16 = 0x8000;
+const INDEX_EXTENDED: u16 = 0x4000;
+const INDEX_SKIP_WORKTREE: u16 = 0x4000;
+
+#[derive(Clone, Default)]
+struct IndexEntry {
+    ctime: (u32, u32),
+    mtime: (u32, u32),
+    dev: u32,
+    ino: u32,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    size: u32,
+    sha: String,
+    // Assume-valid bit and merge stage; the name length is recomputed on write
+    flags: u16,
+    // Skip-worktree and intent-to-add bits, stored only by version 3 and up
+    extended_flags: u16,
+    path: Vec<u8>,
+}
+
+impl IndexEntry {
+    fn stage(&self) -> u16 {
+        (self.flags >> 12) & 3
+    }
+
+    fn set_stat(&mut self, metadata: &fs::Metadata) {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            self.ctime = (metadata.ctime() as u32, metadata.ctime_nsec() as u32);
+            self.mtime = (metadata.mtime() as u32, metadata.mtime_nsec() as u32);
+            self.dev = metadata.dev() as u32;
+            self.ino = metadata.ino() as u32;
+            self.uid = metadata.uid();
+            self.gid = metadata.gid();
+        }
+        self.size = metadata.len() as u32;
+    }
+
+    // Whether the file still looks like it did when its stat data was
+    // recorded. Only then can hashing it again be skipped.
+    fn stat_matches(&self, metadata: &fs::Metadata) -> bool {
+        let mut recorded = IndexEntry::default();
+        recorded.set_stat(metadata);
+        self.ctime == recorded.ctime
+            && self.mtime == recorded.mtime
+            && self.dev == recorded.dev
+            && self.ino == recorded.ino
+            && self.uid == recorded.uid
+            && self.gid == recorded.gid
+            && self.size == recorded.size
+    }
+}
+
+struct GitIndex {
+    version: u32,
+    // Sorted by path, then stage
+    entries: Vec<IndexEntry>,
+}
+
+impl GitIndex {
+    fn find(&self, path: &[u8]) -> Result<usize, usize> {
+        self.entries
+            .binary_search_by(|entry| (entry.path.as_slice(), entry.stage()).cmp(&(path, 0)))
+    }
+
+    // The spelling the index tracks `path` under. On a case-insensitive
+    // filesystem `dir/foo.txt` names the file tracked as `Dir/Foo.txt`, and
+    // new files join the existing spelling of their directories.
+    fn tracked_path(&self, path: &[u8], ignore_case: bool) -> Vec<u8> {
+        if !ignore_case || self.find(path).is_ok() {
+            return path.to_vec();
+        }
+        if let Some(entry) = self
+            .entries
+            .iter()
+            .find(|entry| entry.path.eq_ignore_ascii_case(path))
+        {
+            return entry.path.clone();
+        }
+
+        let mut tracked = path.to_vec();
+        for (i, _) in path.iter().enumerate().filter(|(_, &b)| b == b'/') {
+            if let Some(entry) = self.entries.iter().find(|entry| {
+                entry.path.len() > i && entry.path[..=i].eq_ignore_ascii_case(&tracked[..=i])
+            }) {
+                tracked[..=i].copy_from_slice(&entry.path[..=i]);
+            }
+        }
+        tracked
+    }
+
+    // Whether `path` is tracked at any stage
+    fn contains(&self, path: &[u8]) -> bool {
+        let i = self
+            .entries
+            .partition_point(|entry| entry.path.as_slice() < path);
+        self.entries.get(i).is_some_and(|entry| entry.path == path)
+    }
+
+    fn get_mut(&mut self, path: &[u8]) -> Option<&mut IndexEntry> {
+        let i = self.find(path).ok()?;
+        Some(&mut self.entries[i])
+    }
+
+    // Entries for `path` at any stage, so resolving a conflict replaces them
+    fn remove(&mut self, path: &[u8]) -> bool {
+        let before = self.entries.len();
+        self.entries.retain(|entry| entry.path != path);
+        self.entries.len() != before
+    }
+
+    fn insert(&mut self, entry: IndexEntry) -> Result<(), Box<dyn Error>> {
+        if !verify_path(&entry.path) {
+            return Err(From::from(format!(
+                "Invalid path '{}'",
+                String::from_utf8_lossy(&entry.path)
+            )));
+        }
+
+        // A path cannot be both a file and a directory
+        let mut dir = entry.path.clone();
+        dir.push(b'/');
+        let parent_is_file = entry
+            .path
+            .iter()
+            .enumerate()
+            .filter(|(_, &b)| b == b'/')
+            .any(|(i, _)| self.find(&entry.path[..i]).is_ok());
+        if parent_is_file || self.entries.iter().any(|e| e.path.starts_with(&dir)) {
+            return Err(From::from(format!(
+                "'{}' appears as both a file and as a directory",
+                String::from_utf8_lossy(&entry.path)
+            )));
+        }
+
+        self.remove(&entry.path);
+        match self.find(&entry.path) {
+            Ok(i) | Err(i) => self.entries.insert(i, entry),
+        }
+        Ok(())
+    }
+}
+
+fn index_read(repo: &GitRepository) -> Result<GitIndex, Box<dyn Error>> {
+    let data = match fs::read(repo_path(repo, "index")) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Ok(GitIndex {
+                version: 2,
+                entries: Vec::new(),
+            })
+        }
+        Err(e) => return Err(From::from(e)),
+    };
+
+    if data.len() < 32 || &data[0..4] != INDEX_SIGNATURE {
+        return Err(From::from("index file is corrupt: bad signature"));
+    }
+    let (content, checksum) = data.split_at(data.len() - 20);
+    if Sha1::digest(content).as_slice() != checksum {
+        return Err(From::from("index file is corrupt: bad checksum"));
+    }
+
+    let u32_at = |at: usize| -> Result<u32, Box<dyn Error>> {
+        let bytes = content
+            .get(at..at + 4)
+            .ok_or("index file is corrupt: truncated")?;
+        Ok(u32::from_be_bytes(bytes.try_into()?))
+    };
+    let u16_at = |at: usize| -> Result<u16, Box<dyn Error>> {
+        let bytes = content
+            .get(at..at + 2)
+            .ok_or("index file is corrupt: truncated")?;
+        Ok(u16::from_be_bytes(bytes.try_into()?))
+    };
+
+    let version = u32_at(4)?;
+    if !(2..=3).contains(&version) {
+        return Err(From::from(format!(
+            "index file version {} is not supported",
+            version
+        )));
+    }
+
+    // Each entry takes at least 62 bytes, whatever the count claims
+    let count = u32_at(8)? as usize;
+    let mut entries = Vec::with_capacity(count.min(content.len() / 62));
+    let mut at = 12;
+    for _ in 0..count {
+        let start = at;
+        let mut entry = IndexEntry {
+            ctime: (u32_at(at)?, u32_at(at + 4)?),
+            mtime: (u32_at(at + 8)?, u32_at(at + 12)?),
+            dev: u32_at(at + 16)?,
+            ino: u32_at(at + 20)?,
+            mode: u32_at(at + 24)?,
+            uid: u32_at(at + 28)?,
+            gid: u32_at(at + 32)?,
+            size: u32_at(at + 36)?,
+            sha: hex::encode(
+                content
+                    .get(at + 40..at + 60)
+                    .ok_or("index file is corrupt: truncated")?,
+            ),
+            flags: u16_at(at + 60)?,
+            extended_flags: 0,
+            path: Vec::new(),
+        };
+        at += 62;
+        if entry.flags & INDEX_EXTENDED != 0 {
+            if version < 3 {
+                return Err(From::from(
+                    "index file is corrupt: extended flags in version 2",
+                ));
+            }
+            entry.extended_flags = u16_at(at)?;
+            at += 2;
+        }
+        entry.flags &= !INDEX_EXTENDED;
+
+        let len = content[at..]
+            .iter()
+            .position(|&b| b == 0)
+            .ok_or("index file is corrupt: unterminated path")?;
+        entry.path = content[at..at + len].to_vec();
+        entry.flags &= !0xfff;
+
+        // Entries are padded with NULs to a multiple of eight bytes
+        at = start + (at - start + len + 8) / 8 * 8;
+        entries.push(entry);
+    }
+
+    // Extensions only cache what the entries already say, unless their
+    // signature is lowercase, which marks them as required
+    while at + 8 <= content.len() {
+        let signature = &content[at..at + 4];
+        if !signature[0].is_ascii_uppercase() {
+            return Err(From::from(format!(
+                "index uses {} extension, which we do not understand",
+                String::from_utf8_lossy(signature)
+            )));
+        }
+        at += 8 + u32_at(at + 4)? as usize;
+    }
+
+    Ok(GitIndex { version, entries })
+}
+
+// Write the index through `index.lock`. Cached extensions are dropped, as
+// they may no longer describe the entries.
+fn index_write(repo: &GitRepository, index: &GitIndex) -> Result<(), Box<dyn Error>> {
+    let extended = index.entries.iter().any(|entry| entry.extended_flags != 0);
+    let version = match extended {
+        true => index.version.max(3),
+        false => index.version,
+    };
+
+    let mut data = Vec::new();
+    data.extend_from_slice(INDEX_SIGNATURE);
+    data.extend_from_slice(&version.to_be_bytes());
+    data.extend_from_slice(&(index.entries.len() as u32).to_be_bytes());
+    for entry in &index.entries {
+        let start = data.len();
+        for value in [
+            entry.ctime.0,
+            entry.ctime.1,
+            entry.mtime.0,
+            entry.mtime.1,
+            entry.dev,
+            entry.ino,
+            entry.mode,
+            entry.uid,
+            entry.gid,
+            entry.size,
+        ] {
+            data.extend_from_slice(&value.to_be_bytes());
+        }
+        data.extend_from_slice(&hex::decode(&entry.sha)?);
+
+        let mut flags = entry.flags | entry.path.len().min(0xfff) as u16;
+        if entry.extended_flags != 0 {
+            flags |= INDEX_EXTENDED;
+        }
+        data.extend_from_slice(&flags.to_be_bytes());
+        if version >= 3 && entry.extended_flags != 0 {
+            data.extend_from_slice(&entry.extended_flags.to_be_bytes());
+        }
+        data.extend_from_slice(&entry.path);
+        let padding = 8 - (data.len() - start) % 8;
+        data.resize(data.len() + padding, 0);
+    }
+    let checksum = Sha1::digest(&data);
+    data.extend_from_slice(&checksum);
+
+    let path = repo_path(repo, "index");
+    let lock = repo_path(repo, "index.lock");
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&lock)
+        .map_err(|e| format!("Unable to create '{}': {}", lock.display(), e))?;
+    if let Err(e) = file.write_all(&data) {
+        let _ = fs::remove_file(&lock);
+        return Err(From::from(e));
+    }
+    rename_over(&lock, &path)?;
+
+    Ok(())
+}
+
+// The worktree-relative path named by `arg`, which is relative to the
+// current directory, as `/`-separated bytes.
+fn worktree_path(repo: &GitRepository, arg: &Path) -> Result<Vec<u8>, Box<dyn Error>> {
+    let worktree = fs::canonicalize(&repo.worktree)?;
+    let mut path = PathBuf::new();
+    // Canonical like the worktree, so both carry the same prefix on Windows
+    for component in fs::canonicalize(std::env::current_dir()?)?
+        .join(arg)
+        .components()
+    {
+        match component {
+            std::path::Component::ParentDir => {
+                path.pop();
+            }
+            std::path::Component::CurDir => {}
+            component => path.push(component),
+        }
+    }
+
+    let relative = path.strip_prefix(&worktree).map_err(|_| {
+        format!(
+            "{}: '{}' is outside repository",
+            arg.display(),
+            path.display()
+        )
+    })?;
+    let parts = relative
+        .components()
+        .map(|component| path_bytes(component.as_os_str()))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(parts.join(&b'/'))
+}
+
+// The index mode and blob of a worktree file, writing the blob unless
+// only its id is wanted. Without
+// core.fileMode the executable bit is taken from `old_mode` instead, and
+// without core.symlinks a plain file stays a symlink if it was one.
+fn worktree_blob(
+    repo: &GitRepository,
+    config: &Ini,
+    path: &Path,
+    metadata: &fs::Metadata,
+    old_mode: Option<u32>,
+    write: bool,
+) -> Result<(u32, String), Box<dyn Error>> {
+    if metadata.file_type().is_symlink() {
+        let target = path_bytes(fs::read_link(path)?.as_os_str())?;
+        return Ok((0o120000, worktree_blob_id(repo, target, write)?));
+    }
+
+    if metadata.is_dir() {
+        let head = GitRepository::new(path.to_str().ok_or("path is not valid UTF-8")?, false)
+            .ok()
+            .and_then(|submodule| ref_resolve(&submodule, "HEAD").ok().flatten());
+        return match head {
+            Some(head) => Ok((0o160000, head)),
+            None => Err(From::from("is a directory - add individual files instead")),
+        };
+    }
+
+    let mut mode = 0o100644;
+    if old_mode == Some(0o120000) && !config_get_bool(config, "core.symlinks")?.unwrap_or(true) {
+        mode = 0o120000;
+    } else if config_get_bool(config, "core.filemode")?.unwrap_or(true) {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            if metadata.permissions().mode() & 0o100 != 0 {
+                mode = 0o100755;
+            }
+        }
+    } else if old_mode == Some(0o100755) {
+        mode = 0o100755;
+    }
+
+    Ok((mode, worktree_blob_id(repo, fs::read(path)?, write)?))
+}
+
+fn worktree_blob_id(
+    repo: &GitRepository,
+    data: Vec<u8>,
+    write: bool,
+) -> Result<String, Box<dyn Error>> {
+    match write {
+        true => write_object(repo, &GitObject::new(data, "blob")?),
+        false => Ok(hash_object("blob", &data)),
+    }
+}
+
+#[derive(Default)]
+struct UpdateIndexOptions<'a> {
+    add: bool,
+    remove: bool,
+    refresh: bool,
+    cacheinfo: &'a [String],
+    chmod: Option<&'a str>,
+    skip_worktree: Option<bool>,
+    assume_unchanged: Option<bool>,
+}
+
+fn update_index_path(
+    repo: &GitRepository,
+    config: &Ini,
+    index: &mut GitIndex,
+    options: &UpdateIndexOptions,
+    name: &[u8],
+) -> Result<(), Box<dyn Error>> {
+    let path = String::from_utf8_lossy(name);
+    if options.skip_worktree.is_some() || options.assume_unchanged.is_some() {
+        let entry = index
+            .get_mut(name)
+            .ok_or_else(|| format!("Unable to mark file {}", path))?;
+        if let Some(set) = options.skip_worktree {
+            entry.extended_flags = match set {
+                true => entry.extended_flags | INDEX_SKIP_WORKTREE,
+                false => entry.extended_flags & !INDEX_SKIP_WORKTREE,
+            };
+        }
+        if let Some(set) = options.assume_unchanged {
+            entry.flags = match set {
+                true => entry.flags | INDEX_ASSUME_VALID,
+                false => entry.flags & !INDEX_ASSUME_VALID,
+            };
+        }
+        return Ok(());
+    }
+
+    if symlink_leading_path(repo, name)?.is_some() {
+        eprintln!("error: '{}' is beyond a symbolic link", path);
+        return Err(From::from(format!("Unable to process path {}", path)));
+    }
+    let file = worktree_file(repo, name)?;
+    let Ok(metadata) = fs::symlink_metadata(&file) else {
+        if !options.remove {
+            eprintln!("error: {}: does not exist and --remove not passed", path);
+            return Err(From::from(format!("Unable to process path {}", path)));
+        }
+        index.remove(name);
+        return Ok(());
+    };
+
+    let old_mode = index.find(name).ok().map(|i| index.entries[i].mode);
+    if old_mode.is_none() && !options.add {
+        eprintln!(
+            "error: {}: cannot add to the index - missing --add option?",
+            path
+        );
+        return Err(From::from(format!("Unable to process path {}", path)));
+    }
+
+    let (mode, sha) = match worktree_blob(repo, config, &file, &metadata, old_mode, true) {
+        Ok(blob) => blob,
+        Err(e) => {
+            eprintln!("error: {}: {}", path, e);
+            return Err(From::from(format!("Unable to process path {}", path)));
+        }
+    };
+    let mut entry = IndexEntry {
+        mode,
+        sha,
+        path: name.to_vec(),
+        ..Default::default()
+    };
+    entry.set_stat(&metadata);
+    if let Err(e) = index.insert(entry) {
+        eprintln!("error: {}", e);
+        return Err(From::from(format!("Unable to process path {}", path)));
+    }
+
+    if let Some(chmod) = options.chmod {
+        let entry = index.get_mut(name).ok_or("entry vanished")?;
+        if entry.mode & 0o170000 != 0o100000 {
+            return Err(From::from(format!(
+                "git update-index: cannot chmod {} '{}'",
+                chmod, path
+            )));
+        }
+        entry.mode = match chmod {
+            "+x" => 0o100755,
+            _ => 0o100644,
+        };
+    }
+
+    Ok(())
+}
+
+// Bring the stat data of unchanged files up to date, listing the files
+// whose content differs from the index.
+fn update_index_refresh(
+    repo: &GitRepository,
+    config: &Ini,
+    index: &mut GitIndex,
+) -> Result<bool, Box<dyn Error>> {
+    let mut clean = true;
+    for entry in &mut index.entries {
+        let path = String::from_utf8_lossy(&entry.path).into_owned();
+        if entry.stage() != 0 {
+            println!("{}: needs merge", path);
+            clean = false;
+            continue;
+        }
+        if entry.flags & INDEX_ASSUME_VALID != 0 || entry.extended_flags & INDEX_SKIP_WORKTREE != 0
+        {
+            continue;
+        }
+
+        let file = worktree_file(repo, &entry.path)?;
+        let metadata = match fs::symlink_metadata(&file) {
+            Ok(metadata) if !entry.stat_matches(&metadata) => metadata,
+            Ok(_) => continue,
+            Err(_) => {
+                println!("{}: needs update", path);
+                clean = false;
+                continue;
+            }
+        };
+        match worktree_blob(repo, config, &file, &metadata, Some(entry.mode), true) {
+            Ok((mode, sha)) if mode == entry.mode && sha == entry.sha => entry.set_stat(&metadata),
+            _ => {
+                println!("{}: needs update", path);
+                clean = false;
+            }
+        }
+    }
+
+    Ok(clean)
+}
+
+fn update_index(
+    repo: &GitRepository,
+    options: &UpdateIndexOptions,
+    paths: &[PathBuf],
+) -> Result<(), Box<dyn Error>> {
+    if options.refresh || !paths.is_empty() {
+        repo_require_worktree(repo)?;
+    }
+    let config = repo_config(repo)?;
+    let mut index = index_read(repo)?;
+
+    let mut clean = true;
+    if options.refresh {
+        clean = update_index_refresh(repo, &config, &mut index)?;
+    }
+
+    for cacheinfo in options.cacheinfo {
+        let mut parts = cacheinfo.splitn(3, ',');
+        let (Some(mode), Some(sha), Some(path)) = (parts.next(), parts.next(), parts.next()) else {
+            return Err(From::from(
+                "option 'cacheinfo' expects <mode>,<sha1>,<path>",
+            ));
+        };
+        let mode = u32::from_str_radix(mode, 8)
+            .map_err(|_| "option 'cacheinfo' expects <mode>,<sha1>,<path>")?;
+        if sha.len() != 40 || !sha.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(From::from(
+                "option 'cacheinfo' expects <mode>,<sha1>,<path>",
+            ));
+        }
+
+        let entry = IndexEntry {
+            mode,
+            sha: sha.to_ascii_lowercase(),
+            path: path.as_bytes().to_vec(),
+            ..Default::default()
+        };
+        let added = match index.find(path.as_bytes()).is_ok() || options.add {
+            true => index.insert(entry),
+            false => Err(From::from(format!(
+                "{}: cannot add to the index - missing --add option?",
+                path
+            ))),
+        };
+        if let Err(e) = added {
+            eprintln!("error: {}", e);
+            return Err(From::from(format!(
+                "git update-index: --cacheinfo cannot add {}",
+                path
+            )));
+        }
+    }
+
+    let ignore_case = config_get_bool(&config, "core.ignorecase")?.unwrap_or(false);
+    for path in paths {
+        let path = index.tracked_path(&worktree_path(repo, path)?, ignore_case);
+        update_index_path(repo, &config, &mut index, options, &path)?;
+    }
+
+    index_write(repo, &index)?;
+    if !clean {
+        process::exit(1);
+    }
+
+    Ok(())
+}
+
+// Write the blob of an index entry to `file`, as an executable, a symlink
+// or, for a submodule, an empty directory. Without core.symlinks a symlink
+// becomes a plain file holding its target.
+fn checkout_entry(
+    repo: &GitRepository,
+    entry: &IndexEntry,
+    file: &Path,
+    symlinks: bool,
+) -> Result<(), Box<dyn Error>> {
+    if let Some(parent) = file.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    if entry.mode == 0o160000 {
+        return Ok(fs::create_dir_all(file)?);
+    }
+
+    let GitObject::Blob(blob) = read_object(repo, &entry.sha)? else {
+        return Err(From::from(format!(
+            "unable to read sha1 file of {} ({})",
+            String::from_utf8_lossy(&entry.path),
+            entry.sha
+        )));
+    };
+    let data = blob.serialize();
+
+    if entry.mode == 0o120000 && symlinks {
+        let target = bytes_path(data)?;
+        #[cfg(unix)]
+        return Ok(std::os::unix::fs::symlink(target, file)?);
+        // Windows needs to know what kind of entry the link points to
+        #[cfg(windows)]
+        return Ok(match file.with_file_name(&target).is_dir() {
+            true => std::os::windows::fs::symlink_dir(&target, file)?,
+            false => std::os::windows::fs::symlink_file(&target, file)?,
+        });
+    }
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(match entry.mode {
+            0o100755 => 0o777,
+            _ => 0o666,
+        });
+    }
+    options.open(file)?.write_all(data)?;
+
+    Ok(())
+}
+
+// Create the directories an entry at `path` goes in. Those the prefix
+// names are taken as they are; below it, a symlink or file in the way
+// is replaced with `force` and is an error otherwise, so nothing is
+// written through a link to outside the worktree.
+fn checkout_leading_dirs(
+    repo: &GitRepository,
+    path: &[u8],
+    prefix_len: usize,
+    force: bool,
+) -> Result<(), Box<dyn Error>> {
+    if let Some(slash) = path[..prefix_len].iter().rposition(|&b| b == b'/') {
+        fs::create_dir_all(worktree_file(repo, &path[..slash])?)?;
+    }
+
+    for (i, _) in path
+        .iter()
+        .enumerate()
+        .skip(prefix_len)
+        .filter(|(_, &b)| b == b'/')
+    {
+        let dir = worktree_file(repo, &path[..i])?;
+        match fs::symlink_metadata(&dir) {
+            Ok(metadata) if metadata.is_dir() => continue,
+            Ok(_) if force => fs::remove_file(&dir)?,
+            Ok(_) => {
+                return Err(From::from(format!(
+                    "cannot create directory at '{}': File exists",
+                    String::from_utf8_lossy(&path[..i])
+                )))
+            }
+            Err(_) => {}
+        }
+        fs::create_dir(&dir)?;
+    }
+
+    Ok(())
+}
+
+struct CheckoutIndexOptions<'a> {
+    all: bool,
+    force: bool,
+    update: bool,
+    prefix: &'a str,
+}
+
+fn checkout_index(
+    repo: &GitRepository,
+    options: &CheckoutIndexOptions,
+    paths: &[PathBuf],
+) -> Result<(), Box<dyn Error>> {
+    repo_require_worktree(repo)?;
+    let config = repo_config(repo)?;
+    let symlinks = config_get_bool(&config, "core.symlinks")?.unwrap_or(true);
+    let mut index = index_read(repo)?;
+
+    let mut selected = Vec::new();
+    let mut failed = false;
+    if options.all {
+        selected.extend(
+            (0..index.entries.len())
+                .filter(|&i| index.entries[i].extended_flags & INDEX_SKIP_WORKTREE == 0),
+        );
+    }
+    let ignore_case = config_get_bool(&config, "core.ignorecase")?.unwrap_or(false);
+    for path in paths {
+        let path = index.tracked_path(&worktree_path(repo, path)?, ignore_case);
+        match index.find(&path) {
+            Ok(i) => selected.push(i),
+            Err(_) => {
+                eprintln!(
+                    "git checkout-index: {} is not in the cache",
+                    String::from_utf8_lossy(&path)
+                );
+                failed = true;
+            }
+        }
+    }
+
+    for i in selected {
+        let entry = &index.entries[i];
+        if entry.stage() != 0 {
+            continue;
+        }
+        let path = String::from_utf8_lossy(&entry.path).into_owned();
+        if !verify_path(&entry.path) {
+            eprintln!("error: Invalid path '{}'", path);
+            failed = true;
+            continue;
+        }
+        let full = [options.prefix.as_bytes(), &entry.path].concat();
+        let file = worktree_file(repo, &full)?;
+        if let Err(e) =
+            checkout_leading_dirs(repo, &full, full.len() - entry.path.len(), options.force)
+        {
+            eprintln!("error: {}", e);
+            failed = true;
+            continue;
+        }
+
+        if let Ok(metadata) = fs::symlink_metadata(&file) {
+            if options.prefix.is_empty() && entry.stat_matches(&metadata) {
+                continue;
+            }
+            if !options.force {
+                eprintln!("{} already exists, no checkout", path);
+                failed = true;
+                continue;
+            }
+            match metadata.is_dir() {
+                true => fs::remove_dir_all(&file)?,
+                false => fs::remove_file(&file)?,
+            }
+        }
+
+        if let Err(e) = checkout_entry(repo, entry, &file, symlinks) {
+            eprintln!("error: {}", e);
+            failed = true;
+            continue;
+        }
+        if options.update && options.prefix.is_empty() {
+            let metadata = fs::symlink_metadata(&file)?;
+            index.entries[i].set_stat(&metadata);
+        }
+    }
+
+    if options.update {
+        index_write(repo, &index)?;
+    }
+    if failed {
+        process::exit(1);
+    }
+
+    Ok(())
+}
+
+// An ignore pattern from a file or the command line. Those read from a
+// `.gitignore` only apply below its directory, `base`.
+struct ExcludePattern {
+    pattern: Vec<u8>,
+    base: Vec<u8>,
+    negated: bool,
+    dir_only: bool,
+    // A slash anywhere but the end ties the pattern to `base`; otherwise
+    // it matches a name at any depth
+    anchored: bool,
+}
+
+impl ExcludePattern {
+    fn parse(line: &[u8], base: &[u8]) -> Option<ExcludePattern> {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.is_empty() || line[0] == b'#' {
+            return None;
+        }
+        // Trailing spaces are dropped unless escaped
+        let mut end = line.len();
+        while end > 0 && line[end - 1] == b' ' && (end < 2 || line[end - 2] != b'\\') {
+            end -= 1;
+        }
+
+        let mut pattern = &line[..end];
+        let negated = pattern.first() == Some(&b'!');
+        if negated {
+            pattern = &pattern[1..];
+        }
+        let dir_only = pattern.last() == Some(&b'/');
+        if dir_only {
+            pattern = &pattern[..pattern.len() - 1];
+        }
+        let anchored = pattern.contains(&b'/');
+        let pattern = pattern.strip_prefix(b"/").unwrap_or(pattern);
+        if pattern.is_empty() {
+            return None;
+        }
+
+        Some(ExcludePattern {
+            pattern: pattern.to_vec(),
+            base: base.to_vec(),
+            negated,
+            dir_only,
+            anchored,
+        })
+    }
+
+    fn matches(&self, path: &[u8], is_dir: bool, ignore_case: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        let Some(rest) = path.strip_prefix(self.base.as_slice()) else {
+            return false;
+        };
+        match self.anchored {
+            true => wildmatch(&self.pattern, rest, ignore_case, true),
+            false => {
+                let name = rest.rsplit(|&b| b == b'/').next().unwrap_or(rest);
+                wildmatch(&self.pattern, name, ignore_case, true)
+            }
+        }
+    }
+}
+
+fn exclude_parse(text: &[u8], base: &[u8]) -> Vec<ExcludePattern> {
+    text.split(|&b| b == b'\n')
+        .filter_map(|line| ExcludePattern::parse(line, base))
+        .collect()
+}
+
+// Within one list the last matching pattern decides
+fn exclude_match(
+    patterns: &[ExcludePattern],
+    path: &[u8],
+    is_dir: bool,
+    ignore_case: bool,
+) -> Option<bool> {
+    patterns
+        .iter()
+        .rev()
+        .find(|pattern| pattern.matches(path, is_dir, ignore_case))
+        .map(|pattern| !pattern.negated)
+}
+
+// Glob matching as gitignore does it: `*`, `?` and `[...]` stop at `/`,
+// while `**` between slashes spans whole directories. Unless `pathname`
+// is set, as for pathspecs, `/` is matched like any other character.
+fn wildmatch(pattern: &[u8], text: &[u8], ignore_case: bool, pathname: bool) -> bool {
+    let separator = |c: u8| pathname && c == b'/';
+    let same = |a: u8, b: u8| a == b || (ignore_case && a.eq_ignore_ascii_case(&b));
+    let (mut p, mut t) = (0, 0);
+    while p < pattern.len() {
+        match pattern[p] {
+            b'?' => {
+                if t == text.len() || separator(text[t]) {
+                    return false;
+                }
+                t += 1;
+                p += 1;
+            }
+            b'*' => {
+                let mut end = p;
+                while end < pattern.len() && pattern[end] == b'*' {
+                    end += 1;
+                }
+                let bounded = (p == 0 || pattern[p - 1] == b'/')
+                    && (end == pattern.len() || pattern[end] == b'/');
+                if end - p > 1 && bounded && pathname {
+                    if end == pattern.len() {
+                        return true;
+                    }
+                    // `**/` matches no directories or any number of them
+                    let rest = &pattern[end + 1..];
+                    return wildmatch(rest, &text[t..], ignore_case, pathname)
+                        || (t..text.len()).any(|i| {
+                            text[i] == b'/'
+                                && wildmatch(rest, &text[i + 1..], ignore_case, pathname)
+                        });
+                }
+
+                let rest = &pattern[end..];
+                let mut i = t;
+                loop {
+                    if wildmatch(rest, &text[i..], ignore_case, pathname) {
+                        return true;
+                    }
+                    if i == text.len() || separator(text[i]) {
+                        return false;
+                    }
+                    i += 1;
+                }
+            }
+            b'[' => {
+                if t == text.len() || separator(text[t]) {
+                    return false;
+                }
+                match wildmatch_class(&pattern[p + 1..], text[t], ignore_case) {
+                    Some((true, len)) => {
+                        t += 1;
+                        p += len + 1;
+                    }
+                    _ => return false,
+                }
+            }
+            b'\\' if p + 1 < pattern.len() => {
+                if t == text.len() || !same(pattern[p + 1], text[t]) {
+                    return false;
+                }
+                t += 1;
+                p += 2;
+            }
+            c => {
+                if t == text.len() || !same(c, text[t]) {
+                    return false;
+                }
+                t += 1;
+                p += 1;
+            }
+        }
+    }
+    t == text.len()
+}
+
+// Whether `c` is in the bracket expression that `class` starts just
+// after the `[`, and how long the expression is including its `]`. An
+// unterminated one matches nothing.
+fn wildmatch_class(class: &[u8], c: u8, ignore_case: bool) -> Option<(bool, usize)> {
+    let negated = matches!(class.first(), Some(b'!' | b'^'));
+    let mut i = negated as usize;
+    let mut found = false;
+    let mut first = true;
+
+    loop {
+        let mut low = *class.get(i)?;
+        if low == b']' && !first {
+            return Some((found != negated, i + 1));
+        }
+        first = false;
+
+        if low == b'[' && class.get(i + 1) == Some(&b':') {
+            let name_end = class[i + 2..].windows(2).position(|w| w == b":]")?;
+            let name = &class[i + 2..i + 2 + name_end];
+            found |= match name {
+                b"alnum" => c.is_ascii_alphanumeric(),
+                b"alpha" => c.is_ascii_alphabetic(),
+                b"blank" => c == b' ' || c == b'\t',
+                b"cntrl" => c.is_ascii_control(),
+                b"digit" => c.is_ascii_digit(),
+                b"graph" => c.is_ascii_graphic(),
+                b"lower" => c.is_ascii_lowercase() || (ignore_case && c.is_ascii_uppercase()),
+                b"print" => c.is_ascii_graphic() || c == b' ',
+                b"punct" => c.is_ascii_punctuation(),
+                b"space" => c.is_ascii_whitespace() || c == 0x0b,
+                b"upper" => c.is_ascii_uppercase() || (ignore_case && c.is_ascii_lowercase()),
+                b"xdigit" => c.is_ascii_hexdigit(),
+                _ => return None,
+            };
+            i += name_end + 4;
+            continue;
+        }
+
+        if low == b'\\' {
+            i += 1;
+            low = *class.get(i)?;
+        }
+        i += 1;
+        let mut high = low;
+        if class.get(i) == Some(&b'-') && class.get(i + 1).is_some_and(|&b| b != b']') {
+            high = class[i + 1];
+            if high == b'\\' {
+                high = *class.get(i + 2)?;
+                i += 1;
+            }
+            i += 2;
+        }
+        found |= [c, c.to_ascii_lowercase(), c.to_ascii_uppercase()]
+            .iter()
+            .any(|b| (*b == c || ignore_case) && (low..=high).contains(b));
+    }
+}
+
+// Where ignore patterns come from, most important first: `--exclude`,
+// then with --exclude-standard each `.gitignore` from the deepest
+// directory up, info/exclude and core.excludesFile.
+struct Excludes {
+    command_line: Vec<ExcludePattern>,
+    // Per-directory patterns by directory, read as the walk reaches them
+    per_directory: Option<HashMap<Vec<u8>, Vec<ExcludePattern>>>,
+    files: Vec<Vec<ExcludePattern>>,
+    ignore_case: bool,
+}
+
+impl Excludes {
+    fn load(
+        repo: &GitRepository,
+        config: &Ini,
+        patterns: &[String],
+        standard: bool,
+    ) -> Result<Excludes, Box<dyn Error>> {
+        let mut excludes = Excludes {
+            command_line: patterns
+                .iter()
+                .filter_map(|pattern| ExcludePattern::parse(pattern.as_bytes(), b""))
+                .collect(),
+            per_directory: None,
+            files: Vec::new(),
+            ignore_case: config_get_bool(config, "core.ignorecase")?.unwrap_or(false),
+        };
+        if !standard {
+            return Ok(excludes);
+        }
+
+        excludes.per_directory = Some(HashMap::new());
+        let excludes_file = config_user_file(config, "core.excludesFile", "ignore");
+        for file in [Some(repo_path(repo, "info/exclude")), excludes_file]
+            .into_iter()
+            .flatten()
+        {
+            if let Ok(text) = fs::read(file) {
+                excludes.files.push(exclude_parse(&text, b""));
+            }
+        }
+        Ok(excludes)
+    }
+
+    // Whether `path` itself is ignored, the directories it is in aside
+    fn matches(
+        &mut self,
+        repo: &GitRepository,
+        path: &[u8],
+        is_dir: bool,
+    ) -> Result<bool, Box<dyn Error>> {
+        let ignore_case = self.ignore_case;
+        if let Some(ignored) = exclude_match(&self.command_line, path, is_dir, ignore_case) {
+            return Ok(ignored);
+        }
+
+        if let Some(per_directory) = &mut self.per_directory {
+            let bases = path
+                .iter()
+                .enumerate()
+                .filter(|(_, &b)| b == b'/')
+                .map(|(i, _)| i + 1)
+                .rev()
+                .chain([0]);
+            for end in bases {
+                let base = &path[..end];
+                if !per_directory.contains_key(base) {
+                    let file = worktree_file(repo, base)?.join(".gitignore");
+                    let patterns = match fs::read(file) {
+                        Ok(text) => exclude_parse(&text, base),
+                        Err(_) => Vec::new(),
+                    };
+                    per_directory.insert(base.to_vec(), patterns);
+                }
+                if let Some(ignored) =
+                    exclude_match(&per_directory[base], path, is_dir, ignore_case)
+                {
+                    return Ok(ignored);
+                }
+            }
+        }
+
+        Ok(self
+            .files
+            .iter()
+            .find_map(|patterns| exclude_match(patterns, path, is_dir, ignore_case))
+            .unwrap_or(false))
+    }
+
+    // Whether `path` is ignored, by itself or by being in an ignored
+    // directory: no pattern can bring back what one of those hides
+    fn excluded(
+        &mut self,
+        repo: &GitRepository,
+        path: &[u8],
+        is_dir: bool,
+    ) -> Result<bool, Box<dyn Error>> {
+        for (i, _) in path.iter().enumerate().filter(|(_, &b)| b == b'/') {
+            if self.matches(repo, &path[..i], true)? {
+                return Ok(true);
+            }
+        }
+        self.matches(repo, path, is_dir)
+    }
+}
+
+#[derive(Clone, PartialEq)]
+enum AttrValue {
+    Set,
+    Unset,
+    Value(String),
+}
+
+// What the last line of a gitattributes file matching `path` says about
+// attribute `name`: nothing, or the value it gives, where `!name` makes
+// it unspecified again. Patterns follow gitignore, without negation.
+fn attr_lookup(
+    text: &[u8],
+    base: &[u8],
+    path: &[u8],
+    name: &str,
+    ignore_case: bool,
+) -> Option<Option<AttrValue>> {
+    let mut found = None;
+    for line in text.split(|&b| b == b'\n') {
+        let mut tokens = line
+            .split(|b| b.is_ascii_whitespace())
+            .filter(|token| !token.is_empty());
+        let Some(pattern) = tokens.next() else {
+            continue;
+        };
+        if pattern.starts_with(b"[attr]") {
+            continue;
+        }
+        match ExcludePattern::parse(pattern, base) {
+            Some(pattern) if !pattern.negated && pattern.matches(path, false, ignore_case) => {}
+            _ => continue,
+        }
+
+        for token in tokens {
+            let token = String::from_utf8_lossy(token);
+            let value = match token.split_once('=') {
+                Some((key, value)) if key == name => Some(AttrValue::Value(value.to_string())),
+                Some(_) => continue,
+                None if token == name => Some(AttrValue::Set),
+                None if token.strip_prefix('-') == Some(name) => Some(AttrValue::Unset),
+                None if token.strip_prefix('!') == Some(name) => None,
+                // The one built-in macro
+                None if token == "binary" && matches!(name, "diff" | "merge" | "text") => {
+                    Some(AttrValue::Unset)
+                }
+                None => continue,
+            };
+            found = Some(value);
+        }
+    }
+    found
+}
+
+// Attribute `name` of `path`. info/attributes wins over `.gitattributes`
+// files, the deepest first, which win over core.attributesFile.
+fn attr_get(
+    repo: &GitRepository,
+    config: &Ini,
+    path: &[u8],
+    name: &str,
+) -> Result<Option<AttrValue>, Box<dyn Error>> {
+    let mut files = vec![(repo_path(repo, "info/attributes"), &path[..0])];
+    if repo.worktree != repo.gitdir {
+        let bases = path
+            .iter()
+            .enumerate()
+            .filter(|(_, &b)| b == b'/')
+            .map(|(i, _)| i + 1)
+            .rev()
+            .chain([0]);
+        for end in bases {
+            let base = &path[..end];
+            files.push((worktree_file(repo, base)?.join(".gitattributes"), base));
+        }
+    }
+    files.extend(
+        config_user_file(config, "core.attributesFile", "attributes")
+            .map(|file| (file, &path[..0])),
+    );
+
+    let ignore_case = config_get_bool(config, "core.ignorecase")?.unwrap_or(false);
+    for (file, base) in files {
+        let Ok(text) = fs::read(file) else {
+            continue;
+        };
+        if let Some(value) = attr_lookup(&text, base, path, name, ignore_case) {
+            return Ok(value);
+        }
+    }
+    Ok(None)
+}
+
+// A file the worktree walk found, or another repository, which the
+// walk reports but does not enter
+struct WorktreeEntry {
+    path: Vec<u8>,
+    repository: bool,
+}
+
+// The names in a directory, with whether each is a directory and
+// whether that directory holds a `.git`
+type DirListing = Vec<(std::ffi::OsString, bool, bool)>;
+
+// Walks the worktree for the files the index does not track, for every
+// command that needs to list them. Directories are read a level at a
+// time from a queue rather than by recursion, each level in parallel
+// when `threads` is above one. Symlinks are reported, never followed,
+// and `.git` is skipped. An ignored directory is pruned whole, unless
+// the ignored files are what is wanted; then it is walked for them.
+struct WorktreeWalk<'a> {
+    repo: &'a GitRepository,
+    index: &'a GitIndex,
+    // Find the ignored files instead of the untracked ones
+    ignored: bool,
+    threads: usize,
+}
+
+impl<'a> WorktreeWalk<'a> {
+    fn new(repo: &'a GitRepository, index: &'a GitIndex) -> Self {
+        WorktreeWalk {
+            repo,
+            index,
+            ignored: false,
+            threads: 1,
+        }
+    }
+
+    // The entries below `dir`, in path order
+    fn walk(
+        &self,
+        excludes: &mut Excludes,
+        dir: &[u8],
+    ) -> Result<Vec<WorktreeEntry>, Box<dyn Error>> {
+        let mut found = Vec::new();
+        // Directories still to read, and whether they are ignored
+        let mut pending = vec![(dir.to_vec(), false)];
+        while !pending.is_empty() {
+            let listings = self.read_dirs(&pending)?;
+            let mut next = Vec::new();
+            for ((dir, dir_ignored), listing) in pending.into_iter().zip(listings) {
+                for (name, is_dir, has_git) in listing {
+                    let name = path_bytes(&name)?;
+                    if name.eq_ignore_ascii_case(b".git") {
+                        continue;
+                    }
+                    let path = match dir.is_empty() {
+                        true => name,
+                        false => [&dir[..], b"/", &name].concat(),
+                    };
+                    if self.index.contains(&path) {
+                        continue;
+                    }
+
+                    let ignored = dir_ignored || excludes.matches(self.repo, &path, is_dir)?;
+                    if !is_dir || (has_git && !self.has_tracked(&path)) {
+                        if ignored == self.ignored {
+                            found.push(WorktreeEntry {
+                                path,
+                                repository: is_dir,
+                            });
+                        }
+                    } else if !ignored || self.ignored {
+                        next.push((path, ignored));
+                    }
+                }
+            }
+            pending = next;
+        }
+
+        found.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(found)
+    }
+
+    fn has_tracked(&self, dir: &[u8]) -> bool {
+        let prefix = [dir, b"/"].concat();
+        let i = self
+            .index
+            .entries
+            .partition_point(|entry| entry.path < prefix);
+        self.index
+            .entries
+            .get(i)
+            .is_some_and(|entry| entry.path.starts_with(&prefix))
+    }
+
+    // A directory that cannot be read is taken as empty
+    fn read_dirs(&self, dirs: &[(Vec<u8>, bool)]) -> Result<Vec<DirListing>, Box<dyn Error>> {
+        let read = |dir: &PathBuf| -> io::Result<DirListing> {
+            let Ok(entries) = fs::read_dir(dir) else {
+                return Ok(Vec::new());
+            };
+            let mut listing = Vec::new();
+            for entry in entries {
+                let entry = entry?;
+                let is_dir = entry.file_type()?.is_dir();
+                let has_git = is_dir && entry.path().join(".git").exists();
+                listing.push((entry.file_name(), is_dir, has_git));
+            }
+            Ok(listing)
+        };
+
+        let files: Vec<PathBuf> = dirs
+            .iter()
+            .map(|(dir, _)| worktree_file(self.repo, dir))
+            .collect::<Result<_, _>>()?;
+        let threads = self.threads.clamp(1, files.len().max(1));
+        if threads == 1 {
+            return Ok(files.iter().map(read).collect::<Result<_, _>>()?);
+        }
+        let chunk_size = files.len().div_ceil(threads);
+        let listings = thread::scope(|scope| {
+            let handles: Vec<_> = files
+                .chunks(chunk_size)
+                .map(|chunk| scope.spawn(|| chunk.iter().map(read).collect::<Vec<_>>()))
+                .collect();
+            handles
+                .into_iter()
+                .flat_map(|handle| handle.join().expect("worktree walk thread panicked"))
+                .collect::<io::Result<Vec<_>>>()
+        })?;
+        Ok(listings)
+    }
+}
+
+// Whether the worktree file of `entry` differs from the index. Only its
+// stat data is looked at when that still matches.
+fn index_entry_modified(
+    repo: &GitRepository,
+    config: &Ini,
+    entry: &IndexEntry,
+    file: &Path,
+    metadata: &fs::Metadata,
+) -> bool {
+    if entry.flags & INDEX_ASSUME_VALID != 0 || entry.stat_matches(metadata) {
+        return false;
+    }
+    match worktree_blob(repo, config, file, metadata, Some(entry.mode), false) {
+        Ok((mode, sha)) => mode != entry.mode || sha != entry.sha,
+        Err(_) => true,
+    }
+}
+
+// `path` as seen from the worktree directory `dir`
+fn relative_path(path: &[u8], dir: &[u8]) -> Vec<u8> {
+    let mut dir = dir;
+    let mut relative = Vec::new();
+    while !dir.is_empty() {
+        if path.starts_with(dir) && path.get(dir.len()) == Some(&b'/') {
+            relative.extend_from_slice(&path[dir.len() + 1..]);
+            return relative;
+        }
+        relative.extend_from_slice(b"../");
+        dir = &dir[..dir.iter().rposition(|&b| b == b'/').unwrap_or(0)];
+    }
+    relative.extend_from_slice(path);
+    relative
+}
+
+#[derive(Default)]
+struct LsFilesOptions<'a> {
+    cached: bool,
+    deleted: bool,
+    modified: bool,
+    others: bool,
+    ignored: bool,
+    null: bool,
+    exclude: &'a [String],
+    exclude_standard: bool,
+    error_unmatch: bool,
+}
+
+// List the files of the index or worktree as selected by `options`, for
+// a current directory `prefix` from the top of the worktree. What is
+// returned is which of `specs` matched nothing.
+fn ls_files(
+    repo: &GitRepository,
+    options: &LsFilesOptions,
+    prefix: &[u8],
+    specs: &Pathspec,
+    out: &mut impl Write,
+) -> Result<Vec<usize>, Box<dyn Error>> {
+    if options.ignored && !options.others && !options.cached {
+        return Err(From::from("ls-files -i must be used with either -o or -c"));
+    }
+    if options.ignored && options.exclude.is_empty() && !options.exclude_standard {
+        return Err(From::from("ls-files --ignored needs some exclude pattern"));
+    }
+    let cached = options.cached || !(options.deleted || options.modified || options.others);
+    if options.others || options.deleted || options.modified || options.ignored {
+        repo_require_worktree(repo)?;
+    }
+
+    let config = repo_config(repo)?;
+    let index = index_read(repo)?;
+    let mut excludes = Excludes::load(
+        repo,
+        &config_all(repo)?,
+        options.exclude,
+        options.exclude_standard,
+    )?;
+
+    // Files of the current directory are the ones listed when no paths
+    // are named. Exclusions are never reported as unmatched
+    let mut matched: Vec<bool> = specs.items.iter().map(|item| item.exclude).collect();
+    let mut selected = |path: &[u8]| {
+        if specs.excluded(path) {
+            return false;
+        }
+        // Only exclusions take them out of the current directory
+        if !specs.has_positive() {
+            return path.starts_with(prefix)
+                && (prefix.is_empty() || path.get(prefix.len()) == Some(&b'/'));
+        }
+        let mut any = false;
+        for (item, matched) in specs.items.iter().zip(matched.iter_mut()) {
+            if !item.exclude && item.matches(path) {
+                *matched = true;
+                any = true;
+            }
+        }
+        any
+    };
+
+    let quote_high = config_get_bool(&config, "core.quotepath")?.unwrap_or(true);
+    let mut show = |path: &[u8], dir: bool| -> io::Result<()> {
+        let mut shown = relative_path(path, prefix);
+        if dir {
+            shown.push(b'/');
+        }
+        match options.null {
+            true => {
+                out.write_all(&shown)?;
+                out.write_all(b"\0")
+            }
+            false => {
+                out.write_all(&quote_path(&shown, quote_high))?;
+                out.write_all(b"\n")
+            }
+        }
+    };
+
+    if options.others {
+        let mut walk = WorktreeWalk::new(repo, &index);
+        walk.ignored = options.ignored;
+        walk.threads = thread::available_parallelism().map_or(1, usize::from);
+        for entry in walk.walk(&mut excludes, b"")? {
+            if selected(&entry.path) {
+                show(&entry.path, entry.repository)?;
+            }
+        }
+    }
+
+    if cached || options.deleted || options.modified {
+        for entry in &index.entries {
+            if !selected(&entry.path)
+                || (options.ignored && !excludes.excluded(repo, &entry.path, false)?)
+            {
+                continue;
+            }
+            if cached {
+                show(&entry.path, false)?;
+            }
+            if !(options.deleted || options.modified)
+                || entry.extended_flags & INDEX_SKIP_WORKTREE != 0
+            {
+                continue;
+            }
+
+            let file = worktree_file(repo, &entry.path)?;
+            match fs::symlink_metadata(&file) {
+                Ok(metadata) => {
+                    if options.modified
+                        && index_entry_modified(repo, &config, entry, &file, &metadata)
+                    {
+                        show(&entry.path, false)?;
+                    }
+                }
+                Err(_) => {
+                    if options.deleted {
+                        show(&entry.path, false)?;
+                    }
+                    if options.modified {
+                        show(&entry.path, false)?;
+                    }
+                }
+            }
+        }
+    }
+    out.flush()?;
+
+    Ok((0..matched.len()).filter(|&i| !matched[i]).collect())
+}
+
+// The notes ref to use: `--ref`, then GIT_NOTES_REF, then core.notesRef.
+// Short names are taken to live under refs/notes/.
+fn notes_ref_name(config: &Ini, name: Option<&str>) -> String {
+    let name = name
+        .map(ToString::to_string)
+        .or_else(|| std::env::var("GIT_NOTES_REF").ok())
+        .or_else(|| config_get(config, "core.notesRef").map(ToString::to_string))
+        .unwrap_or_else(|| "refs/notes/commits".to_string());
+
+    if name.starts_with("refs/") {
+        name
+    } else if name.starts_with("notes/") {
+        format!("refs/{}", name)
+    } else {
+        format!("refs/notes/{}", name)
+    }
+}
+
+// Collect notes from a notes tree, remembering where each one lives so
+// the fanout subtrees git uses for large notes trees survive edits.
+fn notes_read_tree(
+    repo: &GitRepository,
+    tree: &str,
+    prefix: &str,
+    state: &mut NotesState,
+) -> Result<(), Box<dyn Error>> {
+    let GitObject::Tree(data) = read_object(repo, tree)? else {
+        return Err(From::from(format!("{} is not a tree", tree)));
+    };
+    for leaf in parse_tree(data.serialize())? {
+        let name = String::from_utf8_lossy(&leaf.path);
+        let object = format!("{}{}", prefix, name);
+        let path = match prefix.is_empty() {
+            true => name.to_string(),
+            false => format!(
+                "{}/{}",
+                prefix
+                    .as_bytes()
+                    .chunks(2)
+                    .map(String::from_utf8_lossy)
+                    .collect::<Vec<_>>()
+                    .join("/"),
+                name
+            ),
+        };
+        if leaf.mode == "40000" {
+            notes_read_tree(repo, &leaf.sha, &object, state)?;
+        } else if object.len() == 40 && object.bytes().all(|b| b.is_ascii_hexdigit()) {
+            state.notes.insert(object.clone(), leaf.sha);
+            state.paths.insert(object, path.into_bytes());
+        }
+    }
+
+    Ok(())
+}
+
+// The current notes commit, if any, the notes it holds and where they
+// are in its tree.
+struct NotesState {
+    commit: Option<String>,
+    tree: Option<String>,
+    notes: BTreeMap<String, String>,
+    paths: HashMap<String, Vec<u8>>,
+}
+
+fn notes_load(repo: &GitRepository, notes_ref: &str) -> Result<NotesState, Box<dyn Error>> {
+    let mut state = NotesState {
+        commit: ref_resolve(repo, notes_ref)?,
+        tree: None,
+        notes: BTreeMap::new(),
+        paths: HashMap::new(),
+    };
+    if let Some(commit) = &state.commit {
+        state.tree = kvlm_shas(&commit_kvlm(repo, commit)?, "tree")?
+            .into_iter()
+            .next();
+    }
+    if let Some(tree) = state.tree.clone() {
+        notes_read_tree(repo, &tree, "", &mut state)?;
+    }
+
+    Ok(state)
+}
+
+// Record the edited notes as a new commit on the notes ref. Notes keep
+// their place in the tree; new ones are added at the top level.
+fn notes_save(
+    repo: &GitRepository,
+    config: &Ini,
+    notes_ref: &str,
+    state: &NotesState,
+    message: &str,
+) -> Result<(), Box<dyn Error>> {
+    let mut builder = match &state.tree {
+        Some(tree) => TreeBuilder::load(repo, tree)?,
+        None => TreeBuilder::new(repo),
+    };
+    for (object, path) in &state.paths {
+        if !state.notes.contains_key(object) {
+            builder.remove(path)?;
+        }
+    }
+    for (object, blob) in &state.notes {
+        let path = state
+            .paths
+            .get(object)
+            .cloned()
+            .unwrap_or_else(|| object.as_bytes().to_vec());
+        builder.insert(&path, "100644", blob)?;
+    }
+    let tree = builder.write()?;
+
+    let parents: Vec<String> = state.commit.iter().cloned().collect();
+    let commit = commit_create(repo, config, &tree, &parents, message)?;
+    let old = state.commit.clone().unwrap_or_else(|| NULL_SHA.to_string());
+    ref_transaction(repo, &[(notes_ref, &old, &commit)])
+}
+
+fn notes_blob_text(repo: &GitRepository, blob: &str) -> Result<String, Box<dyn Error>> {
+    Ok(String::from_utf8_lossy(read_object(repo, blob)?.serialize()?).into_owned())
+}
+
+// Have the user write a note in the editor, starting from `initial`,
+// with a comment naming the object.
+fn notes_edit_message(
+    repo: &GitRepository,
+    config: &Ini,
+    object: &str,
+    initial: &str,
+) -> Result<String, Box<dyn Error>> {
+    let path = repo_file(repo, "NOTES_EDITMSG");
+    let template = format!(
+        "{}\n#\n# Write/edit the notes for the following object:\n#\n# {}\n",
+        initial, object
+    );
+    fs::write(&path, template)?;
+    launch_editor(config, &path)?;
+
+    Ok(stripspace(&fs::read_to_string(&path)?, true))
+}
+
+fn notes(
+    repo: &GitRepository,
+    notes_ref: Option<&str>,
+    command: &NotesCommand,
+) -> Result<(), Box<dyn Error>> {
+    let config = repo_config(repo)?;
+    let notes_ref = notes_ref_name(&config, notes_ref);
+    let mut state = notes_load(repo, &notes_ref)?;
+    let object_or_head =
+        |object: &Option<String>| object_resolve(repo, object.as_deref().unwrap_or("HEAD"));
+
+    match command {
+        NotesCommand::List { object: None } => {
+            for (object, blob) in &state.notes {
+                println!("{} {}", blob, object);
+            }
+        }
+        NotesCommand::List { object } | NotesCommand::Show { object } => {
+            let object = object_or_head(object)?;
+            let blob = state
+                .notes
+                .get(&object)
+                .ok_or_else(|| format!("no note found for object {}.", object))?;
+            match command {
+                NotesCommand::Show { .. } => print!("{}", notes_blob_text(repo, blob)?),
+                _ => println!("{}", blob),
+            }
+        }
+        NotesCommand::Add {
+            messages,
+            file,
+            force,
+            object,
+        } => {
+            let object = object_or_head(object)?;
+            if state.notes.contains_key(&object) {
+                if !force {
+                    return Err(From::from(format!(
+                        "Cannot add notes. Found existing notes for object {}. Use '-f' to overwrite existing notes",
+                        object
+                    )));
+                }
+                eprintln!("Overwriting existing notes for object {}", object);
+            }
+
+            let message = match (messages.is_empty(), file) {
+                (false, _) => stripspace(&messages.join("\n\n"), false),
+                (true, Some(file)) => stripspace(&fs::read_to_string(file)?, false),
+                (true, None) => notes_edit_message(repo, &config, &object, "")?,
+            };
+            if message.is_empty() {
+                if state.notes.remove(&object).is_some() {
+                    println!("Removing note for object {}", object);
+                    notes_save(
+                        repo,
+                        &config,
+                        &notes_ref,
+                        &state,
+                        "Notes removed by 'eekgit notes add'\n",
+                    )?;
+                }
+                return Ok(());
+            }
+
+            let blob = write_object(repo, &GitObject::new(message.into_bytes(), "blob")?)?;
+            state.notes.insert(object, blob);
+            notes_save(
+                repo,
+                &config,
+                &notes_ref,
+                &state,
+                "Notes added by 'eekgit notes add'\n",
+            )?;
+        }
+        NotesCommand::Edit { object } => {
+            let object = object_or_head(object)?;
+            let initial = match state.notes.get(&object) {
+                Some(blob) => notes_blob_text(repo, blob)?,
+                None => String::new(),
+            };
+            let message = notes_edit_message(repo, &config, &object, &initial)?;
+            if message.is_empty() {
+                if state.notes.remove(&object).is_some() {
+                    println!("Removing note for object {}", object);
+                    notes_save(
+                        repo,
+                        &config,
+                        &notes_ref,
+                        &state,
+                        "Notes removed by 'eekgit notes edit'\n",
+                    )?;
+                }
+                return Ok(());
+            }
+
+            let blob = write_object(repo, &GitObject::new(message.into_bytes(), "blob")?)?;
+            state.notes.insert(object, blob);
+            notes_save(
+                repo,
+                &config,
+                &notes_ref,
+                &state,
+                "Notes added by 'eekgit notes edit'\n",
+            )?;
+        }
+        NotesCommand::Remove { objects } => {
+            let objects = match objects.is_empty() {
+                true => vec!["HEAD".to_string()],
+                false => objects.clone(),
+            };
+            for object in objects {
+                let object = object_resolve(repo, &object)?;
+                if state.notes.remove(&object).is_none() {
+                    return Err(From::from(format!("Object {} has no note", object)));
+                }
+                println!("Removing note for object {}", object);
+            }
+            notes_save(
+                repo,
+                &config,
+                &notes_ref,
+                &state,
+                "Notes removed by 'eekgit notes remove'\n",
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+fn repo_default_config(filemode: bool, symlinks: bool, ignore_case: bool) -> Ini {
+    let mut config = Ini::new();
+
+    config
+        .with_section(Some("core"))
+        .set("repositoryformatversion", "0")
+        .set("filemode", filemode.to_string())
+        .set("bare", "false");
+    if !symlinks {
+        config.with_section(Some("core")).set("symlinks", "false");
+    }
+    if ignore_case {
+        config.with_section(Some("core")).set("ignorecase", "true");
+    }
+
+    config
+}
+
+fn repo_config(repo: &GitRepository) -> Result<Ini, Box<dyn Error>> {
+    let config = Ini::load_from_file(repo_path(repo, "config"))?;
+    match repo.worktree_config {
+        true => config_load(&repo_config_paths(repo)),
+        false => Ok(config),
+    }
+}
+
+// The repository's config files, later ones winning: `config`, then
+// with extensions.worktreeConfig the worktree's own `config.worktree`.
+fn repo_config_paths(repo: &GitRepository) -> Vec<PathBuf> {
+    let mut paths = vec![repo_path(repo, "config")];
+    if repo.worktree_config {
+        paths.push(repo_path(repo, "config.worktree"));
+    }
+    paths
+}
+
+// Where `config --worktree` writes: the worktree's file when the
+// extension is on, and otherwise the one config of the only worktree.
+fn repo_worktree_config_path(repo: &GitRepository) -> PathBuf {
+    match repo.worktree_config {
+        true => repo_path(repo, "config.worktree"),
+        false => repo_path(repo, "config"),
+    }
+}
+
+// Every setting as `section[.subsection].key` and value, in file order,
+// with the case-insensitive parts lowercased.
+fn config_entries(config: &Ini) -> Vec<(String, String)> {
+    let mut entries = Vec::new();
+    for (section, properties) in config.iter() {
+        let Some(section) = section else {
+            continue;
+        };
+        let section = match section.split_once(' ') {
+            Some((name, sub)) => {
+                format!("{}.{}", name.to_lowercase(), sub.trim().trim_matches('"'))
+            }
+            None => section.to_lowercase(),
+        };
+        for (key, value) in properties.iter() {
+            entries.push((
+                format!("{}.{}", section, key.to_lowercase()),
+                value.to_string(),
+            ));
+        }
+    }
+    entries
+}
+
+// Split a `section[.subsection].key` name the way git checks it: the
+// section and key are alphanumerics and `-`, the key starting with a
+// letter, and the subsection is anything but a newline.
+fn config_key_parse(name: &str) -> Result<(&str, Option<&str>, &str), String> {
+    if name.contains('\n') {
+        return Err(format!("invalid key (newline): {}", name));
+    }
+    let (section, key) = name
+        .rsplit_once('.')
+        .filter(|(section, _)| !section.is_empty())
+        .ok_or_else(|| format!("key does not contain a section: {}", name))?;
+    if key.is_empty() {
+        return Err(format!("key does not contain variable name: {}", name));
+    }
+    let (section, subsection) = match section.split_once('.') {
+        Some((section, subsection)) => (section, Some(subsection)),
+        None => (section, None),
+    };
+
+    let is_key_char = |c: char| c.is_ascii_alphanumeric() || c == '-';
+    if section.is_empty()
+        || !section.chars().all(is_key_char)
+        || !key.starts_with(|c: char| c.is_ascii_alphabetic())
+        || !key.chars().all(is_key_char)
+    {
+        return Err(format!("invalid key: {}", name));
+    }
+    Ok((section, subsection, key))
+}
+
+// Set `section[.subsection].key` in the config file at `path`, or with
+// no value remove it. The file is edited line by line like git does, so
+// comments, layout and other entries stay as they were. Returns how many
+// entries held the key; unless `all` is set, several of them are left
+// alone, as one value cannot stand in for them all.
+fn config_write(
+    path: &Path,
+    name: &str,
+    value: Option<&str>,
+    all: bool,
+) -> Result<usize, Box<dyn Error>> {
+    let (section, subsection, key) = config_key_parse(name)?;
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(From::from(e)),
+    };
+    let mut lines: Vec<String> = text
+        .split_inclusive('\n')
+        .map(ToString::to_string)
+        .collect();
+
+    // Line ranges of the matching entries, and the line a new entry
+    // would follow: the last entry or header of the last matching section
+    let mut matches = Vec::new();
+    let mut insert_after = None;
+    let mut in_section = false;
+    let mut i = 0;
+    while i < lines.len() {
+        let line = lines[i].trim_start();
+        if let Some(header) = line.strip_prefix('[') {
+            let header = header.split(']').next().unwrap_or_default();
+            in_section = config_section_matches(header, section, subsection);
+            if in_section {
+                insert_after = Some(i);
+            }
+            i += 1;
+            continue;
+        }
+
+        // A value ending in a backslash goes on over the next line
+        let start = i;
+        while lines[i].trim_end_matches(['\r', '\n']).ends_with('\\') && i + 1 < lines.len() {
+            i += 1;
+        }
+        i += 1;
+        if !in_section || line.starts_with(['#', ';']) || line.trim().is_empty() {
+            continue;
+        }
+        let name_len = line
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '-'))
+            .unwrap_or(line.len());
+        let rest = line[name_len..].trim_start();
+        if rest.is_empty() || rest.starts_with(['=', '#', ';']) {
+            if line[..name_len].eq_ignore_ascii_case(key) {
+                matches.push(start..i);
+            }
+            insert_after = Some(i - 1);
+        }
+    }
+
+    if matches.len() > 1 && !all {
+        return Ok(matches.len());
+    }
+    let Some(value) = value else {
+        if matches.is_empty() {
+            return Ok(0);
+        }
+        for range in matches.iter().rev() {
+            lines.drain(range.clone());
+        }
+        config_write_lines(path, &lines)?;
+        return Ok(matches.len());
+    };
+
+    let entry = format!("\t{} = {}\n", key, config_quote(value));
+    match matches.split_last() {
+        // The last one is replaced and any others removed
+        Some((last, others)) => {
+            lines.splice(last.clone(), [entry]);
+            for range in others.iter().rev() {
+                lines.drain(range.clone());
+            }
+        }
+        None => {
+            if lines.last().is_some_and(|line| !line.ends_with('\n')) {
+                lines.last_mut().ok_or("config vanished")?.push('\n');
+            }
+            match insert_after {
+                Some(i) => lines.insert(i + 1, entry),
+                None => {
+                    lines.push(match subsection {
+                        Some(subsection) => format!(
+                            "[{} \"{}\"]\n",
+                            section,
+                            subsection.replace('\\', "\\\\").replace('"', "\\\"")
+                        ),
+                        None => format!("[{}]\n", section),
+                    });
+                    lines.push(entry);
+                }
+            }
+        }
+    }
+    config_write_lines(path, &lines)?;
+    Ok(matches.len())
+}
+
+// A value as git writes it: quoted when spaces at either end or a
+// comment character would otherwise be lost, with `"`, `\`, tabs and
+// newlines escaped.
+fn config_quote(value: &str) -> String {
+    let quoted = value.starts_with(char::is_whitespace)
+        || value.ends_with(char::is_whitespace)
+        || value.contains(['#', ';']);
+    let mut out = String::new();
+    if quoted {
+        out.push('"');
+    }
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            c => out.push(c),
+        }
+    }
+    if quoted {
+        out.push('"');
+    }
+    out
+}
+
+fn config_write_lines(path: &Path, lines: &[String]) -> Result<(), Box<dyn Error>> {
+    let lock = PathBuf::from(format!("{}.lock", path.display()));
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&lock)
+        .map_err(|e| format!("could not lock config file {}: {}", path.display(), e))?
+        .write_all(lines.concat().as_bytes())?;
+    rename_over(&lock, path)?;
+    Ok(())
+}
+
+// Section names come back from rust-ini as `core` or `trailer "sign"`;
+// the old `[trailer.sign]` spelling is accepted as well.
+fn config_section_matches(name: &str, section: &str, subsection: Option<&str>) -> bool {
+    let (name, sub) = match name.split_once(' ') {
+        Some((name, sub)) => (name, Some(sub.trim().trim_matches('"'))),
+        None => match name.split_once('.') {
+            Some((name, sub)) => (name, Some(sub)),
+            None => (name, None),
+        },
+    };
+
+    name.eq_ignore_ascii_case(section) && sub == subsection
+}
+
+// Every value of a dotted `section[.subsection].key` name, in order.
+fn config_get_all<'a>(config: &'a Ini, name: &str) -> Vec<&'a str> {
+    let Some((section, key)) = name.rsplit_once('.') else {
+        return Vec::new();
+    };
+    let (section, subsection) = match section.split_once('.') {
+        Some((section, subsection)) => (section, Some(subsection)),
+        None => (section, None),
+    };
+
+    config
+        .iter()
+        .filter(|(name, _)| {
+            name.is_some_and(|name| config_section_matches(name, section, subsection))
+        })
+        .flat_map(|(_, properties)| properties.iter())
+        .filter(|(k, _)| k.eq_ignore_ascii_case(key))
+        .map(|(_, v)| v)
+        .collect()
+}
+
+// The last value wins.
+fn config_get<'a>(config: &'a Ini, name: &str) -> Option<&'a str> {
+    config_get_all(config, name).pop()
+}
+
+// The system and global config files, which a repository cannot write
+// for its user: git only trusts settings such as safe.directory there.
+fn config_protected() -> Result<Ini, Box<dyn Error>> {
+    config_load(&config_protected_paths())
+}
+
+fn config_protected_paths() -> Vec<PathBuf> {
+    let env = |name: &str| std::env::var_os(name).filter(|value| !value.is_empty());
+    let home = env("HOME").map(PathBuf::from);
+
+    let mut paths = Vec::new();
+    if env("GIT_CONFIG_NOSYSTEM").is_none() {
+        paths.push(env("GIT_CONFIG_SYSTEM").map_or_else(|| "/etc/gitconfig".into(), PathBuf::from));
+    }
+    match env("GIT_CONFIG_GLOBAL") {
+        Some(global) => paths.push(PathBuf::from(global)),
+        None => {
+            let xdg = env("XDG_CONFIG_HOME")
+                .map(PathBuf::from)
+                .or_else(|| home.as_ref().map(|home| home.join(".config")));
+            paths.extend(xdg.map(|xdg| xdg.join("git/config")));
+            paths.extend(home.map(|home| home.join(".gitconfig")));
+        }
+    }
+    paths
+}
+
+// Every config file that applies in `repo`: the system and global ones,
+// then the repository's own.
+fn config_all(repo: &GitRepository) -> Result<Ini, Box<dyn Error>> {
+    let mut paths = config_protected_paths();
+    paths.extend(repo_config_paths(repo));
+    config_load(&paths)
+}
+
+// A file of the user's set by `key`, or without it `name` in git's
+// directory under XDG_CONFIG_HOME.
+fn config_user_file(config: &Ini, key: &str, name: &str) -> Option<PathBuf> {
+    let home = std::env::var_os("HOME").map(PathBuf::from);
+    match config_get(config, key) {
+        Some(file) => match (file.strip_prefix("~/"), &home) {
+            (Some(rest), Some(home)) => Some(home.join(rest)),
+            _ => Some(PathBuf::from(file)),
+        },
+        None => std::env::var_os("XDG_CONFIG_HOME")
+            .filter(|xdg| !xdg.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| home.map(|home| home.join(".config")))
+            .map(|xdg| xdg.join("git").join(name)),
+    }
+}
+
+// Config files read as one, later files overriding earlier ones. Those
+// that do not exist are skipped.
+fn config_load(paths: &[PathBuf]) -> Result<Ini, Box<dyn Error>> {
+    let mut content = String::new();
+    for path in paths {
+        if let Ok(text) = fs::read_to_string(path) {
+            content.push_str(&text);
+            content.push('\n');
+        }
+    }
+    Ok(Ini::load_from_str(&content)?)
+}
+
+// Git's boolean spellings.
+fn config_get_bool(config: &Ini, name: &str) -> Result<Option<bool>, Box<dyn Error>> {
+    match config_get(config, name)
+        .map(str::to_ascii_lowercase)
+        .as_deref()
+    {
+        None => Ok(None),
+        Some("true" | "yes" | "on" | "1") => Ok(Some(true)),
+        Some("false" | "no" | "off" | "0") => Ok(Some(false)),
+        Some(value) => Err(From::from(format!(
+            "bad boolean config value '{}' for '{}'",
+            value, name
+        ))),
+    }
+}
+
+// The zlib level set by the first of `names` present, or `default`. Like
+// git, -1 stands for zlib's own default.
+fn config_compression(
+    config: &Ini,
+    names: &[&str],
+    default: Compression,
+) -> Result<Compression, Box<dyn Error>> {
+    let Some(value) = names.iter().find_map(|name| config_get(config, name)) else {
+        return Ok(default);
+    };
+    match value.trim().parse::<i32>() {
+        Ok(-1) => Ok(Compression::default()),
+        Ok(level @ 0..=9) => Ok(Compression::new(level as u32)),
+        _ => Err(From::from(format!("bad zlib compression level {}", value))),
+    }
+}
+
+// `Name <email> timestamp tz` for the `AUTHOR` or `COMMITTER` role.
+fn ident(config: &Ini, role: &str) -> Result<String, Box<dyn Error>> {
+    ident_resolve(config, role, &|name| std::env::var(name).ok())
+}
+
+// The ident of `role`, taken from the GIT_<ROLE>_* variables of `env`
+// first, then from <role>.name and <role>.email, then from user.name and
+// user.email. The email may also come from EMAIL, unless
+// user.useConfigOnly asks for the identity to be configured.
+fn ident_resolve(
+    config: &Ini,
+    role: &str,
+    env: &dyn Fn(&str) -> Option<String>,
+) -> Result<String, Box<dyn Error>> {
+    let role_env = |key: &str| env(&format!("GIT_{}_{}", role, key));
+    let configured = |key: &str| {
+        [
+            format!("{}.{}", role.to_lowercase(), key),
+            format!("user.{}", key),
+        ]
+        .iter()
+        .find_map(|name| config_get(config, name))
+        .map(ToString::to_string)
+    };
+    let config_only = config_get_bool(config, "user.useConfigOnly")?.unwrap_or(false);
+    let unknown = |what: &str| match config_only {
+        true => format!("no {} was given and auto-detection is disabled", what),
+        false => format!(
+            "{} identity unknown; set user.name and user.email",
+            role.to_lowercase()
+        ),
+    };
+
+    let email = role_env("EMAIL")
+        .or_else(|| configured("email"))
+        .or_else(|| env("EMAIL").filter(|_| !config_only))
+        .ok_or_else(|| unknown("email"))?;
+    let email = ident_strip(&email);
+    let raw_name = role_env("NAME")
+        .or_else(|| configured("name"))
+        .ok_or_else(|| unknown("name"))?;
+    let name = ident_strip(&raw_name);
+    if raw_name.is_empty() {
+        return Err(From::from(format!(
+            "empty ident name (for <{}>) not allowed",
+            email
+        )));
+    }
+    if name.is_empty() {
+        return Err(From::from(format!(
+            "name consists only of disallowed characters: {}",
+            raw_name
+        )));
+    }
+
+    let date = match role_env("DATE") {
+        Some(value) => date::parse_raw(&value)?,
+        None => date::now_raw(),
+    };
+
+    Ok(format!("{} <{}> {}", name, email, date))
+}
+
+// A name or email as git writes it into an ident: without `<`, `>` or
+// newlines, which would break the line, and without whitespace or
+// punctuation at either end.
+fn ident_strip(value: &str) -> String {
+    let crud = |c: char| c <= ' ' || ".,:;<>\"\\'".contains(c);
+    value
+        .trim_matches(crud)
+        .chars()
+        .filter(|c| !matches!(c, '<' | '>' | '\n'))
+        .collect()
+}
+
+fn commit_create(
+    repo: &GitRepository,
+    config: &Ini,
+    tree: &str,
+    parents: &[String],
+    message: &str,
+) -> Result<String, Box<dyn Error>> {
+    let mut data = format!("tree {}\n", tree);
+    for parent in parents {
+        data.push_str(&format!("parent {}\n", parent));
+    }
+    data.push_str(&format!("author {}\n", ident(config, "AUTHOR")?));
+    data.push_str(&format!("committer {}\n", ident(config, "COMMITTER")?));
+    // The message is taken to be in i18n.commitEncoding already; like
+    // git, only an encoding other than UTF-8 is recorded
+    if let Some(encoding) = config_get(config, "i18n.commitencoding") {
+        if !matches!(encoding.to_ascii_lowercase().as_str(), "utf-8" | "utf8") {
+            data.push_str(&format!("encoding {}\n", encoding));
+        }
+    }
+    data.push('\n');
+    data.push_str(message);
+
+    write_object(repo, &GitObject::new(data.into_bytes(), "commit")?)
+}
+
+// Tidy a message the way git does: drop trailing whitespace and,
+// optionally, `#` comment lines; squeeze runs of blank lines; and end
+// with exactly one newline, or be empty.
+fn stripspace(message: &str, strip_comments: bool) -> String {
+    let mut result = String::new();
+    let mut blank = false;
+    for line in message.lines() {
+        if strip_comments && line.starts_with('#') {
+            continue;
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            blank = !result.is_empty();
+            continue;
+        }
+        if blank {
+            result.push('\n');
+            blank = false;
+        }
+        result.push_str(line);
+        result.push('\n');
+    }
+
+    result
+}
+
+// GIT_EDITOR, core.editor, VISUAL or EDITOR, in that order, falling
+// back to vi.
+fn editor(config: &Ini) -> String {
+    std::env::var("GIT_EDITOR")
+        .ok()
+        .or_else(|| config_get(config, "core.editor").map(ToString::to_string))
+        .or_else(|| std::env::var("VISUAL").ok())
+        .or_else(|| std::env::var("EDITOR").ok())
+        .unwrap_or_else(|| "vi".to_string())
+}
+
+// GIT_PAGER, core.pager or PAGER, falling back to less.
+fn pager(config: &Ini) -> String {
+    std::env::var("GIT_PAGER")
+        .ok()
+        .or_else(|| config_get(config, "core.pager").map(ToString::to_string))
+        .or_else(|| std::env::var("PAGER").ok())
+        .unwrap_or_else(|| "less".to_string())
+}
+
+// Let the user edit `path` with their editor.
+fn launch_editor(config: &Ini, path: &Path) -> Result<(), Box<dyn Error>> {
+    let editor = editor(config);
+
+    // Through the shell, as editors are often configured with arguments
+    let status = process::Command::new("sh")
+        .arg("-c")
+        .arg(format!("{} \"$@\"", editor))
+        .arg(&editor)
+        .arg(path)
+        .status()?;
+    if !status.success() {
+        return Err(From::from(format!(
+            "there was a problem with the editor '{}'",
+            editor
+        )));
+    }
+
+    Ok(())
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum TrailerWhere {
+    End,
+    Start,
+    After,
+    Before,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum TrailerIfExists {
+    AddIfDifferentNeighbor,
+    AddIfDifferent,
+    Add,
+    Replace,
+    DoNothing,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum TrailerIfMissing {
+    Add,
+    DoNothing,
+}
+
+impl TrailerWhere {
+    fn parse(value: &str) -> Result<Self, Box<dyn Error>> {
+        match value.to_ascii_lowercase().as_str() {
+            "end" => Ok(TrailerWhere::End),
+            "start" => Ok(TrailerWhere::Start),
+            "after" => Ok(TrailerWhere::After),
+            "before" => Ok(TrailerWhere::Before),
+            _ => Err(From::from(format!(
+                "unknown value '{}' for trailer where",
+                value
+            ))),
+        }
+    }
+}
+
+impl TrailerIfExists {
+    fn parse(value: &str) -> Result<Self, Box<dyn Error>> {
+        match value.to_ascii_lowercase().as_str() {
+            "addifdifferentneighbor" => Ok(TrailerIfExists::AddIfDifferentNeighbor),
+            "addifdifferent" => Ok(TrailerIfExists::AddIfDifferent),
+            "add" => Ok(TrailerIfExists::Add),
+            "replace" => Ok(TrailerIfExists::Replace),
+            "donothing" => Ok(TrailerIfExists::DoNothing),
+            _ => Err(From::from(format!(
+                "unknown value '{}' for trailer ifExists",
+                value
+            ))),
+        }
+    }
+}
+
+impl TrailerIfMissing {
+    fn parse(value: &str) -> Result<Self, Box<dyn Error>> {
+        match value.to_ascii_lowercase().as_str() {
+            "add" => Ok(TrailerIfMissing::Add),
+            "donothing" => Ok(TrailerIfMissing::DoNothing),
+            _ => Err(From::from(format!(
+                "unknown value '{}' for trailer ifMissing",
+                value
+            ))),
+        }
+    }
+}
+
+struct Trailer {
+    token: String,
+    separator: char,
+    value: String,
+}
+
+impl Trailer {
+    fn same_as(&self, other: &Trailer) -> bool {
+        self.token.eq_ignore_ascii_case(&other.token) && self.value == other.value
+    }
+
+    fn format(&self, unfold: bool) -> String {
+        let value = if unfold {
+            self.value
+                .split('\n')
+                .map(str::trim)
+                .collect::<Vec<_>>()
+                .join(" ")
+        } else {
+            self.value.clone()
+        };
+
+        if value.is_empty() {
+            format!("{}{}", self.token, self.separator)
+        } else {
+            format!("{}{} {}", self.token, self.separator, value)
+        }
+    }
+}
+
+// Command-line overrides applied to every `--trailer`, on top of `trailer.*`.
+struct TrailerOptions {
+    placement: Option<TrailerWhere>,
+    if_exists: Option<TrailerIfExists>,
+    if_missing: Option<TrailerIfMissing>,
+}
+
+fn parse_trailer_line(line: &str, separators: &str) -> Option<Trailer> {
+    // Separators need not be ASCII, so the value starts after the whole char
+    let (position, separator) = line.char_indices().find(|&(_, c)| separators.contains(c))?;
+    let token = line[..position].trim_end();
+    if token.is_empty() || !token.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+        return None;
+    }
+
+    Some(Trailer {
+        token: token.to_string(),
+        separator,
+        value: line[position + separator.len_utf8()..].trim().to_string(),
+    })
+}
+
+// The trailer block is the last paragraph of the message, provided every
+// line in it is a trailer or a continuation of one. The first paragraph
+// is the subject and never counts.
+fn find_trailer_block(lines: &[&str], separators: &str) -> Option<(usize, Vec<Trailer>)> {
+    let start = lines.iter().rposition(|line| line.trim().is_empty())? + 1;
+    let mut trailers: Vec<Trailer> = Vec::new();
+
+    for line in &lines[start..] {
+        if line.starts_with([' ', '\t']) {
+            trailers.last_mut()?.value.push_str(&format!("\n{}", line));
+        } else {
+            trailers.push(parse_trailer_line(line, separators)?);
+        }
+    }
+
+    Some((start, trailers))
+}
+
+fn add_trailer(
+    trailers: &mut Vec<Trailer>,
+    config: &Ini,
+    options: &TrailerOptions,
+    argument: &str,
+    separators: &str,
+) -> Result<(), Box<dyn Error>> {
+    let (token, value) = match argument
+        .char_indices()
+        .find(|&(_, c)| c == '=' || separators.contains(c))
+    {
+        Some((position, c)) => (
+            argument[..position].trim(),
+            argument[position + c.len_utf8()..].trim(),
+        ),
+        None => (argument.trim(), ""),
+    };
+
+    // `--trailer sign=...` may name a `trailer.<alias>` section directly or
+    // match the key it expands to.
+    let alias = config
+        .sections()
+        .flatten()
+        .filter_map(|section| match section.split_once(' ') {
+            Some((name, alias)) if name.eq_ignore_ascii_case("trailer") => {
+                Some(alias.trim_matches('"'))
+            }
+            _ => None,
+        })
+        .find(|alias| {
+            alias.eq_ignore_ascii_case(token)
+                || config_get(config, &format!("trailer.{}.key", alias))
+                    .is_some_and(|key| key.eq_ignore_ascii_case(token))
+        });
+
+    let alias_setting = |name: &str| {
+        alias
+            .and_then(|alias| config_get(config, &format!("trailer.{}.{}", alias, name)))
+            .or_else(|| config_get(config, &format!("trailer.{}", name)))
+    };
+
+    let placement = match (options.placement, alias_setting("where")) {
+        (Some(placement), _) => placement,
+        (None, Some(value)) => TrailerWhere::parse(value)?,
+        (None, None) => TrailerWhere::End,
+    };
+    let if_exists = match (options.if_exists, alias_setting("ifexists")) {
+        (Some(if_exists), _) => if_exists,
+        (None, Some(value)) => TrailerIfExists::parse(value)?,
+        (None, None) => TrailerIfExists::AddIfDifferentNeighbor,
+    };
+    let if_missing = match (options.if_missing, alias_setting("ifmissing")) {
+        (Some(if_missing), _) => if_missing,
+        (None, Some(value)) => TrailerIfMissing::parse(value)?,
+        (None, None) => TrailerIfMissing::Add,
+    };
+
+    let key = alias
+        .and_then(|alias| config_get(config, &format!("trailer.{}.key", alias)))
+        .unwrap_or(token);
+    let new_trailer = Trailer {
+        token: key.to_string(),
+        separator: separators.chars().next().unwrap_or(':'),
+        value: value.to_string(),
+    };
+
+    let matching = |trailers: &Vec<Trailer>| -> Vec<usize> {
+        (0..trailers.len())
+            .filter(|&i| trailers[i].token.eq_ignore_ascii_case(&new_trailer.token))
+            .collect()
+    };
+    let position = |trailers: &Vec<Trailer>, matches: &[usize]| match placement {
+        TrailerWhere::End => trailers.len(),
+        TrailerWhere::Start => 0,
+        TrailerWhere::After => matches.last().map_or(trailers.len(), |i| i + 1),
+        TrailerWhere::Before => matches.first().copied().unwrap_or(0),
+    };
+
+    let matches = matching(trailers);
+    if matches.is_empty() {
+        if if_missing == TrailerIfMissing::Add {
+            let at = position(trailers, &matches);
+            trailers.insert(at, new_trailer);
+        }
+        return Ok(());
+    }
+
+    match if_exists {
+        TrailerIfExists::DoNothing => {}
+        TrailerIfExists::Add => {
+            let at = position(trailers, &matches);
+            trailers.insert(at, new_trailer);
+        }
+        TrailerIfExists::AddIfDifferent => {
+            if !matches.iter().any(|&i| trailers[i].same_as(&new_trailer)) {
+                let at = position(trailers, &matches);
+                trailers.insert(at, new_trailer);
+            }
+        }
+        TrailerIfExists::AddIfDifferentNeighbor => {
+            let at = position(trailers, &matches);
+            let neighbor = match placement {
+                TrailerWhere::End | TrailerWhere::After => at.checked_sub(1),
+                TrailerWhere::Start | TrailerWhere::Before => Some(at),
+            };
+            if !neighbor
+                .and_then(|i| trailers.get(i))
+                .is_some_and(|neighbor| neighbor.same_as(&new_trailer))
+            {
+                trailers.insert(at, new_trailer);
+            }
+        }
+        TrailerIfExists::Replace => {
+            // Drop the existing trailer closest to where the new one goes
+            let closest = match placement {
+                TrailerWhere::End | TrailerWhere::After => *matches.last().unwrap(),
+                TrailerWhere::Start | TrailerWhere::Before => matches[0],
+            };
+            trailers.remove(closest);
+            let at = position(trailers, &matching(trailers));
+            trailers.insert(at, new_trailer);
+        }
+    }
+
+    Ok(())
+}
+
+fn interpret_trailers(
+    message: &str,
+    new_trailers: &[String],
+    config: &Ini,
+    options: &TrailerOptions,
+    only_trailers: bool,
+    unfold: bool,
+) -> Result<String, Box<dyn Error>> {
+    let separators = config_get(config, "trailer.separators").unwrap_or(":");
+    let lines: Vec<&str> = message.lines().collect();
+
+    // Trailing blank lines stay after the trailer block
+    let content_end = lines
+        .iter()
+        .rposition(|line| !line.trim().is_empty())
+        .map_or(0, |i| i + 1);
+
+    let (body_end, mut trailers, had_block) =
+        match find_trailer_block(&lines[..content_end], separators) {
+            Some((start, trailers)) => (start, trailers, true),
+            None => (content_end, Vec::new(), false),
+        };
+
+    for argument in new_trailers {
+        add_trailer(&mut trailers, config, options, argument, separators)?;
+    }
+
+    let mut output = String::new();
+    if !only_trailers {
+        for line in &lines[..body_end] {
+            output.push_str(line);
+            output.push('\n');
+        }
+        if !had_block && !trailers.is_empty() {
+            output.push('\n');
+        }
+    }
+
+    for trailer in &trailers {
+        output.push_str(&trailer.format(unfold));
+        output.push('\n');
+    }
+
+    if !only_trailers {
+        for line in &lines[content_end..] {
+            output.push_str(line);
+            output.push('\n');
+        }
+    }
+
+    Ok(output)
+}
+
+fn main() {
+    let cli = Cli::parse();
+    if let Err(e) = run(&cli) {
+        eprintln!("fatal: {}", e);
+        process::exit(128);
+    }
+}
+
+fn run(cli: &Cli) -> Result<(), Box<dyn Error>> {
+    // Hooks and servers started from here see the namespace too
+    if let Some(namespace) = &cli.namespace {
+        std::env::set_var("GIT_NAMESPACE", namespace);
+    }
+
+    match &cli.command {
+        Some(Commands::Init { path }) => {
+            repo_create(path)?;
+        }
+        Some(Commands::CatFile {
+            show_type,
+            show_size,
+            exists,
+            pretty,
+            textconv,
+            object_type,
+            object,
+        }) => {
+            let repo = repo_find(".")?;
+            // A query takes just the object
+            if *show_type || *show_size || *exists || *pretty || *textconv {
+                if object.is_some() {
+                    return Err(From::from("cat-file: one object at a time"));
+                }
+                if *textconv {
+                    return cat_file_textconv(&repo, object_type);
+                }
+                if *pretty {
+                    return cat_file_pretty(&repo, &object_resolve(&repo, object_type)?);
+                }
+                let header = match object_resolve(&repo, object_type) {
+                    Ok(sha) => repo_odb(&repo)?.read_header(&sha)?,
+                    Err(_) if *exists => None,
+                    Err(e) => return Err(e),
+                };
+                match header {
+                    Some(_) if *exists => {}
+                    Some((object_type, _)) if *show_type => println!("{}", object_type),
+                    Some((_, size)) => println!("{}", size),
+                    None if *exists => process::exit(1),
+                    None => {
+                        return Err(From::from(format!(
+                            "Not a valid object name {}",
+                            object_type
+                        )))
+                    }
+                }
+            } else {
+                let object = object.as_deref().ok_or("cat-file: no object given")?;
+                cat_file(&repo, object)?;
+            }
+        }
+        Some(Commands::CountObjects { verbose }) => {
+            let repo = repo_find(".")?;
+            count_objects(&repo, *verbose)?;
+        }
+        Some(Commands::InterpretTrailers {
+            trailers,
+            placement,
+            if_exists,
+            if_missing,
+            only_trailers,
+            unfold,
+            parse,
+            in_place,
+            files,
+        }) => {
+            let config = match repo_find(".") {
+                Ok(repo) => repo_config(&repo)?,
+                Err(_) => Ini::new(),
+            };
+            let options = TrailerOptions {
+                placement: placement.as_deref().map(TrailerWhere::parse).transpose()?,
+                if_exists: if_exists
+                    .as_deref()
+                    .map(TrailerIfExists::parse)
+                    .transpose()?,
+                if_missing: if_missing
+                    .as_deref()
+                    .map(TrailerIfMissing::parse)
+                    .transpose()?,
+            };
+            // --parse only reports what is already there
+            let trailers: &[String] = if *parse { &[] } else { trailers };
+            let only_trailers = *only_trailers || *parse;
+            let unfold = *unfold || *parse;
+
+            if files.is_empty() {
+                let mut message = String::new();
+                io::stdin().read_to_string(&mut message)?;
+                let output = interpret_trailers(
+                    &message,
+                    trailers,
+                    &config,
+                    &options,
+                    only_trailers,
+                    unfold,
+                )?;
+                io::stdout().write_all(output.as_bytes())?;
+            }
+
+            for file in files {
+                let message = fs::read_to_string(file)?;
+                let output = interpret_trailers(
+                    &message,
+                    trailers,
+                    &config,
+                    &options,
+                    only_trailers,
+                    unfold,
+                )?;
+                if *in_place {
+                    fs::write(file, output)?;
+                } else {
+                    io::stdout().write_all(output.as_bytes())?;
+                }
+            }
+        }
+        Some(Commands::IndexPack {
+            index_file,
+            stdin,
+            strict,
+            pack_file,
+        }) => {
+            if *stdin {
+                let repo = repo_find(".")?;
+                let mut pack = Vec::new();
+                io::stdin().read_to_end(&mut pack)?;
+                let index = index_pack(&pack, *strict)?;
+
+                let name = hex::encode(&pack[pack.len() - 20..]);
+                let pack_path = repo_file(&repo, &format!("objects/pack/pack-{}.pack", name));
+                let index_path = match index_file {
+                    Some(index_file) => PathBuf::from(index_file),
+                    None => pack_path.with_extension("idx"),
+                };
+                fs::write(&pack_path, &pack)?;
+                fs::write(index_path, index)?;
+                println!("pack\t{}", name);
+            } else {
+                let pack_file = pack_file
+                    .as_ref()
+                    .ok_or("index-pack needs a pack file or --stdin")?;
+                let index_path = match index_file {
+                    Some(index_file) => PathBuf::from(index_file),
+                    None if pack_file.ends_with(".pack") => {
+                        Path::new(pack_file).with_extension("idx")
+                    }
+                    None => {
+                        return Err(From::from(format!(
+                            "Packfile name '{}' does not end with '.pack'",
+                            pack_file
+                        )))
+                    }
+                };
+                let pack = fs::read(pack_file)?;
+                fs::write(index_path, index_pack(&pack, *strict)?)?;
+                println!("{}", hex::encode(&pack[pack.len() - 20..]));
+            }
+        }
+        Some(Commands::UnpackObjects { dry_run, strict }) => {
+            let repo = repo_find(".")?;
+            let mut pack = Vec::new();
+            io::stdin().read_to_end(&mut pack)?;
+            unpack_objects(&repo, &pack, *dry_run, *strict)?;
+        }
+        Some(Commands::UploadPack {
+            stateless_rpc,
+            advertise_refs,
+            directory,
+        }) => {
+            let repo = repo_open(directory)?;
+            let mode = ServiceMode::from_flags(*stateless_rpc, *advertise_refs);
+            upload_pack(
+                &repo,
+                &mut io::stdin().lock(),
+                &mut io::stdout().lock(),
+                mode,
+            )?;
+        }
+        Some(Commands::ReceivePack {
+            stateless_rpc,
+            advertise_refs,
+            directory,
+        }) => {
+            let mut repo = repo_open(directory)?;
+            let mode = ServiceMode::from_flags(*stateless_rpc, *advertise_refs);
+            receive_pack(
+                &mut repo,
+                &mut io::stdin().lock(),
+                &mut io::stdout().lock(),
+                mode,
+            )?;
+        }
+        Some(Commands::Daemon {
+            base_path,
+            export_all,
+            listen,
+            port,
+            max_connections,
+            init_timeout,
+            timeout,
+            directories,
+        }) => {
+            let seconds = |n: u64| (n != 0).then(|| Duration::from_secs(n));
+            let options = DaemonOptions {
+                base_path: base_path.clone(),
+                export_all: *export_all,
+                whitelist: directories
+                    .iter()
+                    .map(fs::canonicalize)
+                    .collect::<Result<_, _>>()?,
+                init_timeout: seconds(*init_timeout),
+                timeout: seconds(*timeout),
+            };
+            let listener = TcpListener::bind((listen.as_str(), *port))?;
+            daemon(options, listener, *max_connections)?;
+        }
+        Some(Commands::ServeHttp {
+            listen,
+            port,
+            user,
+            max_connections,
+            timeout,
+            directory,
+        }) => {
+            let options = HttpOptions {
+                root: fs::canonicalize(directory)?,
+                credentials: user.clone(),
+                timeout: (*timeout != 0).then(|| Duration::from_secs(*timeout)),
+            };
+            let listener = TcpListener::bind((listen.as_str(), *port))?;
+            http_server(options, listener, *max_connections)?;
+        }
+        Some(Commands::DiffTree {
+            recursive,
+            show_trees,
+            root,
+            name_only,
+            name_status,
+            abbrev,
+            old,
+            new,
+            paths,
+        }) => {
+            let repo = repo_find(".")?;
+            let mut diff = TreeDiff::new(&repo);
+            diff.recursive = *recursive || *show_trees;
+            diff.show_trees = *show_trees;
+            diff.paths = pathspecs(&repo, paths)?;
+
+            // A single commit is compared with its parent, and named first
+            let (old_tree, new_tree) = match new {
+                Some(new) => (
+                    Some(object_peel(&repo, &object_resolve(&repo, old)?, "tree")?),
+                    object_peel(&repo, &object_resolve(&repo, new)?, "tree")?,
+                ),
+                None => {
+                    let commit = object_peel(&repo, &object_resolve(&repo, old)?, "commit")?;
+                    let kvlm = commit_kvlm(&repo, &commit)?;
+                    let parents = kvlm_shas(&kvlm, "parent")?;
+                    // Merges need -m or -c, which are not supported
+                    if parents.len() > 1 || (parents.is_empty() && !root) {
+                        return Ok(());
+                    }
+                    println!("{}", commit);
+                    let old_tree = match parents.first() {
+                        Some(parent) => Some(object_peel(&repo, parent, "tree")?),
+                        None => None,
+                    };
+                    (old_tree, object_peel(&repo, &commit, "tree")?)
+                }
+            };
+
+            let quote_high =
+                config_get_bool(&repo_config(&repo)?, "core.quotepath")?.unwrap_or(true);
+            let abbrev = match abbrev {
+                Some(min) => Some(Abbrev::new(&repo, *min)?),
+                None => None,
+            };
+            let mut out = io::stdout().lock();
+            for change in diff.diff(old_tree.as_deref(), Some(&new_tree))? {
+                let line = if *name_only {
+                    String::new()
+                } else if *name_status {
+                    format!("{}\t", change.kind.status())
+                } else {
+                    let side = |leaf: &Option<GitTreeLeaf>| match (leaf, &abbrev) {
+                        (Some(leaf), Some(abbrev)) => {
+                            (format!("{:0>6}", leaf.mode), abbrev.apply(&leaf.sha))
+                        }
+                        (Some(leaf), None) => (format!("{:0>6}", leaf.mode), leaf.sha.clone()),
+                        (None, Some(abbrev)) => {
+                            ("000000".to_string(), NULL_SHA[..abbrev.min].to_string())
+                        }
+                        (None, None) => ("000000".to_string(), NULL_SHA.to_string()),
+                    };
+                    let (old_mode, old_sha) = side(&change.old);
+                    let (new_mode, new_sha) = side(&change.new);
+                    format!(
+                        ":{} {} {} {} {}\t",
+                        old_mode,
+                        new_mode,
+                        old_sha,
+                        new_sha,
+                        change.kind.status()
+                    )
+                };
+                // Paths go out as bytes, which need not be UTF-8
+                out.write_all(line.as_bytes())?;
+                out.write_all(&quote_path(&change.path, quote_high))?;
+                out.write_all(b"\n")?;
+            }
+        }
+        Some(Commands::RevList {
+            all,
+            topo_order,
+            date_order,
+            reverse,
+            first_parent,
+            max_count,
+            count,
+            abbrev_commit,
+            abbrev,
+            revisions,
+            paths,
+        }) => {
+            let repo = repo_find(".")?;
+            let mut walk = RevWalk::new(&repo);
+            walk.sort = match (topo_order, date_order) {
+                (true, _) => RevSort::Topo,
+                (false, true) => RevSort::Date,
+                (false, false) => RevSort::Default,
+            };
+            walk.reverse = *reverse;
+            walk.first_parent = *first_parent;
+            walk.max_count = *max_count;
+            walk.paths = pathspecs(&repo, paths)?;
+
+            if *all {
+                for (_, sha) in ref_list(&repo)? {
+                    if object_peel(&repo, &sha, "commit").is_ok() {
+                        walk.push(&sha)?;
+                    }
+                }
+            }
+            for revision in revisions {
+                let or_head = |name: &str| {
+                    if name.is_empty() {
+                        "HEAD".to_string()
+                    } else {
+                        name.to_string()
+                    }
+                };
+                if let Some((from, to)) = revision.split_once("..") {
+                    walk.hide(&object_resolve(&repo, &or_head(from))?)?;
+                    walk.push(&object_resolve(&repo, &or_head(to))?)?;
+                } else if let Some(hidden) = revision.strip_prefix('^') {
+                    walk.hide(&object_resolve(&repo, hidden)?)?;
+                } else {
+                    walk.push(&object_resolve(&repo, revision)?)?;
+                }
+            }
+
+            let commits = walk.collect::<Result<Vec<_>, _>>()?;
+            if *count {
+                println!("{}", commits.len());
+            } else {
+                let abbrev = match abbrev_commit {
+                    true => Some(Abbrev::new(&repo, *abbrev)?),
+                    false => None,
+                };
+                for commit in commits {
+                    match &abbrev {
+                        Some(abbrev) => println!("{}", abbrev.apply(&commit)),
+                        None => println!("{}", commit),
+                    }
+                }
+            }
+        }
+        Some(Commands::Cherry {
+            verbose,
+            upstream,
+            head,
+            limit,
+        }) => {
+            let repo = repo_find(".")?;
+            let upstream = match upstream {
+                Some(upstream) => upstream.clone(),
+                None => branch_upstream(&repo)?.ok_or(
+                    "Could not find a tracked remote branch, please specify <upstream> manually.",
+                )?,
+            };
+            let head = head.as_deref().unwrap_or("HEAD");
+            cherry(&repo, &upstream, head, limit.as_deref(), *verbose)?;
+        }
+        Some(Commands::PatchId { stable, unstable }) => {
+            // Patches can be hashed outside of any repository
+            let stable = match (stable, unstable) {
+                (true, _) => true,
+                (_, true) => false,
+                _ => match repo_find(".") {
+                    Ok(repo) => {
+                        config_get_bool(&repo_config(&repo)?, "patchid.stable")?.unwrap_or(false)
+                    }
+                    Err(_) => false,
+                },
+            };
+
+            let mut input = io::stdin().lock();
+            let mut commit = NULL_SHA.to_string();
+            let mut patch = PatchId::new(stable);
+            let mut line = Vec::new();
+            loop {
+                line.clear();
+                let done = input.read_until(b'\n', &mut line)? == 0;
+                let next = if done {
+                    Some(NULL_SHA.to_string())
+                } else if line.starts_with(b"\\ ") && line.len() > 12 {
+                    // "\ No newline at end of file"
+                    continue;
+                } else if let Some(sha) = patch_id_commit(&line) {
+                    Some(sha)
+                } else if !patch.line(&line) {
+                    Some(NULL_SHA.to_string())
+                } else {
+                    None
+                };
+
+                if let Some(next) = next {
+                    let finished = std::mem::replace(&mut patch, PatchId::new(stable));
+                    if finished.len > 0 {
+                        println!("{} {}", finished.finish(), commit);
+                    }
+                    commit = next;
+                }
+                if done {
+                    break;
+                }
+            }
+        }
+        Some(Commands::NameRev {
+            tags,
+            name_only,
+            no_undefined,
+            always,
+            annotate_stdin,
+            revisions,
+        }) => {
+            let repo = repo_find(".")?;
+            let name_rev = NameRev::new(&repo, *tags, *tags && *name_only)?;
+            if *annotate_stdin {
+                let mut out = io::stdout().lock();
+                for line in io::stdin().lock().lines() {
+                    writeln!(out, "{}", name_rev.annotate(&line?, *name_only))?;
+                }
+                return Ok(());
+            }
+
+            for revision in revisions {
+                let Ok(sha) = object_resolve(&repo, revision) else {
+                    eprintln!("Could not get sha1 for {}. Skipping.", revision);
+                    continue;
+                };
+                if !*name_only {
+                    print!("{} ", revision);
+                }
+                match name_rev.name(&sha) {
+                    Some(name) => println!("{}", name),
+                    None if !*no_undefined => println!("undefined"),
+                    None if *always => println!("{}", Abbrev::new(&repo, None)?.apply(&sha)),
+                    None => return Err(From::from(format!("cannot describe '{}'", sha))),
+                }
+            }
+        }
+        Some(Commands::Reflog {
+            command: ReflogCommand::Expire { expire, all, refs },
+        }) => {
+            let repo = repo_find(".")?;
+            let expire = match expire {
+                Some(expire) => expire.clone(),
+                None => config_get(&repo_config(&repo)?, "gc.reflogexpire")
+                    .unwrap_or("90.days.ago")
+                    .to_string(),
+            };
+            let expire = date::expiry(&expire)
+                .ok_or_else(|| format!("malformed expiration date '{}'", expire))?;
+
+            let refnames = match all {
+                true => reflog_list(&repo)?,
+                false => refs
+                    .iter()
+                    .map(|name| {
+                        reflog_ref(&repo, name)
+                            .ok_or_else(|| format!("reflog could not be found: '{}'", name))
+                    })
+                    .collect::<Result<_, _>>()?,
+            };
+            for refname in refnames {
+                reflog_expire(&repo, &refname, expire)?;
+            }
+        }
+        Some(Commands::VerifyTag { verbose, raw, tags }) => {
+            let repo = repo_find(".")?;
+            let config = repo_config(&repo)?;
+            let mut failed = false;
+            for tag in tags {
+                failed |= !verify_tag(&repo, &config, tag, *verbose, *raw)?;
+            }
+            if failed {
+                process::exit(1);
+            }
+        }
+        Some(Commands::Config {
+            local,
+            worktree,
+            unset,
+            unset_all,
+            replace_all,
+            name,
+            value,
+            ..
+        }) => {
+            let repo = repo_find(".").ok();
+            let in_repo = || repo.as_ref().ok_or("not in a git directory");
+            // --local and --worktree name one file; otherwise reading
+            // sees every layer and writing goes to the repository config
+            let file = match (local, worktree) {
+                (_, true) => Some(repo_worktree_config_path(in_repo()?)),
+                (true, _) => Some(repo_path(in_repo()?, "config")),
+                _ => None,
+            };
+
+            let paths = match &file {
+                Some(path) => vec![path.clone()],
+                None => {
+                    let mut paths = config_protected_paths();
+                    paths.extend(repo.iter().flat_map(repo_config_paths));
+                    paths
+                }
+            };
+
+            let Some(name) = name else {
+                for (name, value) in config_entries(&config_load(&paths)?) {
+                    println!("{}={}", name, value);
+                }
+                return Ok(());
+            };
+            if let Err(e) = config_key_parse(name) {
+                eprintln!("error: {}", e);
+                process::exit(1);
+            }
+
+            if *unset || *unset_all || value.is_some() {
+                let path = match file {
+                    Some(path) => path,
+                    None => repo_path(in_repo()?, "config"),
+                };
+                let all = *unset_all || *replace_all;
+                let matched = config_write(&path, name, value.as_deref(), all)?;
+                if matched > 1 && !all {
+                    eprintln!("warning: {} has multiple values", name);
+                    if value.is_some() {
+                        eprintln!(
+                            "error: cannot overwrite multiple values with a single value\n       \
+                             Use a regexp, --add or --replace-all to change {}.",
+                            name
+                        );
+                    }
+                    process::exit(5);
+                }
+                if matched == 0 && value.is_none() {
+                    process::exit(5);
+                }
+            } else {
+                match config_get(&config_load(&paths)?, name) {
+                    Some(value) => println!("{}", value),
+                    None => process::exit(1),
+                }
+            }
+        }
+        Some(Commands::Var { variable, .. }) => {
+            // Global config counts here too, and no repository is needed
+            let mut paths = config_protected_paths();
+            if let Ok(repo) = repo_find(".") {
+                paths.extend(repo_config_paths(&repo));
+            }
+            let config = config_load(&paths)?;
+
+            let value = |name: &str| -> Result<Option<String>, Box<dyn Error>> {
+                Ok(match name {
+                    "GIT_COMMITTER_IDENT" => Some(ident(&config, "COMMITTER")?),
+                    "GIT_AUTHOR_IDENT" => Some(ident(&config, "AUTHOR")?),
+                    "GIT_EDITOR" => Some(editor(&config)),
+                    "GIT_PAGER" => Some(pager(&config)),
+                    _ => None,
+                })
+            };
+            match variable {
+                Some(variable) => match value(variable)? {
+                    Some(value) => println!("{}", value),
+                    None => {
+                        eprintln!("usage: git var (-l | <variable>)");
+                        process::exit(129);
+                    }
+                },
+                None => {
+                    for (name, value) in config_entries(&config) {
+                        println!("{}={}", name, value);
+                    }
+                    for name in [
+                        "GIT_COMMITTER_IDENT",
+                        "GIT_AUTHOR_IDENT",
+                        "GIT_EDITOR",
+                        "GIT_PAGER",
+                    ] {
+                        // An identity that cannot be made is left out of the list
+                        if let Ok(Some(value)) = value(name) {
+                            println!("{}={}", name, value);
+                        }
+                    }
+                }
+            }
+        }
+        Some(Commands::CheckRefFormat {
+            normalize,
+            allow_onelevel,
+            refspec_pattern,
+            branch,
+            refname,
+            ..
+        }) => {
+            if *branch {
+                // `@{-n}` names the n-th previously checked out branch
+                let previous = match refname
+                    .strip_prefix("@{-")
+                    .and_then(|n| n.strip_suffix('}'))
+                {
+                    Some(n) => match n.parse() {
+                        Ok(n) => reflog_previous_branch(&repo_find(".")?, n)?,
+                        Err(_) => None,
+                    },
+                    None => Some(refname.clone()),
+                };
+                match previous {
+                    Some(name)
+                        if !name.starts_with('-')
+                            && name != "HEAD"
+                            && refname_valid(&format!("refs/heads/{}", name), false, false) =>
+                    {
+                        println!("{}", name)
+                    }
+                    _ => {
+                        return Err(From::from(format!(
+                            "'{}' is not a valid branch name",
+                            refname
+                        )))
+                    }
+                }
+            } else {
+                let name = match normalize {
+                    true => refname_normalize(refname),
+                    false => refname.clone(),
+                };
+                if !refname_valid(&name, *allow_onelevel, *refspec_pattern) {
+                    process::exit(1);
+                }
+                if *normalize {
+                    println!("{}", name);
+                }
+            }
+        }
+        Some(Commands::RevParse { args }) => {
+            let repo = repo_find(".")?;
+            rev_parse(&repo, args)?;
+        }
+        Some(Commands::UpdateIndex {
+            add,
+            remove,
+            refresh,
+            cacheinfo,
+            chmod,
+            skip_worktree,
+            no_skip_worktree,
+            assume_unchanged,
+            no_assume_unchanged,
+            paths,
+        }) => {
+            if chmod
+                .as_deref()
+                .is_some_and(|chmod| chmod != "+x" && chmod != "-x")
+            {
+                return Err(From::from(
+                    "git update-index: --chmod param must be either -x or +x",
+                ));
+            }
+            let flag = |set: bool, unset: bool| match (set, unset) {
+                (true, _) => Some(true),
+                (_, true) => Some(false),
+                _ => None,
+            };
+
+            let repo = repo_find(".")?;
+            let options = UpdateIndexOptions {
+                add: *add,
+                remove: *remove,
+                refresh: *refresh,
+                cacheinfo,
+                chmod: chmod.as_deref(),
+                skip_worktree: flag(*skip_worktree, *no_skip_worktree),
+                assume_unchanged: flag(*assume_unchanged, *no_assume_unchanged),
+            };
+            update_index(&repo, &options, paths)?;
+        }
+        Some(Commands::CheckoutIndex {
+            all,
+            force,
+            update,
+            prefix,
+            paths,
+        }) => {
+            let repo = repo_find(".")?;
+            let options = CheckoutIndexOptions {
+                all: *all,
+                force: *force,
+                update: *update,
+                prefix,
+            };
+            checkout_index(&repo, &options, paths)?;
+        }
+        Some(Commands::LsFiles {
+            cached,
+            deleted,
+            modified,
+            others,
+            ignored,
+            null,
+            exclude,
+            exclude_standard,
+            error_unmatch,
+            paths,
+        }) => {
+            let repo = repo_find(".")?;
+            let options = LsFilesOptions {
+                cached: *cached,
+                deleted: *deleted,
+                modified: *modified,
+                others: *others,
+                ignored: *ignored,
+                null: *null,
+                exclude,
+                exclude_standard: *exclude_standard,
+                error_unmatch: *error_unmatch,
+            };
+            // Paths are relative to the current directory
+            let prefix = match repo.worktree == repo.gitdir {
+                true => Vec::new(),
+                false => worktree_path(&repo, Path::new("."))?,
+            };
+            let specs = pathspecs(&repo, paths)?;
+            let unmatched = ls_files(&repo, &options, &prefix, &specs, &mut io::stdout().lock())?;
+            if options.error_unmatch && !unmatched.is_empty() {
+                for i in unmatched {
+                    eprintln!(
+                        "error: pathspec '{}' did not match any file(s) known to git",
+                        paths[i].display()
+                    );
+                }
+                eprintln!("Did you forget to 'git add'?");
+                process::exit(1);
+            }
+        }
+        Some(Commands::Notes { notes_ref, command }) => {
+            let repo = repo_find(".")?;
+            let command = command
+                .as_ref()
+                .unwrap_or(&NotesCommand::List { object: None });
+            notes(&repo, notes_ref.as_deref(), command)?;
+        }
+        Some(Commands::Repack {
+            all,
+            delete,
+            cruft,
+            cruft_expiration,
+            max_pack_size,
+            quiet,
+        }) => {
+            let repo = repo_find(".")?;
+            let config = repo_config(&repo)?;
+            let cruft_expiration = match cruft_expiration {
+                Some(date) => Some(
+                    date::expiry(date)
+                        .ok_or_else(|| format!("malformed expiration date '{}'", date))?,
+                ),
+                None => None,
+            };
+            let max_pack_size = match max_pack_size
+                .as_deref()
+                .or_else(|| config_get(&config, "pack.packsizelimit"))
+            {
+                Some(value) => {
+                    let size = parse_size(value)
+                        .ok_or_else(|| format!("bad max pack size '{}'", value))?;
+                    // pack-objects will not go below this
+                    if size > 0 && size < 1 << 20 {
+                        eprintln!("warning: minimum pack size limit is 1 MiB");
+                    }
+                    Some(size.max(1 << 20)).filter(|_| size > 0)
+                }
+                None => None,
+            };
+            let options = RepackOptions {
+                all: *all,
+                delete: *delete,
+                cruft: *cruft,
+                cruft_expiration,
+                max_pack_size,
+                quiet: *quiet,
+            };
+            repack(&repo, &options)?;
+        }
+        Some(Commands::VerifyPack {
+            verbose,
+            stat_only,
+            packs,
+        }) => {
+            let mut failed = false;
+            for pack in packs {
+                if let Err(e) = verify_pack(pack, *verbose, *stat_only) {
+                    eprintln!("error: {}", e);
+                    println!("{}: bad", Path::new(pack).with_extension("pack").display());
+                    failed = true;
+                }
+            }
+
+            if failed {
+                process::exit(1);
+            }
+        }
+        None => {
+            let repo = repo_find("target")?;
+            let git_obj: GitObject =
+                read_object(&repo, "4089f12ac270e114bdff71ba7a01ea86fe2f4319")?;
+            write_object(&repo, &git_obj)?;
+            match git_obj {
+                GitObject::Commit(_) => {}
+                GitObject::Tree(_) => {}
+                GitObject::Tag(_) => {}
+                GitObject::Blob(_) => {}
+            }
+            println!("Unknown command");
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Objects git accepts, with their ids, each named for what is odd
+    // about it. The files are the object data without the header.
+    macro_rules! corpus {
+        ($($name:literal => $id:literal,)*) => {
+            &[$(($name, $id, include_bytes!(concat!("../tests/corpus/", $name)))),*]
+        };
+    }
+
+    const CORPUS: &[(&str, &str, &[u8])] = corpus! {
+        "blob-binary.blob" => "b7048348dcd2c76f830ed8c3181f13d854bd9860",
+        "blob-empty.blob" => "e69de29bb2d1d6434b8b29ae775ad8c2e48c5391",
+        "commit-empty-header-value.commit" => "af7de9decc0078b634bcb3ad233084d7d29438ee",
+        "commit-empty-message.commit" => "2c66ffb86468f693ca2390f3e9423d9315bac057",
+        "commit-latin1-crlf.commit" => "e28340c58631e514c907a50c69f0e8dc3e561a48",
+        "commit-mergetag.commit" => "b8e65c08d275a7e1c950b5c3b8576510171ab860",
+        "commit-no-message.commit" => "3b9f044d146082031da5c58b181f11b8a21b1164",
+        "commit-unknown-header.commit" => "9d5162ea32a300272d4fe36740a18bc9e310ddaf",
+        "tag-no-message.tag" => "5ec7913ca5c7478b971f8367cf76d55509910141",
+        "tag-no-tagger.tag" => "62bae030e34af941ef5f8b648668476274db2a8b",
+        "tag-signed.tag" => "62002c04c9bd9eac52a69ea0dddf6b9b67ba6fe0",
+        "tree-modes.tree" => "26d44b8a620a9f734a016f2b61323fe5d155a01c",
+        "tree-zero-padded-mode.tree" => "799448bde17477374097b0e82942d41de3d5abba",
+    };
+
+    fn round_trip(object_type: &str, data: &[u8]) -> Result<Vec<u8>, String> {
+        Ok(match object_type {
+            "blob" => GitBlob::deserialize(data)?.serialize().clone(),
+            "commit" => GitCommit::deserialize(data)?.serialize().clone(),
+            "tag" => GitTag::deserialize(data)?.serialize().clone(),
+            "tree" => GitTree::deserialize(data)?.serialize().clone(),
+            _ => unreachable!(),
+        })
+    }
+
+    fn object_type(name: &str) -> &str {
+        name.rsplit('.').next().unwrap()
+    }
+
+    // A small xorshift generator, so failures are reproducible
+    struct Random(u64);
+
+    impl Random {
+        fn next(&mut self, bound: usize) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 % bound as u64) as usize
+        }
+
+        fn bytes(&mut self, alphabet: &[u8], max_len: usize) -> Vec<u8> {
+            let len = self.next(max_len + 1);
+            (0..len)
+                .map(|_| alphabet[self.next(alphabet.len())])
+                .collect()
+        }
+    }
+
+    #[test]
+    fn corpus_round_trips() {
+        for (name, id, data) in CORPUS {
+            let object_type = object_type(name);
+            assert_eq!(hash_object(object_type, data), *id, "{}", name);
+            let serialized =
+                round_trip(object_type, data).unwrap_or_else(|e| panic!("{}: {}", name, e));
+            assert_eq!(serialized, *data, "{}", name);
+        }
+    }
+
+    #[test]
+    fn corpus_mutations_round_trip_or_are_refused() {
+        for (name, _, data) in CORPUS {
+            for position in 0..data.len() {
+                for byte in [b'\n', b' ', b'\0', b'x'] {
+                    let mut mutated = data.to_vec();
+                    mutated[position] = byte;
+                    if let Ok(serialized) = round_trip(object_type(name), &mutated) {
+                        assert_eq!(serialized, mutated, "{} at {}", name, position);
+                    }
+                }
+                let mut truncated = data.to_vec();
+                truncated.truncate(position);
+                if let Ok(serialized) = round_trip(object_type(name), &truncated) {
+                    assert_eq!(serialized, truncated, "{} cut at {}", name, position);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn generated_commits_round_trip() {
+        let mut random = Random(0x9e3779b97f4a7c15);
+        for _ in 0..2000 {
+            let mut data = b"tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904\n".to_vec();
+            for _ in 0..random.next(4) {
+                let key = random.bytes(b"abcdefgh-", 8);
+                let mut value = random.bytes(b"ab <>\n\t", 24);
+                if key.is_empty() {
+                    continue;
+                }
+                // A value's lines after the first are indented by one space
+                value = value
+                    .split(|&b| b == b'\n')
+                    .collect::<Vec<_>>()
+                    .join(&b"\n "[..]);
+                data.extend(key);
+                data.push(b' ');
+                data.extend(value);
+                data.push(b'\n');
+            }
+            if random.next(4) != 0 {
+                data.push(b'\n');
+                data.extend(random.bytes(b"ab \n\r\0\xff", 32));
+            }
+
+            let serialized = round_trip("commit", &data).unwrap();
+            assert_eq!(serialized, data, "{:?}", String::from_utf8_lossy(&data));
+            let kvlm = parse_kvlm(&data).unwrap();
+            assert_eq!(
+                parse_kvlm(&kvlm.serialize()).unwrap().serialize(),
+                kvlm.serialize()
+            );
+        }
+    }
+
+    // A directory of its own under the system temp directory, removed
+    // with everything in it on drop
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new() -> Self {
+            TempDir(create_unique_dir(&std::env::temp_dir(), "eekgit-test-").unwrap())
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    // A throwaway repository with the history below, each commit named
+    // by its message. B is committed after C although it is on the
+    // first-parent line, so the orderings differ.
+    //
+    //   A (1) - B (3) ---- M (5)
+    //     \               /
+    //      C (2) - D (4)
+    struct RevWalkFixture {
+        repo: GitRepository,
+        names: HashMap<String, &'static str>,
+        shas: HashMap<&'static str, String>,
+    }
+
+    impl RevWalkFixture {
+        fn new() -> Self {
+            let dir = create_unique_dir(&std::env::temp_dir(), "eekgit-test-").unwrap();
+            repo_create(dir.to_str().unwrap()).unwrap();
+            let repo = repo_open(dir.to_str().unwrap()).unwrap();
+            let mut fixture = RevWalkFixture {
+                repo,
+                names: HashMap::new(),
+                shas: HashMap::new(),
+            };
+
+            fixture.commit("A", &[], 1, &[("f", "a")]);
+            fixture.commit("C", &["A"], 2, &[("f", "a"), ("g", "c")]);
+            fixture.commit("B", &["A"], 3, &[("f", "b")]);
+            fixture.commit("D", &["C"], 4, &[("f", "a"), ("g", "d")]);
+            fixture.commit("M", &["B", "D"], 5, &[("f", "b"), ("g", "d")]);
+            fixture
+        }
+
+        fn commit(
+            &mut self,
+            name: &'static str,
+            parents: &[&str],
+            time: i64,
+            files: &[(&str, &str)],
+        ) {
+            let leaves = files
+                .iter()
+                .map(|(path, content)| GitTreeLeaf {
+                    mode: String::from("100644"),
+                    path: path.as_bytes().to_vec(),
+                    sha: write_object(
+                        &self.repo,
+                        &GitObject::Blob(GitBlob::new(content.as_bytes().to_vec())),
+                    )
+                    .unwrap(),
+                })
+                .collect::<Vec<_>>();
+            let tree = GitObject::Tree(GitTree::new(tree_serialize(&leaves).unwrap()));
+            let mut data = format!("tree {}\n", write_object(&self.repo, &tree).unwrap());
+            for parent in parents {
+                data.push_str(&format!("parent {}\n", self.shas[parent]));
+            }
+            for role in ["author", "committer"] {
+                data.push_str(&format!("{} T <t@example.com> {} +0000\n", role, time));
+            }
+            data.push_str(&format!("\n{}\n", name));
+
+            let commit = GitObject::Commit(GitCommit::new(data.into_bytes()));
+            let sha = write_object(&self.repo, &commit).unwrap();
+            self.names.insert(sha.clone(), name);
+            self.shas.insert(name, sha);
+        }
+
+        fn walk(&self, setup: impl FnOnce(&mut RevWalk, &HashMap<&str, String>)) -> Vec<&str> {
+            let mut walk = RevWalk::new(&self.repo);
+            setup(&mut walk, &self.shas);
+            walk.map(|sha| self.names[&sha.unwrap()]).collect()
+        }
+    }
+
+    impl Drop for RevWalkFixture {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.repo.worktree);
+        }
+    }
+
+    // The expected orders are what git rev-list gives for the same objects
+    #[test]
+    fn rev_walk_orders() {
+        let fixture = RevWalkFixture::new();
+        let walk = |sort, reverse| {
+            fixture.walk(|walk, shas| {
+                walk.push(&shas["M"]).unwrap();
+                walk.sort = sort;
+                walk.reverse = reverse;
+            })
+        };
+        assert_eq!(walk(RevSort::Default, false), ["M", "D", "B", "C", "A"]);
+        assert_eq!(walk(RevSort::Date, false), ["M", "D", "B", "C", "A"]);
+        assert_eq!(walk(RevSort::Topo, false), ["M", "D", "C", "B", "A"]);
+        assert_eq!(walk(RevSort::Topo, true), ["A", "B", "C", "D", "M"]);
+    }
+
+    #[test]
+    fn rev_walk_limits() {
+        let fixture = RevWalkFixture::new();
+        let hidden = fixture.walk(|walk, shas| {
+            walk.push(&shas["M"]).unwrap();
+            walk.hide(&shas["B"]).unwrap();
+        });
+        assert_eq!(hidden, ["M", "D", "C"]);
+        let first_parent = fixture.walk(|walk, shas| {
+            walk.push(&shas["M"]).unwrap();
+            walk.first_parent = true;
+        });
+        assert_eq!(first_parent, ["M", "B", "A"]);
+        let counted = fixture.walk(|walk, shas| {
+            walk.push(&shas["M"]).unwrap();
+            walk.max_count = Some(2);
+        });
+        assert_eq!(counted, ["M", "D"]);
+        for (path, expected) in [("f", ["B", "A"]), ("g", ["D", "C"])] {
+            let limited = fixture.walk(|walk, shas| {
+                walk.push(&shas["M"]).unwrap();
+                walk.paths =
+                    Pathspec::parse(&[path], &|path| path_bytes(path.as_os_str())).unwrap();
+            });
+            assert_eq!(limited, expected, "-- {}", path);
+        }
+    }
+
+    #[test]
+    fn refnames_normalize_like_git() {
+        assert_eq!(refname_normalize("//refs//heads///x"), "refs/heads/x");
+        for name in ["refs/heads/x/", "refs/heads/x//"] {
+            assert!(
+                !refname_valid(&refname_normalize(name), false, false),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn trees_out_of_order_are_refused() {
+        let sha = [0x11; 20];
+        let tree = [&b"100644 b\0"[..], &sha, b"100644 a\0", &sha].concat();
+        assert!(GitTree::deserialize(&tree).is_err());
+        let tree = [&b"100644 a\0"[..], &sha, b"100644 a\0", &sha].concat();
+        assert!(GitTree::deserialize(&tree).is_err());
+    }
+
+    #[test]
+    fn config_keys_are_checked_like_git() {
+        for name in ["core.x", "Core.X-y", "a.b c.d", "a.b\"c.d", "a..b"] {
+            assert!(config_key_parse(name).is_ok(), "{}", name);
+        }
+        for name in [
+            "foo", ".x", "core.", "core.1x", "co_re.x", "core.x_y", "a.b\nc.d",
+        ] {
+            assert!(config_key_parse(name).is_err(), "{:?}", name);
+        }
+
+        let dir = TempDir::new();
+        let path = dir.0.join("config");
+        fs::write(&path, "[core]\n\tbare = false\n").unwrap();
+        let injected = "core.x = 1\n[core]\n\tfsmonitor";
+        assert!(config_write(&path, injected, Some("touch pwned"), false).is_err());
+        assert!(config_write(&path, "a.b\n[core]\nfsmonitor=x.c", Some("v"), false).is_err());
+        let value = "1\n[core]\n\tfsmonitor = touch pwned";
+        config_write(&path, "core.x", Some(value), false).unwrap();
+        let config = config_load(&[path]).unwrap();
+        assert_eq!(config_get(&config, "core.fsmonitor"), None);
+        assert_eq!(config_get(&config, "core.x"), Some(value));
+    }
+
+    #[test]
+    fn config_layers_and_writes() {
+        let dir = TempDir::new();
+        let global = dir.0.join("global");
+        let local = dir.0.join("local");
+        fs::write(&global, "[core]\n\tx = 1\n[user]\n\tname = a\n").unwrap();
+        fs::write(&local, "# kept\n[core]\n\tx = 2\n").unwrap();
+        let paths = [global.clone(), local.clone()];
+        let config = config_load(&paths).unwrap();
+        assert_eq!(config_get_all(&config, "core.x"), ["1", "2"]);
+        assert_eq!(config_get(&config, "core.x"), Some("2"));
+        assert_eq!(config_get(&config, "user.name"), Some("a"));
+
+        assert_eq!(config_write(&local, "core.x", Some("3"), false).unwrap(), 1);
+        assert_eq!(
+            config_write(&local, "core.y", Some(" y "), false).unwrap(),
+            0
+        );
+        config_write(&local, "remote.origin.url", Some("u"), false).unwrap();
+        assert_eq!(
+            fs::read_to_string(&local).unwrap(),
+            "# kept\n[core]\n\tx = 3\n\ty = \" y \"\n[remote \"origin\"]\n\turl = u\n"
+        );
+        let config = config_load(&paths).unwrap();
+        assert_eq!(config_get(&config, "core.x"), Some("3"));
+        assert_eq!(config_get(&config, "remote.origin.url"), Some("u"));
+
+        // Several values are only replaced or removed all together
+        fs::write(&local, "[core]\n\tx = 1\n\tx = 2\n").unwrap();
+        assert_eq!(config_write(&local, "core.x", Some("3"), false).unwrap(), 2);
+        assert_eq!(config_write(&local, "core.x", None, false).unwrap(), 2);
+        assert_eq!(config_write(&local, "core.x", Some("3"), true).unwrap(), 2);
+        assert_eq!(fs::read_to_string(&local).unwrap(), "[core]\n\tx = 3\n");
+        assert_eq!(config_write(&local, "core.x", None, false).unwrap(), 1);
+        assert_eq!(config_write(&local, "core.x", None, false).unwrap(), 0);
+        assert_eq!(fs::read_to_string(&local).unwrap(), "[core]\n");
+    }
+
+    #[test]
+    fn reftables_round_trip() {
+        let sha = |i: usize| format!("{:040x}", i + 1);
+        let mut refs: Vec<_> = (0..300)
+            .map(|i| {
+                (
+                    format!("refs/heads/b{:03}", i),
+                    ReftableValue::Object(sha(i)),
+                )
+            })
+            .collect();
+        refs.push(("refs/heads/gone".into(), ReftableValue::Deleted));
+        refs.push((
+            "refs/heads/lnk".into(),
+            ReftableValue::Symref("refs/heads/b001".into()),
+        ));
+        refs.sort_by(|a, b| a.0.cmp(&b.0));
+        let line = format!("{} {} T U <t@example.com> 1200 -0130\tm", sha(0), sha(1));
+        let entry = ReflogEntry {
+            old: sha(0),
+            new: sha(1),
+            time: 1200,
+            tz: "-0130".into(),
+            message: "m".into(),
+            line: line.clone(),
+        };
+        let logs = vec![
+            (("refs/heads/b000".to_string(), 7), Some(entry)),
+            (("refs/heads/b000".to_string(), 3), None),
+        ];
+
+        let table = reftable_parse(&reftable_encode((3, 7), &refs, &logs).unwrap()).unwrap();
+        assert_eq!((table.min_update_index, table.max_update_index), (3, 7));
+        let names = |refs: &[(String, ReftableValue)]| {
+            refs.iter()
+                .map(|(name, _)| name.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(names(&table.refs), names(&refs));
+        assert!(table.refs == refs);
+        assert_eq!(table.logs.len(), 2);
+        assert_eq!(table.logs[0].0, logs[0].0);
+        let read = table.logs[0].1.as_ref().unwrap();
+        assert_eq!(
+            (read.time, &read.tz[..], &read.line),
+            (1200, "-0130", &line)
+        );
+        assert_eq!(table.logs[1].0, logs[1].0);
+        assert!(table.logs[1].1.is_none());
+
+        for len in [0, 100, 4000] {
+            let data = reftable_encode((3, 7), &refs, &logs).unwrap();
+            assert!(reftable_parse(&data[..len]).is_err(), "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn reftable_stack_stays_compact() {
+        let dir = TempDir::new();
+        let path = dir.0.join("repo");
+        repo_create(path.to_str().unwrap()).unwrap();
+        let mut repo = repo_open(path.to_str().unwrap()).unwrap();
+        fs::create_dir(repo_path(&repo, "reftable")).unwrap();
+        fs::write(repo_path(&repo, "reftable/tables.list"), "").unwrap();
+        repo.reftable = true;
+
+        for i in 0..40 {
+            reftable_add(&repo, |refs| {
+                assert_eq!(refs.len(), i);
+                let name = format!("refs/heads/b{:02}", i);
+                Ok((
+                    vec![(name, ReftableValue::Object(format!("{:040x}", i + 1)))],
+                    vec![],
+                ))
+            })
+            .unwrap();
+            let tables = reftable_stack_names(&repo).unwrap().len();
+            assert!(tables <= 6, "{} tables after {} adds", tables, i + 1);
+        }
+        let refs = reftable_refs(&repo).unwrap();
+        assert_eq!(refs.len(), 40);
+        assert!(refs["refs/heads/b07"] == ReftableValue::Object(format!("{:040x}", 8)));
+
+        // Only the tables in the stack are left, and no lock
+        let mut files: Vec<_> = fs::read_dir(repo_path(&repo, "reftable"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name != "tables.list")
+            .collect();
+        files.sort();
+        let mut names = reftable_stack_names(&repo).unwrap();
+        names.sort();
+        assert_eq!(files, names);
+
+        // A failed update leaves the stack as it was
+        let failed = reftable_add(&repo, |_| Err(From::from("refused")));
+        assert!(failed.is_err());
+        assert!(!repo_path(&repo, "reftable/tables.list.lock").exists());
+        assert_eq!(reftable_refs(&repo).unwrap().len(), 40);
+
+        // Another writer's lock is left alone
+        let lock = repo_path(&repo, "reftable/tables.list.lock");
+        fs::write(&lock, "").unwrap();
+        assert!(reftable_add(&repo, |_| Ok((vec![], vec![]))).is_err());
+        assert!(lock.exists());
+    }
+
+    #[test]
+    fn paths_that_open_dot_git_are_refused() {
+        for path in [
+            &b"a/b"[..],
+            b".gitignore",
+            b"a/.git-x",
+            b"x.git",
+            b"git~2",
+            b".gi t",
+            b".gi\xfft",
+            "\u{200c}gi\u{200c}t".as_bytes(),
+            "a/.g\u{200c}itx".as_bytes(),
+        ] {
+            assert!(verify_path(path), "{:?}", String::from_utf8_lossy(path));
+        }
+        for path in [
+            &b""[..],
+            b"a//b",
+            b"./a",
+            b"a/..",
+            b".git",
+            b"a/.GIT/config",
+            b".git.",
+            b".git . ",
+            b"GIT~1",
+            b"git~1/config",
+            b"a\\.git\\config",
+            b".git::$INDEX_ALLOCATION/config",
+            b".git:stream",
+            b"git~1 ::$DATA",
+            ".g\u{200c}it".as_bytes(),
+            "a/\u{feff}.GI\u{200d}T/config".as_bytes(),
+            ".git\u{206f}".as_bytes(),
+            b".git\xff",
+        ] {
+            assert!(!verify_path(path), "{:?}", String::from_utf8_lossy(path));
+        }
+    }
+
+    #[test]
+    fn diff_scripts_are_short_and_apply() {
+        fn lines(text: &[u8]) -> Vec<&[u8]> {
+            text.chunks(1).collect()
+        }
+        // Myers' own example, five edits apart
+        let ops = diff_lines(&lines(b"abcabba"), &lines(b"cbabac"));
+        assert_eq!(ops.iter().filter(|&&op| op != b' ').count(), 5);
+
+        let mut random = Random(12345);
+        for _ in 0..500 {
+            let (old, new) = (random.bytes(b"abcd", 40), random.bytes(b"abcd", 40));
+            let (old, new) = (lines(&old), lines(&new));
+            let (mut i, mut j) = (0, 0);
+            for op in diff_lines(&old, &new) {
+                match op {
+                    b' ' => {
+                        assert_eq!(old[i], new[j]);
+                        (i, j) = (i + 1, j + 1);
+                    }
+                    b'-' => i += 1,
+                    _ => j += 1,
+                }
+            }
+            assert_eq!((i, j), (old.len(), new.len()));
+        }
+
+        // Nothing in common: as many edits as lines
+        let old: Vec<Vec<u8>> = (0..3000).map(|i| format!("{}\n", i).into_bytes()).collect();
+        let new: Vec<Vec<u8>> = (0..3000)
+            .map(|i| format!("x{}\n", i).into_bytes())
+            .collect();
+        let old: Vec<&[u8]> = old.iter().map(Vec::as_slice).collect();
+        let new: Vec<&[u8]> = new.iter().map(Vec::as_slice).collect();
+        assert!(diff_lines(&old, &new).iter().all(|&op| op != b' '));
+    }
+
+    // A repository to push to, and another holding the commits to push
+    struct PushFixture {
+        server: GitRepository,
+        client: GitRepository,
+        _dir: TempDir,
+    }
+
+    impl PushFixture {
+        fn new() -> Self {
+            let dir = TempDir::new();
+            let open = |name: &str| {
+                let path = dir.0.join(name);
+                repo_create(path.to_str().unwrap()).unwrap();
+                fs::write(path.join(".git/HEAD"), "ref: refs/heads/master\n").unwrap();
+                repo_open(path.to_str().unwrap()).unwrap()
+            };
+            PushFixture {
+                server: open("server"),
+                client: open("client"),
+                _dir: dir,
+            }
+        }
+
+        fn commit(&self, parents: &[&str], message: &str) -> String {
+            let tree = GitObject::Tree(GitTree::new(tree_serialize(&[]).unwrap()));
+            let mut data = format!("tree {}\n", write_object(&self.client, &tree).unwrap());
+            for parent in parents {
+                data.push_str(&format!("parent {}\n", parent));
+            }
+            for role in ["author", "committer"] {
+                data.push_str(&format!("{} T <t@example.com> 1 +0000\n", role));
+            }
+            data.push_str(&format!("\n{}\n", message));
+            write_object(
+                &self.client,
+                &GitObject::Commit(GitCommit::new(data.into_bytes())),
+            )
+            .unwrap()
+        }
+
+        // Push `(old, new, ref)` updates with their objects, giving the
+        // report-status lines after the unpack status
+        fn push(&mut self, updates: &[(&str, &str, &str)], atomic: bool) -> Vec<String> {
+            let mut input = Vec::new();
+            for (i, (old, new, name)) in updates.iter().enumerate() {
+                let mut line = format!("{} {} {}", old, new, name);
+                if i == 0 {
+                    line.push_str("\0report-status side-band-64k");
+                    if atomic {
+                        line.push_str(" atomic");
+                    }
+                }
+                pkt_line_write(&mut input, line.as_bytes()).unwrap();
+            }
+            pkt_flush(&mut input).unwrap();
+            let mut shas = vec![hash_object("tree", &tree_serialize(&[]).unwrap())];
+            shas.extend(
+                updates
+                    .iter()
+                    .filter(|(_, new, _)| *new != NULL_SHA)
+                    .map(|(_, new, _)| new.to_string()),
+            );
+            input.extend(write_pack(&self.client, &shas).unwrap());
+
+            let mut output = Vec::new();
+            let mut reader = io::BufReader::new(&input[..]);
+            receive_pack(
+                &mut self.server,
+                &mut reader,
+                &mut output,
+                ServiceMode::StatelessRpc,
+            )
+            .unwrap();
+            // The report comes on band 1; messages on band 2 are dropped
+            let (mut output, mut report) = (&output[..], Vec::new());
+            while let Some(packet) = pkt_line_read(&mut output).unwrap() {
+                if packet[0] == 1 {
+                    report.extend_from_slice(&packet[1..]);
+                }
+            }
+            let (mut report, mut lines) = (&report[..], Vec::new());
+            while let Some(line) = pkt_line_read_text(&mut report).unwrap() {
+                lines.push(line.trim_end().to_string());
+            }
+            assert_eq!(lines.first().map(String::as_str), Some("unpack ok"));
+
+            // Whatever happened, the quarantine is gone
+            let objects = fs::read_dir(repo_path(&self.server, "objects")).unwrap();
+            assert!(objects
+                .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+                .all(|name| !name.starts_with("incoming-")));
+            lines.split_off(1)
+        }
+
+        fn resolve(&self, name: &str) -> Option<String> {
+            ref_resolve(&self.server, name).unwrap()
+        }
+
+        fn config(&self, name: &str, value: &str) {
+            config_write(&repo_path(&self.server, "config"), name, Some(value), false).unwrap();
+        }
+    }
+
+    #[test]
+    fn pushes_follow_the_deny_rules() {
+        let mut fixture = PushFixture::new();
+        let a = fixture.commit(&[], "a");
+        let b = fixture.commit(&[&a], "b");
+        let c = fixture.commit(&[], "c");
+
+        let report = fixture.push(&[(NULL_SHA, &a, "refs/heads/master")], false);
+        assert_eq!(
+            report,
+            ["ng refs/heads/master branch is currently checked out"]
+        );
+        fixture.config("receive.denyCurrentBranch", "warn");
+        let report = fixture.push(&[(NULL_SHA, &a, "refs/heads/master")], false);
+        assert_eq!(report, ["ok refs/heads/master"]);
+        assert_eq!(fixture.resolve("refs/heads/master"), Some(a.clone()));
+
+        let report = fixture.push(&[(&a, NULL_SHA, "refs/heads/master")], false);
+        assert_eq!(
+            report,
+            ["ng refs/heads/master deletion of the current branch prohibited"]
+        );
+
+        fixture.config("receive.denyNonFastForwards", "true");
+        fixture.push(&[(NULL_SHA, &b, "refs/heads/topic")], false);
+        let report = fixture.push(&[(&b, &c, "refs/heads/topic")], false);
+        assert_eq!(report, ["ng refs/heads/topic non-fast-forward"]);
+        let report = fixture.push(&[(&b, NULL_SHA, "refs/heads/topic")], false);
+        assert_eq!(report, ["ok refs/heads/topic"]);
+        assert_eq!(fixture.resolve("refs/heads/topic"), None);
+
+        let report = fixture.push(&[(NULL_SHA, &a, "refs/heads/a..b")], false);
+        assert_eq!(report, ["ng refs/heads/a..b funny refname"]);
+    }
+
+    #[test]
+    fn atomic_pushes_land_whole_or_not_at_all() {
+        let mut fixture = PushFixture::new();
+        let a = fixture.commit(&[], "a");
+        let b = fixture.commit(&[&a], "b");
+        let updates = [
+            (NULL_SHA, a.as_str(), "refs/heads/x"),
+            (b.as_str(), a.as_str(), "refs/heads/y"),
+        ];
+
+        let report = fixture.push(&updates, true);
+        assert_eq!(
+            report,
+            [
+                "ng refs/heads/x failed to update ref",
+                "ng refs/heads/y failed to update ref"
+            ]
+        );
+        assert_eq!(fixture.resolve("refs/heads/x"), None);
+        let locks = |fixture: &PushFixture| {
+            fs::read_dir(repo_path(&fixture.server, "refs/heads"))
+                .unwrap()
+                .filter(|entry| entry.as_ref().unwrap().path().extension().is_some())
+                .count()
+        };
+        assert_eq!(locks(&fixture), 0);
+
+        let report = fixture.push(&updates, false);
+        assert_eq!(
+            report,
+            ["ok refs/heads/x", "ng refs/heads/y failed to update ref"]
+        );
+        assert_eq!(fixture.resolve("refs/heads/x"), Some(a.clone()));
+        assert_eq!(locks(&fixture), 0);
+
+        // A refusal by a check fails the rest of an atomic push too
+        let updates = [
+            (NULL_SHA, a.as_str(), "refs/heads/master"),
+            (NULL_SHA, b.as_str(), "refs/heads/z"),
+        ];
+        let report = fixture.push(&updates, true);
+        assert_eq!(
+            report,
+            [
+                "ng refs/heads/master branch is currently checked out",
+                "ng refs/heads/z atomic push failure"
+            ]
+        );
+        assert_eq!(fixture.resolve("refs/heads/z"), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn hooks_can_refuse_pushes() {
+        use std::os::unix::fs::PermissionsExt;
+        let mut fixture = PushFixture::new();
+        let hook = |fixture: &PushFixture, name: &str, script: &str| {
+            let path = repo_file(&fixture.server, &format!("hooks/{}", name));
+            fs::write(&path, format!("#!/bin/sh\n{}\n", script)).unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        };
+        let a = fixture.commit(&[], "a");
+
+        // The objects of a refused push never leave the quarantine
+        hook(&fixture, "pre-receive", "exit 1");
+        let report = fixture.push(&[(NULL_SHA, &a, "refs/heads/x")], false);
+        assert_eq!(report, ["ng refs/heads/x pre-receive hook declined"]);
+        assert!(read_object(&fixture.server, &a).is_err());
+
+        hook(&fixture, "pre-receive", "exit 0");
+        hook(&fixture, "update", "test \"$1\" != refs/heads/no");
+        let updates = [
+            (NULL_SHA, a.as_str(), "refs/heads/no"),
+            (NULL_SHA, a.as_str(), "refs/heads/x"),
+        ];
+        let report = fixture.push(&updates, false);
+        assert_eq!(
+            report,
+            ["ng refs/heads/no hook declined", "ok refs/heads/x"]
+        );
+        assert!(read_object(&fixture.server, &a).is_ok());
+        assert_eq!(fixture.resolve("refs/heads/no"), None);
+    }
+
+    #[test]
+    fn failed_ref_transactions_leave_no_locks() {
+        let fixture = PushFixture::new();
+        let a = fixture.commit(&[], "a");
+        let repo = &fixture.server;
+        assert!(ref_transaction(repo, &[("refs/heads/x", &a, &a)]).is_err());
+        assert!(ref_transaction(
+            repo,
+            &[("refs/heads/y", NULL_SHA, &a), ("refs/heads/z", &a, &a)]
+        )
+        .is_err());
+        assert!(!repo_path(repo, "refs/heads/x.lock").exists());
+        assert!(!repo_path(repo, "refs/heads/y.lock").exists());
+        assert!(!repo_path(repo, "refs/heads/y").exists());
+
+        // Someone else's lock stays
+        fs::write(repo_path(repo, "refs/heads/y.lock"), "").unwrap();
+        assert!(ref_transaction(repo, &[("refs/heads/y", NULL_SHA, &a)]).is_err());
+        assert!(repo_path(repo, "refs/heads/y.lock").exists());
+    }
+
+    #[test]
+    fn daemon_drops_idle_and_excess_connections() {
+        let start = |max_connections| {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let address = listener.local_addr().unwrap();
+            let options = DaemonOptions {
+                base_path: None,
+                export_all: false,
+                whitelist: Vec::new(),
+                init_timeout: Some(Duration::from_millis(100)),
+                timeout: Some(Duration::from_millis(100)),
+            };
+            thread::spawn(move || {
+                let _ = daemon(options, listener, max_connections);
+            });
+            address
+        };
+        let read_all = |stream: &mut TcpStream| {
+            stream
+                .set_read_timeout(Some(Duration::from_secs(10)))
+                .unwrap();
+            let mut data = Vec::new();
+            stream.read_to_end(&mut data).unwrap();
+            data
+        };
+
+        // Saying nothing for too long ends the connection
+        let mut stream = TcpStream::connect(start(1)).unwrap();
+        assert_eq!(read_all(&mut stream), b"");
+
+        let mut stream = TcpStream::connect(start(0)).unwrap();
+        let data = read_all(&mut stream);
+        let line = pkt_line_read_text(&mut &data[..]).unwrap().unwrap();
+        assert!(line.starts_with("ERR "), "{}", line);
+    }
+
+    // Send `request` on `stream`, giving the status and body of the answer
+    fn http_exchange(stream: &mut BufReader<TcpStream>, request: &str) -> (String, Vec<u8>) {
+        stream.get_mut().write_all(request.as_bytes()).unwrap();
+        let status = http_read_line(stream).unwrap().unwrap();
+        let mut length = 0;
+        while let Some(line) = http_read_line(stream)
+            .unwrap()
+            .filter(|line| !line.is_empty())
+        {
+            if let Some(value) = line.strip_prefix("Content-Length: ") {
+                length = value.parse().unwrap();
+            }
+        }
+        let mut body = vec![0; length];
+        stream.read_exact(&mut body).unwrap();
+        (status, body)
+    }
+
+    #[test]
+    fn http_serves_only_what_it_should() {
+        let dir = TempDir::new();
+        let root = dir.0.join("root");
+        let repo = repo_create(root.join("repo").to_str().unwrap()).unwrap();
+        config_write(
+            &repo_path(&repo, "config"),
+            "http.maxRequestBuffer",
+            Some("10"),
+            false,
+        )
+        .unwrap();
+        repo_create(dir.0.join("outside").to_str().unwrap()).unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(dir.0.join("outside"), root.join("link")).unwrap();
+
+        let start = |max_connections| {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let address = listener.local_addr().unwrap();
+            let options = HttpOptions {
+                root: fs::canonicalize(&root).unwrap(),
+                credentials: Some("user:secret".to_string()),
+                timeout: Some(Duration::from_secs(10)),
+            };
+            thread::spawn(move || {
+                let _ = http_server(options, listener, max_connections);
+            });
+            BufReader::new(TcpStream::connect(address).unwrap())
+        };
+        let get = |path: &str, auth: bool| {
+            let auth = match auth {
+                true => format!("Authorization: Basic {}\r\n", base64_encode(b"user:secret")),
+                false => String::new(),
+            };
+            format!(
+                "GET {}/info/refs?service=git-upload-pack HTTP/1.1\r\n{}\r\n",
+                path, auth
+            )
+        };
+
+        // One connection carries several requests, refused or not
+        let mut stream = start(4);
+        let (status, _) = http_exchange(&mut stream, &get("/repo", false));
+        assert_eq!(status, "HTTP/1.1 401 Unauthorized");
+        let (status, body) = http_exchange(&mut stream, &get("/repo", true));
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert!(body.starts_with(b"001e# service=git-upload-pack\n"));
+        for path in ["/../outside", "/repo/../../outside", "/link", "/missing"] {
+            let (status, _) = http_exchange(&mut stream, &get(path, true));
+            assert_eq!(status, "HTTP/1.1 404 Not Found", "{}", path);
+        }
+
+        // A body over http.maxRequestBuffer is refused before it is sent,
+        // which ends the connection
+        let post = format!(
+            "POST /repo/git-upload-pack HTTP/1.1\r\n\
+             Authorization: Basic {}\r\nContent-Length: 11\r\n\r\n",
+            base64_encode(b"user:secret")
+        );
+        let (status, _) = http_exchange(&mut stream, &post);
+        assert_eq!(status, "HTTP/1.1 413 Payload Too Large");
+        assert_eq!(stream.read(&mut [0]).unwrap(), 0);
+
+        // Answered before any request is sent
+        let mut stream = start(0);
+        let (status, _) = http_exchange(&mut stream, "");
+        assert_eq!(status, "HTTP/1.1 503 Service Unavailable");
+    }
+
+    #[test]
+    fn identities_resolve_like_git() {
+        let dir = TempDir::new();
+        let resolve = |config: &str, env: &[(&str, &str)]| {
+            let path = dir.0.join("config");
+            fs::write(&path, config).unwrap();
+            let config = config_load(&[path]).unwrap();
+            let env: HashMap<String, String> = env
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .chain([("GIT_AUTHOR_DATE".to_string(), "1 +0100".to_string())])
+                .collect();
+            ident_resolve(&config, "AUTHOR", &|key| env.get(key).cloned())
+                .map_err(|e| e.to_string())
+        };
+
+        let user = "[user]\n\tname = U\n\temail = u@example.com\n";
+        assert_eq!(resolve(user, &[]).unwrap(), "U <u@example.com> 1 +0100");
+        let env = [
+            ("GIT_AUTHOR_NAME", "E"),
+            ("GIT_AUTHOR_EMAIL", "e@example.com"),
+        ];
+        assert_eq!(resolve(user, &env).unwrap(), "E <e@example.com> 1 +0100");
+        let author = format!("{}[author]\n\tname = A\n", user);
+        assert_eq!(resolve(&author, &[]).unwrap(), "A <u@example.com> 1 +0100");
+
+        // What would break the line is dropped
+        let env = [
+            ("GIT_AUTHOR_NAME", " <Evil>\nName. "),
+            ("GIT_AUTHOR_EMAIL", "<a@b>\n"),
+        ];
+        assert_eq!(resolve("", &env).unwrap(), "EvilName <a@b> 1 +0100");
+        let env = [("GIT_AUTHOR_NAME", " <> "), ("GIT_AUTHOR_EMAIL", "a@b")];
+        assert_eq!(
+            resolve("", &env).unwrap_err(),
+            "name consists only of disallowed characters:  <> "
+        );
+        let env = [("GIT_AUTHOR_NAME", ""), ("GIT_AUTHOR_EMAIL", "a@b")];
+        assert_eq!(
+            resolve("", &env).unwrap_err(),
+            "empty ident name (for <a@b>) not allowed"
+        );
+
+        // EMAIL stands in for user.email, unless only config may be used
+        let name = "[user]\n\tname = U\n";
+        assert_eq!(
+            resolve(name, &[("EMAIL", "m@example.com")]).unwrap(),
+            "U <m@example.com> 1 +0100"
+        );
+        let config_only = format!("{}\tuseConfigOnly = true\n", name);
+        assert_eq!(
+            resolve(&config_only, &[("EMAIL", "m@example.com")]).unwrap_err(),
+            "no email was given and auto-detection is disabled"
+        );
+        assert_eq!(
+            resolve("[user]\n\tuseConfigOnly = true\n", &[]).unwrap_err(),
+            "no email was given and auto-detection is disabled"
+        );
+        assert_eq!(
+            resolve("", &[]).unwrap_err(),
+            "author identity unknown; set user.name and user.email"
+        );
+    }
+
+    #[test]
+    fn safe_directory_values_apply_in_order() {
+        let safe = |values: &[&str], path: &str| {
+            let mut config = String::new();
+            for value in values {
+                config.push_str(&format!("[safe]\n\tdirectory = {}\n", value));
+            }
+            safe_directory(&Ini::load_from_str(&config).unwrap(), path)
+        };
+        assert!(!safe(&[], "/srv/repo"));
+        assert!(safe(&["/srv/repo"], "/srv/repo"));
+        assert!(!safe(&["/srv/repo"], "/srv/repo/sub"));
+        assert!(!safe(&["/srv/rep"], "/srv/repo"));
+        assert!(safe(&["*"], "/srv/repo"));
+
+        // An empty value forgets everything listed before it
+        assert!(!safe(&["*", ""], "/srv/repo"));
+        assert!(!safe(&["/srv/repo", ""], "/srv/repo"));
+        assert!(safe(&["*", "", "/srv/repo"], "/srv/repo"));
+        assert!(safe(&["/srv/repo", "/other"], "/srv/repo"));
+
+        // A trailing `/*` trusts what is below the directory, not itself
+        assert!(safe(&["/srv/*"], "/srv/repo"));
+        assert!(safe(&["/srv/*"], "/srv/a/b"));
+        assert!(!safe(&["/srv/*"], "/srv"));
+        assert!(!safe(&["/srv/*"], "/srvx/repo"));
+        assert!(!safe(&["/srv/*", ""], "/srv/repo"));
+        assert!(!safe(&["/srv*"], "/srv/repo"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn repositories_of_other_users_are_not_owned() {
+        use std::os::unix::fs::chown;
+        let dir = TempDir::new();
+        let path = dir.0.join("repo");
+        repo_create(path.to_str().unwrap()).unwrap();
+        let repo = repo_open(path.to_str().unwrap()).unwrap();
+        assert!(repo_owned(&repo));
+
+        // Only root can give the repository away
+        // SAFETY: geteuid cannot fail and touches no memory
+        if unsafe { libc::geteuid() } != 0 || std::env::var_os("SUDO_UID").is_some() {
+            return;
+        }
+        chown(&repo.gitdir, Some(12345), None).unwrap();
+        assert!(!repo_owned(&repo));
+        chown(&repo.gitdir, Some(0), None).unwrap();
+        chown(&repo.worktree, Some(12345), None).unwrap();
+        assert!(!repo_owned(&repo));
+    }
+
+    // A worktree with tracked files, one of them ignored, and untracked
+    // ones, some ignored by the .gitignore
+    struct LsFilesFixture {
+        repo: GitRepository,
+        _dir: TempDir,
+    }
+
+    impl LsFilesFixture {
+        fn new() -> Self {
+            let dir = TempDir::new();
+            let path = dir.0.join("repo");
+            repo_create(path.to_str().unwrap()).unwrap();
+            let repo = repo_open(path.to_str().unwrap()).unwrap();
+            let write = |name: &str, content: &str| {
+                let file = path.join(name);
+                fs::create_dir_all(file.parent().unwrap()).unwrap();
+                fs::write(file, content).unwrap();
+            };
+            for name in [".gitignore", "a.c", "b.h", "sub/c.c", "sub/d.o"] {
+                write(
+                    name,
+                    if name == ".gitignore" {
+                        "*.o\nbuild/\n"
+                    } else {
+                        name
+                    },
+                );
+            }
+            let config = repo_config(&repo).unwrap();
+            let mut index = index_read(&repo).unwrap();
+            let options = UpdateIndexOptions {
+                add: true,
+                ..Default::default()
+            };
+            for name in [".gitignore", "a.c", "b.h", "sub/c.c", "sub/d.o"] {
+                update_index_path(&repo, &config, &mut index, &options, name.as_bytes()).unwrap();
+            }
+            index_write(&repo, &index).unwrap();
+
+            for name in ["new.c", "sub/e.o", "build/x.o"] {
+                write(name, name);
+            }
+            write("a.c", "changed a.c");
+            fs::remove_file(path.join("b.h")).unwrap();
+            LsFilesFixture { repo, _dir: dir }
+        }
+
+        // The lines listed from the directory `prefix` and the indices of
+        // the paths that matched nothing
+        fn list(
+            &self,
+            options: &LsFilesOptions,
+            prefix: &str,
+            paths: &[&str],
+        ) -> (Vec<String>, Vec<usize>) {
+            let specs = Pathspec::parse(paths, &|path| {
+                let mut full = prefix.as_bytes().to_vec();
+                full.push(b'/');
+                full.extend(path_bytes(path.as_os_str())?);
+                pathspec::pathspec_normalize(&full)
+            })
+            .unwrap();
+            let mut out = Vec::new();
+            let unmatched =
+                ls_files(&self.repo, options, prefix.as_bytes(), &specs, &mut out).unwrap();
+            let out = String::from_utf8(out).unwrap();
+            (out.lines().map(String::from).collect(), unmatched)
+        }
+
+        fn ls(&self, options: &LsFilesOptions, paths: &[&str]) -> Vec<String> {
+            self.list(options, "", paths).0
+        }
+    }
+
+    #[test]
+    fn ls_files_lists_the_index() {
+        let fixture = LsFilesFixture::new();
+        let all = LsFilesOptions::default();
+        assert_eq!(
+            fixture.ls(&all, &[]),
+            [".gitignore", "a.c", "b.h", "sub/c.c", "sub/d.o"]
+        );
+        assert_eq!(fixture.list(&all, "sub", &[]).0, ["c.c", "d.o"]);
+        assert_eq!(fixture.list(&all, "sub", &["../a.c"]).0, ["../a.c"]);
+        assert_eq!(fixture.ls(&all, &["*.c", ":!sub"]), ["a.c"]);
+
+        let deleted = LsFilesOptions {
+            deleted: true,
+            ..Default::default()
+        };
+        assert_eq!(fixture.ls(&deleted, &[]), ["b.h"]);
+        // A deleted file is modified too, and shows once for each
+        let modified = LsFilesOptions {
+            modified: true,
+            ..Default::default()
+        };
+        assert_eq!(fixture.ls(&modified, &[]), ["a.c", "b.h"]);
+        let both = LsFilesOptions {
+            deleted: true,
+            modified: true,
+            ..Default::default()
+        };
+        assert_eq!(fixture.ls(&both, &[]), ["a.c", "b.h", "b.h"]);
+        let cached = LsFilesOptions {
+            cached: true,
+            deleted: true,
+            ..Default::default()
+        };
+        assert_eq!(fixture.ls(&cached, &["b.h"]), ["b.h", "b.h"]);
+    }
+
+    #[test]
+    fn ls_files_lists_untracked_and_ignored_files() {
+        let fixture = LsFilesFixture::new();
+        let others = LsFilesOptions {
+            others: true,
+            ..Default::default()
+        };
+        assert_eq!(fixture.ls(&others, &[]), ["build/x.o", "new.c", "sub/e.o"]);
+        assert_eq!(fixture.list(&others, "sub", &[]).0, ["e.o"]);
+        let others = LsFilesOptions {
+            others: true,
+            exclude_standard: true,
+            ..Default::default()
+        };
+        assert_eq!(fixture.ls(&others, &[]), ["new.c"]);
+
+        let ignored = LsFilesOptions {
+            ignored: true,
+            ..others
+        };
+        assert_eq!(fixture.ls(&ignored, &[]), ["build/x.o", "sub/e.o"]);
+        let exclude = ["*.h".to_string()];
+        let ignored = LsFilesOptions {
+            cached: true,
+            ignored: true,
+            exclude: &exclude,
+            ..Default::default()
+        };
+        assert_eq!(fixture.ls(&ignored, &[]), ["b.h"]);
+        let ignored = LsFilesOptions {
+            exclude_standard: true,
+            ..ignored
+        };
+        assert_eq!(fixture.ls(&ignored, &[]), ["b.h", "sub/d.o"]);
+
+        let options = LsFilesOptions {
+            ignored: true,
+            exclude_standard: true,
+            ..Default::default()
+        };
+        let error = ls_files(
+            &fixture.repo,
+            &options,
+            b"",
+            &Pathspec::default(),
+            &mut Vec::new(),
+        );
+        assert_eq!(
+            error.err().unwrap().to_string(),
+            "ls-files -i must be used with either -o or -c"
+        );
+        let options = LsFilesOptions {
+            others: true,
+            ignored: true,
+            ..Default::default()
+        };
+        let error = ls_files(
+            &fixture.repo,
+            &options,
+            b"",
+            &Pathspec::default(),
+            &mut Vec::new(),
+        );
+        assert_eq!(
+            error.err().unwrap().to_string(),
+            "ls-files --ignored needs some exclude pattern"
+        );
+    }
+
+    #[test]
+    fn ls_files_reports_unmatched_paths() {
+        let fixture = LsFilesFixture::new();
+        let options = LsFilesOptions {
+            error_unmatch: true,
+            ..Default::default()
+        };
+        let (listed, unmatched) = fixture.list(&options, "", &["a.c", "nope", ":!nope", "sub"]);
+        assert_eq!(listed, ["a.c", "sub/c.c", "sub/d.o"]);
+        assert_eq!(unmatched, [1]);
+
+        // Untracked files count when they are listed
+        let others = LsFilesOptions {
+            others: true,
+            ..options
+        };
+        assert_eq!(fixture.list(&others, "", &["new.c"]).1, []);
+        assert_eq!(fixture.list(&options, "", &["new.c"]).1, [0]);
+        assert_eq!(fixture.list(&options, "sub", &["e.o", "c.c"]).1, [0]);
+    }
+
+    #[test]
+    fn index_files_round_trip() {
+        let dir = TempDir::new();
+        let path = dir.0.join("repo");
+        repo_create(path.to_str().unwrap()).unwrap();
+        let repo = repo_open(path.to_str().unwrap()).unwrap();
+        let entry = |path: &[u8], flags: u16, extended_flags: u16| IndexEntry {
+            ctime: (1, 2),
+            mtime: (3, 4),
+            dev: 5,
+            ino: 6,
+            mode: 0o100644,
+            uid: 7,
+            gid: 8,
+            size: 9,
+            sha: hash_object("blob", path),
+            flags,
+            extended_flags,
+            path: path.to_vec(),
+        };
+        // Names too long for the length bits, and of every padding
+        let long = vec![b'x'; 5000];
+        let mut entries = vec![
+            entry(b"a", 0, 0),
+            entry(b"ab", INDEX_ASSUME_VALID, 0),
+            entry(b"conflict", 1 << 12, 0),
+            entry(b"conflict", 2 << 12, 0),
+            entry(b"conflict", 3 << 12, 0),
+            entry(b"dir/abcdefgh", 0, 0),
+            entry(&long, 0, 0),
+        ];
+        let same = |index: &GitIndex, entries: &[IndexEntry]| {
+            assert_eq!(index.entries.len(), entries.len());
+            for (read, written) in index.entries.iter().zip(entries) {
+                assert_eq!(
+                    (read.ctime, read.mtime, read.dev, read.ino, read.mode),
+                    (
+                        written.ctime,
+                        written.mtime,
+                        written.dev,
+                        written.ino,
+                        written.mode
+                    )
+                );
+                assert_eq!(
+                    (read.uid, read.gid, read.size, &read.sha, read.flags),
+                    (
+                        written.uid,
+                        written.gid,
+                        written.size,
+                        &written.sha,
+                        written.flags
+                    )
+                );
+                assert_eq!(read.extended_flags, written.extended_flags);
+                assert_eq!(read.path, written.path);
+            }
+        };
+
+        let index = GitIndex {
+            version: 2,
+            entries: entries.clone(),
+        };
+        index_write(&repo, &index).unwrap();
+        let read = index_read(&repo).unwrap();
+        assert_eq!(read.version, 2);
+        same(&read, &entries);
+        assert_eq!(read.entries[2].stage(), 1);
+        assert!(!repo_path(&repo, "index.lock").exists());
+
+        // Extended flags need version 3
+        entries[0].extended_flags = INDEX_SKIP_WORKTREE;
+        let index = GitIndex {
+            version: 2,
+            entries: entries.clone(),
+        };
+        index_write(&repo, &index).unwrap();
+        let read = index_read(&repo).unwrap();
+        assert_eq!(read.version, 3);
+        same(&read, &entries);
+
+        // Someone else holding the lock keeps the index as it is
+        fs::write(repo_path(&repo, "index.lock"), "").unwrap();
+        assert!(index_write(
+            &repo,
+            &GitIndex {
+                version: 2,
+                entries: Vec::new()
+            }
+        )
+        .is_err());
+        assert_eq!(index_read(&repo).unwrap().entries.len(), entries.len());
+    }
+
+    #[test]
+    fn bad_index_files_are_refused() {
+        let dir = TempDir::new();
+        let path = dir.0.join("repo");
+        repo_create(path.to_str().unwrap()).unwrap();
+        let repo = repo_open(path.to_str().unwrap()).unwrap();
+        assert!(index_read(&repo).unwrap().entries.is_empty());
+
+        let index = GitIndex {
+            version: 2,
+            entries: vec![IndexEntry {
+                mode: 0o100644,
+                sha: NULL_SHA.to_string(),
+                path: b"a".to_vec(),
+                ..Default::default()
+            }],
+        };
+        index_write(&repo, &index).unwrap();
+        let good = fs::read(repo_path(&repo, "index")).unwrap();
+        let read_with = |data: &[u8]| {
+            fs::write(repo_path(&repo, "index"), data).unwrap();
+            index_read(&repo).map(|index| index.entries.len())
+        };
+        // Rewrite the checksum after changing the content
+        let sealed = |mut content: Vec<u8>| {
+            let checksum = Sha1::digest(&content);
+            content.extend_from_slice(&checksum);
+            content
+        };
+        let content = good[..good.len() - 20].to_vec();
+
+        let mut extension = content.clone();
+        extension.extend_from_slice(b"TREE\0\0\0\x02xy");
+        assert_eq!(read_with(&sealed(extension)).unwrap(), 1);
+
+        let mut bad = good.clone();
+        bad[0] = b'X';
+        let mut flipped = good.clone();
+        flipped[20] ^= 1;
+        let mut version = content.clone();
+        version[7] = 4;
+        let mut extended = content.clone();
+        extended[12 + 60] |= 0x40;
+        let mut required = content.clone();
+        required.extend_from_slice(b"link\0\0\0\0");
+        let mut count = content.clone();
+        count[11] = 2;
+        for (data, error) in [
+            (bad, "index file is corrupt: bad signature"),
+            (flipped, "index file is corrupt: bad checksum"),
+            (sealed(version), "index file version 4 is not supported"),
+            (
+                sealed(extended),
+                "index file is corrupt: extended flags in version 2",
+            ),
+            (
+                sealed(required),
+                "index uses link extension, which we do not understand",
+            ),
+            (sealed(count), "index file is corrupt: truncated"),
+            (good[..20].to_vec(), "index file is corrupt: bad signature"),
+        ] {
+            assert_eq!(read_with(&data).err().unwrap().to_string(), error);
+        }
+    }
+
+    #[test]
+    fn update_index_adds_marks_and_refreshes() {
+        let dir = TempDir::new();
+        let path = dir.0.join("repo");
+        repo_create(path.to_str().unwrap()).unwrap();
+        let repo = repo_open(path.to_str().unwrap()).unwrap();
+        let config = repo_config(&repo).unwrap();
+        let mut index = index_read(&repo).unwrap();
+        fs::write(path.join("a"), "a").unwrap();
+        fs::create_dir(path.join("d")).unwrap();
+        fs::write(path.join("d/f"), "f").unwrap();
+        let mut update = |options: &UpdateIndexOptions, name: &str| {
+            update_index_path(&repo, &config, &mut index, options, name.as_bytes())
+                .map_err(|e| e.to_string())
+        };
+
+        let plain = UpdateIndexOptions::default();
+        assert_eq!(update(&plain, "a").unwrap_err(), "Unable to process path a");
+        let add = UpdateIndexOptions {
+            add: true,
+            ..Default::default()
+        };
+        update(&add, "a").unwrap();
+        update(&add, "d/f").unwrap();
+        let chmod = UpdateIndexOptions {
+            chmod: Some("+x"),
+            ..Default::default()
+        };
+        update(&chmod, "a").unwrap();
+        let mark = UpdateIndexOptions {
+            skip_worktree: Some(true),
+            assume_unchanged: Some(true),
+            ..Default::default()
+        };
+        update(&mark, "d/f").unwrap();
+        assert_eq!(
+            update(&mark, "nope").unwrap_err(),
+            "Unable to mark file nope"
+        );
+
+        // A file cannot replace the directory it would be in
+        fs::remove_file(path.join("d/f")).unwrap();
+        fs::remove_dir(path.join("d")).unwrap();
+        fs::write(path.join("d"), "d").unwrap();
+        assert_eq!(update(&add, "d").unwrap_err(), "Unable to process path d");
+
+        fs::remove_file(path.join("a")).unwrap();
+        assert_eq!(update(&plain, "a").unwrap_err(), "Unable to process path a");
+        let remove = UpdateIndexOptions {
+            remove: true,
+            ..Default::default()
+        };
+        update(&remove, "a").unwrap();
+        // Removing what is not tracked does nothing
+        fs::remove_file(path.join("d")).unwrap();
+        update(&remove, "d").unwrap();
+
+        assert_eq!(index.entries.len(), 1);
+        let entry = &index.entries[0];
+        assert_eq!(entry.path, b"d/f");
+        assert_eq!(entry.sha, hash_object("blob", b"f"));
+        assert_eq!(entry.mode, 0o100644);
+        assert_eq!(entry.extended_flags, INDEX_SKIP_WORKTREE);
+        assert_eq!(entry.flags, INDEX_ASSUME_VALID);
+
+        // Updating a file again takes its mode from the worktree
+        fs::write(path.join("a"), "a").unwrap();
+        let mut index = index_read(&repo).unwrap();
+        let mut update = |options: &UpdateIndexOptions, name: &str| {
+            update_index_path(&repo, &config, &mut index, options, name.as_bytes()).unwrap()
+        };
+        update(&add, "a");
+        update(&chmod, "a");
+        update(&plain, "a");
+        assert_eq!(index.entries[0].mode, 0o100644);
+
+        // Refreshing takes new stat data for content that is the same, and
+        // reports content that is not
+        index.entries[0].mtime = (0, 0);
+        assert!(update_index_refresh(&repo, &config, &mut index).unwrap());
+        let mtime = index.entries[0].mtime;
+        assert_ne!(mtime, (0, 0));
+        fs::write(path.join("a"), "changed").unwrap();
+        assert!(!update_index_refresh(&repo, &config, &mut index).unwrap());
+        assert_eq!(index.entries[0].mtime, mtime);
+    }
+
+    #[test]
+    fn update_index_takes_cacheinfo() {
+        let dir = TempDir::new();
+        let path = dir.0.join("repo");
+        repo_create(path.to_str().unwrap()).unwrap();
+        let repo = repo_open(path.to_str().unwrap()).unwrap();
+        let sha = hash_object("blob", b"x");
+        let cacheinfo = |values: &[String], add: bool| {
+            let options = UpdateIndexOptions {
+                add,
+                cacheinfo: values,
+                ..Default::default()
+            };
+            update_index(&repo, &options, &[]).map_err(|e| e.to_string())
+        };
+
+        let values = [format!("100755,{},sub/x", sha.to_uppercase())];
+        assert_eq!(
+            cacheinfo(&values, false).unwrap_err(),
+            "git update-index: --cacheinfo cannot add sub/x"
+        );
+        cacheinfo(&values, true).unwrap();
+        // Entries that exist can be changed without --add
+        let values = [format!("120000,{},sub/x", sha)];
+        cacheinfo(&values, false).unwrap();
+
+        let index = index_read(&repo).unwrap();
+        assert_eq!(index.entries.len(), 1);
+        assert_eq!(index.entries[0].path, b"sub/x");
+        assert_eq!(index.entries[0].sha, sha);
+        assert_eq!(index.entries[0].mode, 0o120000);
+
+        for value in [
+            "100644",
+            "x,y,z",
+            "100644,abc,p",
+            &format!("1008,{},p", sha),
+        ] {
+            assert_eq!(
+                cacheinfo(&[value.to_string()], true).unwrap_err(),
+                "option 'cacheinfo' expects <mode>,<sha1>,<path>"
+            );
         }
+        let value = format!("100644,{},sub", sha);
+        assert_eq!(
+            cacheinfo(&[value], true).unwrap_err(),
+            "git update-index: --cacheinfo cannot add sub"
+        );
     }
 }
@@ -0,0 +1,337 @@
+// Pathspecs, the path arguments that limit what ls-files lists and what
+// diff-tree and rev-list compare. Each one names a file, a directory and
+// everything below it, or, with wildcards, what it matches as a glob.
+// Magic before the path changes how it is read: `:(top)` or `:/` takes it
+// from the top of the worktree rather than the current directory,
+// `:(icase)` ignores case, `:(literal)` turns wildcards off, `:(glob)`
+// keeps `*` from crossing `/`, and `:(exclude)`, `:!` or `:^` take what
+// it matches out instead.
+
+use std::error::Error;
+use std::path::Path;
+
+use super::{bytes_path, path_bytes, wildmatch};
+
+// Turns a path relative to the current directory into one from the top
+// of the worktree
+type Resolve<'a> = dyn Fn(&Path) -> Result<Vec<u8>, Box<dyn Error>> + 'a;
+
+#[derive(Clone, Default)]
+pub(crate) struct Pathspec {
+    pub(crate) items: Vec<PathspecItem>,
+}
+
+#[derive(Clone)]
+pub(crate) struct PathspecItem {
+    // From the top of the worktree, without a trailing slash; empty for
+    // the whole tree
+    pub(crate) path: Vec<u8>,
+    pub(crate) icase: bool,
+    pub(crate) exclude: bool,
+    literal: bool,
+    glob: bool,
+    // How much of `path` comes before its first wildcard
+    nowildcard: usize,
+}
+
+// A path from the top of the worktree with `.`, empty components and
+// trailing slashes dropped, refused if `..` would leave the worktree.
+pub(crate) fn pathspec_normalize(path: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut parts: Vec<&[u8]> = Vec::new();
+    for part in path.split(|&b| b == b'/') {
+        match part {
+            b"" | b"." => {}
+            b".." => {
+                parts.pop().ok_or_else(|| {
+                    format!("'{}' is outside repository", String::from_utf8_lossy(path))
+                })?;
+            }
+            part => parts.push(part),
+        }
+    }
+    Ok(parts.join(&b'/'))
+}
+
+impl Pathspec {
+    // Read command line `args`, with paths not given from the top resolved.
+    pub(crate) fn parse(
+        args: &[impl AsRef<Path>],
+        resolve: &Resolve,
+    ) -> Result<Pathspec, Box<dyn Error>> {
+        let items = args
+            .iter()
+            .map(|arg| PathspecItem::parse(arg.as_ref(), resolve))
+            .collect::<Result<_, _>>()?;
+        Ok(Pathspec { items })
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    // Whether some item other than an exclusion is given; without one,
+    // exclusions are taken out of everything.
+    pub(crate) fn has_positive(&self) -> bool {
+        self.items.iter().any(|item| !item.exclude)
+    }
+
+    pub(crate) fn excluded(&self, path: &[u8]) -> bool {
+        self.items
+            .iter()
+            .any(|item| item.exclude && item.matches(path))
+    }
+
+    pub(crate) fn matches(&self, path: &[u8]) -> bool {
+        let included = !self.has_positive()
+            || self
+                .items
+                .iter()
+                .any(|item| !item.exclude && item.matches(path));
+        included && !self.excluded(path)
+    }
+
+    // Whether the directory `dir`, though not matched itself, may hold
+    // paths that are, so a walk of a tree has to go into it.
+    pub(crate) fn leads(&self, dir: &[u8]) -> bool {
+        !self.excluded(dir)
+            && self
+                .items
+                .iter()
+                .any(|item| !item.exclude && item.leads(dir))
+    }
+}
+
+impl PathspecItem {
+    fn parse(arg: &Path, resolve: &Resolve) -> Result<PathspecItem, Box<dyn Error>> {
+        let bytes = path_bytes(arg.as_os_str())?;
+        let shown = || String::from_utf8_lossy(&bytes).into_owned();
+        let mut item = PathspecItem {
+            path: Vec::new(),
+            icase: false,
+            exclude: false,
+            literal: false,
+            glob: false,
+            nowildcard: 0,
+        };
+        let mut top = false;
+
+        let rest = if let Some(long) = bytes.strip_prefix(b":(") {
+            let end = long.iter().position(|&b| b == b')').ok_or_else(|| {
+                format!("Missing ')' at the end of pathspec magic in '{}'", shown())
+            })?;
+            for magic in long[..end].split(|&b| b == b',') {
+                match magic {
+                    b"top" => top = true,
+                    b"icase" => item.icase = true,
+                    b"exclude" => item.exclude = true,
+                    b"literal" => item.literal = true,
+                    b"glob" => item.glob = true,
+                    b"" => {}
+                    magic => {
+                        return Err(From::from(format!(
+                            "Invalid pathspec magic '{}' in '{}'",
+                            String::from_utf8_lossy(magic),
+                            shown()
+                        )))
+                    }
+                }
+            }
+            &long[end + 1..]
+        } else if let Some(short) = bytes.strip_prefix(b":") {
+            let mut i = 0;
+            while let Some(&b) = short.get(i) {
+                match b {
+                    b'/' => top = true,
+                    b'!' | b'^' => item.exclude = true,
+                    _ => break,
+                }
+                i += 1;
+            }
+            // The magic may be ended with another `:`
+            if short.get(i) == Some(&b':') {
+                i += 1;
+            }
+            &short[i..]
+        } else {
+            &bytes[..]
+        };
+        if item.literal && item.glob {
+            return Err(From::from(format!(
+                "'literal' and 'glob' are incompatible in '{}'",
+                shown()
+            )));
+        }
+
+        item.path = match top {
+            true => pathspec_normalize(rest)?,
+            // What is left of `:/` alone, or of an argument that is all
+            // magic, is the whole tree or the current directory
+            false if rest.is_empty() => resolve(Path::new("."))?,
+            false => resolve(&bytes_path(rest)?)?,
+        };
+        item.nowildcard = match item.literal {
+            true => item.path.len(),
+            false => item
+                .path
+                .iter()
+                .position(|b| b"*?[\\".contains(b))
+                .unwrap_or(item.path.len()),
+        };
+        Ok(item)
+    }
+
+    fn same(&self, a: &[u8], b: &[u8]) -> bool {
+        match self.icase {
+            true => a.eq_ignore_ascii_case(b),
+            false => a == b,
+        }
+    }
+
+    // Whether `path` is the item's path, lies below it, or matches it as
+    // a glob.
+    pub(crate) fn matches(&self, path: &[u8]) -> bool {
+        let spec = self.path.as_slice();
+        let at_boundary = path.len() == spec.len() || path.get(spec.len()) == Some(&b'/');
+        if spec.is_empty()
+            || (path.len() >= spec.len() && at_boundary && self.same(&path[..spec.len()], spec))
+        {
+            return true;
+        }
+        self.nowildcard < spec.len()
+            && path.len() >= self.nowildcard
+            && self.same(&path[..self.nowildcard], &spec[..self.nowildcard])
+            && wildmatch(spec, path, self.icase, self.glob)
+    }
+
+    fn leads(&self, dir: &[u8]) -> bool {
+        let literal = &self.path[..self.nowildcard];
+        match literal.len() > dir.len() {
+            // The part without wildcards goes on below `dir`
+            true => literal[dir.len()] == b'/' && self.same(&literal[..dir.len()], dir),
+            // Or `dir` is inside it, and a wildcard may match below
+            false => self.nowildcard < self.path.len() && self.same(&dir[..literal.len()], literal),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Pathspec {
+        // As from a subdirectory `sub` of the worktree
+        let resolve = |path: &Path| {
+            let mut full = b"sub/".to_vec();
+            full.extend(path_bytes(path.as_os_str())?);
+            pathspec_normalize(&full)
+        };
+        Pathspec::parse(args, &resolve).unwrap()
+    }
+
+    fn matched<'a>(spec: &Pathspec, paths: &[&'a str]) -> Vec<&'a str> {
+        paths
+            .iter()
+            .copied()
+            .filter(|path| spec.matches(path.as_bytes()))
+            .collect()
+    }
+
+    const PATHS: &[&str] = &[
+        "README",
+        "sub/a.c",
+        "sub/B.C",
+        "sub/deep/c.c",
+        "sub/deep/d.h",
+        "sub/x*y",
+        "sub/xzy",
+        "top.c",
+    ];
+
+    #[test]
+    fn pathspecs_match_like_git() {
+        let spec = parse(&["deep"]);
+        assert_eq!(matched(&spec, PATHS), ["sub/deep/c.c", "sub/deep/d.h"]);
+        assert_eq!(matched(&parse(&["."]), PATHS).len(), 6);
+        assert_eq!(
+            matched(&parse(&["../README", "a.c"]), PATHS),
+            ["README", "sub/a.c"]
+        );
+
+        // Without :(glob), `*` crosses directories
+        assert_eq!(
+            matched(&parse(&["*.c"]), PATHS),
+            ["sub/a.c", "sub/deep/c.c"]
+        );
+        assert_eq!(matched(&parse(&[":(glob)*.c"]), PATHS), ["sub/a.c"]);
+        assert_eq!(
+            matched(&parse(&[":(glob)**/*.c"]), PATHS),
+            ["sub/a.c", "sub/deep/c.c"]
+        );
+        assert_eq!(matched(&parse(&["x*y"]), PATHS), ["sub/x*y", "sub/xzy"]);
+        assert_eq!(matched(&parse(&[":(literal)x*y"]), PATHS), ["sub/x*y"]);
+
+        assert_eq!(
+            matched(&parse(&[":(icase)*.c"]), PATHS),
+            ["sub/a.c", "sub/B.C", "sub/deep/c.c"]
+        );
+        assert_eq!(
+            matched(&parse(&[":(icase)DEEP/C.C"]), PATHS),
+            ["sub/deep/c.c"]
+        );
+
+        assert_eq!(
+            matched(&parse(&[":/*.c"]), PATHS),
+            ["sub/a.c", "sub/deep/c.c", "top.c"]
+        );
+        assert_eq!(matched(&parse(&[":(top)README"]), PATHS), ["README"]);
+        assert_eq!(matched(&parse(&[":/"]), PATHS).len(), PATHS.len());
+
+        assert_eq!(
+            matched(&parse(&[".", ":!deep"]), PATHS),
+            ["sub/a.c", "sub/B.C", "sub/x*y", "sub/xzy"]
+        );
+        assert_eq!(
+            matched(&parse(&[":(exclude)*.c", ":^x*"]), PATHS),
+            ["README", "sub/B.C", "sub/deep/d.h", "top.c"]
+        );
+        assert_eq!(
+            matched(&parse(&[":/", ":(exclude,top,icase)SUB"]), PATHS),
+            ["README", "top.c"]
+        );
+    }
+
+    #[test]
+    fn pathspecs_say_which_directories_to_enter() {
+        let spec = parse(&["deep/c.c", ":!deep/x"]);
+        assert!(spec.leads(b"sub") && spec.leads(b"sub/deep"));
+        assert!(!spec.leads(b"su") && !spec.leads(b"sub/dee") && !spec.leads(b"other"));
+        assert!(!parse(&["deep/c.c", ":!deep"]).leads(b"sub/deep"));
+
+        let spec = parse(&["de*/c.c"]);
+        assert!(spec.leads(b"sub") && spec.leads(b"sub/deep") && spec.leads(b"sub/dex/y"));
+        assert!(!spec.leads(b"other") && !spec.leads(b"sub/x"));
+        let spec = parse(&[":(icase)DEEP/x"]);
+        assert!(spec.leads(b"SUB") && spec.leads(b"sub/Deep"));
+    }
+
+    #[test]
+    fn bad_pathspecs_are_refused() {
+        let resolve = |path: &Path| pathspec_normalize(&path_bytes(path.as_os_str())?);
+        for (arg, error) in [
+            (
+                ":(top",
+                "Missing ')' at the end of pathspec magic in ':(top'",
+            ),
+            (":(tpo)x", "Invalid pathspec magic 'tpo' in ':(tpo)x'"),
+            (
+                ":(literal,glob)x",
+                "'literal' and 'glob' are incompatible in ':(literal,glob)x'",
+            ),
+            (":/../x", "'../x' is outside repository"),
+            ("a/../../x", "'a/../../x' is outside repository"),
+        ] {
+            let result = Pathspec::parse(&[arg], &resolve);
+            assert_eq!(result.err().unwrap().to_string(), error, "{}", arg);
+        }
+    }
+}
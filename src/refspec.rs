@@ -0,0 +1,679 @@
+// Refspecs, as remote.<name>.fetch and push take them, and the ref
+// mappings they give: `[+]<src>[:<dst>]`, with one `*` on each side of a
+// pattern, or `^<src>` to leave matching refs out.
+
+// No fetch or push client uses this yet
+#![allow(dead_code)]
+
+use std::error::Error;
+
+use super::{is_hex_sha, refname_valid};
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Refspec {
+    pub(crate) force: bool,
+    pub(crate) negative: bool,
+    pub(crate) pattern: bool,
+    // The push refspec `:`, for branches of the same name on both sides
+    pub(crate) matching: bool,
+    // A fetch of an object by its full id rather than by a ref
+    pub(crate) exact_sha: bool,
+    // Empty to fetch HEAD, or to delete `dst` when pushing
+    pub(crate) src: String,
+    pub(crate) dst: Option<String>,
+}
+
+// One ref a fetch or push would update. An empty `dst` fetches without
+// storing; an empty `src` pushes a deletion.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct RefMapping {
+    pub(crate) src: String,
+    pub(crate) dst: String,
+    pub(crate) force: bool,
+}
+
+// The ref names an abbreviated name stands for, in the order git tries
+// them.
+fn abbrev_candidates(name: &str) -> [String; 6] {
+    [
+        name.to_string(),
+        format!("refs/{}", name),
+        format!("refs/tags/{}", name),
+        format!("refs/heads/{}", name),
+        format!("refs/remotes/{}", name),
+        format!("refs/remotes/{}/HEAD", name),
+    ]
+}
+
+// `name` matched against the pattern `key`, giving `value` with its `*`
+// replaced by what the `*` of `key` matched.
+fn pattern_apply(key: &str, name: &str, value: Option<&str>) -> Option<String> {
+    let (prefix, suffix) = key.split_once('*')?;
+    if name.len() < prefix.len() + suffix.len() {
+        return None;
+    }
+    let matched = name.strip_prefix(prefix)?.strip_suffix(suffix)?;
+    Some(match value {
+        Some(value) => value.replacen('*', matched, 1),
+        None => String::new(),
+    })
+}
+
+impl Refspec {
+    // Parse a refspec as given for fetching, or for pushing, following
+    // the rules of git's parse_refspec.
+    pub(crate) fn parse(spec: &str, fetch: bool) -> Result<Refspec, Box<dyn Error>> {
+        Refspec::parse_checked(spec, fetch)
+            .ok_or_else(|| From::from(format!("invalid refspec '{}'", spec)))
+    }
+
+    fn parse_checked(spec: &str, fetch: bool) -> Option<Refspec> {
+        let mut refspec = Refspec {
+            force: false,
+            negative: false,
+            pattern: false,
+            matching: false,
+            exact_sha: false,
+            src: String::new(),
+            dst: None,
+        };
+        let mut lhs = spec;
+        if let Some(rest) = lhs.strip_prefix('+') {
+            refspec.force = true;
+            lhs = rest;
+        } else if let Some(rest) = lhs.strip_prefix('^') {
+            refspec.negative = true;
+            lhs = rest;
+        }
+        if !fetch && lhs == ":" {
+            refspec.matching = true;
+            return Some(refspec);
+        }
+
+        if let Some((src, dst)) = lhs.rsplit_once(':') {
+            lhs = src;
+            refspec.pattern = dst.contains('*');
+            refspec.dst = Some(dst.to_string());
+        }
+        if lhs.contains('*') {
+            // Both sides are patterns, or there is only the one side; a
+            // fetch has to say where a pattern goes
+            if (refspec.dst.is_some() && !refspec.pattern)
+                || (refspec.dst.is_none() && fetch && !refspec.negative)
+            {
+                return None;
+            }
+            refspec.pattern = true;
+        } else if refspec.pattern {
+            return None;
+        }
+        refspec.src = match lhs {
+            "@" => "HEAD".to_string(),
+            _ => lhs.to_string(),
+        };
+        let valid = |name: &str| refname_valid(name, true, refspec.pattern);
+
+        if refspec.negative {
+            let valid = refspec.dst.is_none()
+                && !refspec.src.is_empty()
+                && !is_hex_sha(refspec.src.as_bytes())
+                && valid(&refspec.src);
+            return valid.then_some(refspec);
+        }
+        if fetch {
+            if is_hex_sha(refspec.src.as_bytes()) {
+                refspec.exact_sha = true;
+            } else if !refspec.src.is_empty() && !valid(&refspec.src) {
+                return None;
+            }
+            match refspec.dst.as_deref() {
+                Some(dst) if !dst.is_empty() && !valid(dst) => None,
+                _ => Some(refspec),
+            }
+        } else {
+            // The source of a push may also be any object name, which is
+            // only looked up once there are refs to match it against
+            match refspec.dst.as_deref() {
+                None if !valid(&refspec.src) => None,
+                Some("") => None,
+                Some(dst) if !valid(dst) => None,
+                _ => Some(refspec),
+            }
+        }
+    }
+
+    // Whether the source side takes in `name`.
+    pub(crate) fn matches(&self, name: &str) -> bool {
+        match self.pattern {
+            true => pattern_apply(&self.src, name, None).is_some(),
+            false => self.src == name,
+        }
+    }
+
+    // Where `name` goes on the destination side, if the source side takes
+    // it in; empty if it is not stored.
+    pub(crate) fn map(&self, name: &str) -> Option<String> {
+        match self.pattern {
+            true => pattern_apply(&self.src, name, self.dst.as_deref()),
+            false => (self.src == name).then(|| self.dst.clone().unwrap_or_default()),
+        }
+    }
+
+    // The source that maps to `name` on the destination side, as when
+    // finding which remote branch a remote-tracking branch follows.
+    pub(crate) fn reverse(&self, name: &str) -> Option<String> {
+        let dst = self.dst.as_deref().filter(|dst| !dst.is_empty())?;
+        match self.pattern {
+            true => pattern_apply(dst, name, Some(&self.src)),
+            false => (dst == name).then(|| self.src.clone()),
+        }
+    }
+}
+
+// Whether a negative refspec leaves `name` out.
+fn refspec_excludes(refspecs: &[Refspec], name: &str) -> bool {
+    refspecs
+        .iter()
+        .any(|refspec| refspec.negative && refspec.matches(name))
+}
+
+// The full name of a local ref a fetch stores into
+fn fetch_local_ref(name: &str) -> String {
+    if name.starts_with("refs/") {
+        name.to_string()
+    } else if ["heads/", "tags/", "remotes/"]
+        .iter()
+        .any(|prefix| name.starts_with(prefix))
+    {
+        format!("refs/{}", name)
+    } else {
+        format!("refs/heads/{}", name)
+    }
+}
+
+// The refs a fetch with `refspecs` takes from a remote advertising
+// `remote_refs`, and where it stores them. A ref named explicitly must
+// exist; two refs may not be stored in the same place.
+pub(crate) fn refspec_fetch_map(
+    refspecs: &[Refspec],
+    remote_refs: &[String],
+) -> Result<Vec<RefMapping>, Box<dyn Error>> {
+    let mut mappings = Vec::new();
+    for refspec in refspecs.iter().filter(|refspec| !refspec.negative) {
+        let dst = refspec.dst.as_deref().unwrap_or_default();
+        if refspec.pattern {
+            for name in remote_refs.iter().filter(|name| !name.contains('^')) {
+                let Some(dst) = refspec.map(name) else {
+                    continue;
+                };
+                if dst.is_empty() || refname_valid(&dst, false, false) {
+                    mappings.push(RefMapping {
+                        src: name.clone(),
+                        dst,
+                        force: refspec.force,
+                    });
+                }
+            }
+            continue;
+        }
+
+        let src = match refspec.src.as_str() {
+            "" => "HEAD",
+            src => src,
+        };
+        let src = match refspec.exact_sha {
+            true => src.to_string(),
+            // The name most specifically meant, like a branch over a
+            // remote-tracking branch of the same name
+            false => abbrev_candidates(src)
+                .into_iter()
+                .find(|candidate| remote_refs.contains(candidate))
+                .ok_or_else(|| format!("couldn't find remote ref {}", src))?,
+        };
+        mappings.push(RefMapping {
+            src,
+            dst: match dst {
+                "" => String::new(),
+                dst => fetch_local_ref(dst),
+            },
+            force: refspec.force,
+        });
+    }
+
+    mappings.retain(|mapping| !refspec_excludes(refspecs, &mapping.src));
+    let mut unique: Vec<RefMapping> = Vec::new();
+    for mapping in mappings {
+        match unique
+            .iter()
+            .find(|other| !mapping.dst.is_empty() && other.dst == mapping.dst)
+        {
+            Some(other) if other.src != mapping.src => {
+                return Err(From::from(format!(
+                    "{} tracks both {} and {}",
+                    mapping.dst, other.src, mapping.src
+                )));
+            }
+            Some(_) => {}
+            None => unique.push(mapping),
+        }
+    }
+    Ok(unique)
+}
+
+// The one ref of `refs` an abbreviated `name` stands for. Matches outside
+// refs/heads/ and refs/tags/ are weak, unless `name` spells them out: a
+// single strong match wins over any weak ones, and otherwise there must
+// be just one weak match.
+fn push_match<'a>(name: &str, refs: &'a [String]) -> Result<Option<&'a String>, usize> {
+    let candidates = abbrev_candidates(name);
+    let (mut strong, mut weak) = (Vec::new(), Vec::new());
+    for full in refs.iter().filter(|full| candidates.contains(full)) {
+        let spelled_out = full.len() == name.len() || full.len() == name.len() + 5;
+        match spelled_out || full.starts_with("refs/heads/") || full.starts_with("refs/tags/") {
+            true => strong.push(full),
+            false => weak.push(full),
+        }
+    }
+    let found = if strong.is_empty() { weak } else { strong };
+    match found.len() {
+        0 => Ok(None),
+        1 => Ok(Some(found[0])),
+        n => Err(n),
+    }
+}
+
+// The updates a push with `refspecs` makes of a remote that has
+// `remote_refs`, from the local refs `local_refs`, as git's
+// match_push_refs works them out. Explicit refspecs come first; patterns
+// and `:` then add refs not already pushed, leaving out any a negative
+// refspec excludes.
+pub(crate) fn refspec_push_map(
+    refspecs: &[Refspec],
+    local_refs: &[String],
+    remote_refs: &[String],
+) -> Result<Vec<RefMapping>, Box<dyn Error>> {
+    let mut mappings: Vec<RefMapping> = Vec::new();
+    let explicit = refspecs
+        .iter()
+        .filter(|refspec| !refspec.negative && !refspec.pattern && !refspec.matching);
+    for refspec in explicit {
+        let src = match refspec.src.as_str() {
+            "" => String::new(),
+            src => match push_match(src, local_refs) {
+                Ok(Some(full)) => full.clone(),
+                Ok(None) if is_hex_sha(src.as_bytes()) => src.to_string(),
+                Ok(None) => {
+                    return Err(From::from(format!(
+                        "src refspec {} does not match any",
+                        src
+                    )))
+                }
+                Err(_) => {
+                    return Err(From::from(format!(
+                        "src refspec {} matches more than one",
+                        src
+                    )))
+                }
+            },
+        };
+        let dst = match &refspec.dst {
+            Some(dst) => dst.clone(),
+            None if src.starts_with("refs/") => src.clone(),
+            None => return Err(From::from(format!("{} cannot be resolved to branch", src))),
+        };
+        let dst = match push_match(&dst, remote_refs) {
+            Ok(Some(full)) => full.clone(),
+            Ok(None) if dst.starts_with("refs/") => dst,
+            Ok(None) if src.is_empty() => {
+                return Err(From::from(format!(
+                    "unable to delete '{}': remote ref does not exist",
+                    dst
+                )))
+            }
+            // An unqualified name new to the remote is taken to be of the
+            // same kind as the source
+            Ok(None) => match ["refs/heads/", "refs/tags/"]
+                .into_iter()
+                .find(|prefix| src.starts_with(prefix))
+            {
+                Some(prefix) => format!("{}{}", prefix, dst),
+                None => {
+                    return Err(From::from(format!(
+                        "the destination '{}' of pushing '{}' is not a full refname",
+                        dst, src
+                    )))
+                }
+            },
+            Err(_) => {
+                return Err(From::from(format!(
+                    "dst refspec {} matches more than one",
+                    dst
+                )))
+            }
+        };
+        if mappings.iter().all(|mapping| mapping.dst != dst) {
+            mappings.push(RefMapping {
+                src,
+                dst,
+                force: refspec.force,
+            });
+        }
+    }
+
+    for name in local_refs {
+        if refspec_excludes(refspecs, name) {
+            continue;
+        }
+        // The first pattern that takes the ref in, pushing it to the same
+        // name if it has no destination, or else `:`
+        let matched = refspecs
+            .iter()
+            .filter(|refspec| !refspec.negative && refspec.pattern)
+            .find_map(|refspec| {
+                let dst = refspec.map(name)?;
+                Some((
+                    refspec.dst.as_ref().map_or(name.clone(), |_| dst),
+                    refspec.force,
+                ))
+            })
+            .or_else(|| {
+                let matching = refspecs
+                    .iter()
+                    .filter(|refspec| refspec.matching)
+                    .max_by_key(|refspec| refspec.force)?;
+                let wanted = name.starts_with("refs/heads/") && remote_refs.contains(name);
+                wanted.then(|| (name.clone(), matching.force))
+            });
+        let Some((dst, force)) = matched else {
+            continue;
+        };
+        if mappings.iter().all(|mapping| mapping.dst != dst) {
+            mappings.push(RefMapping {
+                src: name.clone(),
+                dst,
+                force,
+            });
+        }
+    }
+    Ok(mappings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHA: &str = "0123456789abcdef0123456789abcdef01234567";
+
+    fn names(list: &[&str]) -> Vec<String> {
+        list.iter().map(ToString::to_string).collect()
+    }
+
+    fn refspecs(list: &[&str], fetch: bool) -> Vec<Refspec> {
+        list.iter()
+            .map(|spec| Refspec::parse(spec, fetch).unwrap())
+            .collect()
+    }
+
+    fn mappings(list: &[(&str, &str, bool)]) -> Vec<RefMapping> {
+        list.iter()
+            .map(|&(src, dst, force)| RefMapping {
+                src: src.to_string(),
+                dst: dst.to_string(),
+                force,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn refspecs_parse_like_git() {
+        let spec = Refspec::parse("+refs/heads/*:refs/remotes/origin/*", true).unwrap();
+        assert!(spec.force && spec.pattern && !spec.negative);
+        assert_eq!(spec.src, "refs/heads/*");
+        assert_eq!(spec.dst.as_deref(), Some("refs/remotes/origin/*"));
+
+        let spec = Refspec::parse("main", true).unwrap();
+        assert!(!spec.force && !spec.pattern);
+        assert_eq!((spec.src.as_str(), spec.dst), ("main", None));
+        let spec = Refspec::parse("@:refs/heads/x", false).unwrap();
+        assert_eq!(spec.src, "HEAD");
+        let spec = Refspec::parse(SHA, true).unwrap();
+        assert!(spec.exact_sha);
+        let spec = Refspec::parse("^refs/heads/wip-*", true).unwrap();
+        assert!(spec.negative && spec.pattern);
+        assert!(Refspec::parse(":", false).unwrap().matching);
+        assert!(Refspec::parse("+:", false).unwrap().force);
+
+        for spec in [
+            "",
+            ":",
+            "main:",
+            "refs/heads/*-x:refs/x/*",
+            "refs/heads/x:",
+            ":refs/heads/x",
+            "^refs/heads/x",
+        ] {
+            assert!(Refspec::parse(spec, true).is_ok(), "fetch {:?}", spec);
+        }
+        for spec in [
+            "refs/heads/*",
+            "refs/heads/*:refs/x",
+            "refs/heads/x:refs/x/*",
+            "refs/*/*:refs/x/*",
+            "a..b",
+            "main:a..b",
+            "main:refs/heads/x:y",
+            "^",
+            "^main:x",
+            &format!("^{}", SHA),
+            "^a..b",
+            "+^main",
+            "::",
+        ] {
+            assert!(Refspec::parse(spec, true).is_err(), "fetch {:?}", spec);
+        }
+
+        for spec in [
+            "main",
+            "HEAD~1:refs/heads/x",
+            ":refs/heads/x",
+            "refs/heads/*",
+        ] {
+            assert!(Refspec::parse(spec, false).is_ok(), "push {:?}", spec);
+        }
+        for spec in ["", "main:", "HEAD~1", "main:a..b", "refs/heads/*:x"] {
+            assert!(Refspec::parse(spec, false).is_err(), "push {:?}", spec);
+        }
+        assert_eq!(
+            Refspec::parse("main:", false).unwrap_err().to_string(),
+            "invalid refspec 'main:'"
+        );
+    }
+
+    #[test]
+    fn refspecs_map_both_ways() {
+        let spec = Refspec::parse("refs/heads/*:refs/remotes/origin/*", true).unwrap();
+        assert!(spec.matches("refs/heads/main"));
+        assert!(!spec.matches("refs/tags/v1"));
+        assert_eq!(
+            spec.map("refs/heads/a/b").as_deref(),
+            Some("refs/remotes/origin/a/b")
+        );
+        assert_eq!(
+            spec.reverse("refs/remotes/origin/main").as_deref(),
+            Some("refs/heads/main")
+        );
+        assert_eq!(spec.reverse("refs/remotes/other/main"), None);
+
+        // A `*` in the middle matches what is between prefix and suffix
+        let spec = Refspec::parse("refs/heads/*-wip:refs/wip/*", true).unwrap();
+        assert_eq!(spec.map("refs/heads/x-wip").as_deref(), Some("refs/wip/x"));
+        assert_eq!(spec.map("refs/heads/-wip").as_deref(), Some("refs/wip/"));
+        assert_eq!(spec.map("refs/heads/wip"), None);
+        assert_eq!(spec.map("refs/heads/x-wip2"), None);
+
+        let spec = Refspec::parse("main:refs/remotes/origin/main", true).unwrap();
+        assert_eq!(
+            spec.map("main").as_deref(),
+            Some("refs/remotes/origin/main")
+        );
+        assert_eq!(spec.map("refs/heads/main"), None);
+        assert_eq!(
+            spec.reverse("refs/remotes/origin/main").as_deref(),
+            Some("main")
+        );
+        let spec = Refspec::parse("main", true).unwrap();
+        assert_eq!(spec.map("main").as_deref(), Some(""));
+        assert_eq!(spec.reverse("main"), None);
+    }
+
+    #[test]
+    fn fetches_map_remote_refs() {
+        let remote = names(&[
+            "HEAD",
+            "refs/heads/main",
+            "refs/heads/wip-1",
+            "refs/heads/topic",
+            "refs/remotes/topic",
+            "refs/tags/v1",
+            "refs/tags/v1^{}",
+            "refs/pull/1/head",
+        ]);
+        let map = |list: &[&str]| refspec_fetch_map(&refspecs(list, true), &remote);
+
+        assert_eq!(
+            map(&["+refs/heads/*:refs/remotes/origin/*", "^refs/heads/wip-*"]).unwrap(),
+            mappings(&[
+                ("refs/heads/main", "refs/remotes/origin/main", true),
+                ("refs/heads/topic", "refs/remotes/origin/topic", true),
+            ])
+        );
+        assert_eq!(
+            map(&["refs/tags/*:refs/tags/*"]).unwrap(),
+            mappings(&[("refs/tags/v1", "refs/tags/v1", false)])
+        );
+
+        // Explicit names are looked up as git abbreviates them, and short
+        // destinations are taken to be branches
+        assert_eq!(
+            map(&["main", "topic:t", "tags/v1:tags/v1", ":remotes/h", SHA]).unwrap(),
+            mappings(&[
+                ("refs/heads/main", "", false),
+                ("refs/heads/topic", "refs/heads/t", false),
+                ("refs/tags/v1", "refs/tags/v1", false),
+                ("HEAD", "refs/remotes/h", false),
+                (SHA, "", false),
+            ])
+        );
+        assert_eq!(
+            map(&["nope"]).unwrap_err().to_string(),
+            "couldn't find remote ref nope"
+        );
+
+        // Negative refspecs also leave out what is named explicitly
+        assert_eq!(map(&["topic", "^refs/heads/topic"]).unwrap(), []);
+
+        assert_eq!(
+            map(&["main:refs/x", "topic:refs/x"])
+                .unwrap_err()
+                .to_string(),
+            "refs/x tracks both refs/heads/main and refs/heads/topic"
+        );
+        assert_eq!(
+            map(&["main:refs/x", "refs/heads/main:refs/x"]).unwrap(),
+            mappings(&[("refs/heads/main", "refs/x", false)])
+        );
+    }
+
+    #[test]
+    fn pushes_map_local_refs() {
+        let local = names(&[
+            "refs/heads/main",
+            "refs/remotes/main",
+            "refs/heads/topic",
+            "refs/heads/wip",
+            "refs/remotes/origin/main",
+            "refs/remotes/origin/only",
+            "refs/tags/v1",
+        ]);
+        let remote = names(&["refs/heads/main", "refs/heads/wip", "refs/heads/old"]);
+        let map = |list: &[&str]| refspec_push_map(&refspecs(list, false), &local, &remote);
+
+        // A short source means the branch over the remote-tracking branch
+        assert_eq!(
+            map(&["main", "+topic:refs/heads/t", "v1"]).unwrap(),
+            mappings(&[
+                ("refs/heads/main", "refs/heads/main", false),
+                ("refs/heads/topic", "refs/heads/t", true),
+                ("refs/tags/v1", "refs/tags/v1", false),
+            ])
+        );
+        assert_eq!(
+            map(&["origin/only:refs/heads/only"]).unwrap(),
+            mappings(&[("refs/remotes/origin/only", "refs/heads/only", false)])
+        );
+        assert_eq!(
+            map(&["v1:release", "main:old", "main:main"]).unwrap(),
+            mappings(&[
+                ("refs/tags/v1", "refs/tags/release", false),
+                ("refs/heads/main", "refs/heads/old", false),
+                ("refs/heads/main", "refs/heads/main", false),
+            ])
+        );
+        assert_eq!(
+            map(&[&format!("{}:refs/heads/x", SHA), ":old"]).unwrap(),
+            mappings(&[(SHA, "refs/heads/x", false), ("", "refs/heads/old", false)])
+        );
+
+        for (spec, error) in [
+            ("nope", "src refspec nope does not match any"),
+            (
+                ":gone",
+                "unable to delete 'gone': remote ref does not exist",
+            ),
+            (
+                "origin/main:new",
+                "the destination 'new' of pushing 'refs/remotes/origin/main' is not a full refname",
+            ),
+        ] {
+            assert_eq!(map(&[spec]).unwrap_err().to_string(), error, "{}", spec);
+        }
+        let ambiguous = names(&["refs/remotes/x", "refs/remotes/x/HEAD", "refs/heads/y"]);
+        let spec = refspecs(&["x:refs/heads/x"], false);
+        assert_eq!(
+            refspec_push_map(&spec, &ambiguous, &remote)
+                .unwrap_err()
+                .to_string(),
+            "src refspec x matches more than one"
+        );
+
+        // `:` pushes the branches the remote has too, patterns any they
+        // take in, and explicit refspecs win over both
+        assert_eq!(
+            map(&[":"]).unwrap(),
+            mappings(&[
+                ("refs/heads/main", "refs/heads/main", false),
+                ("refs/heads/wip", "refs/heads/wip", false),
+            ])
+        );
+        assert_eq!(
+            map(&[
+                "refs/heads/*:refs/heads/*",
+                "^refs/heads/wip",
+                "+topic:refs/heads/main"
+            ])
+            .unwrap(),
+            mappings(&[
+                ("refs/heads/topic", "refs/heads/main", true),
+                ("refs/heads/topic", "refs/heads/topic", false),
+            ])
+        );
+        assert_eq!(
+            map(&["refs/heads/*"]).unwrap(),
+            mappings(&[
+                ("refs/heads/main", "refs/heads/main", false),
+                ("refs/heads/topic", "refs/heads/topic", false),
+                ("refs/heads/wip", "refs/heads/wip", false),
+            ])
+        );
+    }
+}